@@ -0,0 +1,59 @@
+use automerge::get_automerge_actions;
+use criterion::{criterion_group, criterion_main, Criterion};
+use rich_text::{rich_text::RichText, Behavior, Expand, InternalString, Style, VersionVector};
+mod automerge;
+
+fn bold() -> Style {
+    Style {
+        expand: Expand::After,
+        behavior: Behavior::Merge,
+        type_: InternalString::from("bold"),
+        value: serde_json::Value::Null,
+    }
+}
+
+fn build_doc() -> RichText {
+    let actions = get_automerge_actions();
+    let mut text = RichText::new(1);
+    for (i, action) in actions.iter().enumerate() {
+        if action.del > 0 {
+            text.delete(action.pos..action.pos + action.del);
+        }
+        if !action.ins.is_empty() {
+            text.insert(action.pos, &action.ins);
+        }
+        if i % 1000 == 0 && text.len() > 10 {
+            let start = action.pos.min(text.len() - 10);
+            text.annotate(start..start + 10, bold()).unwrap();
+        }
+    }
+    text
+}
+
+pub fn bench(c: &mut Criterion) {
+    let text = build_doc();
+    let updates = text.export(&VersionVector::default()).unwrap();
+    let snapshot = text.export_snapshot();
+
+    c.bench_function("automerge export updates", |b| {
+        b.iter(|| text.export(&VersionVector::default()).unwrap())
+    });
+
+    c.bench_function("automerge export snapshot", |b| {
+        b.iter(|| text.export_snapshot())
+    });
+
+    c.bench_function("automerge import updates", |b| {
+        b.iter(|| {
+            let mut doc = RichText::new(2);
+            doc.import(&updates).unwrap();
+        })
+    });
+
+    c.bench_function("automerge load snapshot", |b| {
+        b.iter(|| RichText::from_snapshot(2, &snapshot).unwrap())
+    });
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -71,7 +71,7 @@ pub fn main() {
                 text.insert(action.pos, &action.ins)
             }
         }
-        let data = text.export(&Default::default());
+        let data = text.export(&Default::default()).unwrap();
         println!("Size = {}", data.len());
     } else {
         println!("Running on random generated actions 10k");
@@ -1,5 +1,5 @@
 #![no_main]
 use libfuzzer_sys::fuzz_target;
-use rich_text::legacy::test_utils::{fuzzing, Action};
+use peritext::rich_text::test_utils::{fuzzing, Action};
 
 fuzz_target!(|actions: [Action; 100]| { fuzzing(5, actions.to_vec()) });
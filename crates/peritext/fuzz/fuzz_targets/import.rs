@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use peritext::rich_text::{vv::VersionVector, RichText};
+
+fuzz_target!(|data: &[u8]| {
+    let mut text = RichText::new(1);
+    text.insert(0, "123");
+    let _ = text.import(data);
+    let _ = VersionVector::decode(data);
+    let _ = RichText::from_snapshot(1, data);
+});
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use peritext::rich_text::test_utils::{fuzzing_undo_all, Action};
+
+fuzz_target!(|actions: Vec<Action>| { fuzzing_undo_all(actions) });
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use peritext::rich_text::test_utils::{fuzzing_undo, UndoFuzzAction};
+
+fuzz_target!(|actions: Vec<UndoFuzzAction>| { fuzzing_undo(5, actions) });
@@ -1,6 +1,6 @@
 use std::ops::RangeBounds;
 
-use crate::{formatting::Formatting, Behavior, Expand, RichText, Style};
+use crate::{formatting::Formatting, Behavior, Error, Expand, RichText, Style};
 
 pub struct Actor {
     pub text: RichText,
@@ -21,7 +21,11 @@ impl Actor {
         self.text.delete(pos..pos + len)
     }
 
-    pub fn annotate(&mut self, range: impl RangeBounds<usize>, type_: Formatting) {
+    pub fn annotate(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        type_: Formatting,
+    ) -> Result<(), Error> {
         match type_ {
             Formatting::Bold => self.text.annotate(
                 range,
@@ -68,8 +72,8 @@ impl Actor {
                     value: serde_json::Value::Null,
                 },
             ),
-            _ => {}
-        };
+            _ => Ok(()),
+        }
     }
 
     fn merge(&mut self, other: &Self) {
@@ -87,7 +91,9 @@ mod tests {
 
         actor.insert(0, "Hello, this is Hector");
 
-        actor.annotate(.., Formatting::Comment("This is a comment".to_string()));
+        actor
+            .annotate(.., Formatting::Comment("This is a comment".to_string()))
+            .unwrap();
 
         let spans = actor.text.get_spans();
 
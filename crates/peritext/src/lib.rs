@@ -14,12 +14,14 @@ use std::{
     sync::Arc,
 };
 
-use rich_text::Error;
+pub use rich_text::Error;
 pub use rich_text::{vv::VersionVector, RichText};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use string_cache::DefaultAtom;
 mod small_set;
+#[cfg(feature = "sim")]
+pub mod sim;
 #[cfg(feature = "test")]
 mod test_utils;
 
@@ -90,7 +92,7 @@ pub enum RangeOp {
     Annotate(Annotation),
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub enum AnchorType {
     Before,
     After,
@@ -111,16 +113,30 @@ pub enum Behavior {
     Delete = 1,
 }
 
-/// If both `move_start_to` and `move_end_to` equal to None, the target range will be deleted
-#[derive(Clone, Copy, Debug)]
+/// An edit of the annotation created by `target_range_id`
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Patch {
     pub id: OpID,
     pub target_range_id: OpID,
-    pub move_start_to: Option<OpID>,
-    pub move_end_to: Option<OpID>,
+    pub action: PatchAction,
     pub lamport: Lamport,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchAction {
+    /// Remove the annotation. It wins over the concurrent patches of the annotation,
+    /// except a [PatchAction::Restore] with a greater lamport
+    Remove,
+    /// Replace the value of the annotation. The patch with the greater lamport wins
+    SetValue(Value),
+    /// Move the anchors of the annotation to the new range. The anchor types are kept.
+    /// The patch wins if its lamport is greater than the `range_lamport` of the annotation
+    Move(AnchorRange),
+    /// Restore the removed annotation with its id, and move it to the new range like
+    /// [PatchAction::Move]. The removal or the restoration with the greater lamport wins
+    Restore(AnchorRange),
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Annotation {
     pub id: OpID,
@@ -196,27 +212,14 @@ impl TryFrom<Option<&str>> for Expand {
 }
 
 impl Expand {
+    #[deprecated = "use the `expand` of `StyleSchema::get` instead"]
     pub fn infer_insert_expand(type_: &str) -> Self {
-        match type_ {
-            "comment" => Self::None,
-            "header" => Self::None,
-            "indent" => Self::None,
-            "list" => Self::None,
-            "align" => Self::None,
-            "direction" => Self::None,
-            "code-block" => Self::None,
-            "code" => Self::None,
-            "link" => Self::None,
-            "script" => Self::None,
-            "formula" => Self::None,
-            "image" => Self::None,
-            "video" => Self::None,
-            _ => Self::After,
-        }
+        rich_text::StyleSchema::default().get(type_).expand
     }
 
+    #[deprecated = "use the toggled `expand` of `StyleSchema::get` instead"]
     pub fn infer_delete_expand(type_: &str) -> Self {
-        Self::infer_insert_expand(type_).toggle()
+        rich_text::StyleSchema::default().get(type_).expand.toggle()
     }
 
     /// For a target format, the Expand type of insertion is different
@@ -260,20 +263,6 @@ pub struct Style {
 }
 
 impl Style {
-    pub(crate) fn new_from_expand(
-        expand: Expand,
-        type_: InternalString,
-        value: Value,
-        behavior: Behavior,
-    ) -> Result<Self, Error> {
-        Ok(Style {
-            expand,
-            behavior,
-            type_,
-            value,
-        })
-    }
-
     pub fn new_bold_like(type_: InternalString, value: Value) -> Self {
         Self {
             expand: Expand::After,
@@ -5,17 +5,25 @@ use serde_json::Value;
 use smallvec::SmallVec;
 use std::{mem::take, sync::Arc};
 
-use crate::{small_set::SmallSetI32, AnchorType, Annotation, Behavior, InternalString, OpID};
+use crate::{
+    small_set::SmallSetI32, AnchorRange, AnchorType, Annotation, Behavior, InternalString, Lamport,
+    OpID,
+};
 
 use super::rich_tree::{CacheDiff, Elem};
 
 /// Use negative to represent deletions
 pub type AnnIdx = i32;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct AnnManager {
     idx_to_ann: Vec<Arc<Annotation>>,
     id_to_idx: FxHashMap<OpID, AnnIdx>,
+    /// the lamport of the patch that set the current value of the annotation
+    value_lamport: FxHashMap<AnnIdx, (Lamport, OpID)>,
+    /// the lamport of the patch that removed or restored the annotation last
+    removal_lamport: FxHashMap<AnnIdx, (Lamport, OpID)>,
+    removed: FxHashSet<AnnIdx>,
 }
 
 impl AnnManager {
@@ -42,18 +50,93 @@ impl AnnManager {
         self.idx_to_ann.get(idx as usize)
     }
 
-    #[allow(unused)]
     #[inline(always)]
     pub fn get_ann_by_id(&self, id: OpID) -> Option<&Arc<Annotation>> {
         let idx = self.id_to_idx.get(&id)?;
         self.idx_to_ann.get(*idx as usize)
     }
 
-    #[allow(unused)]
     #[inline(always)]
     pub fn get_idx_by_id(&self, id: OpID) -> Option<AnnIdx> {
         self.id_to_idx.get(&id).copied()
     }
+
+    /// Iterate all the annotations in the order of their idx, including the removed ones
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Annotation>> + '_ {
+        // skip the placeholder at the zero pos
+        self.idx_to_ann.iter().skip(1)
+    }
+
+    /// Iterate the removed annotations
+    pub fn iter_removed(&self) -> impl Iterator<Item = &Arc<Annotation>> + '_ {
+        self.removed
+            .iter()
+            .map(|idx| &self.idx_to_ann[*idx as usize])
+    }
+
+    #[inline(always)]
+    pub fn is_removed(&self, idx: AnnIdx) -> bool {
+        self.removed.contains(&idx)
+    }
+
+    #[inline(always)]
+    pub fn get_removal_lamport(&self, idx: AnnIdx) -> Option<(Lamport, OpID)> {
+        self.removal_lamport.get(&idx).copied()
+    }
+
+    /// Remove or restore the annotation if the lamport is greater than the lamport
+    /// of the last removal or restoration.
+    ///
+    /// It only updates the annotation, the anchors in the content tree should be updated by the caller.
+    /// Return whether the state is replaced
+    pub fn set_removed(&mut self, idx: AnnIdx, removed: bool, lamport: (Lamport, OpID)) -> bool {
+        if matches!(self.removal_lamport.get(&idx), Some(last) if *last >= lamport) {
+            return false;
+        }
+
+        self.removal_lamport.insert(idx, lamport);
+        if removed {
+            self.removed.insert(idx);
+        } else {
+            self.removed.remove(&idx);
+        }
+        true
+    }
+
+    #[inline(always)]
+    pub fn get_value_lamport(&self, idx: AnnIdx) -> Option<(Lamport, OpID)> {
+        self.value_lamport.get(&idx).copied()
+    }
+
+    /// Replace the value of the annotation if the lamport is greater than the
+    /// lamport of the current value.
+    ///
+    /// Return whether the value is replaced
+    pub fn set_value(&mut self, idx: AnnIdx, value: Value, lamport: (Lamport, OpID)) -> bool {
+        if matches!(self.value_lamport.get(&idx), Some(last) if *last >= lamport) {
+            return false;
+        }
+
+        self.value_lamport.insert(idx, lamport);
+        Arc::make_mut(&mut self.idx_to_ann[idx as usize]).value = value;
+        true
+    }
+
+    /// Replace the range of the annotation if the lamport is greater than its `range_lamport`.
+    ///
+    /// It only updates the annotation, the anchors in the content tree should be moved by the caller.
+    /// Return whether the range is replaced
+    pub fn set_range(&mut self, idx: AnnIdx, range: AnchorRange, lamport: (Lamport, OpID)) -> bool {
+        let ann = &mut self.idx_to_ann[idx as usize];
+        if ann.range_lamport >= lamport {
+            return false;
+        }
+
+        let ann = Arc::make_mut(ann);
+        ann.range = range;
+        ann.range_lamport = lamport;
+        true
+    }
 }
 
 /// The annotated text span.
@@ -62,6 +145,10 @@ pub struct Span {
     // TODO: use byte slice
     pub insert: String,
     pub attributes: FxHashMap<InternalString, Value>,
+    /// The payload if the span is an embed, whose `insert` is the
+    /// [super::EMBED_CHAR] placeholder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed: Option<Value>,
 }
 
 impl Span {
@@ -80,7 +167,7 @@ impl Span {
 
 impl Mergeable for Span {
     fn can_merge(&self, rhs: &Self) -> bool {
-        self.attributes == rhs.attributes
+        self.attributes == rhs.attributes && self.embed.is_none() && rhs.embed.is_none()
     }
 
     fn merge_right(&mut self, rhs: &Self) {
@@ -197,6 +284,13 @@ impl CacheAnchorSet {
 }
 
 impl ElemAnchorSet {
+    pub fn is_empty(&self) -> bool {
+        self.start_before.is_empty()
+            && self.end_before.is_empty()
+            && self.start_after.is_empty()
+            && self.end_after.is_empty()
+    }
+
     pub fn has_start_before(&self) -> bool {
         !self.start_before.is_empty()
     }
@@ -218,6 +312,30 @@ impl ElemAnchorSet {
         (a || b, b)
     }
 
+    /// Iterate all the anchors as (ann, anchor type, is_start)
+    pub fn iter(&self) -> impl Iterator<Item = (AnnIdx, AnchorType, bool)> + '_ {
+        let start_before = self
+            .start_before
+            .iter()
+            .map(|x| (*x, AnchorType::Before, true));
+        let start_after = self
+            .start_after
+            .iter()
+            .map(|x| (*x, AnchorType::After, true));
+        let end_before = self
+            .end_before
+            .iter()
+            .map(|x| (*x, AnchorType::Before, false));
+        let end_after = self
+            .end_after
+            .iter()
+            .map(|x| (*x, AnchorType::After, false));
+        start_before
+            .chain(start_after)
+            .chain(end_before)
+            .chain(end_after)
+    }
+
     pub fn insert_ann(&mut self, idx: AnnIdx, type_: AnchorType, is_start: bool) {
         if is_start {
             match type_ {
@@ -232,6 +350,21 @@ impl ElemAnchorSet {
         }
     }
 
+    /// Return whether the anchor is found
+    pub fn remove_ann(&mut self, idx: AnnIdx, type_: AnchorType, is_start: bool) -> bool {
+        if is_start {
+            match type_ {
+                AnchorType::Before => self.start_before.remove(&idx),
+                AnchorType::After => self.start_after.remove(&idx),
+            }
+        } else {
+            match type_ {
+                AnchorType::Before => self.end_before.remove(&idx),
+                AnchorType::After => self.end_after.remove(&idx),
+            }
+        }
+    }
+
     pub(crate) fn split(&mut self) -> ElemAnchorSet {
         ElemAnchorSet {
             start_before: Default::default(),
@@ -304,6 +437,13 @@ impl AnchorSetDiff {
         diff.insert(ann, is_start);
         diff
     }
+
+    /// The diff that removes the anchor of the annotation
+    pub fn from_removed_ann(ann: AnnIdx, is_start: bool) -> AnchorSetDiff {
+        let mut diff = AnchorSetDiff::default();
+        diff.insert(-ann, is_start);
+        diff
+    }
 }
 
 impl From<AnchorSetDiff> for CacheDiff {
@@ -312,6 +452,7 @@ impl From<AnchorSetDiff> for CacheDiff {
             anchor_diff: value,
             len_diff: 0,
             utf16_len_diff: 0,
+            unicode_len_diff: 0,
             line_break_diff: 0,
         }
     }
@@ -322,6 +463,11 @@ pub struct StyleCalculator {
     inner: FxHashSet<AnnIdx>,
     cached_start_after: FxHashSet<AnnIdx>,
     cached_end_after: FxHashSet<AnnIdx>,
+    /// The annotations whose end anchor was visited before their start anchor.
+    ///
+    /// They can only be the styles that start from the very beginning of the doc,
+    /// which is needed when the calculation doesn't start from the doc start
+    unmatched_ends: FxHashSet<AnnIdx>,
 }
 
 impl StyleCalculator {
@@ -329,6 +475,10 @@ impl StyleCalculator {
         self.inner.insert(start);
     }
 
+    pub fn remove_start(&mut self, start: AnnIdx) {
+        self.inner.remove(&start);
+    }
+
     pub fn apply_node_start(&mut self, anchor_set: &CacheAnchorSet) {
         if !anchor_set.start.is_empty() {
             for ann in anchor_set.start.iter() {
@@ -340,7 +490,7 @@ impl StyleCalculator {
     pub fn apply_node_end(&mut self, anchor_set: &CacheAnchorSet) {
         if !anchor_set.end.is_empty() {
             for ann in anchor_set.end.iter() {
-                self.inner.remove(ann);
+                self.remove(*ann);
             }
         }
     }
@@ -353,7 +503,7 @@ impl StyleCalculator {
         }
         if !anchor_set.end_before.is_empty() {
             for ann in anchor_set.end_before.iter() {
-                self.inner.remove(ann);
+                self.remove(*ann);
             }
         }
     }
@@ -366,7 +516,7 @@ impl StyleCalculator {
         }
         if !anchor_set.end_after.is_empty() {
             for ann in anchor_set.end_after.iter() {
-                self.inner.remove(ann);
+                self.remove(*ann);
             }
         }
     }
@@ -394,12 +544,34 @@ impl StyleCalculator {
 
         if !self.cached_end_after.is_empty() {
             for ann in self.cached_end_after.iter() {
-                self.inner.remove(ann);
+                if !self.inner.remove(ann) {
+                    self.unmatched_ends.insert(*ann);
+                }
             }
             self.cached_end_after.clear();
         }
     }
 
+    #[inline]
+    fn remove(&mut self, ann: AnnIdx) {
+        if !self.inner.remove(&ann) {
+            self.unmatched_ends.insert(ann);
+        }
+    }
+
+    /// Add the styles that start from the very beginning of the doc and have not
+    /// ended yet at the current position.
+    ///
+    /// The queries start their calculation from an empty set, so the result needs
+    /// to be completed with the init styles of the doc.
+    pub fn apply_init_styles(&mut self, init: &StyleCalculator) {
+        for ann in init.inner.iter() {
+            if !self.unmatched_ends.contains(ann) {
+                self.inner.insert(*ann);
+            }
+        }
+    }
+
     #[allow(unused)]
     pub fn iter(&self) -> impl Iterator<Item = &AnnIdx> {
         self.inner.iter()
@@ -0,0 +1,126 @@
+//! Query the annotations and their current ranges.
+
+use std::ops::{Bound, Range, RangeBounds};
+
+use crate::{Annotation, OpID};
+
+use super::{
+    ann::AnnIdx,
+    rich_tree::query::{AnnotationFinderEnd, AnnotationFinderStart},
+    IndexType, RichText,
+};
+
+impl RichText {
+    /// Get all the annotations with their current utf8 ranges, in the order they are applied.
+    ///
+    /// The annotations that remove styles, e.g. an unbold, are included as well.
+    /// The removed annotations are not included.
+    pub fn annotations(&self) -> Vec<(OpID, Annotation, Range<usize>)> {
+        self.ann
+            .iter()
+            .filter_map(|ann| {
+                let range = self.annotation_range_with(ann.id, IndexType::Utf8)?;
+                Some((ann.id, (**ann).clone(), range))
+            })
+            .collect()
+    }
+
+    /// Get the annotations that overlap with the given range.
+    ///
+    /// The ranges of the returned annotations use the same index type. An empty
+    /// annotation is included if it's inside the range, and an empty range
+    /// includes the annotations that contain it.
+    pub fn annotations_in_range(
+        &self,
+        range: impl RangeBounds<usize>,
+        index_type: IndexType,
+    ) -> Vec<(OpID, Annotation, Range<usize>)> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len_with(index_type),
+        };
+
+        self.ann
+            .iter()
+            .filter_map(|ann| {
+                let range = self.annotation_range_with(ann.id, index_type)?;
+                overlap(&range, &(start..end)).then(|| (ann.id, (**ann).clone(), range))
+            })
+            .collect()
+    }
+
+    /// Get the current utf8 range of the annotation.
+    ///
+    /// Return `None` if the annotation is unknown or removed.
+    pub fn annotation_range(&self, id: OpID) -> Option<Range<usize>> {
+        self.annotation_range_with(id, IndexType::Utf8)
+    }
+
+    /// Get the current utf16 range of the annotation, see [RichText::annotation_range]
+    pub fn annotation_range_utf16(&self, id: OpID) -> Option<Range<usize>> {
+        self.annotation_range_with(id, IndexType::Utf16)
+    }
+
+    /// Get the current range of the annotation in chars, see [RichText::annotation_range]
+    pub fn annotation_range_unicode(&self, id: OpID) -> Option<Range<usize>> {
+        self.annotation_range_with(id, IndexType::Unicode)
+    }
+
+    pub(crate) fn annotation_range_with(
+        &self,
+        id: OpID,
+        index_type: IndexType,
+    ) -> Option<Range<usize>> {
+        let idx = self.ann.get_idx_by_id(id)?;
+        if self.ann.is_removed(idx) {
+            return None;
+        }
+
+        let ann = self.ann.get_ann_by_idx(idx)?;
+        let start = match ann.range.start.id {
+            Some(_) => self
+                .find_ann_start(idx, index_type)
+                .unwrap_or_else(|| self.anchor_to_index(&ann.range.start, index_type)),
+            None => self.anchor_to_index(&ann.range.start, index_type),
+        };
+        let end = match ann.range.end.id {
+            Some(_) => self
+                .find_ann_end(idx, index_type)
+                .unwrap_or_else(|| self.anchor_to_index(&ann.range.end, index_type)),
+            None => self.anchor_to_index(&ann.range.end, index_type),
+        };
+
+        // the chars between the anchors may be deleted
+        Some(start..end.max(start))
+    }
+
+    fn find_ann_start(&self, idx: AnnIdx, index_type: IndexType) -> Option<usize> {
+        let (path, finder) = self
+            .content
+            .query_with_finder_return::<AnnotationFinderStart>(&(idx, index_type));
+        path.found.then_some(finder.visited_len)
+    }
+
+    fn find_ann_end(&self, idx: AnnIdx, index_type: IndexType) -> Option<usize> {
+        let (path, finder) = self
+            .content
+            .query_with_finder_return::<AnnotationFinderEnd>(&(idx, index_type));
+        path.found
+            .then(|| self.len_with(index_type) - finder.visited_len)
+    }
+}
+
+/// Whether the ranges overlap. An empty range overlaps with the ranges that contain it.
+fn overlap(a: &Range<usize>, b: &Range<usize>) -> bool {
+    if a.is_empty() || b.is_empty() {
+        a.start <= b.end && b.start <= a.end
+    } else {
+        a.start < b.end && b.start < a.end
+    }
+}
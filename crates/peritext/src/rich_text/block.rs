@@ -0,0 +1,166 @@
+//! Paragraph attributes.
+//!
+//! The styles of [StyleKind::Block], e.g. headers and lists, are the attributes of
+//! the lines. Like in Quill, they are set on the line break at the end of the line,
+//! by annotations that only cover the line break. So they are merged like the other
+//! annotations: the concurrent values of the same attribute are ordered by lamport,
+//! and the different attributes of a line are kept together. When two lines are
+//! joined, the line keeps the attributes of the remaining line break.
+
+use std::ops::Range;
+
+use fxhash::FxHashMap;
+use serde_json::Value;
+
+use crate::{InternalString, Style};
+
+use super::{
+    ann::Span, rich_tree::query::LineStartFinder, DeltaItem, Error, IndexType, RichText, StyleKind,
+};
+
+/// A line of the document
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Line {
+    /// The spans of the line, including its line break
+    pub spans: Vec<Span>,
+    /// The block attributes of the line
+    pub attributes: FxHashMap<InternalString, Value>,
+}
+
+impl RichText {
+    /// Set the block attribute of the line, e.g. to turn it into a header. A `null`
+    /// value removes the attribute.
+    ///
+    /// The last line gets a line break if it doesn't have one, as the lines in Quill.
+    /// Return an error if the line doesn't exist, the type isn't a [StyleKind::Block]
+    /// style of the [super::StyleSchema], or the value can't be converted to the
+    /// value schema of the type.
+    pub fn format_line(&mut self, line: usize, type_: &str, value: Value) -> Result<(), Error> {
+        if line >= self.lines() {
+            return Err(Error::LineOutOfRange(line));
+        }
+        if self.schema.get(type_).kind != StyleKind::Block {
+            return Err(Error::NotBlockStyle(type_.into()));
+        }
+
+        let style = self.schema.style(type_, value)?;
+        let index = match self.line_break_index(line) {
+            Some(index) => index,
+            None => {
+                let len = self.len();
+                self.insert(len, "\n");
+                len
+            }
+        };
+
+        self.annotate_inner(index..index + 1, style, IndexType::Utf8);
+        Ok(())
+    }
+
+    /// Get the block attributes of the line
+    pub fn line_attributes(&self, line: usize) -> FxHashMap<InternalString, Value> {
+        match self.line_break_index(line) {
+            Some(index) => {
+                let span = self.slice(index..index + 1, IndexType::Utf8).pop().unwrap();
+                self.block_attributes(span.attributes)
+            }
+            None => FxHashMap::default(),
+        }
+    }
+
+    /// Get the document as a delta of insertions, e.g. to set the content of the editor.
+    ///
+    /// The block attributes are on the line breaks and the embeds are `{"insert": <payload>}`
+    /// items, as in Quill.
+    pub fn to_delta(&self, index_type: IndexType) -> Vec<DeltaItem> {
+        self.iter()
+            .map(|span| {
+                let attributes = (!span.attributes.is_empty()).then(|| {
+                    span.attributes
+                        .into_iter()
+                        .map(|(key, value)| (key.to_string(), value))
+                        .collect()
+                });
+                match (span.embed, attributes) {
+                    (Some(embed), None) => DeltaItem::embed(embed),
+                    (Some(embed), Some(attributes)) => {
+                        DeltaItem::embed_with_attributes(embed, attributes)
+                    }
+                    (None, None) => DeltaItem::insert(span.insert, index_type),
+                    (None, Some(attributes)) => {
+                        DeltaItem::insert_with_attributes(span.insert, index_type, attributes)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Annotate the range with the style from the schema. The block styles are
+    /// only set on the line breaks inside the range.
+    pub(super) fn annotate_by_kind(
+        &mut self,
+        range: Range<usize>,
+        style: Style,
+        index_type: IndexType,
+    ) {
+        if self.schema.get(&style.type_).kind == StyleKind::Inline {
+            self.annotate_inner(range, style, index_type);
+            return;
+        }
+
+        for index in self.line_breaks_in(range, index_type) {
+            self.annotate_inner(index..index + 1, style.clone(), index_type);
+        }
+    }
+
+    pub(super) fn to_line(&self, spans: Vec<Span>) -> Line {
+        let attributes = match spans.last() {
+            Some(span) if span.insert.ends_with('\n') => {
+                self.block_attributes(span.attributes.clone())
+            }
+            _ => FxHashMap::default(),
+        };
+
+        Line { spans, attributes }
+    }
+
+    /// The utf8 index of the line break at the end of the line.
+    ///
+    /// Return `None` if it's the last line, which has no line break
+    fn line_break_index(&self, line: usize) -> Option<usize> {
+        let next_line = self.content.query::<LineStartFinder>(&(line + 1));
+        if !next_line.found {
+            return None;
+        }
+
+        Some(self.get_index_from_path(next_line, IndexType::Utf8) - 1)
+    }
+
+    fn line_breaks_in(&self, range: Range<usize>, index_type: IndexType) -> Vec<usize> {
+        let mut ans = Vec::new();
+        let mut index = range.start;
+        for span in self.iter_range(range, index_type) {
+            for c in span.insert.chars() {
+                if c == '\n' {
+                    ans.push(index);
+                }
+
+                index += match index_type {
+                    IndexType::Utf8 => c.len_utf8(),
+                    IndexType::Utf16 => c.len_utf16(),
+                    IndexType::Unicode => 1,
+                };
+            }
+        }
+
+        ans
+    }
+
+    fn block_attributes(
+        &self,
+        mut attributes: FxHashMap<InternalString, Value>,
+    ) -> FxHashMap<InternalString, Value> {
+        attributes.retain(|key, _| self.schema.get(key).kind == StyleKind::Block);
+        attributes
+    }
+}
@@ -0,0 +1,53 @@
+//! Time travel: materialize the document at a previous version.
+
+use std::{
+    mem::take,
+    sync::{Arc, Mutex},
+};
+
+use super::{delta::compose, vv::VersionVector, DeltaItem, RichText};
+
+impl RichText {
+    /// Create a document that only contains the ops covered by `vv`.
+    ///
+    /// The returned document is meant to be read. It uses the same client id as
+    /// this document, so it should not be edited and merged back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the history of this document is trimmed, i.e. it's loaded from a
+    /// snapshot or compacted.
+    pub fn checkout(&self, vv: &VersionVector) -> RichText {
+        assert!(
+            self.store.trimmed_vv().vv.is_empty(),
+            "Cannot checkout a document whose history is trimmed"
+        );
+        let mut doc = RichText::new(self.id());
+        doc.event_index_type = self.event_index_type;
+        doc.import_inner(self.store.export_between(&Default::default(), vv))
+            .unwrap();
+        doc
+    }
+
+    /// Get the delta that turns the document at `from` into the document at `to`.
+    ///
+    /// `from` should be included by `to`. The delta uses the event index type,
+    /// see [RichText::set_event_index_type].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the history of this document is trimmed.
+    pub fn diff(&self, from: &VersionVector, to: &VersionVector) -> Vec<DeltaItem> {
+        let mut doc = self.checkout(from);
+        let delta = Arc::new(Mutex::new(Vec::new()));
+        let delta_clone = delta.clone();
+        doc.observe(Box::new(move |event| {
+            let mut delta = delta_clone.lock().unwrap();
+            *delta = compose(take(&mut *delta), event.ops.clone());
+        }))
+        .detach();
+        doc.import_inner(self.store.export_between(from, to))
+            .unwrap();
+        take(&mut *delta.lock().unwrap())
+    }
+}
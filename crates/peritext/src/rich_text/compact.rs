@@ -0,0 +1,390 @@
+//! Compaction of the history and the tombstones.
+//!
+//! When all the peers have reached a version, the ops before it will never be
+//! requested again, and the chars deleted before it are invisible to everyone.
+//! [RichText::compact] drops these ops and tombstones.
+//!
+//! The tombstones that the known ops use as their origins are kept until every
+//! peer's version covers these ops. Otherwise the peers that haven't compacted
+//! would order the concurrent insertions around them differently.
+//!
+//! The peers may still send ops that refer to the removed tombstones, e.g. as the
+//! origins of their insertions. These references are redirected to the nearest
+//! chars that were kept when the tombstones were removed. Because the removed chars
+//! are invisible, the redirected op is inserted at the same visible position.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use append_only_bytes::AppendOnlyBytes;
+use fxhash::FxHashMap;
+use generic_btree::BTree;
+
+use crate::{Anchor, AnchorRange, AnchorType, ClientID, Counter, OpID, PatchAction};
+
+use super::{
+    cursor::CursorMap,
+    op::{Op, OpContent},
+    rich_tree::{rich_tree_btree_impl::RichTreeTrait, Elem},
+    vv::VersionVector,
+    RichText,
+};
+
+/// A span of ids whose tombstones are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct RemovedSpan {
+    pub len: usize,
+    /// the nearest kept char on the left when the span is removed
+    pub left: Option<OpID>,
+    /// the nearest kept char on the right when the span is removed
+    pub right: Option<OpID>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub(super) struct RemovedSpans {
+    map: FxHashMap<ClientID, BTreeMap<Counter, RemovedSpan>>,
+}
+
+impl RemovedSpans {
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn insert(&mut self, id: OpID, span: RemovedSpan) {
+        self.map
+            .entry(id.client)
+            .or_default()
+            .insert(id.counter, span);
+    }
+
+    /// Get the removed span that contains the id, and the offset of the id inside it
+    pub fn get(&self, id: OpID) -> Option<(&RemovedSpan, usize)> {
+        let (counter, span) = self.map.get(&id.client)?.range(..=id.counter).next_back()?;
+        let offset = (id.counter - counter) as usize;
+        if offset < span.len {
+            Some((span, offset))
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (OpID, &RemovedSpan)> + '_ {
+        self.map.iter().flat_map(|(client, tree)| {
+            tree.iter()
+                .map(|(counter, span)| (OpID::new(*client, *counter), span))
+        })
+    }
+
+    fn resolve_left(&self, mut id: Option<OpID>) -> Option<OpID> {
+        while let Some((span, _)) = id.and_then(|id| self.get(id)) {
+            id = span.left;
+        }
+
+        id
+    }
+
+    fn resolve_right(&self, mut id: Option<OpID>) -> Option<OpID> {
+        while let Some((span, _)) = id.and_then(|id| self.get(id)) {
+            id = span.right;
+        }
+
+        id
+    }
+
+    /// Move the anchor attached to a removed char to the nearest kept char.
+    ///
+    /// Return `None` if the char is not removed
+    pub fn redirect_anchor(&self, anchor: Anchor) -> Option<Anchor> {
+        self.get(anchor.id?)?;
+        Some(match anchor.type_ {
+            AnchorType::Before => Anchor {
+                id: self.resolve_right(anchor.id),
+                type_: AnchorType::Before,
+            },
+            AnchorType::After => Anchor {
+                id: self.resolve_left(anchor.id),
+                type_: AnchorType::After,
+            },
+        })
+    }
+
+    /// Redirect the references to the removed chars inside the op.
+    ///
+    /// The deletions of removed chars are skipped when they are applied, so they are not changed here.
+    pub fn redirect(&self, mut op: Op) -> Op {
+        if self.is_empty() {
+            return op;
+        }
+
+        match &mut op.content {
+            OpContent::Text(text) => {
+                text.left = self.resolve_left(text.left);
+                text.right = self.resolve_right(text.right);
+            }
+            OpContent::Ann(ann) => {
+                if let Some(range) = self.redirect_range(&ann.range) {
+                    Arc::make_mut(ann).range = range;
+                }
+            }
+            OpContent::Patch(patch) => match &patch.action {
+                PatchAction::Move(range) => {
+                    if let Some(range) = self.redirect_range(range) {
+                        Arc::make_mut(patch).action = PatchAction::Move(range);
+                    }
+                }
+                PatchAction::Restore(range) => {
+                    if let Some(range) = self.redirect_range(range) {
+                        Arc::make_mut(patch).action = PatchAction::Restore(range);
+                    }
+                }
+                PatchAction::Remove | PatchAction::SetValue(_) => {}
+            },
+            OpContent::Del(_) => {}
+        }
+
+        op
+    }
+
+    /// Redirect the anchors of the range. Return `None` if none of them refers to a removed char
+    fn redirect_range(&self, range: &AnchorRange) -> Option<AnchorRange> {
+        let start = self.redirect_anchor(range.start);
+        let end = self.redirect_anchor(range.end);
+        if start.is_none() && end.is_none() {
+            return None;
+        }
+
+        Some(AnchorRange {
+            start: start.unwrap_or(range.start),
+            end: end.unwrap_or(range.end),
+        })
+    }
+}
+
+/// Non-overlapping id spans with a value attached
+#[derive(Default)]
+struct SpanMap {
+    map: FxHashMap<ClientID, BTreeMap<Counter, (Counter, usize)>>,
+}
+
+impl SpanMap {
+    fn insert(&mut self, id: OpID, len: usize, value: usize) {
+        self.map
+            .entry(id.client)
+            .or_default()
+            .insert(id.counter, (id.counter + len as Counter, value));
+    }
+
+    /// Visit the values of the spans that overlap with the given span
+    fn for_each_overlap(&self, id: OpID, len: usize, mut f: impl FnMut(usize)) {
+        let Some(tree) = self.map.get(&id.client) else {
+            return;
+        };
+
+        for (_, (end, value)) in tree.range(..id.counter + len as Counter).rev() {
+            if *end <= id.counter {
+                break;
+            }
+
+            f(*value);
+        }
+    }
+
+    fn overlap(&self, id: OpID, len: usize) -> bool {
+        let mut ans = false;
+        self.for_each_overlap(id, len, |_| ans = true);
+        ans
+    }
+}
+
+impl RichText {
+    /// Drop the ops and the tombstones before the given version.
+    ///
+    /// `vv` should be a version that all the peers have reached. After the compaction,
+    /// the document can still merge with the peers whose versions are no less than `vv`,
+    /// but it cannot send the ops before `vv` to others anymore.
+    ///
+    /// A tombstone is removed only if both its insertion and its deletion are before `vv`.
+    /// The tombstones that carry annotation anchors (including the anchors of the removed
+    /// annotations), are the origins of the insertions after `vv`, or are needed by the
+    /// undo history, are kept.
+    pub fn compact(&mut self, vv: &VersionVector) {
+        let version = self.store.vv();
+        let mut target = VersionVector::default();
+        for (client, counter) in vv.vv.iter() {
+            let counter = (*counter).min(version.vv.get(client).copied().unwrap_or(0));
+            if counter > 0 {
+                target.vv.insert(*client, counter);
+            }
+        }
+
+        // the history before the last compaction is already gone
+        for (client, trimmed) in self.store.trimmed_vv().vv.iter() {
+            let counter = target.vv.entry(*client).or_insert(0);
+            *counter = (*counter).max(*trimmed);
+        }
+
+        // the chars deleted by the ops after the target version may still be visible to some peers
+        let mut late_deletions: FxHashMap<ClientID, Vec<(Counter, Counter)>> = Default::default();
+        for (_, ops) in self.store.export(&target).unwrap() {
+            for op in ops {
+                if let OpContent::Del(del) = op.content {
+                    let del = del.positive();
+                    late_deletions
+                        .entry(del.start.client)
+                        .or_default()
+                        .push((del.start.counter, del.start.counter + del.len as Counter));
+                }
+            }
+        }
+
+        let mut late_deleted = SpanMap::default();
+        for (client, mut spans) in late_deletions {
+            spans.sort_unstable();
+            let mut merged: Vec<(Counter, Counter)> = Vec::with_capacity(spans.len());
+            for (start, end) in spans {
+                match merged.last_mut() {
+                    Some(last) if last.1 >= start => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+
+            for (start, end) in merged {
+                late_deleted.insert(OpID::new(client, start), (end - start) as usize, 0);
+            }
+        }
+
+        self.store.trim(&target);
+        let elems: Vec<Elem> = self.content.iter().cloned().collect();
+        let removable = self.find_removable_tombstones(&elems, &target, &late_deleted);
+        self.rebuild_without(elems, removable);
+    }
+
+    fn find_removable_tombstones(
+        &self,
+        elems: &[Elem],
+        vv: &VersionVector,
+        late_deleted: &SpanMap,
+    ) -> Vec<bool> {
+        let mut removable = vec![false; elems.len()];
+        let mut candidates = SpanMap::default();
+        for (i, elem) in elems.iter().enumerate() {
+            let end = elem.id.counter + elem.atom_len() as Counter;
+            if elem.is_dead()
+                && elem.anchor_set.is_empty()
+                && end <= vv.vv.get(&elem.id.client).copied().unwrap_or(0)
+                && !late_deleted.overlap(elem.id, elem.atom_len())
+            {
+                removable[i] = true;
+                candidates.insert(elem.id, elem.atom_len(), i);
+            }
+        }
+
+        // The right origins of the kept chars are looked up when new chars are integrated,
+        // so they should be kept. So do the tombstones the undo history relies on.
+        // The left origins of the ops before `vv` are only compared by ids, they can be removed.
+        let mut stack: Vec<(OpID, usize)> = match &self.undo {
+            Some(undo) => undo.referenced_ids(),
+            None => Vec::new(),
+        };
+        for (elem, removable) in elems.iter().zip(removable.iter()) {
+            if !removable {
+                stack.extend(elem.right.map(|id| (id, 1)));
+            }
+        }
+
+        // The peers that don't have the insertions after `vv` yet may integrate their
+        // own concurrent insertions around the origins, so both origins are kept
+        for elem in elems.iter() {
+            if elem.id.counter >= vv.vv.get(&elem.id.client).copied().unwrap_or(0) {
+                stack.extend(elem.left.map(|id| (id, 1)));
+                stack.extend(elem.right.map(|id| (id, 1)));
+            }
+        }
+        for op in self.pending_ops.iter() {
+            if let OpContent::Text(text) = &op.content {
+                stack.extend(text.left.map(|id| (id, 1)));
+                stack.extend(text.right.map(|id| (id, 1)));
+            }
+        }
+
+        // The removed annotations have no anchors in the tree, but they may be restored
+        for ann in self.ann.iter_removed() {
+            stack.extend(ann.range.start.id.map(|id| (id, 1)));
+            stack.extend(ann.range.end.id.map(|id| (id, 1)));
+        }
+
+        while let Some((id, len)) = stack.pop() {
+            let mut kept = Vec::new();
+            candidates.for_each_overlap(id, len, |i| {
+                if removable[i] {
+                    kept.push(i);
+                }
+            });
+            for i in kept {
+                removable[i] = false;
+                stack.extend(elems[i].right.map(|id| (id, 1)));
+            }
+        }
+
+        removable
+    }
+
+    /// Rebuild the tree and the bytes with the elements that are not removed
+    fn rebuild_without(&mut self, elems: Vec<Elem>, removable: Vec<bool>) {
+        let mut bytes = AppendOnlyBytes::new();
+        let mut cursor_map: CursorMap = Default::default();
+        let mut content: BTree<RichTreeTrait> = BTree::new();
+        content.set_listener(Some(cursor_map.gen_update_fn()));
+        let mut last_kept: Option<OpID> = None;
+        let mut run: Vec<(OpID, usize)> = Vec::new();
+        for (mut elem, removable) in elems.into_iter().zip(removable) {
+            if removable {
+                run.push((elem.id, elem.atom_len()));
+                continue;
+            }
+
+            for (id, len) in run.drain(..) {
+                self.removed.insert(
+                    id,
+                    RemovedSpan {
+                        len,
+                        left: last_kept,
+                        right: Some(elem.id),
+                    },
+                );
+            }
+
+            last_kept = Some(elem.id_last());
+            let start = bytes.len();
+            bytes.push_slice(&elem.string);
+            elem.string = bytes.slice(start..);
+            content.push(elem);
+        }
+
+        for (id, len) in run {
+            self.removed.insert(
+                id,
+                RemovedSpan {
+                    len,
+                    left: last_kept,
+                    right: None,
+                },
+            );
+        }
+
+        // the ops share the old bytes with the elements,
+        // move them to the new buffer so the old one can be freed
+        for op in self.store.iter_mut() {
+            if let OpContent::Text(text) = &mut op.content {
+                let start = bytes.len();
+                bytes.push_slice(&text.text);
+                text.text = bytes.slice(start..);
+            }
+        }
+
+        cursor_map.flush();
+        self.bytes = bytes;
+        self.content = content;
+        self.cursor_map = cursor_map;
+    }
+}
@@ -1,14 +1,67 @@
 use std::{
     mem::replace,
-    sync::{Arc, Mutex},
+    sync::mpsc::{channel, Receiver, Sender},
 };
 
-use generic_btree::{rle::HasLength, ArenaIndex, MoveEvent, MoveListener};
+use generic_btree::{ArenaIndex, MoveEvent, MoveListener};
+use serde::{Deserialize, Serialize};
 
-use crate::{Counter, OpID};
+use crate::{Anchor, AnchorType, Counter, OpID};
 
 use super::{id_map::IdMap, rich_tree::Elem};
 
+/// A position in the text that stays at the same place after local and remote edits.
+///
+/// It's attached to a char rather than an index:
+///
+/// - [AnchorType::Before] is attached to the char at the index. Text inserted at
+///   the position goes before the sticky index.
+/// - [AnchorType::After] is attached to the char before the index. Text inserted at
+///   the position goes after the sticky index.
+///
+/// If the char is deleted, it resolves to the position the char used to be.
+///
+/// It can be created by [crate::RichText::get_sticky_index] and resolved by
+/// [crate::RichText::resolve_sticky_index]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StickyIndex {
+    /// if id is None, it's at the beginning or the end of the document
+    id: Option<OpID>,
+    type_: AnchorType,
+}
+
+impl StickyIndex {
+    pub fn new(id: Option<OpID>, type_: AnchorType) -> Self {
+        Self { id, type_ }
+    }
+
+    pub fn id(&self) -> Option<OpID> {
+        self.id
+    }
+
+    pub fn anchor_type(&self) -> AnchorType {
+        self.type_
+    }
+}
+
+impl From<Anchor> for StickyIndex {
+    fn from(anchor: Anchor) -> Self {
+        Self {
+            id: anchor.id,
+            type_: anchor.type_,
+        }
+    }
+}
+
+impl From<StickyIndex> for Anchor {
+    fn from(index: StickyIndex) -> Self {
+        Self {
+            id: index.id,
+            type_: index.type_,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Cursor {
     Insert(ArenaIndex),
@@ -16,28 +69,70 @@ pub enum Cursor {
     // Ann(Arc<Annotation>),
 }
 
+/// Map the ids of the chars to the leaves of the B-tree that contain them.
+///
+/// The move listener of the B-tree can't borrow the map, so it only sends the
+/// moved elements. They are applied by [CursorMap::flush], which the doc calls
+/// after every change of the B-tree, so the lookups always see the latest leaves.
 #[derive(Debug)]
 pub struct CursorMap {
-    map: Arc<Mutex<IdMap<Cursor>>>,
+    map: IdMap<Cursor>,
+    sender: Sender<Move>,
+    moves: Receiver<Move>,
+}
+
+/// The elements starting from `id` are moved to `leaf`
+#[derive(Debug)]
+struct Move {
+    id: OpID,
+    len: usize,
+    leaf: ArenaIndex,
+}
+
+impl Move {
+    fn new(event: MoveEvent<Elem>) -> Option<Self> {
+        Some(Move {
+            id: event.elem.id,
+            len: event.elem.atom_len(),
+            leaf: event.target_leaf?,
+        })
+    }
 }
 
 impl CursorMap {
     pub fn new() -> Self {
+        let (sender, moves) = channel();
         CursorMap {
-            map: Arc::new(Mutex::new(IdMap::new())),
+            map: IdMap::new(),
+            sender,
+            moves,
         }
     }
 
     pub fn gen_update_fn(&self) -> MoveListener<Elem> {
-        let map = self.map.clone();
+        let sender = self.sender.clone();
         Box::new(move |event| {
-            listen(event, &mut map.try_lock().unwrap());
+            if let Some(moved) = Move::new(event) {
+                // the receiver is only dropped with the doc that owns the tree
+                let _ = sender.send(moved);
+            }
         })
     }
 
     #[inline]
     pub fn update(&mut self, event: MoveEvent<Elem>) {
-        listen(event, &mut self.map.try_lock().unwrap());
+        // keep the order of the moves
+        self.flush();
+        if let Some(moved) = Move::new(event) {
+            listen(moved, &mut self.map);
+        }
+    }
+
+    /// Apply the moves sent by the listener of the B-tree
+    pub fn flush(&mut self) {
+        while let Ok(moved) = self.moves.try_recv() {
+            listen(moved, &mut self.map);
+        }
     }
 
     // pub fn register_del(&mut self, op: &Op) {
@@ -85,8 +180,7 @@ impl CursorMap {
     // }
 
     pub fn get_insert(&self, id: OpID) -> Option<(ArenaIndex, usize)> {
-        let map = self.map.try_lock().unwrap();
-        if let Some(start) = map.get(id) {
+        if let Some(start) = self.map.get(id) {
             if start.start_counter <= id.counter
                 && start.start_counter + start.len as Counter > id.counter
             {
@@ -105,18 +199,18 @@ impl CursorMap {
     }
 }
 
-fn listen(event: MoveEvent<Elem>, m: &mut IdMap<Cursor>) {
-    let Some(leaf) = event.target_leaf else {
-        return;
-    };
-    let elem = event.elem;
-    let mut id = elem.id;
+fn listen(moved: Move, m: &mut IdMap<Cursor>) {
+    let Move {
+        id: elem_id,
+        len: elem_len,
+        leaf,
+    } = moved;
+    let mut id = elem_id;
     let mut cursor = Cursor::Insert(leaf);
-    let mut len = elem.atom_len();
+    let mut len = elem_len;
     'handle_old: {
-        if let Some(nearest_last_span) = m.remove_range_return_last(elem.id, elem.atom_len()) {
-            let mut nearest_last = nearest_last_span.borrow_mut();
-            if nearest_last.start_counter + (nearest_last.len as Counter) <= elem.id.counter {
+        if let Some(nearest_last) = m.remove_range_return_last(elem_id, elem_len) {
+            if nearest_last.start_counter + (nearest_last.len as Counter) <= elem_id.counter {
                 // It have no overlap with the new element, break here
                 break 'handle_old;
             }
@@ -124,31 +218,31 @@ fn listen(event: MoveEvent<Elem>, m: &mut IdMap<Cursor>) {
             if nearest_last.value == Cursor::Insert(leaf) {
                 // already has the same value as new elem
                 if nearest_last.start_counter + (nearest_last.len as Counter)
-                    < elem.id.counter + elem.atom_len() as Counter
+                    < elem_id.counter + elem_len as Counter
                 {
                     // extend the length if it's not enough
                     nearest_last.len =
-                        (elem.id.counter - nearest_last.start_counter) as usize + elem.atom_len();
+                        (elem_id.counter - nearest_last.start_counter) as usize + elem_len;
                 }
                 return;
             }
 
-            if nearest_last.start_counter == elem.id.counter {
+            if nearest_last.start_counter == elem_id.counter {
                 // both have the same start counter
-                if elem.rle_len() >= nearest_last.len {
+                if elem_len >= nearest_last.len {
                     // if new elem is longer, replace the target value
                     nearest_last.value = Cursor::Insert(leaf);
-                    nearest_last.len = elem.atom_len();
+                    nearest_last.len = elem_len;
                     return;
                 } else {
                     // if new elem is shorter, split the last span:
                     //
                     // 1. set the new value and new len to the span
                     // 2. insert the rest of the last span to the map
-                    let left_len = nearest_last.len - elem.atom_len();
-                    let start_id = elem.id.inc(elem.atom_len() as Counter);
+                    let left_len = nearest_last.len - elem_len;
+                    let start_id = elem_id.inc(elem_len as Counter);
                     let old_value = replace(&mut nearest_last.value, Cursor::Insert(leaf));
-                    nearest_last.len = elem.atom_len();
+                    nearest_last.len = elem_len;
                     id = start_id;
                     cursor = old_value;
                     len = left_len;
@@ -157,7 +251,7 @@ fn listen(event: MoveEvent<Elem>, m: &mut IdMap<Cursor>) {
                 // remove the overlapped part from last span
                 nearest_last.len = nearest_last
                     .len
-                    .min((elem.id.counter - nearest_last.start_counter) as usize);
+                    .min((elem_id.counter - nearest_last.start_counter) as usize);
             }
         }
     }
@@ -6,7 +6,7 @@ use serde_json::Value;
 
 use super::rich_tree::{
     query::IndexType,
-    utf16::{get_utf16_len, utf16_to_utf8},
+    utf16::{get_unicode_len, get_utf16_len, unicode_to_utf8, utf16_to_utf8},
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -22,6 +22,11 @@ pub enum DeltaItem {
         len: Option<usize>,
         index_type: Option<IndexType>,
     },
+    /// The insertion of an embed, e.g. `{"insert": {"image": "..."}}`. It has length 1
+    Embed {
+        insert: Value,
+        attributes: Option<FxHashMap<String, Value>>,
+    },
     Delete {
         delete: usize,
     },
@@ -40,6 +45,7 @@ impl DeltaItem {
             len: Some(match index_type {
                 IndexType::Utf8 => insert.len(),
                 IndexType::Utf16 => get_utf16_len(&insert),
+                IndexType::Unicode => get_unicode_len(&insert),
             }),
             insert,
             index_type: Some(index_type),
@@ -47,6 +53,13 @@ impl DeltaItem {
         }
     }
 
+    pub fn embed(insert: Value) -> Self {
+        Self::Embed {
+            insert,
+            attributes: None,
+        }
+    }
+
     pub fn delete(delete: usize) -> Self {
         Self::Delete { delete }
     }
@@ -67,6 +80,7 @@ impl DeltaItem {
             len: Some(match index_type {
                 IndexType::Utf8 => insert.len(),
                 IndexType::Utf16 => get_utf16_len(&insert),
+                IndexType::Unicode => get_unicode_len(&insert),
             }),
             insert,
             index_type: Some(index_type),
@@ -74,12 +88,19 @@ impl DeltaItem {
         }
     }
 
+    pub fn embed_with_attributes(insert: Value, attributes: FxHashMap<String, Value>) -> Self {
+        Self::Embed {
+            insert,
+            attributes: Some(attributes),
+        }
+    }
+
     pub fn is_retain(&self) -> bool {
         matches!(self, Self::Retain { .. })
     }
 
     pub fn is_insert(&self) -> bool {
-        matches!(self, Self::Insert { .. })
+        matches!(self, Self::Insert { .. } | Self::Embed { .. })
     }
 
     pub fn is_delete(&self) -> bool {
@@ -90,6 +111,7 @@ impl DeltaItem {
         match self {
             Self::Retain { attributes, .. } => attributes.as_ref(),
             Self::Insert { attributes, .. } => attributes.as_ref(),
+            Self::Embed { attributes, .. } => attributes.as_ref(),
             Self::Delete { .. } => None,
         }
     }
@@ -98,6 +120,7 @@ impl DeltaItem {
         match self {
             Self::Retain { retain, .. } => *retain,
             Self::Insert { len, insert, .. } => len.unwrap_or_else(|| get_utf16_len(insert)),
+            Self::Embed { .. } => 1,
             Self::Delete { delete, .. } => *delete,
         }
     }
@@ -105,7 +128,7 @@ impl DeltaItem {
     pub fn should_remove(&self) -> bool {
         match self {
             Self::Retain { retain, .. } => *retain == 0,
-            Self::Insert { .. } => false,
+            Self::Insert { .. } | Self::Embed { .. } => false,
             Self::Delete { delete, .. } => *delete == 0,
         }
     }
@@ -149,7 +172,21 @@ impl DeltaItem {
                         attributes: attributes.clone(),
                     }
                 }
+                Some(IndexType::Unicode) => {
+                    let utf8length = unicode_to_utf8(insert.as_bytes(), length);
+                    let mut v = insert.split_off(utf8length);
+                    swap(&mut v, insert);
+                    *len = Some(get_unicode_len(insert));
+
+                    Self::Insert {
+                        insert: v,
+                        len: Some(length),
+                        index_type: Some(IndexType::Unicode),
+                        attributes: attributes.clone(),
+                    }
+                }
             },
+            DeltaItem::Embed { .. } => unreachable!("an embed can't be split"),
             DeltaItem::Retain { retain, attributes } => {
                 *retain -= length;
                 Self::Retain {
@@ -168,6 +205,7 @@ impl DeltaItem {
         let attributions = match self {
             DeltaItem::Retain { attributes, .. } => attributes,
             DeltaItem::Insert { attributes, .. } => attributes,
+            DeltaItem::Embed { attributes, .. } => attributes,
             DeltaItem::Delete { .. } => return,
         };
 
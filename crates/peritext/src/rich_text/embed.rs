@@ -0,0 +1,55 @@
+//! Embedded objects, e.g. images, formulas or the references to other documents.
+//!
+//! An embed is an element of the rich tree whose text is a single [EMBED_CHAR] and
+//! which holds a json payload. So it has length 1 in all the index types, and it's
+//! edited, styled and merged like a char. It's never merged with its neighbors,
+//! and it's a `{"insert": <payload>}` item in the deltas.
+//!
+//! So the [EMBED_CHAR]s in the text inserted by the user are replaced with
+//! [EMBED_CHAR_REPLACEMENT], and only the embeds are the placeholder.
+
+use std::{borrow::Cow, sync::Arc};
+
+use serde_json::Value;
+
+use super::{IndexType, RichText};
+
+/// The placeholder of the embeds in the text, e.g. in [RichText::to_string] and
+/// [super::Span::insert]
+pub const EMBED_CHAR: char = '\u{1A}';
+pub(super) const EMBED_STR: &str = "\u{1A}";
+/// The char that replaces the [EMBED_CHAR]s in the inserted text
+pub const EMBED_CHAR_REPLACEMENT: char = char::REPLACEMENT_CHARACTER;
+
+/// Replace the [EMBED_CHAR]s in the text inserted by the user, so they can't be
+/// mistaken for the embeds
+pub(super) fn escape_embed_char(text: &str) -> Cow<str> {
+    if text.contains(EMBED_CHAR) {
+        Cow::Owned(text.replace(EMBED_CHAR, EMBED_CHAR_REPLACEMENT.encode_utf8(&mut [0; 4])))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+impl RichText {
+    /// Insert the embed at the utf8 index
+    #[inline]
+    pub fn insert_embed(&mut self, index: usize, value: Value) {
+        assert!(index <= self.len());
+        self.insert_with_embed(index, EMBED_STR, Some(Arc::new(value)), IndexType::Utf8);
+    }
+
+    /// Insert the embed at the utf16 index
+    #[inline]
+    pub fn insert_embed_utf16(&mut self, index: usize, value: Value) {
+        assert!(index <= self.utf16_len());
+        self.insert_with_embed(index, EMBED_STR, Some(Arc::new(value)), IndexType::Utf16);
+    }
+
+    /// Insert the embed at the index in chars
+    #[inline]
+    pub fn insert_embed_unicode(&mut self, index: usize, value: Value) {
+        assert!(index <= self.len_unicode());
+        self.insert_with_embed(index, EMBED_STR, Some(Arc::new(value)), IndexType::Unicode);
+    }
+}
@@ -1,21 +1,65 @@
-use std::io::prelude::*;
-use std::ops::Deref;
+//! Encoding of the updates.
+//!
+//! An encoded blob starts with a header:
+//!
+//! | magic   | format version | flags  | checksum   | body |
+//! |---------|----------------|--------|------------|------|
+//! | 4 bytes | 1 byte         | 1 byte | 4 bytes LE | ...  |
+//!
+//! The body is the columnar [DocEncoding], gzipped if it's large. The checksum
+//! is the CRC32 of the version, the flags and the body.
+//!
+//! The blobs exported before the header was added have no magic bytes. They are
+//! treated as format version 0 and migrated to the current layout when decoded.
+//!
+//! An update stream is a sequence of frames, each of them is a `u32` LE length and
+//! an encoded blob of that length. The frames are small, so the stream can be
+//! written and read in bounded memory, and the stream ends at the end of a frame.
+
+use std::io::{self, prelude::*};
 use std::{hash::Hash, sync::Arc};
 
 use append_only_bytes::AppendOnlyBytes;
 use flate2::write::GzEncoder;
 use flate2::{read::GzDecoder, Compression};
 use fxhash::FxHashMap;
-use generic_btree::rle::HasLength;
+use generic_btree::rle::{HasLength, Mergeable, Sliceable};
 use serde::{Deserialize, Serialize};
 use serde_columnar::{columnar, from_bytes, to_vec};
 
 use crate::{
-    Anchor, AnchorRange, AnchorType, Annotation, Behavior, ClientID, InternalString, OpID,
+    Anchor, AnchorRange, AnchorType, Annotation, Behavior, ClientID, Counter, InternalString, OpID,
+    Patch, PatchAction,
+};
+
+use super::{
+    error::Error,
+    op::{DeleteOp, Op, OpContent, TextInsertOp},
 };
 
-use super::op::{DeleteOp, Op, OpContent, TextInsertOp};
 const COMPRESS_THRESHOLD: usize = 1024;
+const MAGIC: [u8; 4] = *b"PTXT";
+/// The current format version. Bump it when the layout of [DocEncoding] changes,
+/// and migrate the old layout in [decode_body]
+const FORMAT_VERSION: u8 = 1;
+/// magic + version + flags + checksum
+pub(super) const HEADER_LEN: usize = 10;
+/// The body is gzipped
+const FLAG_COMPRESSED: u8 = 1;
+/// The max length of the ops in a frame of the update stream, see [frame_len]
+const FRAME_OP_LEN: usize = 64 * 1024;
+/// The estimated encoded length of an op besides its text and values
+const OP_OVERHEAD: usize = 32;
+/// The larger frames are rejected when reading a stream. It leaves room for the
+/// ops that are encoded longer than estimated
+const MAX_FRAME_LEN: usize = 2 * FRAME_OP_LEN;
+/// The gzipped data may expand at most this many times. A tiny blob can be
+/// decompressed to gigabytes, so the longer data is rejected
+const MAX_COMPRESSION_RATIO: usize = 32;
+/// The small blobs can always be decompressed to this length
+const MIN_DECOMPRESSED_LIMIT: usize = 1024 * 1024;
+/// The max length of any data after decompression
+const MAX_DECOMPRESSED_LEN: usize = 256 * 1024 * 1024;
 
 #[columnar(vec, ser, de)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,6 +110,39 @@ pub(super) struct AnnEncoding {
     value: u32,
 }
 
+#[columnar(vec, ser, de)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct PatchEncoding {
+    #[columnar(strategy = "Rle")]
+    target_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    target_counter: u32,
+    /// [PATCH_REMOVE], [PATCH_SET_VALUE], [PATCH_MOVE] or [PATCH_RESTORE]
+    #[columnar(strategy = "Rle")]
+    action: u8,
+    /// index to ann_types_and_values, `u32::MAX` if the action has no value
+    value: u32,
+}
+
+const PATCH_REMOVE: u8 = 0;
+const PATCH_SET_VALUE: u8 = 1;
+/// The new range is the next one in [DocEncoding::moves]
+const PATCH_MOVE: u8 = 2;
+/// The new range is the next one in [DocEncoding::moves]
+const PATCH_RESTORE: u8 = 3;
+
+/// The new range of a [PATCH_MOVE] or [PATCH_RESTORE] patch
+#[columnar(vec, ser, de)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct MoveEncoding {
+    start: Option<OpID>,
+    #[columnar(strategy = "Rle")]
+    is_start_before_anchor: bool,
+    end: Option<OpID>,
+    #[columnar(strategy = "Rle")]
+    is_end_before_anchor: bool,
+}
+
 #[columnar(ser, de)]
 #[derive(Debug, Serialize, Deserialize)]
 struct DocEncoding {
@@ -77,6 +154,33 @@ struct DocEncoding {
     deletes: Vec<DeleteEncoding>,
     #[columnar(type = "vec")]
     annotations: Vec<AnnEncoding>,
+    #[columnar(type = "vec")]
+    patches: Vec<PatchEncoding>,
+    #[columnar(type = "vec")]
+    moves: Vec<MoveEncoding>,
+
+    str: Vec<u8>,
+    clients: Vec<ClientID>,
+    ann_types_and_values: Vec<InternalString>,
+    /// The payloads of the [OpContentType::Embed] ops, index to ann_types_and_values.
+    /// Their positions are in `inserts`
+    embeds: Vec<u32>,
+    op_len: Vec<u32>,
+    start_counters: Vec<u32>,
+}
+
+/// The layout of format version 0, which has no header and only compresses the text
+#[columnar(ser, de)]
+#[derive(Debug, Serialize, Deserialize)]
+struct DocEncodingV0 {
+    #[columnar(type = "vec")]
+    ops: Vec<OpEncoding>,
+    #[columnar(type = "vec")]
+    inserts: Vec<InsertEncoding>,
+    #[columnar(type = "vec")]
+    deletes: Vec<DeleteEncoding>,
+    #[columnar(type = "vec")]
+    annotations: Vec<AnnEncoding>,
 
     str: Vec<u8>,
     compressed_str: bool,
@@ -86,11 +190,33 @@ struct DocEncoding {
     start_counters: Vec<u32>,
 }
 
+impl DocEncodingV0 {
+    fn migrate(self) -> Result<DocEncoding, Error> {
+        Ok(DocEncoding {
+            ops: self.ops,
+            inserts: self.inserts,
+            deletes: self.deletes,
+            annotations: self.annotations,
+            patches: Vec::new(),
+            moves: Vec::new(),
+            str: decompress(&self.str, self.compressed_str)?,
+            clients: self.clients,
+            ann_types_and_values: self.ann_types_and_values,
+            embeds: Vec::new(),
+            op_len: self.op_len,
+            start_counters: self.start_counters,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum OpContentType {
     Insert = 0,
     Delete = 1,
     Ann = 2,
+    Patch = 3,
+    /// An insert of an embed
+    Embed = 4,
 }
 
 impl From<OpContentType> for u8 {
@@ -99,13 +225,17 @@ impl From<OpContentType> for u8 {
     }
 }
 
-impl From<u8> for OpContentType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for OpContentType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => OpContentType::Insert,
-            1 => OpContentType::Delete,
-            2 => OpContentType::Ann,
-            _ => unreachable!(),
+            0 => Ok(OpContentType::Insert),
+            1 => Ok(OpContentType::Delete),
+            2 => Ok(OpContentType::Ann),
+            3 => Ok(OpContentType::Patch),
+            4 => Ok(OpContentType::Embed),
+            _ => Err(Error::UnknownOpType(value)),
         }
     }
 }
@@ -114,11 +244,234 @@ type InnerUpdates = FxHashMap<ClientID, Vec<Op>>;
 
 pub fn encode(exported: InnerUpdates) -> Vec<u8> {
     let data = to_doc_encoding(exported);
-    to_vec(&data).unwrap()
+    let (body, compressed) = compress(to_vec(&data).unwrap());
+    let flags = if compressed { FLAG_COMPRESSED } else { 0 };
+    let mut ans = Vec::with_capacity(HEADER_LEN + body.len());
+    ans.extend_from_slice(&MAGIC);
+    ans.push(FORMAT_VERSION);
+    ans.push(flags);
+    ans.extend_from_slice(&checksum(FORMAT_VERSION, flags, &body).to_le_bytes());
+    ans.extend_from_slice(&body);
+    ans
 }
 
-pub fn decode(encoded: &[u8]) -> InnerUpdates {
-    from_doc_encoding(from_bytes(encoded).unwrap())
+pub fn decode(encoded: &[u8]) -> Result<InnerUpdates, Error> {
+    let Some(rest) = encoded.strip_prefix(&MAGIC) else {
+        return from_doc_encoding(decode_body(0, encoded)?);
+    };
+
+    if encoded.len() < HEADER_LEN {
+        return Err(Error::BadHeader);
+    }
+
+    let version = rest[0];
+    let flags = rest[1];
+    let expected = u32::from_le_bytes(rest[2..6].try_into().unwrap());
+    let body = &encoded[HEADER_LEN..];
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(Error::IncompatibleVersion(version));
+    }
+    if flags & !FLAG_COMPRESSED != 0 {
+        return Err(Error::BadHeader);
+    }
+    if checksum(version, flags, body) != expected {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    let body = decompress(body, flags & FLAG_COMPRESSED != 0)?;
+    from_doc_encoding(decode_body(version, &body)?)
+}
+
+/// Merge the encoded updates into one blob, without loading them into a document.
+///
+/// The overlapping ops are kept once, so the updates can be merged in any order.
+/// The ops of each client must be contiguous after the merge, otherwise
+/// [Error::UpdateGap] is returned with the first missing id.
+pub fn merge_updates(updates: &[&[u8]]) -> Result<Vec<u8>, Error> {
+    let mut merged = InnerUpdates::default();
+    for data in updates {
+        for (client, ops) in decode(data)? {
+            merged.entry(client).or_default().extend(ops);
+        }
+    }
+
+    for (client, ops) in merged.iter_mut() {
+        ops.sort_by_key(|op| op.id.counter);
+        let mut ans: Vec<Op> = Vec::with_capacity(ops.len());
+        for op in ops.drain(..) {
+            let Some(last) = ans.last_mut() else {
+                ans.push(op);
+                continue;
+            };
+
+            let end = last.id.counter + last.rle_len() as Counter;
+            if op.id.counter > end {
+                return Err(Error::UpdateGap(OpID::new(*client, end)));
+            }
+            if op.id.counter + op.rle_len() as Counter <= end {
+                continue;
+            }
+
+            let op = if op.id.counter < end {
+                op.slice((end - op.id.counter) as usize..)
+            } else {
+                op
+            };
+            if last.can_merge(&op) {
+                last.merge_right(&op);
+            } else {
+                ans.push(op);
+            }
+        }
+
+        *ops = ans;
+    }
+
+    Ok(encode(merged))
+}
+
+/// Write the ops as the frames of an update stream. The long insertions are split
+/// at the char boundaries, so every frame stays small.
+pub fn encode_stream(ops: impl Iterator<Item = Op>, writer: impl Write) -> io::Result<()> {
+    let mut frames = FrameWriter {
+        writer,
+        frame: Default::default(),
+        len: 0,
+    };
+    for mut op in ops {
+        while let Some(at) = split_point(&op) {
+            frames.push(op.slice(..at))?;
+            op = op.slice(at..);
+        }
+        frames.push(op)?;
+    }
+
+    frames.flush()
+}
+
+/// Read the next frame of the update stream, which is an encoded blob.
+/// Return `None` at the end of the stream
+pub fn read_frame(mut reader: impl Read) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(Error::DecodeError),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::DecodeError);
+    }
+
+    // the length is untrusted, so the buffer only grows with the received bytes
+    let mut frame = Vec::new();
+    reader.take(len as u64).read_to_end(&mut frame)?;
+    if frame.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(Some(frame))
+}
+
+struct FrameWriter<W> {
+    writer: W,
+    frame: InnerUpdates,
+    /// The length of the ops in the frame, see [FRAME_OP_LEN]
+    len: usize,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn push(&mut self, op: Op) -> io::Result<()> {
+        let len = frame_len(&op);
+        if self.len + len > FRAME_OP_LEN {
+            self.flush()?;
+        }
+
+        self.len += len;
+        self.frame.entry(op.id.client).or_default().push(op);
+        if self.len >= FRAME_OP_LEN {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+
+        let blob = encode(std::mem::take(&mut self.frame));
+        self.len = 0;
+        if blob.len() > MAX_FRAME_LEN {
+            // e.g. an embed with a huge value, the readers would reject it
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the op is too large for a frame",
+            ));
+        }
+
+        self.writer.write_all(&(blob.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&blob)
+    }
+}
+
+/// The estimated encoded length of the op. The text and the values count by
+/// their bytes
+fn frame_len(op: &Op) -> usize {
+    let payload = match &op.content {
+        OpContent::Text(text) => match &text.embed {
+            Some(embed) => embed.to_string().len(),
+            None => text.text.len(),
+        },
+        OpContent::Ann(ann) => ann.type_.len() + ann.value.to_string().len(),
+        OpContent::Patch(patch) => match &patch.action {
+            PatchAction::SetValue(value) => value.to_string().len(),
+            _ => 0,
+        },
+        OpContent::Del(_) => 0,
+    };
+    payload + OP_OVERHEAD
+}
+
+/// The last char boundary within [FRAME_OP_LEN] if the insertion is longer than it
+fn split_point(op: &Op) -> Option<usize> {
+    let OpContent::Text(text) = &op.content else {
+        return None;
+    };
+    if text.text.len() <= FRAME_OP_LEN {
+        return None;
+    }
+
+    // the byte at a char boundary is not a continuation byte
+    (1..=FRAME_OP_LEN)
+        .rev()
+        .find(|&i| text.text[i] & 0xC0 != 0x80)
+}
+
+pub(super) fn checksum(version: u8, flags: u8, body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[version, flags]);
+    hasher.update(body);
+    hasher.finalize()
+}
+
+/// Decode the uncompressed body of the given format version, migrating the
+/// headerless layout to the current one
+fn decode_body(version: u8, body: &[u8]) -> Result<DocEncoding, Error> {
+    match version {
+        0 => from_bytes::<DocEncodingV0>(body)
+            .map_err(|_| Error::DecodeError)?
+            .migrate(),
+        FORMAT_VERSION => from_bytes(body).map_err(|_| Error::DecodeError),
+        _ => Err(Error::IncompatibleVersion(version)),
+    }
 }
 
 fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
@@ -126,6 +479,9 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
     let mut inserts = Vec::new();
     let mut deletes = Vec::new();
     let mut annotations = Vec::new();
+    let mut patches = Vec::new();
+    let mut moves = Vec::new();
+    let mut embeds = Vec::new();
     let mut client_mapping = VecMapping::new();
     for client in exported_map.keys() {
         client_mapping.get_or_insert(*client);
@@ -158,7 +514,14 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
                             .unwrap_or(u32::MAX),
                         right_counter: text.right.unwrap_or(zero).counter,
                     });
-                    OpContentType::Insert
+                    match &text.embed {
+                        Some(embed) => {
+                            let value = serde_json::to_string(embed).unwrap();
+                            embeds.push(ann_str_mapping.get_or_insert(value.into()) as u32);
+                            OpContentType::Embed
+                        }
+                        None => OpContentType::Insert,
+                    }
                 }
                 crate::rich_text::op::OpContent::Del(del) => {
                     deletes.push(DeleteEncoding {
@@ -185,6 +548,37 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
                     });
                     OpContentType::Ann
                 }
+                crate::rich_text::op::OpContent::Patch(patch) => {
+                    let (action, value) = match &patch.action {
+                        PatchAction::Remove => (PATCH_REMOVE, u32::MAX),
+                        PatchAction::SetValue(value) => {
+                            let value = serde_json::to_string(value).unwrap();
+                            let value = ann_str_mapping.get_or_insert(value.into());
+                            (PATCH_SET_VALUE, value as u32)
+                        }
+                        PatchAction::Move(range) | PatchAction::Restore(range) => {
+                            moves.push(MoveEncoding {
+                                start: range.start.id,
+                                is_start_before_anchor: range.start.type_ == AnchorType::Before,
+                                end: range.end.id,
+                                is_end_before_anchor: range.end.type_ == AnchorType::Before,
+                            });
+                            let action = match &patch.action {
+                                PatchAction::Move(_) => PATCH_MOVE,
+                                _ => PATCH_RESTORE,
+                            };
+                            (action, u32::MAX)
+                        }
+                    };
+                    let target = patch.target_range_id;
+                    patches.push(PatchEncoding {
+                        target_client: client_mapping.get_or_insert(target.client) as u32,
+                        target_counter: target.counter,
+                        action,
+                        value,
+                    });
+                    OpContentType::Patch
+                }
             };
 
             ops.push(OpEncoding {
@@ -201,44 +595,48 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
         str.len(),
         inserts.iter().map(|x| x.len).sum::<u32>() as usize
     );
-    let mut compressed_str = false;
-    if str.len() > COMPRESS_THRESHOLD {
-        compressed_str = true;
-        let mut e = GzEncoder::new(Vec::new(), Compression::default());
-        e.write_all(&str).unwrap();
-        str = e.finish().unwrap();
-    }
-
     DocEncoding {
         ops,
         inserts,
         deletes,
         annotations,
-        compressed_str,
+        patches,
+        moves,
         clients: client_mapping.vec,
         ann_types_and_values: ann_str_mapping.vec,
+        embeds,
         op_len,
         start_counters,
         str,
     }
 }
 
-fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
+fn from_doc_encoding(exported: DocEncoding) -> Result<InnerUpdates, Error> {
     let clients = &exported.clients;
-    let mut str = AppendOnlyBytes::new();
-    if exported.compressed_str {
-        let mut d = GzDecoder::new(exported.str.deref());
-        let mut ans = vec![];
-        d.read_to_end(&mut ans).unwrap();
-        str.push_slice(&ans);
-    } else {
-        str.push_slice(&exported.str);
+    if exported.op_len.len() != exported.start_counters.len()
+        || exported.op_len.len() > clients.len()
+    {
+        return Err(Error::BadHeader);
+    }
+
+    let op_sum = exported
+        .op_len
+        .iter()
+        .try_fold(0usize, |sum, len| sum.checked_add(*len as usize));
+    if op_sum != Some(exported.ops.len()) {
+        return Err(Error::ColumnMismatch);
     }
+
+    let mut str = AppendOnlyBytes::new();
+    str.push_slice(&exported.str);
     let mut str_index = 0;
     let mut ans: InnerUpdates = Default::default();
     let mut insert_iter = exported.inserts.iter();
     let mut delete_iter = exported.deletes.iter();
     let mut ann_iter = exported.annotations.iter();
+    let mut patch_iter = exported.patches.iter();
+    let mut move_iter = exported.moves.iter();
+    let mut embed_iter = exported.embeds.iter();
     let mut op_iter = exported.ops.iter();
     for ((client, op_len), counter) in exported
         .clients
@@ -249,76 +647,132 @@ fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
         let mut counter = *counter;
         let mut arr = Vec::with_capacity((*op_len) as usize);
         for _ in 0..*op_len {
-            let op = op_iter.next().unwrap();
+            let op = op_iter.next().ok_or(Error::ColumnMismatch)?;
             let id = OpID {
                 client: *client,
                 counter,
             };
-            let content = match op.type_.into() {
-                OpContentType::Insert => {
-                    let insert = insert_iter.next().unwrap();
-                    let left = if insert.left_client != u32::MAX {
-                        Some(OpID {
-                            client: clients[insert.left_client as usize],
-                            counter: insert.left_counter,
-                        })
-                    } else {
-                        None
-                    };
-                    let right = if insert.right_client != u32::MAX {
-                        Some(OpID {
-                            client: clients[insert.right_client as usize],
-                            counter: insert.right_counter,
-                        })
-                    } else {
-                        None
-                    };
+            let type_ = OpContentType::try_from(op.type_)?;
+            let content = match type_ {
+                OpContentType::Insert | OpContentType::Embed => {
+                    let insert = insert_iter.next().ok_or(Error::ColumnMismatch)?;
+                    let left = decode_id(clients, insert.left_client, insert.left_counter)?;
+                    let right = decode_id(clients, insert.right_client, insert.right_counter)?;
                     let end = str_index + insert.len as usize;
+                    if insert.len == 0 || end > str.len() {
+                        return Err(Error::ColumnMismatch);
+                    }
+
                     let text = str.slice(str_index..end);
+                    if std::str::from_utf8(&text).is_err() {
+                        return Err(Error::DecodeError);
+                    }
+
                     str_index = end;
-                    OpContent::Text(TextInsertOp { left, right, text })
+                    let embed = if type_ == OpContentType::Embed {
+                        let value = embed_iter.next().ok_or(Error::ColumnMismatch)?;
+                        let value = exported
+                            .ann_types_and_values
+                            .get(*value as usize)
+                            .ok_or(Error::ColumnMismatch)?;
+                        if insert.len != 1 {
+                            return Err(Error::DecodeError);
+                        }
+
+                        Some(Arc::new(
+                            serde_json::from_str(value).map_err(|_| Error::DecodeError)?,
+                        ))
+                    } else {
+                        None
+                    };
+                    OpContent::Text(TextInsertOp {
+                        left,
+                        right,
+                        text,
+                        embed,
+                    })
                 }
                 OpContentType::Delete => {
-                    let delete = delete_iter.next().unwrap();
+                    let delete = delete_iter.next().ok_or(Error::ColumnMismatch)?;
+                    let len = delete.len.unsigned_abs();
+                    let out_of_range = if delete.len > 0 {
+                        delete.start_counter.checked_add(len).is_none()
+                    } else {
+                        len - 1 > delete.start_counter
+                    };
+                    if delete.len == 0 || delete.len == i32::MIN || out_of_range {
+                        return Err(Error::DecodeError);
+                    }
+
                     OpContent::Del(DeleteOp {
-                        start: OpID {
-                            client: clients[delete.start_client as usize],
-                            counter: delete.start_counter,
-                        },
+                        start: decode_id(clients, delete.start_client, delete.start_counter)?
+                            .ok_or(Error::ColumnMismatch)?,
                         len: delete.len,
                     })
                 }
                 OpContentType::Ann => {
-                    let ann = ann_iter.next().unwrap();
-                    let range = AnchorRange {
-                        start: Anchor {
-                            id: ann.start,
-                            type_: if ann.is_start_before_anchor {
-                                AnchorType::Before
-                            } else {
-                                AnchorType::After
-                            },
-                        },
-                        end: Anchor {
-                            id: ann.end,
-                            type_: if ann.is_end_before_anchor {
-                                AnchorType::Before
-                            } else {
-                                AnchorType::After
-                            },
-                        },
-                    };
+                    let ann = ann_iter.next().ok_or(Error::ColumnMismatch)?;
+                    let range = decode_range(
+                        ann.start,
+                        ann.is_start_before_anchor,
+                        ann.end,
+                        ann.is_end_before_anchor,
+                    );
+                    let type_ = exported
+                        .ann_types_and_values
+                        .get(ann.type_ as usize)
+                        .ok_or(Error::ColumnMismatch)?;
+                    let value = exported
+                        .ann_types_and_values
+                        .get(ann.value as usize)
+                        .ok_or(Error::ColumnMismatch)?;
 
                     OpContent::Ann(Arc::new(Annotation {
                         range,
                         behavior: ann.behavior,
-                        type_: exported.ann_types_and_values[ann.type_ as usize].clone(),
+                        type_: type_.clone(),
                         id,
                         range_lamport: (op.lamport, id),
-                        value: serde_json::from_str(
-                            &exported.ann_types_and_values[ann.value as usize],
-                        )
-                        .unwrap(),
+                        value: serde_json::from_str(value).map_err(|_| Error::DecodeError)?,
+                    }))
+                }
+                OpContentType::Patch => {
+                    let patch = patch_iter.next().ok_or(Error::ColumnMismatch)?;
+                    let action = match patch.action {
+                        PATCH_REMOVE => PatchAction::Remove,
+                        PATCH_SET_VALUE => {
+                            let value = exported
+                                .ann_types_and_values
+                                .get(patch.value as usize)
+                                .ok_or(Error::ColumnMismatch)?;
+                            PatchAction::SetValue(
+                                serde_json::from_str(value).map_err(|_| Error::DecodeError)?,
+                            )
+                        }
+                        PATCH_MOVE | PATCH_RESTORE => {
+                            let range = move_iter.next().ok_or(Error::ColumnMismatch)?;
+                            let range = decode_range(
+                                range.start,
+                                range.is_start_before_anchor,
+                                range.end,
+                                range.is_end_before_anchor,
+                            );
+                            if patch.action == PATCH_MOVE {
+                                PatchAction::Move(range)
+                            } else {
+                                PatchAction::Restore(range)
+                            }
+                        }
+                        _ => return Err(Error::DecodeError),
+                    };
+
+                    let target = decode_id(clients, patch.target_client, patch.target_counter)?
+                        .ok_or(Error::ColumnMismatch)?;
+                    OpContent::Patch(Arc::new(Patch {
+                        id,
+                        target_range_id: target,
+                        action,
+                        lamport: op.lamport,
                     }))
                 }
             };
@@ -328,23 +782,115 @@ fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
                 lamport: op.lamport,
                 content,
             };
-            counter += op.rle_len() as u32;
+            counter = counter
+                .checked_add(op.rle_len() as Counter)
+                .ok_or(Error::DecodeError)?;
             arr.push(op);
         }
 
-        ans.insert(*client, arr);
+        if ans.insert(*client, arr).is_some() {
+            return Err(Error::BadHeader);
+        }
     }
 
-    ans
+    if insert_iter.next().is_some()
+        || delete_iter.next().is_some()
+        || ann_iter.next().is_some()
+        || patch_iter.next().is_some()
+        || move_iter.next().is_some()
+        || embed_iter.next().is_some()
+        || str_index != str.len()
+    {
+        return Err(Error::ColumnMismatch);
+    }
+
+    Ok(ans)
+}
+
+fn decode_range(
+    start: Option<OpID>,
+    is_start_before_anchor: bool,
+    end: Option<OpID>,
+    is_end_before_anchor: bool,
+) -> AnchorRange {
+    let type_of = |is_before: bool| {
+        if is_before {
+            AnchorType::Before
+        } else {
+            AnchorType::After
+        }
+    };
+    AnchorRange {
+        start: Anchor {
+            id: start,
+            type_: type_of(is_start_before_anchor),
+        },
+        end: Anchor {
+            id: end,
+            type_: type_of(is_end_before_anchor),
+        },
+    }
+}
+
+/// Decode an optional id. `u32::MAX` client index stands for `None`
+pub(super) fn decode_id(
+    clients: &[ClientID],
+    client: u32,
+    counter: Counter,
+) -> Result<Option<OpID>, Error> {
+    if client == u32::MAX {
+        return Ok(None);
+    }
+
+    let client = *clients.get(client as usize).ok_or(Error::ColumnMismatch)?;
+    Ok(Some(OpID { client, counter }))
+}
+
+/// Gzip the data if it's large enough. Returns the data and whether it's compressed
+pub(super) fn compress(data: Vec<u8>) -> (Vec<u8>, bool) {
+    if data.len() <= COMPRESS_THRESHOLD {
+        return (data, false);
+    }
+
+    let mut e = GzEncoder::new(Vec::new(), Compression::default());
+    e.write_all(&data).unwrap();
+    (e.finish().unwrap(), true)
+}
+
+pub(super) fn decompress(data: &[u8], compressed: bool) -> Result<Vec<u8>, Error> {
+    if !compressed {
+        return Ok(data.to_vec());
+    }
+
+    gunzip(data, decompressed_limit(data.len()))
+}
+
+/// The max length of the decompressed data, which is bounded by the length of
+/// the compressed data
+fn decompressed_limit(len: usize) -> usize {
+    (len * MAX_COMPRESSION_RATIO).clamp(MIN_DECOMPRESSED_LIMIT, MAX_DECOMPRESSED_LEN)
+}
+
+/// Decompress the data, return [Error::BadCompression] if it's longer than `limit`
+fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    // read one more byte to tell whether the limit is exceeded
+    let mut d = GzDecoder::new(data).take(limit as u64 + 1);
+    let mut ans = vec![];
+    d.read_to_end(&mut ans).map_err(|_| Error::BadCompression)?;
+    if ans.len() > limit {
+        return Err(Error::BadCompression);
+    }
+
+    Ok(ans)
 }
 
-struct VecMapping<T> {
-    vec: Vec<T>,
+pub(super) struct VecMapping<T> {
+    pub(super) vec: Vec<T>,
     map: FxHashMap<T, usize>,
 }
 
 impl<T: Eq + Hash + Clone> VecMapping<T> {
-    fn new() -> Self {
+    pub(super) fn new() -> Self {
         Self {
             vec: Vec::new(),
             map: FxHashMap::default(),
@@ -355,7 +901,7 @@ impl<T: Eq + Hash + Clone> VecMapping<T> {
         &self.vec[idx]
     }
 
-    fn get_or_insert(&mut self, val: T) -> usize {
+    pub(super) fn get_or_insert(&mut self, val: T) -> usize {
         if let Some(idx) = self.map.get(&val) {
             *idx
         } else {
@@ -366,3 +912,71 @@ impl<T: Eq + Hash + Clone> VecMapping<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_columnar::to_vec;
+
+    use crate::rich_text::{error::Error, RichText};
+
+    use super::{
+        compress, decode, decompress, decompressed_limit, read_frame, to_doc_encoding,
+        DocEncodingV0, MAX_FRAME_LEN, MIN_DECOMPRESSED_LIMIT,
+    };
+
+    #[test]
+    fn migrate_from_version_0() {
+        let mut text = RichText::new(1);
+        text.insert(0, &"hello world ".repeat(200));
+        text.delete(0..6);
+        let exported = text.store.export(&Default::default()).unwrap();
+        let data = to_doc_encoding(exported.clone());
+        let (str, compressed_str) = compress(data.str);
+        assert!(compressed_str);
+        let legacy = DocEncodingV0 {
+            ops: data.ops,
+            inserts: data.inserts,
+            deletes: data.deletes,
+            annotations: data.annotations,
+            str,
+            compressed_str,
+            clients: data.clients,
+            ann_types_and_values: data.ann_types_and_values,
+            op_len: data.op_len,
+            start_counters: data.start_counters,
+        };
+        assert_eq!(decode(&to_vec(&legacy).unwrap()).unwrap(), exported);
+    }
+
+    #[test]
+    fn reject_zip_bomb() {
+        let (blob, compressed) = compress(vec![0; MIN_DECOMPRESSED_LIMIT]);
+        assert!(compressed);
+        assert_eq!(decompressed_limit(blob.len()), MIN_DECOMPRESSED_LIMIT);
+        assert_eq!(
+            decompress(&blob, true).unwrap().len(),
+            MIN_DECOMPRESSED_LIMIT
+        );
+
+        // one byte over the limit
+        let (bomb, compressed) = compress(vec![0; MIN_DECOMPRESSED_LIMIT + 1]);
+        assert!(compressed);
+        assert_eq!(decompressed_limit(bomb.len()), MIN_DECOMPRESSED_LIMIT);
+        assert!(matches!(
+            decompress(&bomb, true),
+            Err(Error::BadCompression)
+        ));
+    }
+
+    #[test]
+    fn read_frame_with_forged_length() {
+        let mut data = (MAX_FRAME_LEN as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(b"tiny");
+        assert!(matches!(read_frame(data.as_slice()), Err(Error::Io(_))));
+        let data = (MAX_FRAME_LEN as u32 + 1).to_le_bytes();
+        assert!(matches!(
+            read_frame(data.as_slice()),
+            Err(Error::DecodeError)
+        ));
+    }
+}
@@ -1,7 +1,43 @@
+use serde_json::Value;
+
+use crate::{InternalString, OpID};
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Decode error")]
     DecodeError,
     #[error("Invalid expand")]
     InvalidExpand,
+    #[error("Bad header")]
+    BadHeader,
+    #[error("Failed to decompress the text")]
+    BadCompression,
+    #[error("The columns don't match")]
+    ColumnMismatch,
+    #[error("Unknown op type {0}")]
+    UnknownOpType(u8),
+    #[error("Incompatible format version {0}")]
+    IncompatibleVersion(u8),
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Dangling op id {0:?}")]
+    DanglingOpID(OpID),
+    #[error("Unknown annotation {0:?}")]
+    UnknownAnnotation(OpID),
+    #[error("Invalid value {1} of style {0}")]
+    InvalidStyleValue(InternalString, Value),
+    #[error("Line {0} is out of range")]
+    LineOutOfRange(usize),
+    #[error("Style {0} is not a block style")]
+    NotBlockStyle(InternalString),
+    #[error("Style {0} is a block style, use format_line instead")]
+    NotInlineStyle(InternalString),
+    #[error("The expand or the behavior of style {0} doesn't match its registered config")]
+    StyleMismatch(InternalString),
+    #[error("The updates miss the ops from {0:?}")]
+    UpdateGap(OpID),
+    #[error("The ops from {0:?} are trimmed from the history, load a snapshot instead")]
+    HistoryTrimmed(OpID),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
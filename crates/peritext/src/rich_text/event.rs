@@ -1,5 +1,12 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
 use serde::{Deserialize, Serialize};
 
+use crate::InternalString;
+
 use super::{delta::DeltaItem, rich_tree::query::IndexType};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -7,4 +14,80 @@ pub struct Event {
     pub ops: Vec<DeltaItem>,
     pub is_local: bool,
     pub index_type: IndexType,
+    /// the origin attached by [super::Transaction::set_origin]
+    pub origin: Option<InternalString>,
+}
+
+/// A listener registered by [super::RichText::observe].
+///
+/// The listener is removed when the subscription is dropped. Call
+/// [Subscription::detach] to keep it as long as the document lives.
+#[must_use = "the listener is removed when the subscription is dropped"]
+#[derive(Debug)]
+pub struct Subscription {
+    active: Option<Arc<AtomicBool>>,
+}
+
+impl Subscription {
+    pub(super) fn new(active: Arc<AtomicBool>) -> Self {
+        Self {
+            active: Some(active),
+        }
+    }
+
+    /// Keep the listener until the document is dropped
+    pub fn detach(mut self) {
+        self.active = None;
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(active) = self.active.take() {
+            active.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Decide which events a listener receives. The default filter accepts all events.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    /// `Some(true)` to receive only the local events, `Some(false)` for only the remote ones
+    pub is_local: Option<bool>,
+    /// Only receive the events that change the styles of this annotation type
+    pub ann_type: Option<InternalString>,
+}
+
+impl EventFilter {
+    pub fn local() -> Self {
+        Self {
+            is_local: Some(true),
+            ann_type: None,
+        }
+    }
+
+    pub fn remote() -> Self {
+        Self {
+            is_local: Some(false),
+            ann_type: None,
+        }
+    }
+
+    pub fn with_ann_type(mut self, ann_type: impl Into<InternalString>) -> Self {
+        self.ann_type = Some(ann_type.into());
+        self
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if self.is_local.is_some() && self.is_local != Some(event.is_local) {
+            return false;
+        }
+
+        match &self.ann_type {
+            Some(ann_type) => event.ops.iter().any(
+                |item| matches!(item.attributions(), Some(attrs) if attrs.contains_key(&**ann_type)),
+            ),
+            None => true,
+        }
+    }
 }
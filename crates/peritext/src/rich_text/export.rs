@@ -0,0 +1,583 @@
+//! Export the document as plain text, CommonMark or HTML.
+//!
+//! The exporters walk the lines of the document. The block styles of a line are
+//! the prefix of the line in Markdown and the element of the line in HTML, and the
+//! inline styles wrap the text of the spans. How each style is written is decided
+//! by the [StyleSerializer].
+//!
+//! The HTML is sanitized: the text and the attribute values are escaped, the tag
+//! and attribute names that are not alphanumeric are dropped, and so are the urls
+//! with a scheme other than http, https, mailto or tel.
+
+use fxhash::FxHashMap;
+use serde_json::Value;
+
+use crate::InternalString;
+
+use super::{embed::EMBED_CHAR, Line, RichText, Span, StyleKind};
+
+/// An HTML element, e.g. `<a href="...">`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtmlElement {
+    pub tag: String,
+    pub attributes: Vec<(String, String)>,
+    /// The element that wraps the consecutive lines with the same parent, e.g. the
+    /// `ul` of the list items. It's only used for the lines
+    pub parent: Option<String>,
+}
+
+impl HtmlElement {
+    pub fn new(tag: impl Into<String>) -> Self {
+        Self {
+            tag: tag.into(),
+            attributes: Vec::new(),
+            parent: None,
+        }
+    }
+
+    pub fn with_attribute(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_parent(mut self, parent: impl Into<String>) -> Self {
+        self.parent = Some(parent.into());
+        self
+    }
+}
+
+/// How a style is written in HTML
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HtmlStyle {
+    /// Wrap the text in the element, or use it as the element of the line for
+    /// the block styles
+    Element(HtmlElement),
+    /// Keep the style as a `data-<type>` attribute
+    Data,
+    Drop,
+}
+
+/// How the styles and the embeds are written by the exporters
+pub trait StyleSerializer {
+    /// The delimiters that wrap the text with the inline style in Markdown, e.g.
+    /// `("**", "**")` for bold. Return `None` to drop the style
+    fn markdown_inline(&self, type_: &str, value: &Value) -> Option<(String, String)>;
+
+    /// The prefix of the line with the block style in Markdown, e.g. `"# "` for a
+    /// header. Return `None` to drop the style
+    fn markdown_block(&self, type_: &str, value: &Value) -> Option<String>;
+
+    fn html_inline(&self, type_: &str, value: &Value) -> HtmlStyle;
+
+    fn html_block(&self, type_: &str, value: &Value) -> HtmlStyle;
+
+    /// The embeds are dropped by default, except the `{"image": <url>}` ones
+    fn markdown_embed(&self, value: &Value) -> Option<String> {
+        let src = value.get("image")?.as_str()?;
+        is_safe_url(src).then(|| format!("![]({})", markdown_url(src)))
+    }
+
+    /// The embeds are dropped by default, except the `{"image": <url>}` ones
+    fn html_embed(&self, value: &Value) -> Option<HtmlElement> {
+        let src = value.get("image")?.as_str()?;
+        Some(HtmlElement::new("img").with_attribute("src", src))
+    }
+}
+
+/// The serializer of the Quill formats and the `comment` and `citation` styles
+#[derive(Debug, Clone)]
+pub struct DefaultStyleSerializer {
+    /// Whether the unknown styles are kept as `data-` attributes in HTML
+    pub keep_unknown_styles: bool,
+}
+
+impl Default for DefaultStyleSerializer {
+    fn default() -> Self {
+        Self {
+            keep_unknown_styles: true,
+        }
+    }
+}
+
+impl DefaultStyleSerializer {
+    fn unknown(&self) -> HtmlStyle {
+        if self.keep_unknown_styles {
+            HtmlStyle::Data
+        } else {
+            HtmlStyle::Drop
+        }
+    }
+}
+
+impl StyleSerializer for DefaultStyleSerializer {
+    fn markdown_inline(&self, type_: &str, value: &Value) -> Option<(String, String)> {
+        let wrap = |x: &str| Some((x.to_string(), x.to_string()));
+        match type_ {
+            "bold" => wrap("**"),
+            "italic" => wrap("*"),
+            "strike" => wrap("~~"),
+            "code" => wrap("`"),
+            "link" => {
+                let url = value_to_string(value);
+                is_safe_url(&url).then(|| ("[".to_string(), format!("]({})", markdown_url(&url))))
+            }
+            "citation" => Some((String::new(), format!("[^{}]", value_to_string(value)))),
+            _ => None,
+        }
+    }
+
+    fn markdown_block(&self, type_: &str, value: &Value) -> Option<String> {
+        match (type_, value.as_str()) {
+            ("header", _) => Some(format!("{} ", "#".repeat(header_level(value)?))),
+            ("list", Some("bullet")) => Some("- ".to_string()),
+            ("list", Some("ordered")) => Some("1. ".to_string()),
+            ("list", Some("checked")) => Some("- [x] ".to_string()),
+            ("list", Some("unchecked")) => Some("- [ ] ".to_string()),
+            ("code-block", _) => Some("    ".to_string()),
+            _ => None,
+        }
+    }
+
+    fn html_inline(&self, type_: &str, value: &Value) -> HtmlStyle {
+        let element = match (type_, value.as_str()) {
+            ("bold", _) => HtmlElement::new("strong"),
+            ("italic", _) => HtmlElement::new("em"),
+            ("underline", _) => HtmlElement::new("u"),
+            ("strike", _) => HtmlElement::new("s"),
+            ("code", _) => HtmlElement::new("code"),
+            ("script", Some("sub")) => HtmlElement::new("sub"),
+            ("script", Some("super")) => HtmlElement::new("sup"),
+            ("link", _) => HtmlElement::new("a").with_attribute("href", value_to_string(value)),
+            ("comment", _) => HtmlElement::new("span")
+                .with_attribute("class", "comment")
+                .with_attribute("data-comment", value_to_string(value)),
+            ("citation", _) => {
+                HtmlElement::new("cite").with_attribute("data-citation", value_to_string(value))
+            }
+            _ => return self.unknown(),
+        };
+
+        HtmlStyle::Element(element)
+    }
+
+    fn html_block(&self, type_: &str, value: &Value) -> HtmlStyle {
+        let element = match (type_, value.as_str()) {
+            ("header", _) => match header_level(value) {
+                Some(level) => HtmlElement::new(format!("h{}", level)),
+                None => return self.unknown(),
+            },
+            ("list", Some("bullet")) => HtmlElement::new("li").with_parent("ul"),
+            ("list", Some("ordered")) => HtmlElement::new("li").with_parent("ol"),
+            ("list", Some("checked")) => HtmlElement::new("li")
+                .with_attribute("data-checked", "true")
+                .with_parent("ul"),
+            ("list", Some("unchecked")) => HtmlElement::new("li")
+                .with_attribute("data-checked", "false")
+                .with_parent("ul"),
+            ("code-block", _) => HtmlElement::new("pre"),
+            _ => return self.unknown(),
+        };
+
+        HtmlStyle::Element(element)
+    }
+}
+
+impl RichText {
+    /// Get the text without the embeds
+    pub fn to_plain_text(&self) -> String {
+        self.to_string().replace(EMBED_CHAR, "")
+    }
+
+    /// Export the document as CommonMark.
+    ///
+    /// The lines are paragraphs, except that the consecutive lines with the same
+    /// block styles, e.g. the list items, are not separated by empty lines.
+    pub fn to_markdown(&self, serializer: &impl StyleSerializer) -> String {
+        let mut ans = String::new();
+        let mut last_types: Option<Vec<String>> = None;
+        for line in self.export_lines() {
+            let mut prefix = String::new();
+            let mut types = Vec::new();
+            for (type_, value) in sorted_attributes(&line.attributes) {
+                if let Some(x) = serializer.markdown_block(type_, value) {
+                    prefix.push_str(&x);
+                    types.push(type_.to_string());
+                }
+            }
+
+            if let Some(last_types) = &last_types {
+                if !types.is_empty() && types == *last_types {
+                    ans.push('\n');
+                } else {
+                    ans.push_str("\n\n");
+                }
+            }
+
+            ans.push_str(&prefix);
+            // the text of the code blocks and the code spans is not escaped
+            let is_code_block = types.iter().any(|x| x == "code-block");
+            let fence = "`".repeat(self.longest_backtick_run(&line) + 1);
+            let mut stack = Vec::new();
+            for span in line.spans.iter() {
+                let wrappers: Vec<Wrapper> = self
+                    .inline_attributes(span)
+                    .filter_map(|(type_, value)| {
+                        let (open, close) = serializer.markdown_inline(type_, value)?;
+                        if type_ == "code" && open == "`" && close == "`" {
+                            return Some(Wrapper::new_code_span(value, &fence));
+                        }
+
+                        Some(Wrapper::new(type_, value, open, close))
+                    })
+                    .collect();
+                let is_code = is_code_block || wrappers.iter().any(|x| x.is_code_span);
+                match &span.embed {
+                    Some(embed) => {
+                        if let Some(x) = serializer.markdown_embed(embed) {
+                            update_stack(&mut ans, &mut stack, wrappers);
+                            ans.push_str(&x);
+                        }
+                    }
+                    None => {
+                        let text = span.insert.strip_suffix('\n').unwrap_or(&span.insert);
+                        if !text.is_empty() {
+                            update_stack(&mut ans, &mut stack, wrappers);
+                            if is_code {
+                                ans.push_str(text);
+                            } else {
+                                ans.push_str(&escape_markdown(text));
+                            }
+                        }
+                    }
+                }
+            }
+
+            update_stack(&mut ans, &mut stack, Vec::new());
+            last_types = Some(types);
+        }
+
+        ans
+    }
+
+    /// Export the document as sanitized HTML, see the module doc.
+    ///
+    /// The lines are `<p>` elements unless their block styles are mapped to other
+    /// elements, and the empty lines contain a `<br>`, as in Quill.
+    pub fn to_html(&self, serializer: &impl StyleSerializer) -> String {
+        let mut ans = String::new();
+        let mut parent: Option<String> = None;
+        for line in self.export_lines() {
+            let mut element = HtmlElement::new("p");
+            let mut data = Vec::new();
+            for (type_, value) in sorted_attributes(&line.attributes) {
+                match serializer.html_block(type_, value) {
+                    HtmlStyle::Element(x) => element = x,
+                    HtmlStyle::Data => data.push(data_attribute(type_, value)),
+                    HtmlStyle::Drop => {}
+                }
+            }
+            element.attributes.extend(data);
+
+            if parent != element.parent {
+                if let Some(parent) = &parent {
+                    ans.push_str(&close_tag(parent));
+                }
+                if let Some(parent) = &element.parent {
+                    ans.push_str(&open_tag(&HtmlElement::new(parent.as_str())));
+                }
+                parent = element.parent.clone();
+            }
+
+            ans.push_str(&open_tag(&element));
+            let start = ans.len();
+            let mut stack = Vec::new();
+            for span in line.spans.iter() {
+                let wrappers = self
+                    .inline_attributes(span)
+                    .filter_map(|(type_, value)| {
+                        let element = match serializer.html_inline(type_, value) {
+                            HtmlStyle::Element(x) => x,
+                            HtmlStyle::Data => {
+                                let (name, value) = data_attribute(type_, value);
+                                HtmlElement::new("span").with_attribute(name, value)
+                            }
+                            HtmlStyle::Drop => return None,
+                        };
+                        Some(Wrapper::new(
+                            type_,
+                            value,
+                            open_tag(&element),
+                            close_tag(&element.tag),
+                        ))
+                    })
+                    .collect();
+                match &span.embed {
+                    Some(embed) => {
+                        if let Some(x) = serializer.html_embed(embed) {
+                            update_stack(&mut ans, &mut stack, wrappers);
+                            ans.push_str(&open_tag(&x));
+                            if !is_void_element(&x.tag) {
+                                ans.push_str(&close_tag(&x.tag));
+                            }
+                        }
+                    }
+                    None => {
+                        let text = span.insert.strip_suffix('\n').unwrap_or(&span.insert);
+                        if !text.is_empty() {
+                            update_stack(&mut ans, &mut stack, wrappers);
+                            ans.push_str(&escape_html(text));
+                        }
+                    }
+                }
+            }
+
+            update_stack(&mut ans, &mut stack, Vec::new());
+            if ans.len() == start {
+                ans.push_str("<br>");
+            }
+            ans.push_str(&close_tag(&element.tag));
+        }
+
+        if let Some(parent) = &parent {
+            ans.push_str(&close_tag(parent));
+        }
+
+        ans
+    }
+
+    /// The lines of the document, without the empty line after the last line break
+    fn export_lines(&self) -> impl Iterator<Item = Line> + '_ {
+        (0..self.lines())
+            .map(move |i| self.get_line(i))
+            .filter(|line| line.spans.iter().any(|x| !x.insert.is_empty()))
+    }
+
+    /// The longest run of backticks in the code spans of the line, so the fence of
+    /// the code spans can be longer
+    fn longest_backtick_run(&self, line: &Line) -> usize {
+        line.spans
+            .iter()
+            .filter(|span| {
+                self.inline_attributes(span)
+                    .any(|(type_, _)| type_ == "code")
+            })
+            .flat_map(|span| span.insert.split(|c| c != '`'))
+            .map(|run| run.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn inline_attributes<'a>(
+        &'a self,
+        span: &'a Span,
+    ) -> impl Iterator<Item = (&'a str, &'a Value)> {
+        sorted_attributes(&span.attributes)
+            .into_iter()
+            .filter(move |(type_, _)| self.schema.get(type_).kind == StyleKind::Inline)
+    }
+}
+
+/// A style that wraps the text, e.g. `<strong>` and `</strong>`
+struct Wrapper {
+    type_: String,
+    value: Value,
+    open: String,
+    close: String,
+    /// Whether it's a Markdown code span, which is padded with spaces when it's closed
+    /// if its text starts or ends with a backtick
+    is_code_span: bool,
+    /// The offset of the text after the open delimiter
+    start: usize,
+}
+
+impl Wrapper {
+    fn new(type_: &str, value: &Value, open: String, close: String) -> Self {
+        Self {
+            type_: type_.to_string(),
+            value: value.clone(),
+            open,
+            close,
+            is_code_span: false,
+            start: 0,
+        }
+    }
+
+    fn new_code_span(value: &Value, fence: &str) -> Self {
+        Self {
+            is_code_span: true,
+            ..Self::new("code", value, fence.to_string(), fence.to_string())
+        }
+    }
+
+    fn is_same_style(&self, other: &Wrapper) -> bool {
+        self.type_ == other.type_ && self.value == other.value
+    }
+}
+
+/// Close the opened styles that are not wanted, and the styles opened after them,
+/// then open the wanted styles that are not opened
+fn update_stack(out: &mut String, stack: &mut Vec<Wrapper>, wanted: Vec<Wrapper>) {
+    let keep = stack
+        .iter()
+        .take_while(|x| wanted.iter().any(|y| y.is_same_style(x)))
+        .count();
+    for wrapper in stack.drain(keep..).rev() {
+        if wrapper.is_code_span {
+            pad_code_span(out, wrapper.start);
+        }
+        out.push_str(&wrapper.close);
+    }
+
+    for mut wrapper in wanted {
+        if !stack.iter().any(|x| x.is_same_style(&wrapper)) {
+            out.push_str(&wrapper.open);
+            wrapper.start = out.len();
+            stack.push(wrapper);
+        }
+    }
+}
+
+/// CommonMark strips a space from both sides of the code span text, and the text
+/// can't start or end with a backtick next to the fence. So pad the text in these
+/// cases
+fn pad_code_span(out: &mut String, start: usize) {
+    let text = &out[start..];
+    let is_stripped =
+        text.starts_with(' ') && text.ends_with(' ') && !text.trim_matches(' ').is_empty();
+    if text.starts_with('`') || text.ends_with('`') || is_stripped {
+        out.insert(start, ' ');
+        out.push(' ');
+    }
+}
+
+fn sorted_attributes(attributes: &FxHashMap<InternalString, Value>) -> Vec<(&str, &Value)> {
+    let mut ans: Vec<_> = attributes
+        .iter()
+        .map(|(key, value)| (&**key, value))
+        .collect();
+    ans.sort_by_key(|(key, _)| *key);
+    ans
+}
+
+fn header_level(value: &Value) -> Option<usize> {
+    let level = match value {
+        Value::Number(x) => x.as_u64()? as usize,
+        Value::String(x) => x.parse().ok()?,
+        _ => return None,
+    };
+    (1..=6).contains(&level).then_some(level)
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(x) => x.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn data_attribute(type_: &str, value: &Value) -> (String, String) {
+    let name: String = type_
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    (format!("data-{}", name), value_to_string(value))
+}
+
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    match url.find(|c| matches!(c, ':' | '/' | '?' | '#')) {
+        Some(index) if url[index..].starts_with(':') => {
+            matches!(&url[..index], "http" | "https" | "mailto" | "tel")
+        }
+        // a relative url
+        _ => true,
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn is_void_element(tag: &str) -> bool {
+    matches!(tag, "img" | "br" | "hr")
+}
+
+/// The open tag of the element. The tag or attribute with an invalid name is
+/// dropped, and so is the url attribute with an unsafe scheme
+fn open_tag(element: &HtmlElement) -> String {
+    if !is_valid_name(&element.tag) {
+        return String::new();
+    }
+
+    let mut ans = format!("<{}", element.tag);
+    for (name, value) in element.attributes.iter() {
+        if !is_valid_name(name) || (matches!(name.as_str(), "href" | "src") && !is_safe_url(value))
+        {
+            continue;
+        }
+
+        ans.push_str(&format!(" {}=\"{}\"", name, escape_html(value)));
+    }
+    ans.push('>');
+    ans
+}
+
+fn close_tag(tag: &str) -> String {
+    if !is_valid_name(tag) {
+        return String::new();
+    }
+
+    format!("</{}>", tag)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut ans = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => ans.push_str("&amp;"),
+            '<' => ans.push_str("&lt;"),
+            '>' => ans.push_str("&gt;"),
+            '"' => ans.push_str("&quot;"),
+            '\'' => ans.push_str("&#39;"),
+            EMBED_CHAR => {}
+            c => ans.push(c),
+        }
+    }
+    ans
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut ans = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '~' | '!' | '|' => {
+                ans.push('\\');
+                ans.push(c);
+            }
+            EMBED_CHAR => {}
+            c => ans.push(c),
+        }
+    }
+    ans
+}
+
+fn markdown_url(url: &str) -> String {
+    let mut ans = String::with_capacity(url.len());
+    for c in url.chars() {
+        match c {
+            ' ' => ans.push_str("%20"),
+            '(' | ')' | '<' | '>' | '\\' => {
+                ans.push('\\');
+                ans.push(c);
+            }
+            c => ans.push(c),
+        }
+    }
+    ans
+}
@@ -0,0 +1,73 @@
+//! Grapheme cluster boundaries, e.g. to move the caret over an emoji or a letter
+//! with combining marks as a whole.
+//!
+//! A line break is always a boundary after it, so the boundaries are found by
+//! segmenting the line that contains the index.
+
+use std::ops::Range;
+
+use unicode_segmentation::GraphemeCursor;
+
+use super::{rich_tree::query::IndexFinder, IndexType, RichText};
+
+impl RichText {
+    /// Whether the index is at the boundary of the grapheme clusters
+    pub fn is_grapheme_boundary(&self, index: usize, index_type: IndexType) -> bool {
+        assert!(index <= self.len_with(index_type));
+        let index = self.convert_index(index, index_type, IndexType::Utf8);
+        let range = self.line_range_at(index);
+        let line = self.slice_str(range.clone(), IndexType::Utf8);
+        let mut cursor = GraphemeCursor::new(index - range.start, line.len(), true);
+        cursor.is_boundary(&line, 0).unwrap()
+    }
+
+    /// The first grapheme boundary after the index, or the len of the doc if there's none
+    pub fn next_grapheme_boundary(&self, index: usize, index_type: IndexType) -> usize {
+        let len = self.len_with(index_type);
+        assert!(index <= len);
+        if index == len {
+            return len;
+        }
+
+        let index = self.convert_index(index, index_type, IndexType::Utf8);
+        let range = self.line_range_at(index);
+        let line = self.slice_str(range.clone(), IndexType::Utf8);
+        let mut cursor = GraphemeCursor::new(index - range.start, line.len(), true);
+        let next = match cursor.next_boundary(&line, 0).unwrap() {
+            Some(next) => range.start + next,
+            None => range.end,
+        };
+        self.convert_index(next, IndexType::Utf8, index_type)
+    }
+
+    /// The last grapheme boundary before the index, or 0 if there's none
+    pub fn prev_grapheme_boundary(&self, index: usize, index_type: IndexType) -> usize {
+        assert!(index <= self.len_with(index_type));
+        if index == 0 {
+            return 0;
+        }
+
+        let index = self.convert_index(index, index_type, IndexType::Utf8);
+        let mut range = self.line_range_at(index);
+        if range.start == index {
+            // the prev grapheme is the line break of the prev line
+            range = self.line_range_at(index - 1);
+        }
+
+        let line = self.slice_str(range.clone(), IndexType::Utf8);
+        let mut cursor = GraphemeCursor::new(index - range.start, line.len(), true);
+        let prev = match cursor.prev_boundary(&line, 0).unwrap() {
+            Some(prev) => range.start + prev,
+            None => range.start,
+        };
+        self.convert_index(prev, IndexType::Utf8, index_type)
+    }
+
+    /// The utf8 range of the line that contains the char at the utf8 index,
+    /// including its line break
+    fn line_range_at(&self, index: usize) -> Range<usize> {
+        let path = self.content.query::<IndexFinder>(&(index, IndexType::Utf8));
+        let line = self.get_line_from_path(path);
+        self.line_range(line, IndexType::Utf8)
+    }
+}
@@ -1,14 +1,10 @@
-use std::{
-    cell::{RefCell, RefMut},
-    collections::BTreeMap,
-    rc::Rc,
-};
+use std::collections::BTreeMap;
 
 use fxhash::FxHashMap;
 
 use crate::{ClientID, Counter, OpID};
 
-type Tree<T> = BTreeMap<Counter, Rc<RefCell<Entry<T>>>>;
+type Tree<T> = BTreeMap<Counter, Entry<T>>;
 /// This structure helps to map a range of IDs to a value.
 ///
 /// It's the call site's responsibility to ensure there is no overlap in the range
@@ -41,13 +37,12 @@ impl<Value: Clone + std::fmt::Debug> IdMap<Value> {
         self.map.is_empty()
     }
 
-    pub fn get(&self, id: OpID) -> Option<RefMut<'_, Entry<Value>>> {
+    pub fn get(&self, id: OpID) -> Option<&Entry<Value>> {
         let client_map = self.map.get(&id.client)?;
         client_map
             .range(..=id.counter)
             .next_back()
             .and_then(|(counter, v)| {
-                let v = v.borrow_mut();
                 debug_assert_eq!(v.start_counter, *counter);
                 if counter + v.len as Counter > id.counter {
                     Some(v)
@@ -57,13 +52,28 @@ impl<Value: Clone + std::fmt::Debug> IdMap<Value> {
             })
     }
 
-    pub fn get_last(&self, id: OpID) -> Option<RefMut<'_, Entry<Value>>> {
-        let client_map = self.map.get(&id.client)?;
+    pub fn get_mut(&mut self, id: OpID) -> Option<&mut Entry<Value>> {
+        let client_map = self.map.get_mut(&id.client)?;
         client_map
-            .range(..=id.counter)
+            .range_mut(..=id.counter)
+            .next_back()
+            .and_then(|(counter, v)| {
+                debug_assert_eq!(v.start_counter, *counter);
+                if counter + v.len as Counter > id.counter {
+                    Some(v)
+                } else {
+                    None
+                }
+            })
+    }
+
+    #[allow(unused)]
+    pub fn get_last(&mut self, id: OpID) -> Option<&mut Entry<Value>> {
+        let client_map = self.map.get_mut(&id.client)?;
+        client_map
+            .range_mut(..=id.counter)
             .next_back()
             .map(|(counter, v)| {
-                let v = v.borrow_mut();
                 debug_assert_eq!(v.start_counter, *counter);
                 v
             })
@@ -71,39 +81,35 @@ impl<Value: Clone + std::fmt::Debug> IdMap<Value> {
 
     /// Remove any entries that start within the range of (exclusive_from, exclusive_from + len)
     ///
-    /// It'll return the pointer to the alive last entry, which is the same as [`IdMap::get_last`]
+    /// It'll return the alive last entry, which is the same as [`IdMap::get_last`]
     pub fn remove_range_return_last(
         &mut self,
         exclusive_from: OpID,
         len: usize,
-    ) -> Option<Rc<RefCell<Entry<Value>>>> {
+    ) -> Option<&mut Entry<Value>> {
         let last_id = exclusive_from.inc((len - 1) as Counter);
-        let Some(client_map) = self.map.get_mut(&last_id.client) else { return None };
+        let client_map = self.map.get_mut(&last_id.client)?;
         loop {
-            let mutex_item = client_map
-                .range(..=last_id.counter)
-                .next_back()
-                .map(|(_, v)| v);
-
-            let item = mutex_item.map(|x| x.borrow_mut());
-            let Some(item_inner) = item.as_ref() else { return None };
-            let item_counter = item_inner.start_counter;
-            let item_end = item_inner.len as Counter + item_counter;
-            if item_inner.start_counter <= exclusive_from.counter {
-                return mutex_item.cloned();
+            let (_, item) = client_map.range(..=last_id.counter).next_back()?;
+            let item_counter = item.start_counter;
+            let item_end = item.len as Counter + item_counter;
+            if item_counter <= exclusive_from.counter {
+                break;
             }
 
-            drop(item);
-            let item = client_map.remove(&item_counter).unwrap();
+            let mut item = client_map.remove(&item_counter).unwrap();
             let new_item_counter = last_id.counter + 1;
             if item_end > new_item_counter {
-                let mut inner = item.borrow_mut();
-                inner.len = (item_end - new_item_counter) as usize;
-                inner.start_counter = new_item_counter;
-                drop(inner);
+                item.len = (item_end - new_item_counter) as usize;
+                item.start_counter = new_item_counter;
                 client_map.insert(new_item_counter, item);
             }
         }
+
+        client_map
+            .range_mut(..=last_id.counter)
+            .next_back()
+            .map(|(_, v)| v)
     }
 
     pub fn insert(&mut self, id: OpID, v: Value, len: usize) {
@@ -115,29 +121,29 @@ impl<Value: Clone + std::fmt::Debug> IdMap<Value> {
             self.get(id).unwrap()
         );
         let client_map = self.map.entry(id.client).or_default();
-        let elem = Rc::new(RefCell::new(Entry {
+        let elem = Entry {
             len,
             value: v,
             start_counter: id.counter,
-        }));
+        };
         client_map.insert(id.counter, elem);
     }
 
     #[allow(unused)]
     pub fn remove(&mut self, id: OpID, len: usize) -> bool {
-        let Some(mut g) = self.get(id) else { return false };
+        let Some(g) = self.get_mut(id) else {
+            return false;
+        };
         if g.start_counter == id.counter && g.len == len {
             // remove entry directly
-            drop(g);
             let client_map = self.map.get_mut(&id.client).unwrap();
             client_map.remove(&id.counter);
         } else if g.start_counter == id.counter {
             // split entry
             g.start_counter += len as Counter;
             g.len -= len;
-            drop(g);
             let client_map = self.map.get_mut(&id.client).unwrap();
-            let Some((_, value)) = client_map.remove_entry(&id.counter) else {unreachable!()};
+            let value = client_map.remove(&id.counter).unwrap();
             client_map.insert(id.counter + len as Counter, value);
         } else if g.start_counter + g.len as Counter == id.counter + len as Counter {
             // adjust length
@@ -145,13 +151,12 @@ impl<Value: Clone + std::fmt::Debug> IdMap<Value> {
         } else {
             // adjust length + split
             let start_counter = id.counter + len as Counter;
-            let new_elem = Rc::new(RefCell::new(Entry {
+            let new_elem = Entry {
                 len: g.len - len - (id.counter - g.start_counter) as usize,
                 value: g.value.clone(),
                 start_counter,
-            }));
+            };
             g.len -= len;
-            drop(g);
             let client_map = self.map.get_mut(&id.client).unwrap();
             client_map.insert(start_counter, new_elem);
         }
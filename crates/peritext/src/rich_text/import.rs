@@ -0,0 +1,225 @@
+//! Import Markdown and HTML, e.g. the pasted content.
+//!
+//! The input is parsed into the text and the styles on it, which are built from
+//! the [super::StyleSchema]. Then they are inserted and annotated in one
+//! transaction. The blocks of the input are separated by line breaks, and the
+//! headers, the list items and the code blocks are the block attributes of the
+//! lines. The images are inserted as `{"image": <url>}` embeds.
+//!
+//! The Markdown parser needs the `markup-import` feature.
+
+use std::{ops::Range, sync::Arc};
+
+use serde_json::Value;
+
+use super::{
+    embed::{escape_embed_char, EMBED_STR},
+    Error, IndexType, RichText,
+};
+
+mod html;
+#[cfg(feature = "markup-import")]
+mod markdown;
+
+impl RichText {
+    /// Insert the Markdown at the utf8 index, with bold, italic, strike, code,
+    /// links, headers, lists and code blocks.
+    ///
+    /// Return an error without changing the document if a style value can't be
+    /// converted to the value schema of its type.
+    #[cfg(feature = "markup-import")]
+    pub fn insert_markdown(&mut self, index: usize, markdown: &str) -> Result<(), Error> {
+        self.insert_markdown_with(index, markdown, IndexType::Utf8)
+    }
+
+    /// Insert the Markdown at the utf16 index, see [RichText::insert_markdown]
+    #[cfg(feature = "markup-import")]
+    pub fn insert_markdown_utf16(&mut self, index: usize, markdown: &str) -> Result<(), Error> {
+        self.insert_markdown_with(index, markdown, IndexType::Utf16)
+    }
+
+    /// Insert the Markdown at the index in chars, see [RichText::insert_markdown]
+    #[cfg(feature = "markup-import")]
+    pub fn insert_markdown_unicode(&mut self, index: usize, markdown: &str) -> Result<(), Error> {
+        self.insert_markdown_with(index, markdown, IndexType::Unicode)
+    }
+
+    #[cfg(feature = "markup-import")]
+    fn insert_markdown_with(
+        &mut self,
+        index: usize,
+        markdown: &str,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        let content = markdown::parse(&escape_embed_char(markdown));
+        self.insert_imported(index, content, index_type)
+    }
+
+    /// Insert the HTML at the utf8 index. The tags that can't be mapped to
+    /// the styles are ignored, but their text is kept, except for the scripts and
+    /// the stylesheets.
+    ///
+    /// Return an error without changing the document if a style value can't be
+    /// converted to the value schema of its type.
+    pub fn insert_html(&mut self, index: usize, html: &str) -> Result<(), Error> {
+        self.insert_html_with(index, html, IndexType::Utf8)
+    }
+
+    /// Insert the HTML at the utf16 index, see [RichText::insert_html]
+    pub fn insert_html_utf16(&mut self, index: usize, html: &str) -> Result<(), Error> {
+        self.insert_html_with(index, html, IndexType::Utf16)
+    }
+
+    /// Insert the HTML at the index in chars, see [RichText::insert_html]
+    pub fn insert_html_unicode(&mut self, index: usize, html: &str) -> Result<(), Error> {
+        self.insert_html_with(index, html, IndexType::Unicode)
+    }
+
+    fn insert_html_with(
+        &mut self,
+        index: usize,
+        html: &str,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        let content = html::parse(&escape_embed_char(html));
+        self.insert_imported(index, content, index_type)
+    }
+
+    fn insert_imported(
+        &mut self,
+        index: usize,
+        content: Content,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        assert!(index <= self.len_with(index_type));
+        // the offsets of the content are in utf8
+        let index = self.convert_index(index, index_type, IndexType::Utf8);
+        let styles = content
+            .styles
+            .into_iter()
+            .map(|(range, type_, value)| Ok((range, self.schema.style(type_, value)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.transact(|txn| {
+            let mut last = 0;
+            for (offset, embed) in content.embeds {
+                txn.insert(index + last, &content.text[last..offset]);
+                txn.insert_with_embed(
+                    index + offset,
+                    EMBED_STR,
+                    Some(Arc::new(embed)),
+                    IndexType::Utf8,
+                );
+                last = offset + EMBED_STR.len();
+            }
+            txn.insert(index + last, &content.text[last..]);
+
+            for (range, style) in styles {
+                let range = index + range.start..index + range.end;
+                txn.annotate_by_kind(range, style, IndexType::Utf8);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// The text and the styles parsed from the input
+#[derive(Debug, Default)]
+struct Content {
+    /// The embeds are [EMBED_STR] in the text
+    text: String,
+    /// The utf8 offsets of the embeds
+    embeds: Vec<(usize, Value)>,
+    styles: Vec<(Range<usize>, &'static str, Value)>,
+    /// The inline styles that are not ended yet
+    open: Vec<(usize, &'static str, Value)>,
+    /// The block attributes of the current line
+    line: Vec<(&'static str, Value)>,
+    /// The block attributes of the ended lines, on the offsets of their line breaks
+    blocks: Vec<(usize, &'static str, Value)>,
+}
+
+impl Content {
+    fn push_str(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    fn push_embed(&mut self, value: Value) {
+        self.embeds.push((self.text.len(), value));
+        self.text.push_str(EMBED_STR);
+    }
+
+    fn start_style(&mut self, type_: &'static str, value: Value) {
+        self.open.push((self.text.len(), type_, value));
+    }
+
+    /// End the last started style of the type
+    fn end_style(&mut self, type_: &'static str) {
+        let Some(pos) = self.open.iter().rposition(|x| x.1 == type_) else {
+            return;
+        };
+
+        let (start, type_, value) = self.open.remove(pos);
+        if start < self.text.len() {
+            self.styles.push((start..self.text.len(), type_, value));
+        }
+    }
+
+    /// Set the block attribute of the current line
+    fn set_line_attribute(&mut self, type_: &'static str, value: Value) {
+        self.line.retain(|x| x.0 != type_);
+        self.line.push((type_, value));
+    }
+
+    fn is_line_empty(&self) -> bool {
+        self.text.is_empty() || self.text.ends_with('\n')
+    }
+
+    /// End the current line if it has text, before a new block starts
+    fn start_block(&mut self) {
+        if !self.is_line_empty() {
+            self.end_line(false);
+        }
+    }
+
+    /// End the current line with a line break that has its block attributes.
+    ///
+    /// The empty line is skipped unless `force`, so the nested blocks don't
+    /// create empty lines
+    fn end_line(&mut self, force: bool) {
+        if !force && self.is_line_empty() && self.line.is_empty() {
+            return;
+        }
+
+        let offset = self.text.len();
+        self.text.push('\n');
+        for (type_, value) in std::mem::take(&mut self.line) {
+            self.blocks.push((offset, type_, value));
+        }
+    }
+
+    /// End the open styles and the last line. The last line break is removed if
+    /// it has no block attributes
+    fn finish(mut self) -> Self {
+        self.end_line(false);
+        let len = self.text.len();
+        if self.text.ends_with('\n') && self.blocks.last().map(|x| x.0) != Some(len - 1) {
+            self.text.pop();
+        }
+
+        let len = self.text.len();
+        for (start, type_, value) in std::mem::take(&mut self.open) {
+            if start < len {
+                self.styles.push((start..len, type_, value));
+            }
+        }
+        for (range, ..) in self.styles.iter_mut() {
+            range.end = range.end.min(len);
+        }
+        self.styles.retain(|x| !x.0.is_empty());
+        for (offset, type_, value) in std::mem::take(&mut self.blocks) {
+            self.styles.push((offset..offset + 1, type_, value));
+        }
+        self
+    }
+}
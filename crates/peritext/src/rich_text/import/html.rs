@@ -0,0 +1,314 @@
+use serde_json::{json, Value};
+
+use super::Content;
+
+/// The elements whose content is skipped
+const SKIPPED: [&str; 4] = ["head", "script", "style", "template"];
+
+const BLOCKS: [&str; 9] = [
+    "p",
+    "div",
+    "blockquote",
+    "section",
+    "article",
+    "header",
+    "footer",
+    "tr",
+    "table",
+];
+
+pub(super) fn parse(html: &str) -> Content {
+    let mut content = Content::default();
+    // the kinds of the nested lists
+    let mut lists: Vec<&'static str> = Vec::new();
+    let mut pre = 0usize;
+    // the collapsed whitespace that is written before the next char of the line
+    let mut space = false;
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("<!--") {
+            rest = tail.find("-->").map_or("", |i| &tail[i + 3..]);
+            continue;
+        }
+
+        let tag = if rest.starts_with('<') {
+            parse_tag(rest)
+        } else {
+            None
+        };
+        let Some((tag, tail)) = tag else {
+            // the text until the next tag
+            let first = rest.chars().next().unwrap().len_utf8();
+            let end = rest[first..].find('<').map_or(rest.len(), |i| i + first);
+            let text = decode_entities(&rest[..end]);
+            if pre > 0 {
+                push_preformatted(&mut content, &text);
+            } else {
+                push_text(&mut content, &text, &mut space);
+            }
+            rest = &rest[end..];
+            continue;
+        };
+
+        rest = tail;
+        let name = tag.name.as_str();
+        if !tag.is_end && SKIPPED.contains(&name) {
+            let end = rest.to_ascii_lowercase().find(&format!("</{}", name));
+            rest = end
+                .and_then(|end| rest[end..].find('>').map(|i| &rest[end + i + 1..]))
+                .unwrap_or("");
+            continue;
+        }
+
+        if BLOCKS.contains(&name)
+            || matches!(
+                name,
+                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "ul" | "ol" | "li" | "pre" | "br"
+            )
+        {
+            space = false;
+        } else if space && !tag.is_end {
+            // the space before the inline element is not styled by it
+            content.push_str(" ");
+            space = false;
+        }
+
+        match (name, tag.is_end) {
+            ("b" | "strong", false) => content.start_style("bold", Value::Bool(true)),
+            ("b" | "strong", true) => content.end_style("bold"),
+            ("i" | "em", false) => content.start_style("italic", Value::Bool(true)),
+            ("i" | "em", true) => content.end_style("italic"),
+            ("s" | "strike" | "del", false) => content.start_style("strike", Value::Bool(true)),
+            ("s" | "strike" | "del", true) => content.end_style("strike"),
+            ("u", false) => content.start_style("underline", Value::Bool(true)),
+            ("u", true) => content.end_style("underline"),
+            ("code", false) if pre == 0 => content.start_style("code", Value::Bool(true)),
+            ("code", true) => content.end_style("code"),
+            ("a", false) => {
+                if let Some(href) = tag.get("href") {
+                    content.start_style("link", json!(href));
+                }
+            }
+            ("a", true) => content.end_style("link"),
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => {
+                content.start_block();
+                let level: u8 = name[1..].parse().unwrap();
+                content.set_line_attribute("header", json!(level));
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => content.end_line(false),
+            ("ul" | "ol", false) => {
+                content.start_block();
+                lists.push(if name == "ol" { "ordered" } else { "bullet" });
+            }
+            ("ul" | "ol", true) => {
+                content.start_block();
+                lists.pop();
+            }
+            ("li", false) => {
+                content.start_block();
+                let kind = lists.last().copied().unwrap_or("bullet");
+                content.set_line_attribute("list", json!(kind));
+            }
+            ("li", true) => content.end_line(false),
+            ("pre", false) => {
+                content.start_block();
+                pre += 1;
+            }
+            ("pre", true) => {
+                if pre > 0 && !content.is_line_empty() {
+                    content.set_line_attribute("code-block", Value::Bool(true));
+                    content.end_line(false);
+                }
+                pre = pre.saturating_sub(1);
+            }
+            ("br", _) => content.end_line(true),
+            ("img", false) => {
+                if let Some(src) = tag.get("src") {
+                    content.push_embed(json!({ "image": src }));
+                }
+            }
+            (name, false) if BLOCKS.contains(&name) => content.start_block(),
+            (name, true) if BLOCKS.contains(&name) => content.end_line(false),
+            _ => {}
+        }
+    }
+
+    content.finish()
+}
+
+/// Push the text with the whitespaces collapsed, and without the whitespaces at
+/// the start of the line
+fn push_text(content: &mut Content, text: &str, space: &mut bool) {
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            *space = !content.is_line_empty();
+            continue;
+        }
+
+        if *space {
+            content.push_str(" ");
+            *space = false;
+        }
+        content.push_str(c.encode_utf8(&mut [0; 4]));
+    }
+}
+
+/// Push the text of `<pre>`, every line of it is a code block
+fn push_preformatted(content: &mut Content, text: &str) {
+    for line in text.split_inclusive('\n') {
+        content.push_str(line.strip_suffix('\n').unwrap_or(line));
+        if line.ends_with('\n') {
+            content.set_line_attribute("code-block", Value::Bool(true));
+            content.end_line(true);
+        }
+    }
+}
+
+struct HtmlTag {
+    /// In lowercase
+    name: String,
+    is_end: bool,
+    attributes: Vec<(String, String)>,
+}
+
+impl HtmlTag {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|x| x.0 == name)
+            .map(|x| x.1.as_str())
+    }
+}
+
+/// Parse the tag at the start of the input, and return the rest of the input.
+///
+/// The doctype and the processing instructions are tags without name. Return
+/// `None` if it's not a tag, so the `<` is a char of the text
+fn parse_tag(input: &str) -> Option<(HtmlTag, &str)> {
+    let bytes = input.as_bytes();
+    let len = bytes.len();
+    let mut i = 1;
+    let is_end = bytes.get(i) == Some(&b'/');
+    if is_end {
+        i += 1;
+    }
+
+    if matches!(bytes.get(i), Some(b'!' | b'?')) {
+        let end = input.find('>')?;
+        let tag = HtmlTag {
+            name: String::new(),
+            is_end,
+            attributes: Vec::new(),
+        };
+        return Some((tag, &input[end + 1..]));
+    }
+
+    if !bytes.get(i)?.is_ascii_alphabetic() {
+        return None;
+    }
+
+    let start = i;
+    while i < len && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'-') {
+        i += 1;
+    }
+    let name = input[start..i].to_ascii_lowercase();
+    let mut attributes = Vec::new();
+    loop {
+        while i < len && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        if i >= len {
+            return None;
+        }
+        if bytes[i] == b'>' {
+            i += 1;
+            break;
+        }
+
+        let start = i;
+        while i < len && !bytes[i].is_ascii_whitespace() && !matches!(bytes[i], b'=' | b'>' | b'/')
+        {
+            i += 1;
+        }
+        let attribute = input[start..i].to_ascii_lowercase();
+        while i < len && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let mut value = String::new();
+        if i < len && bytes[i] == b'=' {
+            i += 1;
+            while i < len && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if i < len && matches!(bytes[i], b'"' | b'\'') {
+                let quote = bytes[i];
+                i += 1;
+                let start = i;
+                while i < len && bytes[i] != quote {
+                    i += 1;
+                }
+                value = decode_entities(&input[start..i]);
+                i = (i + 1).min(len);
+            } else {
+                let start = i;
+                while i < len && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                    i += 1;
+                }
+                value = decode_entities(&input[start..i]);
+            }
+        }
+        attributes.push((attribute, value));
+    }
+
+    let tag = HtmlTag {
+        name,
+        is_end,
+        attributes,
+    };
+    Some((tag, &input[i..]))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut ans = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        ans.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match decoded {
+            Some((c, end)) => {
+                ans.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                ans.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    ans.push_str(rest);
+    ans
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{A0}'),
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use pulldown_cmark::{Event, HeadingLevel, Options, Parser, Tag};
+use serde_json::{json, Value};
+
+use super::Content;
+
+pub(super) fn parse(markdown: &str) -> Content {
+    let mut content = Content::default();
+    // the kinds of the nested lists
+    let mut lists: Vec<&'static str> = Vec::new();
+    let mut in_code_block = false;
+    // the alt text of the images is skipped
+    let mut in_image = 0;
+    for event in Parser::new_ext(
+        markdown,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS,
+    ) {
+        match event {
+            Event::Start(Tag::Image(_, url, _)) => {
+                in_image += 1;
+                if in_image == 1 {
+                    content.push_embed(json!({ "image": url.to_string() }));
+                }
+            }
+            Event::End(Tag::Image(..)) => in_image -= 1,
+            _ if in_image > 0 => {}
+            Event::Start(tag) => match tag {
+                Tag::Paragraph => content.start_block(),
+                Tag::Heading(level, ..) => {
+                    content.start_block();
+                    content.set_line_attribute("header", json!(heading_level(level)));
+                }
+                Tag::CodeBlock(_) => {
+                    content.start_block();
+                    in_code_block = true;
+                }
+                Tag::List(start) => {
+                    content.start_block();
+                    lists.push(if start.is_some() { "ordered" } else { "bullet" });
+                }
+                Tag::Item => {
+                    content.start_block();
+                    let kind = lists.last().copied().unwrap_or("bullet");
+                    content.set_line_attribute("list", json!(kind));
+                }
+                Tag::Emphasis => content.start_style("italic", Value::Bool(true)),
+                Tag::Strong => content.start_style("bold", Value::Bool(true)),
+                Tag::Strikethrough => content.start_style("strike", Value::Bool(true)),
+                Tag::Link(_, url, _) => content.start_style("link", json!(url.to_string())),
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                Tag::Paragraph | Tag::Heading(..) | Tag::Item => content.end_line(false),
+                Tag::CodeBlock(_) => in_code_block = false,
+                Tag::List(_) => {
+                    lists.pop();
+                }
+                Tag::Emphasis => content.end_style("italic"),
+                Tag::Strong => content.end_style("bold"),
+                Tag::Strikethrough => content.end_style("strike"),
+                Tag::Link(..) => content.end_style("link"),
+                _ => {}
+            },
+            Event::Text(text) if in_code_block => {
+                // every line of the code block has the attribute
+                for line in text.split_inclusive('\n') {
+                    content.push_str(line.strip_suffix('\n').unwrap_or(line));
+                    if line.ends_with('\n') {
+                        content.set_line_attribute("code-block", Value::Bool(true));
+                        content.end_line(true);
+                    }
+                }
+            }
+            Event::Text(text) => content.push_str(&text),
+            Event::Code(text) => {
+                content.start_style("code", Value::Bool(true));
+                content.push_str(&text);
+                content.end_style("code");
+            }
+            Event::SoftBreak => content.push_str(" "),
+            Event::HardBreak => content.end_line(true),
+            Event::TaskListMarker(checked) => {
+                let value = if checked { "checked" } else { "unchecked" };
+                content.set_line_attribute("list", json!(value));
+            }
+            _ => {}
+        }
+    }
+
+    content.finish()
+}
+
+fn heading_level(level: HeadingLevel) -> u8 {
+    match level {
+        HeadingLevel::H1 => 1,
+        HeadingLevel::H2 => 2,
+        HeadingLevel::H3 => 3,
+        HeadingLevel::H4 => 4,
+        HeadingLevel::H5 => 5,
+        HeadingLevel::H6 => 6,
+    }
+}
@@ -113,6 +113,12 @@ impl<'a> Iterator for Iter<'a> {
                 && self
                     .end
                     .map_or(false, |end| end.elem_index == self.cursor.elem_index);
+            if is_end_elem && self.end.unwrap().offset <= self.cursor.offset {
+                // the range ends at the start of this element, it should not yield an empty span
+                self.done = true;
+                return pending_return;
+            }
+
             self.style_calc.apply_start(&elem.anchor_set);
             let annotations: FxHashMap<_, _> = self
                 .style_calc
@@ -138,6 +144,7 @@ impl<'a> Iterator for Iter<'a> {
                         .to_string()
                 },
                 attributes: annotations,
+                embed: elem.embed.as_deref().cloned(),
             };
 
             self.cursor.offset = 0;
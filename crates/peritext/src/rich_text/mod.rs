@@ -1,8 +1,13 @@
 use std::{
     cmp::Ordering,
     fmt::Display,
-    ops::{Bound, RangeBounds},
-    sync::Arc,
+    io::{Read, Write},
+    ops::{Bound, Range, RangeBounds},
+    sync::{
+        atomic::{self, AtomicBool},
+        mpsc::{channel, Receiver},
+        Arc,
+    },
 };
 
 use append_only_bytes::AppendOnlyBytes;
@@ -21,61 +26,111 @@ use crate::{
         op::OpContent,
         rich_tree::utf16::{bytes_to_str, get_utf16_len_and_line_breaks, Utf16LenAndLineBreaks},
     },
-    Anchor, AnchorType, Annotation, Behavior, ClientID, Counter, Expand, IdSpan, InternalString,
-    OpID, Style,
+    Anchor, AnchorType, Annotation, ClientID, Counter, IdSpan, InternalString, OpID, Style,
 };
 
 use self::{
     ann::{insert_anchor_to_char, AnchorSetDiff, AnnIdx, AnnManager, StyleCalculator},
-    cursor::CursorMap,
+    compact::RemovedSpans,
+    cursor::{CursorMap, StickyIndex},
     delta::compose,
-    encoding::{decode, encode},
+    embed::{escape_embed_char, EMBED_STR},
+    encoding::{decode, encode, encode_stream, read_frame},
     op::{Op, OpStore},
+    pending::PendingOps,
     rich_tree::{
         query::{IndexFinder, IndexFinderWithStyles, LineStartFinder},
         rich_tree_btree_impl::RichTreeTrait,
-        utf16::{get_utf16_len, utf16_to_utf8},
+        utf16::{get_unicode_len, get_utf16_len},
         CacheDiff, Elem,
     },
+    snapshot::{decode_snapshot, encode_snapshot},
+    txn::TxnState,
+    undo::UndoOp,
     vv::VersionVector,
 };
 
 pub use ann::Span;
+pub use block::Line;
+pub use cursor::StickyIndex;
 pub use delta::DeltaItem;
+pub use embed::{EMBED_CHAR, EMBED_CHAR_REPLACEMENT};
+pub use encoding::merge_updates;
 pub use error::Error;
-pub use event::Event;
+pub use event::{Event, EventFilter, Subscription};
+pub use export::{DefaultStyleSerializer, HtmlElement, HtmlStyle, StyleSerializer};
 pub use rich_tree::query::IndexType;
+pub use schema::{StyleConfig, StyleKind, StyleSchema, ValueSchema};
+pub use search::Pattern;
+pub use txn::Transaction;
+pub use undo::{UndoManager, UndoOutcome};
 
 mod ann;
+mod ann_query;
+mod block;
+mod checkout;
+mod compact;
 pub mod cursor;
 mod delta;
+mod embed;
 mod encoding;
 mod error;
 mod event;
+mod export;
+mod grapheme;
 mod id_map;
+mod import;
 mod iter;
 mod op;
+mod patch;
+mod pending;
+mod position;
 mod rich_tree;
+mod schema;
+mod search;
+mod snapshot;
 #[cfg(all(test, feature = "test"))]
 mod test;
 #[cfg(feature = "test")]
 pub mod test_utils;
+mod txn;
+mod undo;
 pub mod vv;
 
-type Listener = Box<dyn FnMut(&Event)>;
+type Listener = Box<dyn FnMut(&Event) + Send>;
+
+struct ListenerEntry {
+    listener: Listener,
+    filter: EventFilter,
+    /// turned off when the subscription is dropped
+    active: Arc<AtomicBool>,
+}
+
+impl ListenerEntry {
+    #[inline(always)]
+    fn is_active(&self) -> bool {
+        self.active.load(atomic::Ordering::Relaxed)
+    }
+}
 
 pub struct RichText {
     bytes: AppendOnlyBytes,
     content: BTree<RichTreeTrait>,
     cursor_map: CursorMap,
     store: OpStore,
-    pending_ops: Vec<Op>,
+    pending_ops: PendingOps,
     ann: AnnManager,
     /// this is the styles starting from the very beginning,
     /// which have start anchor of None
     init_styles: StyleCalculator,
-    listeners: Vec<Listener>,
+    listeners: Vec<ListenerEntry>,
     event_index_type: IndexType,
+    undo: Option<UndoManager>,
+    /// the tombstones removed by [RichText::compact]
+    removed: RemovedSpans,
+    /// the state of the current transaction, see [RichText::transact]
+    txn: Option<TxnState>,
+    schema: StyleSchema,
 }
 
 impl RichText {
@@ -94,6 +149,10 @@ impl RichText {
             init_styles: StyleCalculator::default(),
             listeners: Vec::new(),
             event_index_type: IndexType::Utf8,
+            undo: None,
+            removed: Default::default(),
+            txn: None,
+            schema: StyleSchema::default(),
         }
     }
 
@@ -101,23 +160,103 @@ impl RichText {
         self.store.client
     }
 
+    /// Copy the document with a new client id.
+    ///
+    /// Forking rebuilds the tree and copies the ops, so it takes O(n) time and memory
+    /// in the size of the document. Only the text bytes are shared. The fork can be
+    /// moved to another thread, e.g. to export or render it, and it can be edited
+    /// and merged back. The listeners and the undo history are not copied.
+    pub fn fork(&self, client_id: ClientID) -> RichText {
+        let mut cursor_map: CursorMap = Default::default();
+        let mut content: BTree<RichTreeTrait> = BTree::new();
+        content.set_listener(Some(cursor_map.gen_update_fn()));
+        for elem in self.content.iter() {
+            content.push(elem.clone());
+        }
+
+        cursor_map.flush();
+
+        let mut store = self.store.clone();
+        store.client = client_id;
+        RichText {
+            bytes: AppendOnlyBytes::new(),
+            content,
+            cursor_map,
+            store,
+            pending_ops: self.pending_ops.clone(),
+            ann: self.ann.clone(),
+            init_styles: self.init_styles.clone(),
+            listeners: Vec::new(),
+            event_index_type: self.event_index_type,
+            undo: None,
+            removed: self.removed.clone(),
+            txn: None,
+            schema: self.schema.clone(),
+        }
+    }
+
     pub fn set_event_index_type(&mut self, index_type: IndexType) {
         self.event_index_type = index_type;
     }
 
-    pub fn observe(&mut self, listener: Listener) {
-        self.listeners.push(listener);
+    /// Listen to the changes of the document.
+    ///
+    /// The listener is removed when the returned [Subscription] is dropped.
+    pub fn observe(&mut self, listener: Listener) -> Subscription {
+        self.observe_filtered(EventFilter::default(), listener)
+    }
+
+    /// Listen to the changes that match the filter
+    pub fn observe_filtered(&mut self, filter: EventFilter, listener: Listener) -> Subscription {
+        Subscription::new(self.add_listener(filter, listener))
+    }
+
+    /// Receive the events through a channel, so they can be polled elsewhere.
+    ///
+    /// The listener is removed when the receiver is dropped.
+    pub fn observe_channel(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        let active = Arc::new(AtomicBool::new(true));
+        let active_clone = active.clone();
+        self.listeners.push(ListenerEntry {
+            listener: Box::new(move |event| {
+                if sender.send(event.clone()).is_err() {
+                    active_clone.store(false, atomic::Ordering::Relaxed);
+                }
+            }),
+            filter: EventFilter::default(),
+            active,
+        });
+        receiver
+    }
+
+    fn add_listener(&mut self, filter: EventFilter, listener: Listener) -> Arc<AtomicBool> {
+        let active = Arc::new(AtomicBool::new(true));
+        self.listeners.push(ListenerEntry {
+            listener,
+            filter,
+            active: active.clone(),
+        });
+        active
     }
 
     #[inline(always)]
     fn has_listener(&self) -> bool {
-        !self.listeners.is_empty()
+        self.listeners.iter().any(|x| x.is_active())
     }
 
     fn emit(&mut self, mut event: Event) {
+        if let Some(txn) = &mut self.txn {
+            txn.push(event);
+            return;
+        }
+
         event.ops.retain(|x| !x.should_remove());
-        for listener in &mut self.listeners {
-            listener(&event);
+        self.listeners.retain(|x| x.is_active());
+        for entry in &mut self.listeners {
+            if entry.filter.matches(&event) {
+                (entry.listener)(&event);
+            }
         }
     }
 
@@ -126,6 +265,11 @@ impl RichText {
         self.store.next_id()
     }
 
+    /// Insert the string at the utf16 index.
+    ///
+    /// The [EMBED_CHAR]s in the string are replaced with [EMBED_CHAR_REPLACEMENT],
+    /// which has the same utf16 length but is 3 bytes long in utf8. It's the same
+    /// for all the inserts of the text.
     #[inline]
     pub fn insert_utf16(&mut self, index: usize, string: &str) {
         assert!(index <= self.utf16_len());
@@ -138,7 +282,25 @@ impl RichText {
         self.insert_inner(index, string, IndexType::Utf8);
     }
 
+    /// Insert the string at the index in chars, see [IndexType::Unicode]
+    #[inline]
+    pub fn insert_unicode(&mut self, index: usize, string: &str) {
+        assert!(index <= self.len_unicode());
+        self.insert_inner(index, string, IndexType::Unicode);
+    }
+
     fn insert_inner(&mut self, index: usize, string: &str, index_type: IndexType) {
+        self.insert_with_embed(index, &escape_embed_char(string), None, index_type)
+    }
+
+    /// Insert the string, or the placeholder of the embed if `embed` is set
+    fn insert_with_embed(
+        &mut self,
+        index: usize,
+        string: &str,
+        embed: Option<Arc<Value>>,
+        index_type: IndexType,
+    ) {
         if string.is_empty() {
             return;
         }
@@ -153,6 +315,7 @@ impl RichText {
                 && elem.id.counter + elem.atom_len() as Counter == id.counter
                 && elem.right == right
                 && !elem.is_dead()
+                && elem.embed.is_none()
                 && elem.string.can_merge(slice)
                 && !elem.has_after_anchor()
         }
@@ -160,10 +323,15 @@ impl RichText {
         let start = self.bytes.len();
         self.bytes.push_str(string);
         let slice = self.bytes.slice(start..);
-        let Utf16LenAndLineBreaks { utf16, line_breaks } = get_utf16_len_and_line_breaks(&slice);
+        let Utf16LenAndLineBreaks {
+            utf16,
+            unicode,
+            line_breaks,
+        } = get_utf16_len_and_line_breaks(&slice);
         let cache_diff = Some(CacheDiff::new_len_diff(
             string.len() as isize,
             utf16 as isize,
+            unicode as isize,
             line_breaks as isize,
         ));
         let id = self.next_id();
@@ -175,10 +343,20 @@ impl RichText {
                 .elements()
                 .first()
                 .map(|x| x.id);
-            self.store
-                .insert_local(OpContent::new_insert(None, right_origin, slice.clone()));
-            self.content
-                .prepend(Elem::new(id, None, right_origin, slice));
+            self.store.insert_local(OpContent::new_insert(
+                None,
+                right_origin,
+                slice.clone(),
+                embed.clone(),
+            ));
+            self.content.prepend(Elem::new_with_embed(
+                id,
+                None,
+                right_origin,
+                slice,
+                embed.clone(),
+            ));
+            self.cursor_map.flush();
         } else {
             // need to find left op id
             let path_to_right_origin = self.find_ideal_right_origin(index, index_type);
@@ -223,7 +401,9 @@ impl RichText {
                     }
 
                     if offset == elements[index].rle_len() {
-                        if can_merge_new_slice(&elements[index], id, right, &slice) {
+                        if embed.is_none()
+                            && can_merge_new_slice(&elements[index], id, right, &slice)
+                        {
                             // can merge directly
                             elements[index].merge_slice(&slice);
                             self.cursor_map.update(MoveEvent::new_move(
@@ -233,7 +413,10 @@ impl RichText {
                             return (true, cache_diff);
                         }
 
-                        elements.insert(index + 1, Elem::new(id, left, right, slice));
+                        elements.insert(
+                            index + 1,
+                            Elem::new_with_embed(id, left, right, slice, embed.clone()),
+                        );
                         self.cursor_map.update(MoveEvent::new_move(
                             path_to_right_origin.leaf,
                             &elements[index + 1],
@@ -245,7 +428,10 @@ impl RichText {
                     let right_half = elements[index].split(offset);
                     elements.splice(
                         index + 1..index + 1,
-                        [Elem::new(id, left, right, slice), right_half],
+                        [
+                            Elem::new_with_embed(id, left, right, slice, embed.clone()),
+                            right_half,
+                        ],
                     );
                     self.cursor_map.update(MoveEvent::new_move(
                         path_to_right_origin.leaf,
@@ -253,28 +439,36 @@ impl RichText {
                     ));
                     (true, cache_diff)
                 });
+            self.cursor_map.flush();
 
             self.store
-                .insert_local(OpContent::new_insert(left, right, op_slice));
+                .insert_local(OpContent::new_insert(left, right, op_slice, embed.clone()));
         }
 
+        self.record_undo(UndoOp::Insert {
+            id,
+            len: string.len(),
+        });
+
         if self.has_listener() {
             let retain = self.convert_index(index, index_type, self.event_index_type);
             let annotations = self
                 .get_style_at_position(index, index_type)
                 .map(|(k, v)| (k.to_string(), v))
                 .collect();
+            let insert = match embed {
+                Some(embed) => DeltaItem::embed_with_attributes((*embed).clone(), annotations),
+                None => DeltaItem::insert_with_attributes(
+                    string.to_owned(),
+                    self.event_index_type,
+                    annotations,
+                ),
+            };
             self.emit(Event {
-                ops: vec![
-                    DeltaItem::retain(retain),
-                    DeltaItem::insert_with_attributes(
-                        string.to_owned(),
-                        self.event_index_type,
-                        annotations,
-                    ),
-                ],
+                ops: vec![DeltaItem::retain(retain), insert],
                 is_local: true,
                 index_type: self.event_index_type,
+                origin: None,
             })
         }
     }
@@ -432,6 +626,11 @@ impl RichText {
         self.delete_inner(range, IndexType::Utf8);
     }
 
+    /// Delete the range in chars, see [IndexType::Unicode]
+    pub fn delete_unicode(&mut self, range: impl RangeBounds<usize>) {
+        self.delete_inner(range, IndexType::Unicode);
+    }
+
     fn delete_inner(&mut self, range: impl RangeBounds<usize>, index_type: IndexType) {
         let start = match range.start_bound() {
             Bound::Included(start) => *start,
@@ -450,6 +649,11 @@ impl RichText {
 
         assert!(end <= self.len_with(index_type));
 
+        let undo_op = if self.undo.is_some() {
+            self.capture_deletion(start, end, index_type)
+        } else {
+            None
+        };
         let event = if self.has_listener() {
             let retain = self.convert_index(start, index_type, self.event_index_type);
             let end = self.convert_index(end, index_type, self.event_index_type);
@@ -457,6 +661,7 @@ impl RichText {
                 ops: vec![DeltaItem::retain(retain), DeltaItem::delete(end - retain)],
                 is_local: true,
                 index_type: self.event_index_type,
+                origin: None,
             })
         } else {
             None
@@ -473,10 +678,11 @@ impl RichText {
                 (
                     -(elem.rle_len() as isize),
                     -(elem.utf16_len as isize),
+                    -(elem.unicode_len as isize),
                     -(elem.line_breaks as isize),
                 )
             } else {
-                (0, 0, 0)
+                (0, 0, 0, 0)
             }
         };
         self.content.update_with_filter(
@@ -498,7 +704,8 @@ impl RichText {
 
                         let (additions, diff) =
                             elem.update(start_offset, end_offset, &mut delete_fn);
-                        let (len_diff, utf16_len_diff, line_break_diff) = diff.unwrap();
+                        let (len_diff, utf16_len_diff, unicode_len_diff, line_break_diff) =
+                            diff.unwrap();
                         if !additions.is_empty() {
                             slice
                                 .elements
@@ -512,6 +719,7 @@ impl RichText {
                             Some(CacheDiff::new_len_diff(
                                 len_diff,
                                 utf16_len_diff,
+                                unicode_len_diff,
                                 line_break_diff,
                             )),
                         );
@@ -521,6 +729,7 @@ impl RichText {
 
                 let mut len_diff = 0;
                 let mut utf16_len_diff = 0;
+                let mut unicode_len_diff = 0;
                 let mut line_break_diff = 0;
                 let mut end = match slice.end {
                     Some((end_idx, end_offset)) => {
@@ -535,7 +744,8 @@ impl RichText {
                                 }
                                 len_diff += diff.unwrap().0;
                                 utf16_len_diff += diff.unwrap().1;
-                                line_break_diff += diff.unwrap().2;
+                                unicode_len_diff += diff.unwrap().2;
+                                line_break_diff += diff.unwrap().3;
                             }
                             end_idx + 1
                         }
@@ -560,7 +770,8 @@ impl RichText {
                                 }
                                 len_diff += diff.unwrap().0;
                                 utf16_len_diff += diff.unwrap().1;
-                                line_break_diff += diff.unwrap().2;
+                                unicode_len_diff += diff.unwrap().2;
+                                line_break_diff += diff.unwrap().3;
                             }
                             start_idx + 1
                         }
@@ -573,7 +784,8 @@ impl RichText {
                         let diff = delete_fn(elem);
                         len_diff += diff.0;
                         utf16_len_diff += diff.1;
-                        line_break_diff += diff.2;
+                        unicode_len_diff += diff.2;
+                        line_break_diff += diff.3;
                     }
                 }
 
@@ -588,18 +800,24 @@ impl RichText {
                     Some(CacheDiff::new_len_diff(
                         len_diff,
                         utf16_len_diff,
+                        unicode_len_diff,
                         line_break_diff,
                     )),
                 )
             },
             &|cache| cache.len > 0,
         );
+        self.cursor_map.flush();
 
         for (start, len) in deleted {
             self.store
                 .insert_local(OpContent::new_delete(start, len as i32));
         }
 
+        if let Some(undo_op) = undo_op {
+            self.record_undo(undo_op);
+        }
+
         if let Some(event) = event {
             self.emit(event)
         }
@@ -612,8 +830,18 @@ impl RichText {
     ///
     /// Although the arg is a range bound, a `..` range doesn't necessary means the start anchor
     /// and the end anchor is None. Because the range is also depends on the anchor type.
-    pub fn annotate_utf16(&mut self, range: impl RangeBounds<usize>, style: Style) {
-        self.annotate_inner(range, style, IndexType::Utf16)
+    ///
+    /// An error is returned if the type is a block style (use [RichText::format_line]),
+    /// if the expand or the behavior doesn't match the registered config of the type,
+    /// or if the value can't be converted to the value schema of the type, see [StyleSchema].
+    pub fn annotate_utf16(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        mut style: Style,
+    ) -> Result<(), Error> {
+        self.schema.check(&mut style)?;
+        self.annotate_inner(range, style, IndexType::Utf16);
+        Ok(())
     }
 
     /// Annotate the given range with style.
@@ -623,8 +851,33 @@ impl RichText {
     ///
     /// Although the arg is a range bound, a `..` range doesn't necessary means the start anchor
     /// and the end anchor is None. Because the range is also depends on the anchor type.
-    pub fn annotate(&mut self, range: impl RangeBounds<usize>, style: Style) {
-        self.annotate_inner(range, style, IndexType::Utf8)
+    ///
+    /// An error is returned if the type is a block style (use [RichText::format_line]),
+    /// if the expand or the behavior doesn't match the registered config of the type,
+    /// or if the value can't be converted to the value schema of the type, see [StyleSchema].
+    pub fn annotate(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        mut style: Style,
+    ) -> Result<(), Error> {
+        self.schema.check(&mut style)?;
+        self.annotate_inner(range, style, IndexType::Utf8);
+        Ok(())
+    }
+
+    /// Annotate the given range in chars with style, see [RichText::annotate].
+    ///
+    /// An error is returned if the type is a block style (use [RichText::format_line]),
+    /// if the expand or the behavior doesn't match the registered config of the type,
+    /// or if the value can't be converted to the value schema of the type, see [StyleSchema].
+    pub fn annotate_unicode(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        mut style: Style,
+    ) -> Result<(), Error> {
+        self.schema.check(&mut style)?;
+        self.annotate_inner(range, style, IndexType::Unicode);
+        Ok(())
     }
 
     fn annotate_inner(
@@ -660,11 +913,17 @@ impl RichText {
                 ],
                 is_local: true,
                 index_type: self.event_index_type,
+                origin: None,
             })
         } else {
             None
         };
         inclusive_end = inclusive_end.min(self.len_with(index_type) - 1);
+        let undo_op = if self.undo.is_some() {
+            Some(self.capture_annotation(start..inclusive_end + 1, &style, index_type))
+        } else {
+            None
+        };
         let start = if style.expand.start_type() == AnchorType::Before {
             Some(self.content.query::<IndexFinder>(&(start, index_type)))
         } else if start == 0 {
@@ -733,6 +992,7 @@ impl RichText {
                     );
                     (true, Some(AnchorSetDiff::from_ann(ann_idx, true).into()))
                 });
+                self.cursor_map.flush();
                 // the target ends when the doc ends,
                 // so we do not need to insert an end anchor
             }
@@ -748,6 +1008,7 @@ impl RichText {
                     );
                     (true, Some(AnchorSetDiff::from_ann(ann_idx, false).into()))
                 });
+                self.cursor_map.flush();
                 self.init_styles.insert_start(ann_idx);
             }
             (None, None) => {
@@ -758,6 +1019,10 @@ impl RichText {
 
         // register op to store
         self.store.insert_local(OpContent::new_ann(ann));
+        if let Some(undo_op) = undo_op {
+            self.record_undo(undo_op);
+        }
+
         if let Some(event) = event {
             self.emit(event)
         }
@@ -836,7 +1101,8 @@ impl RichText {
                         true
                     }
                 }
-            })
+            });
+        self.cursor_map.flush();
     }
 
     fn get_id_at_pos(&self, pos: QueryResult) -> OpID {
@@ -847,6 +1113,90 @@ impl RichText {
         elem.id.inc(pos.offset as u32)
     }
 
+    /// Get the anchor that stays at the given position.
+    ///
+    /// - [AnchorType::Before] is attached to the char at the index
+    /// - [AnchorType::After] is attached to the last byte of the char that ends at the index
+    pub(crate) fn index_to_anchor(
+        &self,
+        index: usize,
+        type_: AnchorType,
+        index_type: IndexType,
+    ) -> Anchor {
+        match type_ {
+            AnchorType::Before => {
+                if index >= self.len_with(index_type) {
+                    return Anchor::before_none();
+                }
+
+                let path = self.content.query::<IndexFinder>(&(index, index_type));
+                Anchor::before(self.get_id_at_pos(path))
+            }
+            AnchorType::After => {
+                if index == 0 {
+                    return Anchor::after_none();
+                }
+
+                // the utf8 index is a char boundary, so the byte before it is the
+                // last byte of the char that ends at the index
+                let utf8 = self.convert_index(index, index_type, IndexType::Utf8);
+                let path = self
+                    .content
+                    .query::<IndexFinder>(&(utf8 - 1, IndexType::Utf8));
+                Anchor::after(self.get_id_at_pos(path))
+            }
+        }
+    }
+
+    /// Get the current index of the anchor.
+    ///
+    /// If the char of the anchor is deleted, it's the index of the position the char used to be.
+    pub(crate) fn anchor_to_index(&self, anchor: &Anchor, index_type: IndexType) -> usize {
+        match (anchor.id, anchor.type_) {
+            (None, AnchorType::After) => 0,
+            (None, AnchorType::Before) => self.len_with(index_type),
+            (Some(id), AnchorType::Before) => {
+                self.get_index_from_path(self.find_cursor(id), index_type)
+            }
+            (Some(id), AnchorType::After) => match self.find_next_cursor_of(Some(id)) {
+                Some(path) => self.get_index_from_path(path, index_type),
+                None => self.len_with(index_type),
+            },
+        }
+    }
+
+    /// Get a [StickyIndex] of the given position, which can be resolved to the
+    /// current index of the position after the text is edited.
+    pub fn get_sticky_index(
+        &self,
+        index: usize,
+        type_: AnchorType,
+        index_type: IndexType,
+    ) -> StickyIndex {
+        assert!(index <= self.len_with(index_type));
+        self.index_to_anchor(index, type_, index_type).into()
+    }
+
+    /// Get the current index of the [StickyIndex].
+    ///
+    /// Return `None` if the char it's attached to is not in this document yet,
+    /// e.g. it's created by a peer whose changes haven't been imported.
+    pub fn resolve_sticky_index(
+        &self,
+        index: &StickyIndex,
+        index_type: IndexType,
+    ) -> Option<usize> {
+        if let Some(id) = index.id() {
+            if self.cursor_map.get_insert(id).is_none() {
+                // the char may be removed by compaction
+                let anchor = self.removed.redirect_anchor((*index).into())?;
+                return Some(self.anchor_to_index(&anchor, index_type));
+            }
+        }
+
+        Some(self.anchor_to_index(&(*index).into(), index_type))
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = Span> + '_ {
         iter::Iter::new(self)
     }
@@ -855,8 +1205,33 @@ impl RichText {
         self.iter().collect()
     }
 
-    pub fn iter_range(&self, _range: impl RangeBounds<usize>) {
-        todo!()
+    /// Iterate the spans inside the given range lazily.
+    ///
+    /// Unlike [`RichText::slice`], it doesn't collect the spans, so it's cheap to
+    /// only visit a small window of a long document.
+    pub fn iter_range(
+        &self,
+        range: impl RangeBounds<usize>,
+        index_type: IndexType,
+    ) -> impl Iterator<Item = Span> + '_ {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len_with(index_type),
+        };
+
+        let (start, finder) = self
+            .content
+            .query_with_finder_return::<IndexFinderWithStyles>(&(start, index_type));
+        let mut style = finder.style_calculator;
+        style.apply_init_styles(&self.init_styles);
+        let end = self.content.query::<IndexFinder>(&(end, index_type));
+        iter::Iter::new_range(self, start, Some(end), style)
     }
 
     pub fn len(&self) -> usize {
@@ -867,10 +1242,16 @@ impl RichText {
         self.content.root_cache().utf16_len as usize
     }
 
+    /// The number of chars, i.e. the len in [IndexType::Unicode]
+    pub fn len_unicode(&self) -> usize {
+        self.content.root_cache().unicode_len as usize
+    }
+
     fn len_with(&self, index_type: IndexType) -> usize {
         match index_type {
             IndexType::Utf8 => self.content.root_cache().len as usize,
             IndexType::Utf16 => self.content.root_cache().utf16_len as usize,
+            IndexType::Unicode => self.content.root_cache().unicode_len as usize,
         }
     }
 
@@ -882,16 +1263,86 @@ impl RichText {
         self.content.root_cache().utf16_len as usize
     }
 
-    pub fn export(&self, vv: &VersionVector) -> Vec<u8> {
-        encode(self.store.export(vv))
+    /// Export the updates that are not included by `vv`.
+    ///
+    /// If some of them were removed by [RichText::compact], the peer at `vv`
+    /// can't catch up with the updates and [Error::HistoryTrimmed] is returned.
+    /// Send it [RichText::export_snapshot] instead.
+    pub fn export(&self, vv: &VersionVector) -> Result<Vec<u8>, Error> {
+        Ok(encode(self.store.export(vv)?))
+    }
+
+    /// The version whose history was removed by [RichText::compact].
+    ///
+    /// Every peer that [RichText::export] can send updates to includes it.
+    pub fn trimmed_version(&self) -> &VersionVector {
+        self.store.trimmed_vv()
+    }
+
+    /// Import the updates exported by [RichText::export].
+    ///
+    /// The ops whose dependencies are missing are kept, and they are applied
+    /// automatically once the dependencies arrive, see [RichText::missing_deps].
+    ///
+    /// If the data is malformed, or it refers to ids that are not chars,
+    /// an error is returned and the doc is left unchanged.
+    ///
+    /// The pending ops are only checked when their dependencies arrive. If one of
+    /// them refers to an id that is not a char, it's dropped and
+    /// [Error::DanglingOpID] is returned, but the other ops are still applied.
+    pub fn import(&mut self, data: &[u8]) -> Result<(), Error> {
+        let updates = decode(data)?;
+        self.check_deps(&updates)?;
+        self.import_inner(updates)
+    }
+
+    /// Write the updates that are not included by `vv` to the writer as a stream of
+    /// small frames, without collecting them in memory first.
+    ///
+    /// Every frame is a blob that [RichText::import] accepts too. Like
+    /// [RichText::export], it fails with [Error::HistoryTrimmed] before writing
+    /// anything if the peer at `vv` needs the compacted history.
+    pub fn export_stream(&self, vv: &VersionVector, writer: impl Write) -> Result<(), Error> {
+        encode_stream(self.store.export_iter(vv)?, writer)?;
+        Ok(())
+    }
+
+    /// Import the stream written by [RichText::export_stream], reading one frame
+    /// at a time.
+    ///
+    /// The frames are imported as they are read. So if a frame is malformed, the
+    /// frames before it stay imported and the error is returned.
+    pub fn import_stream(&mut self, mut reader: impl Read) -> Result<(), Error> {
+        while let Some(frame) = read_frame(&mut reader)? {
+            self.import(&frame)?;
+        }
+
+        Ok(())
     }
 
-    pub fn import(&mut self, data: &[u8]) {
-        self.import_inner(decode(data));
+    /// Export the current state of the document, which is much faster to load
+    /// than replaying all the updates.
+    ///
+    /// The snapshot doesn't contain the op history. So the doc loaded from it can
+    /// only send the updates after the snapshot's version to the other peers.
+    pub fn export_snapshot(&self) -> Vec<u8> {
+        encode_snapshot(self)
+    }
+
+    /// Load the document from the data exported by [RichText::export_snapshot].
+    ///
+    /// The new doc uses `client_id` for its local edits.
+    /// It can import the updates from the peers whose versions are no less than
+    /// the snapshot's version.
+    ///
+    /// If the data is malformed, an error is returned.
+    pub fn from_snapshot(client_id: u64, data: &[u8]) -> Result<Self, Error> {
+        decode_snapshot(client_id, data)
     }
 
     fn apply(&mut self, op: Op) -> Vec<DeltaItem> {
         debug_log::group!("apply op");
+        let op = self.removed.redirect(op);
         let mut ans = Vec::new();
         let has_listener = self.has_listener();
         'apply: {
@@ -920,6 +1371,7 @@ impl RichText {
                                     Some(AnchorSetDiff::from_ann(ann_idx, is_start).into()),
                                 )
                             });
+                            self.cursor_map.flush();
                             if has_listener {
                                 ans.push(DeltaItem::retain(start));
                             }
@@ -948,6 +1400,7 @@ impl RichText {
                                 Some(AnchorSetDiff::from_ann(ann_idx, is_start).into()),
                             )
                         });
+                        self.cursor_map.flush();
                     }
                     if has_listener {
                         let mut attributes: FxHashMap<_, _> = Default::default();
@@ -961,23 +1414,21 @@ impl RichText {
                         None => {
                             // insert to the last
                             let index = self.len_with(self.event_index_type);
-                            self.content.push(Elem::new(
+                            self.content.push(Elem::new_with_embed(
                                 op.id,
                                 text.left,
                                 text.right,
                                 text.text.clone(),
+                                text.embed.clone(),
                             ));
+                            self.cursor_map.flush();
                             if has_listener {
                                 let annotations = self
                                     .get_style_at_position(index, self.event_index_type)
                                     .map(|(k, v)| (k.to_string(), v))
                                     .collect();
                                 ans.push(DeltaItem::retain(index));
-                                ans.push(DeltaItem::insert_with_attributes(
-                                    bytes_to_str(&text.text).to_owned(),
-                                    self.event_index_type,
-                                    annotations,
-                                ));
+                                ans.push(inserted_delta(text, self.event_index_type, annotations));
                             }
                             break 'apply;
                         }
@@ -990,18 +1441,27 @@ impl RichText {
                         }
                         self.content.insert_by_query_result(
                             right,
-                            Elem::new(op.id, text.left, text.right, text.text.clone()),
+                            Elem::new_with_embed(
+                                op.id,
+                                text.left,
+                                text.right,
+                                text.text.clone(),
+                                text.embed.clone(),
+                            ),
                         );
+                        self.cursor_map.flush();
                     } else {
                         if has_listener {
                             index = self.len_with(self.event_index_type);
                         }
-                        self.content.push(Elem::new(
+                        self.content.push(Elem::new_with_embed(
                             op.id,
                             text.left,
                             text.right,
                             text.text.clone(),
+                            text.embed.clone(),
                         ));
+                        self.cursor_map.flush();
                     }
 
                     if has_listener {
@@ -1010,17 +1470,16 @@ impl RichText {
                             .map(|(k, v)| (k.to_string(), v))
                             .collect();
                         ans.push(DeltaItem::retain(index));
-                        ans.push(DeltaItem::insert_with_attributes(
-                            bytes_to_str(&text.text).to_owned(),
-                            self.event_index_type,
-                            annotations,
-                        ));
+                        ans.push(inserted_delta(text, self.event_index_type, annotations));
                     }
                 }
                 OpContent::Del(del) => {
                     let del = del.positive();
                     self.delete_in_id_range(del.start, del.len as usize, &mut ans)
                 }
+                OpContent::Patch(patch) => {
+                    ans = self.apply_patch(patch);
+                }
             }
         }
 
@@ -1148,38 +1607,37 @@ impl RichText {
     }
 
     /// Merge data from other data into self
+    ///
+    /// # Panics
+    ///
+    /// Panics if `other` was compacted after a version that self doesn't include,
+    /// see [RichText::export], or if a pending op of self is dropped, see
+    /// [RichText::import].
     pub fn merge(&mut self, other: &Self) {
         let vv = self.store.vv();
-        let exported = other.export(&vv);
-        let exported = decode(&exported);
+        let exported = other
+            .export(&vv)
+            .expect("the history needed by the merge is compacted");
+        let exported = decode(&exported).unwrap();
         if cfg!(debug_assertions) || cfg!(feature = "test") {
-            let expected = other.store.export(&vv);
+            let expected = other.store.export(&vv).unwrap();
             assert_eq!(exported, expected);
         }
 
-        self.import_inner(exported);
+        if let Err(err) = self.import_inner(exported) {
+            panic!("failed to merge: {err}");
+        }
     }
 
-    fn import_inner(&mut self, exported: FxHashMap<ClientID, Vec<Op>>) {
+    /// Apply the ops, or queue them if their dependencies are missing. The error of
+    /// a dropped op is returned after the others are applied
+    fn import_inner(&mut self, exported: FxHashMap<ClientID, Vec<Op>>) -> Result<(), Error> {
+        let mut ans = Ok(());
         let mut all_ops = Vec::new();
+        let mut inserted = Default::default();
         for (_, ops) in exported {
-            for mut op in ops {
-                let op = match self.store.can_apply(&op) {
-                    op::CanApply::Yes => op,
-                    op::CanApply::Trim(len) => {
-                        op.slice_(len as usize..);
-                        op
-                    }
-                    op::CanApply::Pending => {
-                        self.pending_ops.push(op);
-                        continue;
-                    }
-                    op::CanApply::Seen => {
-                        continue;
-                    }
-                };
-                self.store.insert(op.clone());
-                all_ops.push(op);
+            for op in ops {
+                ans = ans.and(self.add_op(op, &mut all_ops, &mut inserted));
             }
         }
         all_ops.sort_by(|a, b| a.lamport.cmp(&b.lamport));
@@ -1212,8 +1670,11 @@ impl RichText {
                 ops: delta,
                 is_local: false,
                 index_type: self.event_index_type,
+                origin: None,
             })
         }
+
+        ans
     }
 
     pub fn version(&self) -> VersionVector {
@@ -1228,7 +1689,14 @@ impl RichText {
         // debug_log::group_end!();
         let has_listener = self.has_listener();
         while len > 0 {
-            let (insert_leaf, mut leaf_del_len) = self.cursor_map.get_insert(id).unwrap();
+            let Some((insert_leaf, mut leaf_del_len)) = self.cursor_map.get_insert(id) else {
+                // the chars are already deleted and removed by compaction
+                let (span, offset) = self.removed.get(id).expect("Cannot find target id");
+                let skip = (span.len - offset).min(len);
+                id.counter += skip as Counter;
+                len -= skip;
+                continue;
+            };
             leaf_del_len = leaf_del_len.min(len);
             // next record retain value
             let mut retain = if has_listener {
@@ -1298,6 +1766,7 @@ impl RichText {
                 // TODO: Perf can be optimized by merge the cache diff from f
                 (true, None)
             });
+            self.cursor_map.flush();
 
             *ans = compose(ans.clone(), new_delta);
             id.counter += leaf_del_len as Counter;
@@ -1421,23 +1890,26 @@ impl RichText {
         }
     }
 
-    pub fn get_line(&self, line: usize) -> Vec<Span> {
+    /// Get the spans and the block attributes of the line
+    pub fn get_line(&self, line: usize) -> Line {
         let (start, finder) = self
             .content
             .query_with_finder_return::<LineStartFinder>(&line);
         if !start.found {
-            return Vec::new();
+            return Line::default();
         }
 
         let end = self.content.query::<LineStartFinder>(&(line + 1));
+        let mut style = finder.style_calculator;
+        style.apply_init_styles(&self.init_styles);
         let iter = iter::Iter::new_range(
             self,
             start,
             if end.found { Some(end) } else { None },
-            finder.style_calculator,
+            style,
         );
 
-        iter.collect()
+        self.to_line(iter.collect())
     }
 
     pub fn slice_str(&self, range: impl RangeBounds<usize>, index_type: IndexType) -> String {
@@ -1466,28 +1938,7 @@ impl RichText {
     }
 
     pub fn slice(&self, range: impl RangeBounds<usize>, index_type: IndexType) -> Vec<Span> {
-        let start = match range.start_bound() {
-            Bound::Included(&start) => start,
-            Bound::Excluded(&start) => start + 1,
-            Bound::Unbounded => 0,
-        };
-        let end = match range.end_bound() {
-            Bound::Included(&end) => end + 1,
-            Bound::Excluded(&end) => end,
-            Bound::Unbounded => self.len_with(index_type),
-        };
-
-        let mut ans = Vec::new();
-        let (start, finder) = self
-            .content
-            .query_with_finder_return::<IndexFinderWithStyles>(&(start, index_type));
-        let style = finder.style_calculator;
-        let end = self.content.query::<IndexFinder>(&(end, index_type));
-        for span in iter::Iter::new_range(self, start, Some(end), style) {
-            ans.push(span)
-        }
-
-        ans
+        self.iter_range(range, index_type).collect()
     }
 
     pub fn get_style_at_position(
@@ -1509,14 +1960,32 @@ impl RichText {
         self.content.root_cache().line_breaks as usize + 1
     }
 
-    pub fn apply_delta(&mut self, delta: impl Iterator<Item = DeltaItem>, index_type: IndexType) {
+    /// Apply the delta, e.g. the changes from the editor.
+    ///
+    /// The styles of the attributes are built from the [StyleSchema]. Return an error
+    /// without changing the document if a value can't be converted to the value schema
+    /// of its type.
+    pub fn apply_delta(
+        &mut self,
+        delta: impl Iterator<Item = DeltaItem>,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        let delta: Vec<DeltaItem> = delta.collect();
+        // check all the values first, so an invalid delta is not partially applied
+        for attributes in delta.iter().filter_map(|x| x.attributions()) {
+            for (key, value) in attributes.iter() {
+                self.schema.style(key, value.clone())?;
+            }
+        }
+
         let mut index = 0;
         for delta_item in delta {
             match delta_item {
                 DeltaItem::Retain { retain, attributes } => {
                     if let Some(attributes) = attributes {
                         let len = self.len_with(index_type);
-                        // Quill assume there is always line break at the end of the text.
+                        // Quill assume there is always line break at the end of the text,
+                        // which carries the block attributes of the last line.
                         // But crdt-richtext doesn't have this assumption.
                         // This line break can be formatted by Quill, which might cause out of bound
                         // error. So we insert a line break if the delta is too short
@@ -1526,26 +1995,8 @@ impl RichText {
                         }
 
                         for (key, value) in attributes {
-                            let behavior = if value.is_null() {
-                                crate::Behavior::Delete
-                            } else {
-                                crate::Behavior::Merge
-                            };
-                            self.annotate_inner(
-                                index..index + retain,
-                                Style::new_from_expand(
-                                    if behavior == crate::Behavior::Delete {
-                                        Expand::infer_delete_expand(&key)
-                                    } else {
-                                        Expand::infer_insert_expand(&key)
-                                    },
-                                    key.into(),
-                                    value,
-                                    behavior,
-                                )
-                                .unwrap(),
-                                index_type,
-                            )
+                            let style = self.schema.style(&key, value)?;
+                            self.annotate_by_kind(index..index + retain, style, index_type)
                         }
                     }
 
@@ -1558,64 +2009,64 @@ impl RichText {
                         continue;
                     }
 
-                    self.insert_inner(index, &insert, index_type);
+                    let insert = escape_embed_char(&insert);
+                    self.insert_with_embed(index, &insert, None, index_type);
                     let end = match index_type {
                         IndexType::Utf8 => index + insert.len(),
                         IndexType::Utf16 => index + get_utf16_len(&insert),
+                        IndexType::Unicode => index + get_unicode_len(&insert),
                     };
 
-                    let span = self
-                        .slice(index..index + 1, index_type)
-                        .into_iter()
-                        .next()
-                        .unwrap();
-                    let inserted_attributes = span.attributes;
-                    let attributes = attributes.unwrap_or_default();
-                    for key in inserted_attributes.keys() {
-                        if !attributes.contains_key(&key.to_string()) {
-                            self.annotate_inner(
-                                index..end,
-                                Style::new_from_expand(
-                                    Expand::infer_delete_expand(&key),
-                                    key.into(),
-                                    Value::Null,
-                                    Behavior::Delete,
-                                )
-                                .unwrap(),
-                                index_type,
-                            )
-                        }
-                    }
-
-                    for (key, value) in attributes {
-                        let behavior = if value.is_null() {
-                            crate::Behavior::Delete
-                        } else {
-                            crate::Behavior::Merge
-                        };
-                        if inserted_attributes.get(&key.as_str().into()) == Some(&value) {
-                            continue;
-                        }
-                        self.annotate_inner(
-                            index..end,
-                            Style::new_from_expand(
-                                Expand::infer_insert_expand(&key),
-                                key.into(),
-                                value,
-                                behavior,
-                            )
-                            .unwrap(),
-                            index_type,
-                        )
-                    }
-
+                    self.format_inserted(index..end, attributes.unwrap_or_default(), index_type)?;
                     index = end;
                 }
+                DeltaItem::Embed { insert, attributes } => {
+                    self.insert_with_embed(index, EMBED_STR, Some(Arc::new(insert)), index_type);
+                    self.format_inserted(
+                        index..index + 1,
+                        attributes.unwrap_or_default(),
+                        index_type,
+                    )?;
+                    index += 1;
+                }
                 DeltaItem::Delete { delete } => {
                     self.delete_inner(index..index + delete, index_type);
                 }
             }
         }
+
+        Ok(())
+    }
+
+    /// Make the styles of the inserted range match the attributes of the delta
+    fn format_inserted(
+        &mut self,
+        range: Range<usize>,
+        attributes: FxHashMap<String, Value>,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        let span = self
+            .slice(range.start..range.start + 1, index_type)
+            .into_iter()
+            .next()
+            .unwrap();
+        let inserted_attributes = span.attributes;
+        for key in inserted_attributes.keys() {
+            if !attributes.contains_key(&key.to_string()) {
+                let style = self.schema.erase_style(key);
+                self.annotate_by_kind(range.clone(), style, index_type)
+            }
+        }
+
+        for (key, value) in attributes {
+            let style = self.schema.style(&key, value)?;
+            if inserted_attributes.get(&style.type_) == Some(&style.value) {
+                continue;
+            }
+            self.annotate_by_kind(range.clone(), style, index_type)
+        }
+
+        Ok(())
     }
 
     pub fn convert_index(&self, index: usize, from: IndexType, to: IndexType) -> usize {
@@ -1630,6 +2081,7 @@ impl RichText {
                 count += match index_type {
                     IndexType::Utf8 => cache.len,
                     IndexType::Utf16 => cache.utf16_len,
+                    IndexType::Unicode => cache.unicode_len,
                 } as usize;
             }
             generic_btree::PreviousCache::PrevSiblingElem(elem) => {
@@ -1637,23 +2089,66 @@ impl RichText {
                     count += match index_type {
                         IndexType::Utf8 => elem.content_len(),
                         IndexType::Utf16 => elem.utf16_len as usize,
+                        IndexType::Unicode => elem.unicode_len as usize,
                     };
                 }
             }
             generic_btree::PreviousCache::ThisElemAndOffset { elem, offset } => {
                 if !elem.is_dead() {
                     match index_type {
-                        IndexType::Utf8 => count += utf16_to_utf8(&elem.string, offset),
+                        IndexType::Utf8 => count += offset,
                         IndexType::Utf16 => {
                             count += get_utf16_len_and_line_breaks(&elem.string[..offset]).utf16
                                 as usize;
                         }
+                        IndexType::Unicode => {
+                            count += get_utf16_len_and_line_breaks(&elem.string[..offset]).unicode
+                                as usize;
+                        }
                     }
                 }
             }
         });
         count
     }
+
+    /// The number of line breaks before the path, i.e. the line of the char at the path
+    fn get_line_from_path(&self, path: QueryResult) -> usize {
+        let mut count: usize = 0;
+        self.content.visit_previous_caches(path, |v| match v {
+            generic_btree::PreviousCache::NodeCache(cache) => {
+                count += cache.line_breaks as usize;
+            }
+            generic_btree::PreviousCache::PrevSiblingElem(elem) => {
+                if !elem.is_dead() {
+                    count += elem.line_breaks as usize;
+                }
+            }
+            generic_btree::PreviousCache::ThisElemAndOffset { elem, offset } => {
+                if !elem.is_dead() {
+                    count +=
+                        get_utf16_len_and_line_breaks(&elem.string[..offset]).line_breaks as usize;
+                }
+            }
+        });
+        count
+    }
+}
+
+/// The delta of the inserted text or embed
+fn inserted_delta(
+    text: &op::TextInsertOp,
+    index_type: IndexType,
+    attributes: FxHashMap<String, Value>,
+) -> DeltaItem {
+    match &text.embed {
+        Some(embed) => DeltaItem::embed_with_attributes((**embed).clone(), attributes),
+        None => DeltaItem::insert_with_attributes(
+            bytes_to_str(&text.text).to_owned(),
+            index_type,
+            attributes,
+        ),
+    }
 }
 
 impl Display for RichText {
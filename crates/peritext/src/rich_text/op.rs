@@ -3,10 +3,11 @@ use std::{ops::Deref, sync::Arc};
 use append_only_bytes::BytesSlice;
 use fxhash::FxHashMap;
 use generic_btree::rle::{HasLength, Mergeable, Sliceable};
+use serde_json::Value;
 
-use crate::{Annotation, ClientID, Counter, Lamport, OpID};
+use crate::{Annotation, ClientID, Counter, Lamport, OpID, Patch};
 
-use super::vv::VersionVector;
+use super::{vv::VersionVector, Error};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Op {
@@ -20,14 +21,22 @@ pub enum OpContent {
     Ann(Arc<Annotation>),
     Text(TextInsertOp),
     Del(DeleteOp),
+    Patch(Arc<Patch>),
 }
 
 impl OpContent {
-    pub fn new_insert(left: Option<OpID>, right: Option<OpID>, slice: BytesSlice) -> Self {
+    /// `embed` is the payload if the slice is the placeholder of an embed
+    pub fn new_insert(
+        left: Option<OpID>,
+        right: Option<OpID>,
+        slice: BytesSlice,
+        embed: Option<Arc<Value>>,
+    ) -> Self {
         OpContent::Text(TextInsertOp {
             text: slice,
             left,
             right,
+            embed,
         })
     }
 
@@ -43,6 +52,10 @@ impl OpContent {
     pub fn new_ann(ann: Arc<Annotation>) -> Self {
         OpContent::Ann(ann)
     }
+
+    pub fn new_patch(patch: Arc<Patch>) -> Self {
+        OpContent::Patch(patch)
+    }
 }
 
 #[derive(Clone)]
@@ -50,6 +63,8 @@ pub struct TextInsertOp {
     pub text: BytesSlice,
     pub left: Option<OpID>,
     pub right: Option<OpID>,
+    /// The payload of the embed. Its text is the [super::EMBED_CHAR] placeholder
+    pub embed: Option<Arc<Value>>,
 }
 
 impl PartialEq for TextInsertOp {
@@ -57,6 +72,7 @@ impl PartialEq for TextInsertOp {
         self.text.deref() == other.text.deref()
             && self.left == other.left
             && self.right == other.right
+            && self.embed == other.embed
     }
 }
 
@@ -68,6 +84,7 @@ impl std::fmt::Debug for TextInsertOp {
             .field("text", &std::str::from_utf8(&self.text))
             .field("left", &self.left)
             .field("right", &self.right)
+            .field("embed", &self.embed)
             .finish()
     }
 }
@@ -205,6 +222,7 @@ impl HasLength for Op {
     fn rle_len(&self) -> usize {
         match &self.content {
             OpContent::Ann(_) => 1,
+            OpContent::Patch(_) => 1,
             OpContent::Text(text) => text.text.len(),
             OpContent::Del(del) => del.len.unsigned_abs() as usize,
         }
@@ -220,6 +238,8 @@ impl Mergeable for Op {
                 (OpContent::Text(left), OpContent::Text(right)) => {
                     right.left == Some(self.id.inc(self.rle_len() as Counter - 1))
                         && right.right == left.right
+                        && left.embed.is_none()
+                        && right.embed.is_none()
                         && left.text.can_merge(&right.text)
                 }
                 (OpContent::Del(a), OpContent::Del(b)) => a.can_merge(b),
@@ -260,6 +280,11 @@ impl Sliceable for Op {
                 lamport: self.lamport + (start as Lamport),
                 content: OpContent::Ann(a.clone()),
             },
+            OpContent::Patch(p) => Op {
+                id: self.id.inc(start as Counter),
+                lamport: self.lamport + (start as Lamport),
+                content: OpContent::Patch(p.clone()),
+            },
             OpContent::Text(text) => Op {
                 id: self.id.inc(start as Counter),
                 lamport: self.lamport + (start as Lamport),
@@ -275,6 +300,7 @@ impl Sliceable for Op {
                     } else {
                         Some(self.id.inc(end as Counter))
                     },
+                    embed: text.embed.clone(),
                 }),
             },
             OpContent::Del(del) => Op {
@@ -286,8 +312,12 @@ impl Sliceable for Op {
     }
 }
 
+#[derive(Clone)]
 pub struct OpStore {
     map: FxHashMap<ClientID, Vec<Op>>,
+    /// The ops before this version are not stored in `map`,
+    /// because the doc is loaded from a snapshot.
+    trimmed_vv: VersionVector,
     pub(crate) client: ClientID,
     next_lamport: Lamport,
 }
@@ -317,11 +347,22 @@ impl OpStore {
     pub fn new(client: ClientID) -> Self {
         Self {
             map: Default::default(),
+            trimmed_vv: Default::default(),
             client,
             next_lamport: 0,
         }
     }
 
+    /// Create a store whose history before `trimmed_vv` is not available
+    pub fn new_trimmed(client: ClientID, trimmed_vv: VersionVector, next_lamport: Lamport) -> Self {
+        Self {
+            map: Default::default(),
+            trimmed_vv,
+            client,
+            next_lamport,
+        }
+    }
+
     pub fn insert_local(&mut self, content: OpContent) -> &Op {
         let op = Op {
             id: self.next_id(),
@@ -353,7 +394,29 @@ impl OpStore {
         }
     }
 
-    pub fn export(&self, other_vv: &VersionVector) -> FxHashMap<ClientID, Vec<Op>> {
+    /// Export the ops that are not included by `other_vv`.
+    ///
+    /// Return [Error::HistoryTrimmed] if some of them are trimmed, the peer
+    /// at `other_vv` can't apply the rest of them.
+    pub fn export(&self, other_vv: &VersionVector) -> Result<FxHashMap<ClientID, Vec<Op>>, Error> {
+        self.check_trimmed(other_vv)?;
+        Ok(self.export_unchecked(other_vv))
+    }
+
+    /// Check that `other_vv` includes the trimmed history
+    fn check_trimmed(&self, other_vv: &VersionVector) -> Result<(), Error> {
+        for (client, trimmed) in self.trimmed_vv.vv.iter() {
+            let counter = other_vv.vv.get(client).copied().unwrap_or(0);
+            if counter < *trimmed {
+                return Err(Error::HistoryTrimmed(OpID::new(*client, counter)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Export the ops after `other_vv`, which should include the trimmed history
+    fn export_unchecked(&self, other_vv: &VersionVector) -> FxHashMap<ClientID, Vec<Op>> {
         let mut ans: FxHashMap<ClientID, Vec<Op>> = FxHashMap::default();
         for (client, vec) in self.map.iter() {
             let target_counter = other_vv.vv.get(client).unwrap_or(&0);
@@ -388,8 +451,51 @@ impl OpStore {
         ans
     }
 
+    /// Iterate the ops that are not included by `other_vv` client by client, like
+    /// [OpStore::export] but without collecting them
+    pub fn export_iter<'a>(
+        &'a self,
+        other_vv: &'a VersionVector,
+    ) -> Result<impl Iterator<Item = Op> + 'a, Error> {
+        self.check_trimmed(other_vv)?;
+        Ok(self.map.iter().flat_map(move |(client, vec)| {
+            let target = other_vv.vv.get(client).copied().unwrap_or(0);
+            let start = vec.partition_point(|op| op.id.counter + op.rle_len() as Counter <= target);
+            vec[start..].iter().map(move |op| {
+                if op.id.counter < target {
+                    op.slice((target - op.id.counter) as usize..)
+                } else {
+                    op.clone()
+                }
+            })
+        }))
+    }
+
+    /// Export the ops that are included by `to` but not by `from`.
+    ///
+    /// The history should not be trimmed
+    pub fn export_between(
+        &self,
+        from: &VersionVector,
+        to: &VersionVector,
+    ) -> FxHashMap<ClientID, Vec<Op>> {
+        let mut ans = self.export_unchecked(from);
+        for (client, ops) in ans.iter_mut() {
+            let end = to.vv.get(client).copied().unwrap_or(0);
+            ops.retain(|op| op.id.counter < end);
+            if let Some(last) = ops.last_mut() {
+                if last.id.counter + last.rle_len() as Counter > end {
+                    last.slice_(..(end - last.id.counter) as usize);
+                }
+            }
+        }
+
+        ans.retain(|_, ops| !ops.is_empty());
+        ans
+    }
+
     pub fn vv(&self) -> VersionVector {
-        let mut ans = VersionVector::default();
+        let mut ans = self.trimmed_vv.clone();
         for (client, vec) in self.map.iter() {
             if let Some(last) = vec.last() {
                 ans.vv
@@ -400,29 +506,34 @@ impl OpStore {
         ans
     }
 
+    #[inline]
+    pub fn trimmed_vv(&self) -> &VersionVector {
+        &self.trimmed_vv
+    }
+
+    /// The counter of the next op of the given client
+    pub fn end_counter(&self, client: ClientID) -> Counter {
+        self.map
+            .get(&client)
+            .and_then(|v| v.last().map(|x| x.id.counter + x.rle_len() as Counter))
+            .or_else(|| self.trimmed_vv.vv.get(&client).copied())
+            .unwrap_or(0)
+    }
+
+    /// Whether the op that created the id is in the store
+    pub fn includes(&self, id: OpID) -> bool {
+        id.counter < self.end_counter(id.client)
+    }
+
     pub fn next_id(&self) -> OpID {
         OpID {
             client: self.client,
-            counter: self
-                .map
-                .get(&self.client)
-                .and_then(|v| v.last().map(|x| x.id.counter + x.rle_len() as Counter))
-                .unwrap_or(0),
+            counter: self.end_counter(self.client),
         }
     }
 
     pub fn can_apply(&self, op: &Op) -> CanApply {
-        let Some(vec) = self.map.get(&op.id.client) else {
-            if op.id.counter == 0 {
-                return CanApply::Yes;
-            } else {
-                return CanApply::Pending;
-            }
-        };
-        let end = vec
-            .last()
-            .map(|x| x.id.counter + x.rle_len() as Counter)
-            .unwrap_or(0);
+        let end = self.end_counter(op.id.client);
         if end == op.id.counter {
             return CanApply::Yes;
         }
@@ -436,6 +547,32 @@ impl OpStore {
         CanApply::Trim(end - op.id.counter)
     }
 
+    /// Drop the ops before the given version.
+    ///
+    /// `vv` should not be greater than the version of the store
+    pub fn trim(&mut self, vv: &VersionVector) {
+        for (client, &counter) in vv.vv.iter() {
+            if let Some(vec) = self.map.get_mut(client) {
+                let start =
+                    vec.partition_point(|op| op.id.counter + op.rle_len() as Counter <= counter);
+                if start < vec.len() && vec[start].id.counter < counter {
+                    vec[start] = vec[start].slice((counter - vec[start].id.counter) as usize..);
+                }
+
+                vec.drain(..start);
+            }
+
+            let trimmed = self.trimmed_vv.vv.entry(*client).or_insert(0);
+            *trimmed = (*trimmed).max(counter);
+        }
+
+        self.map.retain(|_, vec| !vec.is_empty());
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Op> {
+        self.map.values_mut().flat_map(|vec| vec.iter_mut())
+    }
+
     #[inline(always)]
     pub fn next_lamport(&self) -> u32 {
         self.next_lamport
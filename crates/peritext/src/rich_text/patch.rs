@@ -0,0 +1,425 @@
+//! Edits of the existing annotations.
+//!
+//! A [Patch] targets the annotation created by `target_range_id`. It's an op like
+//! the others, so it's merged with the concurrent edits of the peers:
+//!
+//! - [PatchAction::Remove] removes the anchors of the annotation. The removal wins
+//!   over the concurrent moves and values, they only take effect if it's restored.
+//! - [PatchAction::Restore] inserts the anchors of the removed annotation back, e.g. when
+//!   the removal is undone. The concurrent removals and restorations are ordered by
+//!   `(lamport, id)` of the patches, the greater one wins.
+//! - [PatchAction::SetValue] replaces the value. The concurrent values are ordered by
+//!   `(lamport, id)` of the patches, the greater one wins.
+//! - [PatchAction::Move] moves the anchors of the annotation. The patch is compared
+//!   with the `range_lamport` of the annotation, which is updated when it's moved.
+//!   A restoration moves the annotation as well.
+//!
+//! The value and the range of a removed annotation are still updated by the patches,
+//! so the peers agree on them no matter when the annotation is restored.
+
+use std::{
+    ops::{Bound, Range, RangeBounds},
+    sync::Arc,
+};
+
+use fxhash::FxHashMap;
+use serde_json::Value;
+
+use crate::{Anchor, AnchorRange, InternalString, OpID, Patch, PatchAction};
+
+use super::{
+    ann::{insert_anchor_to_char, AnchorSetDiff, AnnIdx, Span},
+    event::Event,
+    op::OpContent,
+    rich_tree::utf16::{get_unicode_len, get_utf16_len},
+    DeltaItem, Error, IndexType, RichText,
+};
+
+impl RichText {
+    /// Remove the annotation created by the op of the given id, e.g. to delete a comment.
+    ///
+    /// Return an error if the annotation is unknown or already removed.
+    pub fn remove_annotation(&mut self, id: OpID) -> Result<(), Error> {
+        self.patch_annotation(id, PatchAction::Remove)
+    }
+
+    /// Replace the value of the annotation created by the op of the given id.
+    ///
+    /// Return an error if the annotation is unknown or already removed, or if the
+    /// value can't be converted to the value schema of its type, see [super::StyleSchema].
+    pub fn update_annotation_value(&mut self, id: OpID, value: Value) -> Result<(), Error> {
+        let Some(ann) = self.ann.get_ann_by_id(id) else {
+            return Err(Error::UnknownAnnotation(id));
+        };
+        let value = self.schema.coerce(&ann.type_, value)?;
+        self.patch_annotation(id, PatchAction::SetValue(value))
+    }
+
+    /// Move the annotation created by the op of the given id to the new utf8 range,
+    /// e.g. when the edge of a comment is dragged. The annotation keeps its id.
+    ///
+    /// The new range is anchored like the annotation was created on it, so it keeps
+    /// its expand behavior. An empty range is ignored, as in [RichText::annotate].
+    ///
+    /// Return an error if the annotation is unknown or already removed.
+    pub fn move_annotation(
+        &mut self,
+        id: OpID,
+        range: impl RangeBounds<usize>,
+    ) -> Result<(), Error> {
+        self.move_annotation_inner(id, range, IndexType::Utf8)
+    }
+
+    /// Move the annotation created by the op of the given id to the new utf16 range.
+    ///
+    /// See [RichText::move_annotation].
+    pub fn move_annotation_utf16(
+        &mut self,
+        id: OpID,
+        range: impl RangeBounds<usize>,
+    ) -> Result<(), Error> {
+        self.move_annotation_inner(id, range, IndexType::Utf16)
+    }
+
+    /// Move the annotation created by the op of the given id to the new range in
+    /// chars, see [IndexType::Unicode].
+    ///
+    /// See [RichText::move_annotation].
+    pub fn move_annotation_unicode(
+        &mut self,
+        id: OpID,
+        range: impl RangeBounds<usize>,
+    ) -> Result<(), Error> {
+        self.move_annotation_inner(id, range, IndexType::Unicode)
+    }
+
+    fn move_annotation_inner(
+        &mut self,
+        id: OpID,
+        range: impl RangeBounds<usize>,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        let ann = match self.ann.get_idx_by_id(id) {
+            Some(idx) if !self.ann.is_removed(idx) => self.ann.get_ann_by_idx(idx).unwrap(),
+            _ => return Err(Error::UnknownAnnotation(id)),
+        };
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len_with(index_type),
+        };
+        assert!(end <= self.len_with(index_type));
+        if start >= end {
+            return Ok(());
+        }
+
+        let range = AnchorRange {
+            start: self.index_to_anchor(start, ann.range.start.type_, index_type),
+            end: self.index_to_anchor(end, ann.range.end.type_, index_type),
+        };
+        self.patch_annotation(id, PatchAction::Move(range))
+    }
+
+    pub(super) fn patch_annotation(
+        &mut self,
+        target: OpID,
+        action: PatchAction,
+    ) -> Result<(), Error> {
+        let idx = match self.ann.get_idx_by_id(target) {
+            // only the removed annotations can be restored
+            Some(idx) if self.ann.is_removed(idx) == matches!(action, PatchAction::Restore(_)) => {
+                idx
+            }
+            _ => return Err(Error::UnknownAnnotation(target)),
+        };
+
+        let undo_op = if self.undo.is_some() {
+            Some(self.capture_patch(idx, &action))
+        } else {
+            None
+        };
+        let patch = Arc::new(Patch {
+            id: self.next_id(),
+            target_range_id: target,
+            action,
+            lamport: self.next_lamport(),
+        });
+        self.store.insert_local(OpContent::new_patch(patch.clone()));
+        let ops = self.apply_patch(&patch);
+        if let Some(undo_op) = undo_op {
+            self.record_undo(undo_op);
+        }
+
+        if !ops.is_empty() {
+            self.emit(Event {
+                ops,
+                is_local: true,
+                index_type: self.event_index_type,
+                origin: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Apply the patch to its target annotation.
+    ///
+    /// Return the delta of the styles if there are listeners
+    pub(super) fn apply_patch(&mut self, patch: &Patch) -> Vec<DeltaItem> {
+        let Some(idx) = self.ann.get_idx_by_id(patch.target_range_id) else {
+            // the annotation is not created by an op, it can't be a valid target
+            return Vec::new();
+        };
+        let was_removed = self.ann.is_removed(idx);
+        let index_type = self.event_index_type;
+        let range = if self.has_listener() {
+            let range = self.annotation_range_with(patch.target_range_id, index_type);
+            match (&patch.action, range) {
+                // the styles of both the old range and the new range may change
+                (PatchAction::Move(new) | PatchAction::Restore(new), Some(old)) => {
+                    let new = self.anchor_range_to_index(new, index_type);
+                    Some(old.start.min(new.start)..old.end.max(new.end))
+                }
+                // the annotation is restored to the new range, or to its current range
+                // if a concurrent move wins
+                (PatchAction::Restore(new), None) => {
+                    let current = self.ann.get_ann_by_idx(idx).unwrap().range.clone();
+                    let new = self.anchor_range_to_index(new, index_type);
+                    let current = self.anchor_range_to_index(&current, index_type);
+                    Some(current.start.min(new.start)..current.end.max(new.end))
+                }
+                (_, range) => range,
+            }
+        } else {
+            None
+        };
+        let before = range
+            .as_ref()
+            .map(|range| self.slice(range.clone(), index_type));
+        let lamport = (patch.lamport, patch.id);
+        match &patch.action {
+            PatchAction::Remove => {
+                if !self.ann.set_removed(idx, true, lamport) || was_removed {
+                    return Vec::new();
+                }
+
+                let range = self.ann.get_ann_by_idx(idx).unwrap().range.clone();
+                self.remove_anchors(idx, &range);
+            }
+            PatchAction::Restore(new) => {
+                let old = self.ann.get_ann_by_idx(idx).unwrap().range.clone();
+                let restored = self.ann.set_removed(idx, false, lamport) && was_removed;
+                let moved = self.ann.set_range(idx, new.clone(), lamport);
+                if self.ann.is_removed(idx) {
+                    return Vec::new();
+                }
+
+                match (restored, moved) {
+                    (true, _) => {
+                        let range = self.ann.get_ann_by_idx(idx).unwrap().range.clone();
+                        self.insert_anchors(idx, &range);
+                    }
+                    (false, true) => {
+                        self.remove_anchors(idx, &old);
+                        self.insert_anchors(idx, new);
+                    }
+                    (false, false) => return Vec::new(),
+                }
+            }
+            PatchAction::SetValue(value) => {
+                if !self.ann.set_value(idx, value.clone(), lamport) || was_removed {
+                    return Vec::new();
+                }
+            }
+            PatchAction::Move(new) => {
+                let range = self.ann.get_ann_by_idx(idx).unwrap().range.clone();
+                if !self.ann.set_range(idx, new.clone(), lamport) || was_removed {
+                    return Vec::new();
+                }
+
+                self.remove_anchors(idx, &range);
+                self.insert_anchors(idx, new);
+            }
+        }
+
+        match (range, before) {
+            (Some(range), Some(before)) => {
+                let after = self.slice(range.clone(), index_type);
+                style_diff(range.start, &before, &after, index_type)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Remove the start anchor and the end anchor of the annotation
+    fn remove_anchors(&mut self, idx: AnnIdx, range: &AnchorRange) {
+        match range.start.id {
+            Some(_) => self.remove_anchor(idx, range.start, true),
+            None => self.init_styles.remove_start(idx),
+        }
+        // the annotation that ends at the end of the doc has no end anchor
+        if range.end.id.is_some() {
+            self.remove_anchor(idx, range.end, false);
+        }
+    }
+
+    fn remove_anchor(&mut self, idx: AnnIdx, anchor: Anchor, is_start: bool) {
+        let cursor = self.find_cursor(anchor.id.unwrap());
+        self.content.update_leaf(cursor.leaf, |elements| {
+            let removed =
+                elements[cursor.elem_index]
+                    .anchor_set
+                    .remove_ann(idx, anchor.type_, is_start);
+            debug_assert!(removed);
+            (
+                true,
+                Some(AnchorSetDiff::from_removed_ann(idx, is_start).into()),
+            )
+        });
+        self.cursor_map.flush();
+    }
+
+    /// Insert the start anchor and the end anchor of the annotation
+    fn insert_anchors(&mut self, idx: AnnIdx, range: &AnchorRange) {
+        match range.start.id {
+            Some(_) => self.insert_anchor(idx, range.start, true),
+            None => self.init_styles.insert_start(idx),
+        }
+        if range.end.id.is_some() {
+            self.insert_anchor(idx, range.end, false);
+        }
+    }
+
+    fn insert_anchor(&mut self, idx: AnnIdx, anchor: Anchor, is_start: bool) {
+        let cursor = self.find_cursor(anchor.id.unwrap());
+        self.content.update_leaf(cursor.leaf, |elements| {
+            insert_anchor_to_char(
+                elements,
+                cursor.elem_index,
+                cursor.offset,
+                idx,
+                anchor.type_,
+                is_start,
+            );
+            (true, Some(AnchorSetDiff::from_ann(idx, is_start).into()))
+        });
+        self.cursor_map.flush();
+    }
+
+    /// The current range of the anchors. A `None` start is the start of the doc,
+    /// and a `None` end is the end of the doc
+    fn anchor_range_to_index(&self, range: &AnchorRange, index_type: IndexType) -> Range<usize> {
+        let start = match range.start.id {
+            Some(_) => self.anchor_to_index(&range.start, index_type),
+            None => 0,
+        };
+        let end = match range.end.id {
+            Some(_) => self.anchor_to_index(&range.end, index_type),
+            None => self.len_with(index_type),
+        };
+        start..end.max(start)
+    }
+}
+
+/// The delta that turns the styles of `before` into the styles of `after`.
+///
+/// They are the spans of the same text that starts at `index`.
+fn style_diff(
+    index: usize,
+    before: &[Span],
+    after: &[Span],
+    index_type: IndexType,
+) -> Vec<DeltaItem> {
+    let text: String = after.iter().map(|x| x.as_str()).collect();
+    let mut ans = vec![DeltaItem::retain(index)];
+    let mut before_iter = before.iter();
+    let mut after_iter = after.iter();
+    let (mut before_end, mut after_end) = (0, 0);
+    let (mut before_span, mut after_span) = (None, None);
+    let mut start = 0;
+    loop {
+        while start == before_end {
+            let Some(span) = before_iter.next() else {
+                break;
+            };
+            before_end += span.len();
+            before_span = Some(span);
+        }
+        while start == after_end {
+            let Some(span) = after_iter.next() else { break };
+            after_end += span.len();
+            after_span = Some(span);
+        }
+
+        let end = before_end.min(after_end);
+        if end == start {
+            break;
+        }
+
+        let len = match index_type {
+            IndexType::Utf8 => end - start,
+            IndexType::Utf16 => get_utf16_len(&text[start..end]),
+            IndexType::Unicode => get_unicode_len(&text[start..end]),
+        };
+        let attributes = attributes_diff(
+            &before_span.unwrap().attributes,
+            &after_span.unwrap().attributes,
+        );
+        match (ans.last_mut().unwrap(), attributes.is_empty()) {
+            (
+                DeltaItem::Retain {
+                    retain,
+                    attributes: None,
+                },
+                true,
+            ) => *retain += len,
+            (
+                DeltaItem::Retain {
+                    retain,
+                    attributes: Some(last),
+                },
+                false,
+            ) if *last == attributes => *retain += len,
+            (_, true) => ans.push(DeltaItem::retain(len)),
+            (_, false) => ans.push(DeltaItem::retain_with_attributes(len, attributes)),
+        }
+        start = end;
+    }
+
+    if !ans.iter().any(|x| x.attributions().is_some()) {
+        return Vec::new();
+    }
+    if let Some(DeltaItem::Retain {
+        attributes: None, ..
+    }) = ans.last()
+    {
+        ans.pop();
+    }
+
+    ans
+}
+
+/// The attributes that changed from `before` to `after`, the removed ones are `null`
+fn attributes_diff(
+    before: &FxHashMap<InternalString, Value>,
+    after: &FxHashMap<InternalString, Value>,
+) -> FxHashMap<String, Value> {
+    let mut ans = FxHashMap::default();
+    for (key, value) in after.iter() {
+        if before.get(key) != Some(value) {
+            ans.insert(key.to_string(), value.clone());
+        }
+    }
+    for key in before.keys() {
+        if !after.contains_key(key) {
+            ans.insert(key.to_string(), Value::Null);
+        }
+    }
+
+    ans
+}
@@ -0,0 +1,295 @@
+//! The ops that arrive before their dependencies.
+//!
+//! An op depends on the previous op of its client and on the chars it refers to.
+//! If some of them are missing, the op is queued by the first missing id, and it's
+//! retried when the op that creates the id arrives.
+
+use std::collections::BTreeMap;
+
+use fxhash::FxHashMap;
+use generic_btree::rle::{HasLength, Sliceable};
+
+use crate::{ClientID, Counter, OpID, PatchAction};
+
+use super::{
+    op::{CanApply, Op, OpContent},
+    vv::VersionVector,
+    Error, RichText,
+};
+
+/// Id spans of each client, from the start counters to the end counters
+type IdSpans = FxHashMap<ClientID, BTreeMap<Counter, Counter>>;
+
+/// The ops waiting for their dependencies, keyed by the first missing id
+#[derive(Debug, Default, Clone)]
+pub(super) struct PendingOps {
+    map: FxHashMap<ClientID, BTreeMap<Counter, Vec<Op>>>,
+}
+
+impl PendingOps {
+    pub fn push(&mut self, missing: OpID, op: Op) {
+        self.map
+            .entry(missing.client)
+            .or_default()
+            .entry(missing.counter)
+            .or_default()
+            .push(op);
+    }
+
+    /// Take the ops waiting for the ids of the client before `end`
+    pub fn take_ready(&mut self, client: ClientID, end: Counter) -> Vec<Op> {
+        let Some(tree) = self.map.get_mut(&client) else {
+            return Vec::new();
+        };
+
+        let rest = tree.split_off(&end);
+        let ready = std::mem::replace(tree, rest);
+        if tree.is_empty() {
+            self.map.remove(&client);
+        }
+
+        ready.into_values().flatten().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Op> + '_ {
+        self.map
+            .values()
+            .flat_map(|tree| tree.values().flat_map(|ops| ops.iter()))
+    }
+}
+
+impl RichText {
+    /// The ops that the pending ops depend on but are not received yet.
+    ///
+    /// For each client, the counter is the end of the missing ops. The sync layer can
+    /// request the ops between [RichText::version] and it from the peers.
+    pub fn missing_deps(&self) -> VersionVector {
+        // the ids created by the pending ops are not missing
+        let mut pending = IdSpans::default();
+        for op in self.pending_ops.iter() {
+            pending
+                .entry(op.id.client)
+                .or_default()
+                .insert(op.id.counter, op.id.counter + op.rle_len() as Counter);
+        }
+
+        let mut ans = VersionVector::default();
+        let mut add = |id: Option<OpID>| {
+            if let Some(id) =
+                id.filter(|id| !self.store.includes(*id) && span_len_from(&pending, *id).is_none())
+            {
+                let end = ans.vv.entry(id.client).or_insert(0);
+                *end = (*end).max(id.counter + 1);
+            }
+        };
+
+        for op in self.pending_ops.iter() {
+            if op.id.counter > 0 {
+                add(Some(OpID::new(op.id.client, op.id.counter - 1)));
+            }
+
+            match &op.content {
+                OpContent::Text(text) => {
+                    add(text.left);
+                    add(text.right);
+                }
+                OpContent::Del(del) => {
+                    let del = del.positive();
+                    add(Some(del.start.inc(del.len as Counter - 1)));
+                }
+                OpContent::Ann(ann) => {
+                    add(ann.range.start.id);
+                    add(ann.range.end.id);
+                }
+                OpContent::Patch(patch) => {
+                    add(Some(patch.target_range_id));
+                    if let PatchAction::Move(range) | PatchAction::Restore(range) = &patch.action {
+                        add(range.start.id);
+                        add(range.end.id);
+                    }
+                }
+            }
+        }
+
+        ans
+    }
+
+    /// Whether there are ops waiting for their dependencies
+    pub fn has_pending(&self) -> bool {
+        self.pending_ops.iter().next().is_some()
+    }
+
+    /// Check that the updates only refer to the chars that are known to this doc
+    /// or inserted by the updates. The ids after the version of this doc and the
+    /// updates can be created by the ops that are not received yet, so they are
+    /// allowed.
+    pub(super) fn check_deps(&self, updates: &FxHashMap<ClientID, Vec<Op>>) -> Result<(), Error> {
+        let mut inserted = IdSpans::default();
+        let mut ranges: FxHashMap<ClientID, (Counter, Counter)> = Default::default();
+        for (client, ops) in updates.iter() {
+            for op in ops {
+                if let OpContent::Text(_) = &op.content {
+                    inserted
+                        .entry(*client)
+                        .or_default()
+                        .insert(op.id.counter, op.id.counter + op.rle_len() as Counter);
+                }
+            }
+
+            if let (Some(first), Some(last)) = (ops.first(), ops.last()) {
+                let end = last.id.counter + last.rle_len() as Counter;
+                ranges.insert(*client, (first.id.counter, end));
+            }
+        }
+
+        let included = |id: OpID| match ranges.get(&id.client) {
+            Some((start, end)) if (*start..*end).contains(&id.counter) => true,
+            _ => self.store.includes(id),
+        };
+        for op in updates.values().flatten() {
+            self.unmet_dep(op, &inserted, included)?;
+        }
+
+        Ok(())
+    }
+
+    /// Insert the op into the store if its dependencies are met, otherwise queue it.
+    /// The pending ops waiting for it are retried.
+    ///
+    /// The ops inserted are pushed to `ready`, they should be applied afterwards.
+    ///
+    /// A pending op may turn out to refer to an id that is not a char once it's
+    /// retried. It can never be applied, so it's dropped and [Error::DanglingOpID]
+    /// is returned after the other ops are inserted.
+    pub(super) fn add_op(
+        &mut self,
+        op: Op,
+        ready: &mut Vec<Op>,
+        inserted: &mut IdSpans,
+    ) -> Result<(), Error> {
+        let mut ans = Ok(());
+        let mut queue = vec![op];
+        while let Some(mut op) = queue.pop() {
+            match self.store.can_apply(&op) {
+                CanApply::Yes => {}
+                CanApply::Trim(len) => op.slice_(len as usize..),
+                CanApply::Pending => {
+                    let end = self.store.end_counter(op.id.client);
+                    self.pending_ops.push(OpID::new(op.id.client, end), op);
+                    continue;
+                }
+                CanApply::Seen => continue,
+            }
+
+            match self.unmet_dep(&op, inserted, |id| self.store.includes(id)) {
+                Ok(None) => {}
+                Ok(Some(missing)) => {
+                    self.pending_ops.push(missing, op);
+                    continue;
+                }
+                Err(err) => {
+                    ans = ans.and(Err(err));
+                    continue;
+                }
+            }
+
+            let end = op.id.counter + op.rle_len() as Counter;
+            if let OpContent::Text(_) = &op.content {
+                inserted
+                    .entry(op.id.client)
+                    .or_default()
+                    .insert(op.id.counter, end);
+            }
+            self.store.insert(op.clone());
+            // reversed, so the ops of the same client are retried in order
+            let released = self.pending_ops.take_ready(op.id.client, end);
+            queue.extend(released.into_iter().rev());
+            ready.push(op);
+        }
+
+        ans
+    }
+
+    /// Find the first id the op refers to that is not included by `included`.
+    ///
+    /// Return an error if the op refers to an included id that is not a char.
+    fn unmet_dep(
+        &self,
+        op: &Op,
+        inserted: &IdSpans,
+        included: impl Fn(OpID) -> bool,
+    ) -> Result<Option<OpID>, Error> {
+        let check = |id: Option<OpID>| match id {
+            Some(id) if !included(id) => Ok(Some(id)),
+            Some(id) if self.known_char_len(id, inserted).is_none() => Err(Error::DanglingOpID(id)),
+            _ => Ok(None),
+        };
+
+        match &op.content {
+            OpContent::Text(text) => {
+                let left = check(text.left)?;
+                let right = check(text.right)?;
+                Ok(left.or(right))
+            }
+            OpContent::Del(del) => {
+                let del = del.positive();
+                let mut id = del.start;
+                let mut len = del.len as usize;
+                while len > 0 {
+                    if !included(id) {
+                        return Ok(Some(id));
+                    }
+
+                    let known = self
+                        .known_char_len(id, inserted)
+                        .ok_or(Error::DanglingOpID(id))?
+                        .min(len);
+                    id = id.inc(known as Counter);
+                    len -= known;
+                }
+
+                Ok(None)
+            }
+            OpContent::Ann(ann) => {
+                let start = check(ann.range.start.id)?;
+                let end = check(ann.range.end.id)?;
+                Ok(start.or(end))
+            }
+            OpContent::Patch(patch) => {
+                // the target is an annotation rather than a char, a patch of an
+                // unknown annotation is ignored when it's applied
+                let target = patch.target_range_id;
+                if !included(target) {
+                    return Ok(Some(target));
+                }
+
+                match &patch.action {
+                    PatchAction::Move(range) | PatchAction::Restore(range) => {
+                        let start = check(range.start.id)?;
+                        let end = check(range.end.id)?;
+                        Ok(start.or(end))
+                    }
+                    PatchAction::Remove | PatchAction::SetValue(_) => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// The number of the known chars starting from `id` in the same span
+    fn known_char_len(&self, id: OpID, inserted: &IdSpans) -> Option<usize> {
+        if let Some((_, len)) = self.cursor_map.get_insert(id) {
+            return Some(len);
+        }
+        if let Some((span, offset)) = self.removed.get(id) {
+            return Some(span.len - offset);
+        }
+
+        span_len_from(inserted, id)
+    }
+}
+
+/// The number of the ids from `id` to the end of the span that contains it
+fn span_len_from(spans: &IdSpans, id: OpID) -> Option<usize> {
+    let (_, end) = spans.get(&id.client)?.range(..=id.counter).next_back()?;
+    (id.counter < *end).then_some((end - id.counter) as usize)
+}
@@ -0,0 +1,71 @@
+//! Line and column positions.
+//!
+//! The lines are found by the line breaks cached in the rich tree, so the
+//! conversions take O(log n). The column is the offset from the line start in
+//! the given index type.
+
+use std::ops::Range;
+
+use super::{
+    rich_tree::query::{IndexFinder, LineStartFinder},
+    IndexType, RichText,
+};
+
+impl RichText {
+    /// Get the range of the line, including its line break
+    ///
+    /// # Panics
+    ///
+    /// Panics if the line doesn't exist
+    pub fn line_range(&self, line: usize, index_type: IndexType) -> Range<usize> {
+        assert!(line < self.lines());
+        let start = if line == 0 {
+            0
+        } else {
+            let start = self.content.query::<LineStartFinder>(&line);
+            self.get_index_from_path(start, index_type)
+        };
+        let end = self.content.query::<LineStartFinder>(&(line + 1));
+        let end = if end.found {
+            self.get_index_from_path(end, index_type)
+        } else {
+            self.len_with(index_type)
+        };
+
+        start..end
+    }
+
+    /// Get the line and the column of the index.
+    ///
+    /// The index after a line break is at the start of the next line.
+    pub fn index_to_line_col(&self, index: usize, index_type: IndexType) -> (usize, usize) {
+        assert!(index <= self.len_with(index_type));
+        if index == 0 {
+            return (0, 0);
+        }
+
+        let path = self.content.query::<IndexFinder>(&(index, index_type));
+        let line = self.get_line_from_path(path);
+        let start = self.content.query::<LineStartFinder>(&line);
+        (line, index - self.get_index_from_path(start, index_type))
+    }
+
+    /// Get the index of the line and the column.
+    ///
+    /// The column is clamped to the end of the line, before its line break.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the line doesn't exist
+    pub fn line_col_to_index(&self, line: usize, col: usize, index_type: IndexType) -> usize {
+        let range = self.line_range(line, index_type);
+        // the line break has length 1 in all the index types
+        let end = if line + 1 < self.lines() {
+            range.end - 1
+        } else {
+            range.end
+        };
+
+        (range.start + col).min(end)
+    }
+}
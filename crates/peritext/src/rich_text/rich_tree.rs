@@ -3,10 +3,12 @@ use append_only_bytes::BytesSlice;
 use core::fmt;
 
 use generic_btree::rle::{HasLength, Mergeable, Sliceable};
+use serde_json::Value;
 use smallvec::SmallVec;
 use std::{
     ops::{Deref, DerefMut, RangeBounds},
     str::Chars,
+    sync::Arc,
 };
 
 use self::{
@@ -34,12 +36,17 @@ pub struct ElemInner {
     pub right: Option<OpID>,
     pub string: BytesSlice,
     pub utf16_len: u32,
+    /// number of chars
+    pub unicode_len: u32,
     /**
      * number of '\n'
      */
     pub line_breaks: u32,
     pub status: Status,
     pub anchor_set: ElemAnchorSet,
+    /// The payload of the embed, whose string is the [super::EMBED_CHAR] placeholder.
+    /// An embed is never merged with its neighbors
+    pub embed: Option<Arc<Value>>,
 }
 
 impl Deref for Elem {
@@ -65,7 +72,9 @@ impl std::fmt::Debug for Elem {
             .field("string", &std::str::from_utf8(&self.string))
             .field("line_breaks", &self.line_breaks)
             .field("utf16_len", &self.utf16_len)
+            .field("unicode_len", &self.unicode_len)
             .field("dead", &self.status.is_dead())
+            .field("embed", &self.embed)
             // .field("anchor_set", &self.anchor_set)
             .finish()
     }
@@ -73,17 +82,33 @@ impl std::fmt::Debug for Elem {
 
 impl Elem {
     pub fn new(id: OpID, left: Option<OpID>, right: Option<OpID>, string: BytesSlice) -> Self {
-        let Utf16LenAndLineBreaks { utf16, line_breaks } = get_utf16_len_and_line_breaks(&string);
+        Self::new_with_embed(id, left, right, string, None)
+    }
+
+    pub fn new_with_embed(
+        id: OpID,
+        left: Option<OpID>,
+        right: Option<OpID>,
+        string: BytesSlice,
+        embed: Option<Arc<Value>>,
+    ) -> Self {
+        let Utf16LenAndLineBreaks {
+            utf16,
+            unicode,
+            line_breaks,
+        } = get_utf16_len_and_line_breaks(&string);
         Elem {
             inner: Box::new(ElemInner {
                 id,
                 left,
                 right,
                 utf16_len: utf16,
+                unicode_len: unicode,
                 string,
                 line_breaks,
                 status: Status::ALIVE,
                 anchor_set: Default::default(),
+                embed,
             }),
         }
     }
@@ -111,6 +136,7 @@ impl Elem {
             match index_type {
                 IndexType::Utf8 => self.string.len(),
                 IndexType::Utf16 => self.utf16_len as usize,
+                IndexType::Unicode => self.unicode_len as usize,
             }
         }
     }
@@ -144,6 +170,9 @@ impl Elem {
                 IndexType::Utf16 => {
                     get_utf16_len_and_line_breaks(&self.string[start..end]).utf16 as usize
                 }
+                IndexType::Unicode => {
+                    get_utf16_len_and_line_breaks(&self.string[start..end]).unicode as usize
+                }
             }
         }
     }
@@ -162,7 +191,11 @@ impl Elem {
         assert!(offset != 0);
         let start = offset;
         let s = self.string.slice_clone(offset..);
-        let Utf16LenAndLineBreaks { utf16, line_breaks } = get_utf16_len_and_line_breaks(&s);
+        let Utf16LenAndLineBreaks {
+            utf16,
+            unicode,
+            line_breaks,
+        } = get_utf16_len_and_line_breaks(&s);
         let right = Self {
             inner: Box::new(ElemInner {
                 anchor_set: self.anchor_set.split(),
@@ -171,11 +204,14 @@ impl Elem {
                 right: self.right,
                 string: s,
                 utf16_len: utf16,
+                unicode_len: unicode,
                 status: self.status,
                 line_breaks,
+                embed: self.embed.clone(),
             }),
         };
         self.utf16_len -= utf16;
+        self.unicode_len -= unicode;
         self.line_breaks -= line_breaks;
         self.string = self.string.slice_clone(..offset);
         right
@@ -289,8 +325,13 @@ impl Elem {
 
     pub fn merge_slice(&mut self, s: &BytesSlice) {
         self.string.try_merge(s).unwrap();
-        let Utf16LenAndLineBreaks { utf16, line_breaks } = get_utf16_len_and_line_breaks(s);
+        let Utf16LenAndLineBreaks {
+            utf16,
+            unicode,
+            line_breaks,
+        } = get_utf16_len_and_line_breaks(s);
         self.utf16_len += utf16;
+        self.unicode_len += unicode;
         self.line_breaks += line_breaks;
     }
 
@@ -352,6 +393,8 @@ impl Mergeable for Elem {
             && rhs.left == Some(self.id_last())
             && self.right == rhs.right
             && self.status == rhs.status
+            && self.embed.is_none()
+            && rhs.embed.is_none()
             && self.string.can_merge(&rhs.string)
             && self.anchor_set.can_merge(&rhs.anchor_set)
     }
@@ -359,6 +402,7 @@ impl Mergeable for Elem {
     fn merge_right(&mut self, rhs: &Self) {
         self.string.try_merge(&rhs.string).unwrap();
         self.utf16_len += rhs.utf16_len;
+        self.unicode_len += rhs.unicode_len;
         self.line_breaks += rhs.line_breaks;
         self.anchor_set.merge_right(&rhs.anchor_set);
     }
@@ -370,6 +414,7 @@ impl Mergeable for Elem {
         string.try_merge(&self.string).unwrap();
         self.string = string;
         self.utf16_len += lhs.utf16_len;
+        self.unicode_len += lhs.unicode_len;
         self.line_breaks += lhs.line_breaks;
         self.anchor_set.merge_left(&lhs.anchor_set);
     }
@@ -394,7 +439,11 @@ impl Sliceable for Elem {
             std::ops::Bound::Unbounded => self.atom_len(),
         };
         let s = self.string.slice_clone(range);
-        let Utf16LenAndLineBreaks { utf16, line_breaks } = get_utf16_len_and_line_breaks(&s);
+        let Utf16LenAndLineBreaks {
+            utf16,
+            unicode,
+            line_breaks,
+        } = get_utf16_len_and_line_breaks(&s);
         Self {
             inner: Box::new(ElemInner {
                 anchor_set: self.anchor_set.trim(start != 0, end != self.rle_len()),
@@ -407,8 +456,10 @@ impl Sliceable for Elem {
                 right: self.right,
                 string: s,
                 utf16_len: utf16,
+                unicode_len: unicode,
                 line_breaks,
                 status: self.status,
+                embed: self.embed.clone(),
             }),
         }
     }
@@ -441,9 +492,13 @@ impl Sliceable for Elem {
             Some(self.id.inc(start as Counter - 1))
         };
         self.string = self.string.slice_clone(range);
-        let Utf16LenAndLineBreaks { utf16, line_breaks } =
-            get_utf16_len_and_line_breaks(&self.string);
+        let Utf16LenAndLineBreaks {
+            utf16,
+            unicode,
+            line_breaks,
+        } = get_utf16_len_and_line_breaks(&self.string);
         self.utf16_len = utf16;
+        self.unicode_len = unicode;
         self.line_breaks = line_breaks;
     }
 }
@@ -477,6 +532,7 @@ impl Status {
 pub(crate) struct Cache {
     pub len: u32,
     pub utf16_len: u32,
+    pub unicode_len: u32,
     pub anchor_set: CacheAnchorSet,
     pub line_breaks: u32,
 }
@@ -486,6 +542,7 @@ pub(crate) struct CacheDiff {
     pub(super) anchor_diff: AnchorSetDiff,
     pub(super) len_diff: isize,
     pub(super) utf16_len_diff: isize,
+    pub(super) unicode_len_diff: isize,
     pub(super) line_break_diff: isize,
 }
 
@@ -493,16 +550,23 @@ impl Cache {
     fn apply_diff(&mut self, diff: &CacheDiff) {
         self.len = (self.len as isize + diff.len_diff) as u32;
         self.utf16_len = (self.utf16_len as isize + diff.utf16_len_diff) as u32;
+        self.unicode_len = (self.unicode_len as isize + diff.unicode_len_diff) as u32;
         self.line_breaks = (self.line_breaks as isize + diff.line_break_diff) as u32;
         self.anchor_set.apply_diff(&diff.anchor_diff);
     }
 }
 
 impl CacheDiff {
-    pub fn new_len_diff(diff: isize, utf16_len_diff: isize, line_break_diff: isize) -> CacheDiff {
+    pub fn new_len_diff(
+        diff: isize,
+        utf16_len_diff: isize,
+        unicode_len_diff: isize,
+        line_break_diff: isize,
+    ) -> CacheDiff {
         CacheDiff {
             len_diff: diff,
             utf16_len_diff,
+            unicode_len_diff,
             anchor_diff: Default::default(),
             line_break_diff,
         }
@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::rich_text::{
     ann::StyleCalculator,
-    rich_tree::utf16::{line_start_to_utf8, utf16_to_utf8},
+    rich_tree::utf16::{line_start_to_utf8, unicode_to_utf8, utf16_to_utf8},
 };
 
 use super::*;
@@ -12,6 +12,9 @@ use super::*;
 pub enum IndexType {
     Utf8,
     Utf16,
+    /// The number of unicode scalar values, i.e. the chars in Rust and the code
+    /// points in Python or Swift
+    Unicode,
 }
 
 pub(crate) struct IndexFinderWithStyles {
@@ -30,14 +33,22 @@ pub(crate) struct LineStartFinder {
     pub(crate) style_calculator: StyleCalculator,
 }
 
-struct AnnotationFinderStart {
+/// Find the start anchor of the annotation.
+///
+/// `visited_len` is the index of the start after the query.
+pub(crate) struct AnnotationFinderStart {
     target: AnnIdx,
-    visited_len: usize,
+    pub(crate) visited_len: usize,
+    index_type: IndexType,
 }
 
-struct AnnotationFinderEnd {
+/// Find the end anchor of the annotation.
+///
+/// `visited_len` is the length from the end to the end of the doc after the query.
+pub(crate) struct AnnotationFinderEnd {
     target: AnnIdx,
-    visited_len: usize,
+    pub(crate) visited_len: usize,
+    index_type: IndexType,
 }
 
 impl Query<RichTreeTrait> for IndexFinder {
@@ -65,6 +76,7 @@ impl Query<RichTreeTrait> for IndexFinder {
             let cache_len = match self.index_type {
                 IndexType::Utf8 => cache.cache.len,
                 IndexType::Utf16 => cache.cache.utf16_len,
+                IndexType::Unicode => cache.cache.unicode_len,
             };
             // prefer the end of an element
             if self.left >= cache_len as usize {
@@ -96,6 +108,13 @@ impl Query<RichTreeTrait> for IndexFinder {
                         cache.utf16_len as usize
                     }
                 }
+                IndexType::Unicode => {
+                    if cache.status.is_dead() {
+                        0
+                    } else {
+                        cache.unicode_len as usize
+                    }
+                }
             };
             // prefer the end of an element
             if self.left >= len {
@@ -213,6 +232,7 @@ impl Query<TreeTrait> for IndexFinderWithStyles {
             let cache_len = match self.index_type {
                 IndexType::Utf8 => cache.cache.len,
                 IndexType::Utf16 => cache.cache.utf16_len,
+                IndexType::Unicode => cache.cache.unicode_len,
             };
             if self.left >= cache_len as usize {
                 last_left = self.left;
@@ -247,6 +267,13 @@ impl Query<TreeTrait> for IndexFinderWithStyles {
                         cache.utf16_len as usize
                     }
                 }
+                IndexType::Unicode => {
+                    if cache.status.is_dead() {
+                        0
+                    } else {
+                        cache.unicode_len as usize
+                    }
+                }
             };
             self.style_calculator.apply_start(&cache.anchor_set);
             self.style_calculator.cache_end(&cache.anchor_set);
@@ -285,16 +312,25 @@ fn reset_left_to_utf8(left: usize, index_type: IndexType, element: &Elem) -> usi
 
             utf16_to_utf8(&element.string, left)
         }
+        IndexType::Unicode => {
+            assert!(element.unicode_len as usize >= left);
+            if element.unicode_len as usize == left {
+                return element.atom_len();
+            }
+
+            unicode_to_utf8(&element.string, left)
+        }
     }
 }
 
 impl Query<TreeTrait> for AnnotationFinderStart {
-    type QueryArg = AnnIdx;
+    type QueryArg = (AnnIdx, IndexType);
 
     fn init(target: &Self::QueryArg) -> Self {
         Self {
-            target: *target,
+            target: target.0,
             visited_len: 0,
+            index_type: target.1,
         }
     }
 
@@ -307,7 +343,7 @@ impl Query<TreeTrait> for AnnotationFinderStart {
             if cache.cache.anchor_set.contains_start(self.target) {
                 return FindResult::new_found(i, 0);
             }
-            self.visited_len += cache.cache.len as usize;
+            self.visited_len += cache_len(&cache.cache, self.index_type);
         }
 
         FindResult::new_missing(0, 0)
@@ -322,12 +358,12 @@ impl Query<TreeTrait> for AnnotationFinderStart {
             let (contains_start, inclusive) = cache.anchor_set.contains_start(self.target);
             if contains_start {
                 if !inclusive {
-                    self.visited_len += cache.content_len();
+                    self.visited_len += cache.content_len_with(self.index_type);
                 }
 
                 return FindResult::new_found(i, 0);
             }
-            self.visited_len += cache.content_len();
+            self.visited_len += cache.content_len_with(self.index_type);
         }
 
         FindResult::new_missing(0, 0)
@@ -335,12 +371,13 @@ impl Query<TreeTrait> for AnnotationFinderStart {
 }
 
 impl Query<TreeTrait> for AnnotationFinderEnd {
-    type QueryArg = AnnIdx;
+    type QueryArg = (AnnIdx, IndexType);
 
     fn init(target: &Self::QueryArg) -> Self {
         Self {
-            target: *target,
+            target: target.0,
             visited_len: 0,
+            index_type: target.1,
         }
     }
 
@@ -353,7 +390,7 @@ impl Query<TreeTrait> for AnnotationFinderEnd {
             if cache.cache.anchor_set.contains_end(self.target) {
                 return FindResult::new_found(i, cache.cache.len as usize);
             }
-            self.visited_len += cache.cache.len as usize;
+            self.visited_len += cache_len(&cache.cache, self.index_type);
         }
 
         FindResult::new_missing(0, 0)
@@ -368,14 +405,22 @@ impl Query<TreeTrait> for AnnotationFinderEnd {
             let (contains_end, inclusive) = cache.anchor_set.contains_end(self.target);
             if contains_end {
                 if !inclusive {
-                    self.visited_len += cache.content_len();
+                    self.visited_len += cache.content_len_with(self.index_type);
                 }
 
                 return FindResult::new_found(i, cache.content_len());
             }
-            self.visited_len += cache.content_len();
+            self.visited_len += cache.content_len_with(self.index_type);
         }
 
         FindResult::new_missing(0, 0)
     }
 }
+
+fn cache_len(cache: &Cache, index_type: IndexType) -> usize {
+    match index_type {
+        IndexType::Utf8 => cache.len as usize,
+        IndexType::Utf16 => cache.utf16_len as usize,
+        IndexType::Unicode => cache.unicode_len as usize,
+    }
+}
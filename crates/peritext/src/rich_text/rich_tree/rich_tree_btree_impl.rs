@@ -26,11 +26,13 @@ impl BTreeTrait for RichTreeTrait {
             None => {
                 let mut len = 0;
                 let mut utf16_len = 0;
+                let mut unicode_len = 0;
                 let mut line_breaks = 0;
                 let mut anchor_set = CacheAnchorSet::default();
                 for child in caches.iter() {
                     len += child.cache.len;
                     utf16_len += child.cache.utf16_len;
+                    unicode_len += child.cache.unicode_len;
                     line_breaks += child.cache.line_breaks;
                     anchor_set.union_(&child.cache.anchor_set);
                 }
@@ -40,11 +42,13 @@ impl BTreeTrait for RichTreeTrait {
                     anchor_diff,
                     len_diff: len as isize - cache.len as isize,
                     utf16_len_diff: utf16_len as isize - cache.utf16_len as isize,
+                    unicode_len_diff: unicode_len as isize - cache.unicode_len as isize,
                     line_break_diff: line_breaks as isize - cache.line_breaks as isize,
                 };
 
                 cache.len = len;
                 cache.utf16_len = utf16_len;
+                cache.unicode_len = unicode_len;
                 cache.line_breaks = line_breaks;
                 Some(diff)
             }
@@ -64,12 +68,14 @@ impl BTreeTrait for RichTreeTrait {
             None => {
                 let mut len = 0;
                 let mut utf16_len = 0;
+                let mut unicode_len = 0;
                 let mut line_breaks = 0;
                 let mut anchor_set = CacheAnchorSet::default();
                 for child in caches.iter() {
                     if !child.is_dead() {
                         len += child.string.len();
                         utf16_len += child.utf16_len;
+                        unicode_len += child.unicode_len;
                         line_breaks += child.line_breaks;
                     }
                     anchor_set.union_elem_set(&child.anchor_set);
@@ -80,10 +86,12 @@ impl BTreeTrait for RichTreeTrait {
                     anchor_diff,
                     len_diff: len as isize - cache.len as isize,
                     utf16_len_diff: utf16_len as isize - cache.utf16_len as isize,
+                    unicode_len_diff: unicode_len as isize - cache.unicode_len as isize,
                     line_break_diff: line_breaks as isize - cache.line_breaks as isize,
                 };
                 cache.len = len as u32;
                 cache.utf16_len = utf16_len;
+                cache.unicode_len = unicode_len;
                 cache.line_breaks = line_breaks;
                 diff
             }
@@ -94,6 +102,7 @@ impl BTreeTrait for RichTreeTrait {
         diff1.anchor_diff.merge(&diff2.anchor_diff);
         diff1.len_diff += diff2.len_diff;
         diff1.utf16_len_diff += diff2.utf16_len_diff;
+        diff1.unicode_len_diff += diff2.unicode_len_diff;
         diff1.line_break_diff += diff2.line_break_diff;
     }
 
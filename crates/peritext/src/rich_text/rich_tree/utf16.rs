@@ -2,6 +2,8 @@ use super::*;
 
 pub struct Utf16LenAndLineBreaks {
     pub utf16: u32,
+    /// number of chars
+    pub unicode: u32,
     pub line_breaks: u32,
 }
 
@@ -14,25 +16,36 @@ pub fn get_utf16_len(str: &str) -> usize {
     iter.count()
 }
 
+pub fn get_unicode_len(str: &str) -> usize {
+    str.chars().count()
+}
+
 #[inline(always)]
 pub fn get_utf16_len_and_line_breaks(bytes: &[u8]) -> Utf16LenAndLineBreaks {
     if bytes.is_empty() {
         return Utf16LenAndLineBreaks {
             line_breaks: 0,
             utf16: 0,
+            unicode: 0,
         };
     }
 
     let str = bytes_to_str(bytes);
-    let mut iter = encode_utf16(str);
     let mut utf16 = 0;
-    for _ in iter.by_ref() {
-        utf16 += 1;
+    let mut unicode = 0;
+    let mut line_breaks = 0;
+    for c in str.chars() {
+        utf16 += c.len_utf16() as u32;
+        unicode += 1;
+        if c == '\n' {
+            line_breaks += 1;
+        }
     }
 
     Utf16LenAndLineBreaks {
         utf16,
-        line_breaks: iter.line_breaks,
+        unicode,
+        line_breaks,
     }
 }
 
@@ -50,6 +63,14 @@ pub fn utf16_to_utf8(bytes: &[u8], utf16_index: usize) -> usize {
     iter.visited
 }
 
+pub fn unicode_to_utf8(bytes: &[u8], unicode_index: usize) -> usize {
+    let str = bytes_to_str(bytes);
+    match str.char_indices().nth(unicode_index) {
+        Some((index, _)) => index,
+        None => str.len(),
+    }
+}
+
 /// get the index of nth line start in bytes (in utf8)
 ///
 /// if n exceed the number of lines in bytes, return None
@@ -88,7 +109,6 @@ fn encode_utf16(s: &str) -> EncodeUtf16 {
         chars: s.chars(),
         extra: 0,
         visited: 0,
-        line_breaks: 0,
     }
 }
 
@@ -98,7 +118,6 @@ pub struct EncodeUtf16<'a> {
     chars: Chars<'a>,
     extra: u16,
     visited: usize,
-    line_breaks: u32,
 }
 
 impl fmt::Debug for EncodeUtf16<'_> {
@@ -121,7 +140,6 @@ impl<'a> Iterator for EncodeUtf16<'a> {
         let mut buf = [0; 2];
         self.chars.next().map(|ch| {
             self.visited += ch.len_utf8();
-            self.line_breaks += if ch.eq(&'\n') { 1 } else { 0 };
             let n = ch.encode_utf16(&mut buf).len();
             if n == 2 {
                 self.extra = buf[1];
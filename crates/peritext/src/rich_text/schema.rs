@@ -0,0 +1,256 @@
+//! The registry of the annotation types.
+//!
+//! The local edits that only know the type and the value of a style, e.g. the
+//! attributes of a delta from the editor, build the [Style] from the [StyleConfig]
+//! registered for the type. [RichText::annotate] also checks the style against it,
+//! and [RichText::update_annotation_value] checks the new value.
+//!
+//! The schema is not part of the document state, and the ops from the peers are
+//! not checked. The peers should register the same types.
+
+use fxhash::FxHashMap;
+use serde_json::{Number, Value};
+
+use crate::{Behavior, Expand, InternalString, Style};
+
+use super::{Error, RichText};
+
+/// Whether the style applies to the chars or to the lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StyleKind {
+    /// e.g. bold, link
+    Inline,
+    /// e.g. header, list. It's set on the line break at the end of the line
+    Block,
+}
+
+/// The type of the values of a style
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueSchema {
+    Any,
+    Bool,
+    Number,
+    String,
+    Object,
+}
+
+impl ValueSchema {
+    /// Convert the value to the type of the schema.
+    ///
+    /// The strings are parsed as booleans or numbers, and the numbers and the booleans
+    /// are formatted as strings. Return `None` if the value can't be converted.
+    pub fn coerce(self, value: Value) -> Option<Value> {
+        match (self, value) {
+            (ValueSchema::Any, value) => Some(value),
+            (ValueSchema::Bool, Value::Bool(x)) => Some(Value::Bool(x)),
+            (ValueSchema::Bool, Value::String(x)) => match x.as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (ValueSchema::Number, Value::Number(x)) => Some(Value::Number(x)),
+            (ValueSchema::Number, Value::String(x)) => {
+                if let Ok(x) = x.parse::<i64>() {
+                    Some(Value::Number(x.into()))
+                } else {
+                    let x = x.parse::<f64>().ok()?;
+                    Number::from_f64(x).map(Value::Number)
+                }
+            }
+            (ValueSchema::String, Value::String(x)) => Some(Value::String(x)),
+            (ValueSchema::String, Value::Number(x)) => Some(Value::String(x.to_string())),
+            (ValueSchema::String, Value::Bool(x)) => Some(Value::String(x.to_string())),
+            (ValueSchema::Object, Value::Object(x)) => Some(Value::Object(x)),
+            _ => None,
+        }
+    }
+}
+
+/// How the annotations of a type behave
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StyleConfig {
+    /// The expand type of the annotations that set the value. The annotations that
+    /// remove the style use the toggled one
+    pub expand: Expand,
+    pub behavior: Behavior,
+    pub value: ValueSchema,
+    pub kind: StyleKind,
+}
+
+impl StyleConfig {
+    pub fn new_bold_like() -> Self {
+        Self {
+            expand: Expand::After,
+            behavior: Behavior::Merge,
+            value: ValueSchema::Any,
+            kind: StyleKind::Inline,
+        }
+    }
+
+    pub fn new_link_like() -> Self {
+        Self {
+            expand: Expand::None,
+            ..Self::new_bold_like()
+        }
+    }
+
+    pub fn new_comment_like() -> Self {
+        Self {
+            expand: Expand::None,
+            behavior: Behavior::AllowMultiple,
+            ..Self::new_bold_like()
+        }
+    }
+
+    pub fn new_block() -> Self {
+        Self {
+            expand: Expand::None,
+            kind: StyleKind::Block,
+            ..Self::new_bold_like()
+        }
+    }
+
+    pub fn with_value(self, value: ValueSchema) -> Self {
+        Self { value, ..self }
+    }
+}
+
+/// The [StyleConfig] of each annotation type.
+///
+/// The default schema has the formats of Quill. The unregistered types are treated
+/// as bold-like styles with any value.
+#[derive(Debug, Clone)]
+pub struct StyleSchema {
+    styles: FxHashMap<InternalString, StyleConfig>,
+    fallback: StyleConfig,
+}
+
+impl Default for StyleSchema {
+    fn default() -> Self {
+        let mut schema = Self::new();
+        for type_ in ["link", "code", "script", "formula", "image", "video"] {
+            schema.register(type_, StyleConfig::new_link_like());
+        }
+        schema.register("comment", StyleConfig::new_comment_like());
+        for type_ in [
+            "header",
+            "indent",
+            "list",
+            "align",
+            "direction",
+            "code-block",
+        ] {
+            schema.register(type_, StyleConfig::new_block());
+        }
+
+        schema
+    }
+}
+
+impl StyleSchema {
+    /// A schema without registered types
+    pub fn new() -> Self {
+        Self {
+            styles: FxHashMap::default(),
+            fallback: StyleConfig::new_bold_like(),
+        }
+    }
+
+    /// Register the type, replacing the old config of it
+    pub fn register(&mut self, type_: impl Into<InternalString>, config: StyleConfig) {
+        self.styles.insert(type_.into(), config);
+    }
+
+    pub fn is_registered(&self, type_: &str) -> bool {
+        self.styles.contains_key(&InternalString::from(type_))
+    }
+
+    /// Get the config of the type, the unregistered type has the bold-like config
+    pub fn get(&self, type_: &str) -> &StyleConfig {
+        self.styles
+            .get(&InternalString::from(type_))
+            .unwrap_or(&self.fallback)
+    }
+
+    /// The style that sets the value of the type. A `null` value removes the type.
+    ///
+    /// Return an error if the value can't be converted to the value schema of the type.
+    pub fn style(&self, type_: &str, value: Value) -> Result<Style, Error> {
+        if value.is_null() {
+            return Ok(self.erase_style(type_));
+        }
+
+        let config = self.get(type_);
+        let value = self.coerce(type_, value)?;
+        Ok(Style {
+            expand: config.expand,
+            behavior: config.behavior,
+            type_: type_.into(),
+            value,
+        })
+    }
+
+    /// The style that removes the type
+    pub fn erase_style(&self, type_: &str) -> Style {
+        Style {
+            expand: self.get(type_).expand.toggle(),
+            behavior: Behavior::Delete,
+            type_: type_.into(),
+            value: Value::Null,
+        }
+    }
+
+    /// Convert the value to the value schema of the type
+    pub(crate) fn coerce(&self, type_: &str, value: Value) -> Result<Value, Error> {
+        self.get(type_)
+            .value
+            .coerce(value.clone())
+            .ok_or_else(|| Error::InvalidStyleValue(type_.into(), value))
+    }
+
+    /// Check the style of an inline annotation against the config of its type, and
+    /// convert its value to the value schema of the type.
+    ///
+    /// The block types can't be annotated inline. The expand and the behavior of a
+    /// registered type should match its config, the unregistered types are not restricted.
+    pub(crate) fn check(&self, style: &mut Style) -> Result<(), Error> {
+        let config = self.get(&style.type_);
+        if config.kind == StyleKind::Block {
+            return Err(Error::NotInlineStyle(style.type_.clone()));
+        }
+
+        if self.is_registered(&style.type_) {
+            let matches = if style.behavior == Behavior::Delete {
+                style.expand == config.expand.toggle()
+            } else {
+                style.expand == config.expand && style.behavior == config.behavior
+            };
+            if !matches {
+                return Err(Error::StyleMismatch(style.type_.clone()));
+            }
+        }
+
+        if style.behavior == Behavior::Delete {
+            return Ok(());
+        }
+
+        style.value = self.coerce(&style.type_, std::mem::take(&mut style.value))?;
+        Ok(())
+    }
+}
+
+impl RichText {
+    pub fn style_schema(&self) -> &StyleSchema {
+        &self.schema
+    }
+
+    /// The schema is used by the following local edits, it doesn't change the
+    /// existing annotations
+    pub fn style_schema_mut(&mut self) -> &mut StyleSchema {
+        &mut self.schema
+    }
+
+    pub fn set_style_schema(&mut self, schema: StyleSchema) {
+        self.schema = schema;
+    }
+}
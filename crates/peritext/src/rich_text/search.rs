@@ -0,0 +1,166 @@
+//! Find and replace.
+//!
+//! The matches are searched in the text of the document, then their utf8 ranges
+//! are converted to the requested index type in one pass. The regex patterns need
+//! the `search-regex` feature.
+//!
+//! The replacement text is inserted after the first char of the replaced range
+//! before the range is deleted. So it has the styles of that char, and the
+//! styles that start or end right after it are kept or not by their expand types,
+//! as for any text inserted there.
+
+use std::{
+    borrow::Cow,
+    ops::{Bound, Range, RangeBounds},
+};
+
+#[cfg(feature = "search-regex")]
+use regex::Regex;
+
+use super::{
+    rich_tree::utf16::{get_unicode_len, get_utf16_len},
+    IndexType, RichText,
+};
+
+/// The pattern of [RichText::find] and [RichText::replace_all]
+#[derive(Debug, Clone, Copy)]
+pub enum Pattern<'a> {
+    /// Match the string as is. An empty string matches nothing
+    Literal(&'a str),
+    #[cfg(feature = "search-regex")]
+    Regex(&'a Regex),
+}
+
+impl<'a> From<&'a str> for Pattern<'a> {
+    fn from(value: &'a str) -> Self {
+        Pattern::Literal(value)
+    }
+}
+
+#[cfg(feature = "search-regex")]
+impl<'a> From<&'a Regex> for Pattern<'a> {
+    fn from(value: &'a Regex) -> Self {
+        Pattern::Regex(value)
+    }
+}
+
+impl RichText {
+    /// Find the non-overlapping matches of the pattern, in the order of the text
+    pub fn find<'a>(
+        &self,
+        pattern: impl Into<Pattern<'a>>,
+        index_type: IndexType,
+    ) -> impl Iterator<Item = Range<usize>> {
+        let text = self.to_string();
+        let ranges = match_ranges(&text, pattern.into());
+        convert_ranges(&text, ranges, index_type).into_iter()
+    }
+
+    /// Replace the text in the range, see the module doc for the styles of the new text.
+    ///
+    /// It's one transaction, so the listeners receive one event.
+    pub fn replace_range(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        text: &str,
+        index_type: IndexType,
+    ) {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len_with(index_type),
+        };
+        assert!(start <= end);
+        assert!(end <= self.len_with(index_type));
+        self.transact(|txn| txn.replace_inner(start..end, text, index_type));
+    }
+
+    /// Replace all the matches of the pattern in one transaction, and return the
+    /// number of the replaced matches.
+    ///
+    /// The replacement of a regex can refer to the capture groups, e.g. `$1`, as in
+    /// `Regex::replace_all`.
+    pub fn replace_all<'a>(&mut self, pattern: impl Into<Pattern<'a>>, replacement: &str) -> usize {
+        let text = self.to_string();
+        let replacements: Vec<(Range<usize>, Cow<str>)> = match pattern.into() {
+            Pattern::Literal(pattern) => match_ranges(&text, Pattern::Literal(pattern))
+                .into_iter()
+                .map(|range| (range, Cow::Borrowed(replacement)))
+                .collect(),
+            #[cfg(feature = "search-regex")]
+            Pattern::Regex(regex) => regex
+                .captures_iter(&text)
+                .map(|captures| {
+                    let mut ans = String::new();
+                    captures.expand(replacement, &mut ans);
+                    (captures.get(0).unwrap().range(), Cow::Owned(ans))
+                })
+                .collect(),
+        };
+
+        // replace from the end, so the ranges before are not moved
+        self.transact(|txn| {
+            for (range, replacement) in replacements.iter().rev() {
+                txn.replace_inner(range.clone(), replacement, IndexType::Utf8);
+            }
+        });
+        replacements.len()
+    }
+
+    fn replace_inner(&mut self, range: Range<usize>, text: &str, index_type: IndexType) {
+        let start = self.convert_index(range.start, index_type, IndexType::Unicode);
+        if range.is_empty() {
+            self.insert_inner(start, text, IndexType::Unicode);
+            return;
+        }
+
+        let end = self.convert_index(range.end, index_type, IndexType::Unicode);
+        let len = get_unicode_len(text);
+        self.insert_inner(start + 1, text, IndexType::Unicode);
+        self.delete_inner(start + 1 + len..end + len, IndexType::Unicode);
+        self.delete_inner(start..start + 1, IndexType::Unicode);
+    }
+}
+
+fn match_ranges(text: &str, pattern: Pattern) -> Vec<Range<usize>> {
+    match pattern {
+        Pattern::Literal("") => Vec::new(),
+        Pattern::Literal(pattern) => text
+            .match_indices(pattern)
+            .map(|(index, _)| index..index + pattern.len())
+            .collect(),
+        #[cfg(feature = "search-regex")]
+        Pattern::Regex(regex) => regex.find_iter(text).map(|x| x.range()).collect(),
+    }
+}
+
+/// Convert the sorted utf8 ranges of the text to the index type
+fn convert_ranges(
+    text: &str,
+    ranges: Vec<Range<usize>>,
+    index_type: IndexType,
+) -> Vec<Range<usize>> {
+    let len = |s: &str| match index_type {
+        IndexType::Utf8 => s.len(),
+        IndexType::Utf16 => get_utf16_len(s),
+        IndexType::Unicode => get_unicode_len(s),
+    };
+
+    let mut last = 0;
+    let mut index = 0;
+    ranges
+        .into_iter()
+        .map(|range| {
+            index += len(&text[last..range.start]);
+            let start = index;
+            index += len(&text[range.clone()]);
+            last = range.end;
+            start..index
+        })
+        .collect()
+}
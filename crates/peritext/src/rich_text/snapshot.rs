@@ -0,0 +1,476 @@
+//! Snapshot encoding of the document state.
+//!
+//! Unlike the update encoding in [super::encoding], a snapshot stores the
+//! elements of the B-tree (including the tombstones), the annotations and
+//! the version vector directly. So loading it doesn't need to replay the
+//! history.
+//!
+//! The snapshot is shallow: the op history, the pending ops and the undo
+//! stack are not included. The tombstones removed by compaction are kept, so
+//! the loaded doc can handle the ops that refer to them. A doc loaded from a
+//! snapshot can only export the ops created after the snapshot's version.
+//!
+//! Like the updates, a snapshot starts with a header of the magic bytes
+//! `PTSN`, the format version, the flags and the crc32 checksum of the body.
+//! The snapshots exported before the header was added are format version 0,
+//! which can't be loaded anymore.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_columnar::{columnar, from_bytes, to_vec};
+
+use crate::{
+    Anchor, AnchorRange, AnchorType, Annotation, Behavior, ClientID, Counter, InternalString, OpID,
+};
+
+use super::{
+    ann::AnnIdx,
+    compact::RemovedSpan,
+    encoding::{checksum, compress, decode_id, decompress, VecMapping, HEADER_LEN},
+    error::Error,
+    op::OpStore,
+    rich_tree::{Elem, Status},
+    vv::VersionVector,
+    RichText,
+};
+
+const MAGIC: [u8; 4] = *b"PTSN";
+/// The current format version. Bump it when the layout of [SnapshotEncoding]
+/// changes
+const FORMAT_VERSION: u8 = 1;
+
+#[columnar(vec, ser, de)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ElemEncoding {
+    #[columnar(strategy = "Rle")]
+    client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    counter: u32,
+    len: u32,
+    #[columnar(strategy = "Rle")]
+    left_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    left_counter: u32,
+    #[columnar(strategy = "Rle")]
+    right_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    right_counter: u32,
+    #[columnar(strategy = "Rle")]
+    deleted_times: u16,
+    #[columnar(strategy = "Rle")]
+    future: bool,
+    /// number of anchors attached to this elem
+    #[columnar(strategy = "Rle")]
+    anchor_len: u32,
+    /// index to ann_types_and_values, `u32::MAX` if it's not an embed
+    #[columnar(strategy = "Rle")]
+    embed: u32,
+}
+
+#[columnar(vec, ser, de)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ElemAnchorEncoding {
+    /// index to annotations (starts from 1)
+    ann: i32,
+    #[columnar(strategy = "Rle")]
+    is_start: bool,
+    #[columnar(strategy = "Rle")]
+    is_before: bool,
+}
+
+#[columnar(vec, ser, de)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SnapshotAnnEncoding {
+    #[columnar(strategy = "Rle")]
+    client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    counter: u32,
+    #[columnar(strategy = "DeltaRle")]
+    range_lamport: u32,
+    #[columnar(strategy = "Rle")]
+    range_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    range_counter: u32,
+    start: Option<OpID>,
+    #[columnar(strategy = "Rle")]
+    is_start_before_anchor: bool,
+    end: Option<OpID>,
+    #[columnar(strategy = "Rle")]
+    is_end_before_anchor: bool,
+    behavior: Behavior,
+    /// index to ann_types_and_values
+    type_: u32,
+    /// index to ann_types_and_values
+    value: u32,
+    /// the lamport of the patch that set the value
+    value_lamport: u32,
+    /// `u32::MAX` if the value is not set by a patch
+    #[columnar(strategy = "Rle")]
+    value_client: u32,
+    value_counter: u32,
+    #[columnar(strategy = "Rle")]
+    removed: bool,
+    /// the lamport of the patch that removed or restored the annotation last
+    #[columnar(strategy = "Rle")]
+    removal_lamport: u32,
+    /// `u32::MAX` if the annotation is never removed
+    #[columnar(strategy = "Rle")]
+    removal_client: u32,
+    #[columnar(strategy = "Rle")]
+    removal_counter: u32,
+}
+
+/// The tombstones removed by compaction
+#[columnar(vec, ser, de)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RemovedEncoding {
+    #[columnar(strategy = "Rle")]
+    client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    counter: u32,
+    len: u32,
+    #[columnar(strategy = "Rle")]
+    left_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    left_counter: u32,
+    #[columnar(strategy = "Rle")]
+    right_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    right_counter: u32,
+}
+
+#[columnar(ser, de)]
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEncoding {
+    #[columnar(type = "vec")]
+    elems: Vec<ElemEncoding>,
+    #[columnar(type = "vec")]
+    anchors: Vec<ElemAnchorEncoding>,
+    #[columnar(type = "vec")]
+    annotations: Vec<SnapshotAnnEncoding>,
+    #[columnar(type = "vec")]
+    removed: Vec<RemovedEncoding>,
+
+    str: Vec<u8>,
+    compressed_str: bool,
+    clients: Vec<ClientID>,
+    ann_types_and_values: Vec<InternalString>,
+    /// index to clients
+    vv_clients: Vec<u32>,
+    vv_counters: Vec<Counter>,
+    next_lamport: u32,
+}
+
+fn encode_id(client_mapping: &mut VecMapping<ClientID>, id: Option<OpID>) -> (u32, Counter) {
+    match id {
+        Some(id) => (client_mapping.get_or_insert(id.client) as u32, id.counter),
+        None => (u32::MAX, 0),
+    }
+}
+
+fn anchor_type(is_before: bool) -> AnchorType {
+    if is_before {
+        AnchorType::Before
+    } else {
+        AnchorType::After
+    }
+}
+
+pub(super) fn encode_snapshot(text: &RichText) -> Vec<u8> {
+    let mut client_mapping = VecMapping::new();
+    let mut ann_str_mapping = VecMapping::new();
+    let mut str = Vec::with_capacity(text.bytes.len());
+    let mut elems = Vec::new();
+    let mut anchors = Vec::new();
+    for elem in text.content.iter() {
+        str.extend_from_slice(&elem.string);
+        let mut anchor_len = 0;
+        for (ann, type_, is_start) in elem.anchor_set.iter() {
+            anchors.push(ElemAnchorEncoding {
+                ann,
+                is_start,
+                is_before: type_ == AnchorType::Before,
+            });
+            anchor_len += 1;
+        }
+
+        let (client, counter) = encode_id(&mut client_mapping, Some(elem.id));
+        let (left_client, left_counter) = encode_id(&mut client_mapping, elem.left);
+        let (right_client, right_counter) = encode_id(&mut client_mapping, elem.right);
+        let embed = match &elem.embed {
+            Some(embed) => {
+                let value = serde_json::to_string(embed).unwrap();
+                ann_str_mapping.get_or_insert(value.into()) as u32
+            }
+            None => u32::MAX,
+        };
+        elems.push(ElemEncoding {
+            client,
+            counter,
+            len: elem.string.len() as u32,
+            left_client,
+            left_counter,
+            right_client,
+            right_counter,
+            deleted_times: elem.status.deleted_times,
+            future: elem.status.future,
+            anchor_len,
+            embed,
+        });
+    }
+
+    let mut annotations = Vec::new();
+    for (i, ann) in text.ann.iter().enumerate() {
+        let idx = i as AnnIdx + 1;
+        let (client, counter) = encode_id(&mut client_mapping, Some(ann.id));
+        let (range_client, range_counter) =
+            encode_id(&mut client_mapping, Some(ann.range_lamport.1));
+        let type_ = ann_str_mapping.get_or_insert(ann.type_.clone());
+        let value = serde_json::to_string(&ann.value).unwrap();
+        let value = ann_str_mapping.get_or_insert(value.into());
+        let value_lamport = text.ann.get_value_lamport(idx);
+        let (value_client, value_counter) =
+            encode_id(&mut client_mapping, value_lamport.map(|x| x.1));
+        let removal_lamport = text.ann.get_removal_lamport(idx);
+        let (removal_client, removal_counter) =
+            encode_id(&mut client_mapping, removal_lamport.map(|x| x.1));
+        annotations.push(SnapshotAnnEncoding {
+            client,
+            counter,
+            range_lamport: ann.range_lamport.0,
+            range_client,
+            range_counter,
+            start: ann.range.start.id,
+            is_start_before_anchor: ann.range.start.type_ == AnchorType::Before,
+            end: ann.range.end.id,
+            is_end_before_anchor: ann.range.end.type_ == AnchorType::Before,
+            behavior: ann.behavior,
+            type_: type_ as u32,
+            value: value as u32,
+            value_lamport: value_lamport.map(|x| x.0).unwrap_or(0),
+            value_client,
+            value_counter,
+            removed: text.ann.is_removed(idx),
+            removal_lamport: removal_lamport.map(|x| x.0).unwrap_or(0),
+            removal_client,
+            removal_counter,
+        });
+    }
+
+    let mut removed = Vec::new();
+    for (id, span) in text.removed.iter() {
+        let (client, counter) = encode_id(&mut client_mapping, Some(id));
+        let (left_client, left_counter) = encode_id(&mut client_mapping, span.left);
+        let (right_client, right_counter) = encode_id(&mut client_mapping, span.right);
+        removed.push(RemovedEncoding {
+            client,
+            counter,
+            len: span.len as u32,
+            left_client,
+            left_counter,
+            right_client,
+            right_counter,
+        });
+    }
+
+    let mut vv_clients = Vec::new();
+    let mut vv_counters = Vec::new();
+    for (client, counter) in text.store.vv().vv.iter() {
+        vv_clients.push(client_mapping.get_or_insert(*client) as u32);
+        vv_counters.push(*counter);
+    }
+
+    let (str, compressed_str) = compress(str);
+    let data = SnapshotEncoding {
+        elems,
+        anchors,
+        annotations,
+        removed,
+        str,
+        compressed_str,
+        clients: client_mapping.vec,
+        ann_types_and_values: ann_str_mapping.vec,
+        vv_clients,
+        vv_counters,
+        next_lamport: text.store.next_lamport(),
+    };
+    // the str is compressed on its own, so no flag is set for now
+    let body = to_vec(&data).unwrap();
+    let flags = 0;
+    let mut ans = Vec::with_capacity(HEADER_LEN + body.len());
+    ans.extend_from_slice(&MAGIC);
+    ans.push(FORMAT_VERSION);
+    ans.push(flags);
+    ans.extend_from_slice(&checksum(FORMAT_VERSION, flags, &body).to_le_bytes());
+    ans.extend_from_slice(&body);
+    ans
+}
+
+/// Check the header and return the body
+fn decode_header(data: &[u8]) -> Result<&[u8], Error> {
+    let Some(rest) = data.strip_prefix(&MAGIC) else {
+        // the snapshots without the header have an older layout
+        return Err(Error::IncompatibleVersion(0));
+    };
+
+    if data.len() < HEADER_LEN {
+        return Err(Error::BadHeader);
+    }
+
+    let version = rest[0];
+    let flags = rest[1];
+    let expected = u32::from_le_bytes(rest[2..6].try_into().unwrap());
+    let body = &data[HEADER_LEN..];
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(Error::IncompatibleVersion(version));
+    }
+    if flags != 0 {
+        return Err(Error::BadHeader);
+    }
+    if checksum(version, flags, body) != expected {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    Ok(body)
+}
+
+pub(super) fn decode_snapshot(client_id: ClientID, data: &[u8]) -> Result<RichText, Error> {
+    let body = decode_header(data)?;
+    let encoded: SnapshotEncoding = from_bytes(body).map_err(|_| Error::DecodeError)?;
+    let clients = &encoded.clients;
+    let strs = &encoded.ann_types_and_values;
+    let mut text = RichText::new(client_id);
+
+    // register the annotations in the same order, so the ann indexes
+    // stored in the anchors are still valid
+    for ann in encoded.annotations.iter() {
+        let id = decode_id(clients, ann.client, ann.counter)?.ok_or(Error::ColumnMismatch)?;
+        let range_id = decode_id(clients, ann.range_client, ann.range_counter)?
+            .ok_or(Error::ColumnMismatch)?;
+        let type_ = strs.get(ann.type_ as usize).ok_or(Error::ColumnMismatch)?;
+        let value = strs.get(ann.value as usize).ok_or(Error::ColumnMismatch)?;
+        let annotation = Arc::new(Annotation {
+            id,
+            range_lamport: (ann.range_lamport, range_id),
+            range: AnchorRange {
+                start: Anchor {
+                    id: ann.start,
+                    type_: anchor_type(ann.is_start_before_anchor),
+                },
+                end: Anchor {
+                    id: ann.end,
+                    type_: anchor_type(ann.is_end_before_anchor),
+                },
+            },
+            behavior: ann.behavior,
+            type_: type_.clone(),
+            value: serde_json::from_str(value).map_err(|_| Error::DecodeError)?,
+        });
+        let start = annotation.range.start.id;
+        let value = annotation.value.clone();
+        let idx = text.ann.register(annotation);
+        if let Some(id) = decode_id(clients, ann.value_client, ann.value_counter)? {
+            text.ann.set_value(idx, value, (ann.value_lamport, id));
+        }
+        let removal = match decode_id(clients, ann.removal_client, ann.removal_counter)? {
+            Some(removal_id) => Some((ann.removal_lamport, removal_id)),
+            // the old formats have no lamport of the removal, any restoration wins over it
+            None if ann.removed => Some((0, id)),
+            None => None,
+        };
+        if let Some(lamport) = removal {
+            text.ann.set_removed(idx, ann.removed, lamport);
+        }
+        // the anchors of the removed annotations are removed as well
+        if !ann.removed && start.is_none() {
+            text.init_styles.insert_start(idx);
+        }
+    }
+
+    text.bytes
+        .push_slice(&decompress(&encoded.str, encoded.compressed_str)?);
+    let ann_len = encoded.annotations.len() as AnnIdx;
+    let mut str_index = 0;
+    let mut anchor_iter = encoded.anchors.iter();
+    for elem in encoded.elems.iter() {
+        let end = str_index + elem.len as usize;
+        if elem.len == 0 || end > text.bytes.len() {
+            return Err(Error::ColumnMismatch);
+        }
+
+        let string = text.bytes.slice(str_index..end);
+        if std::str::from_utf8(&string).is_err() {
+            return Err(Error::DecodeError);
+        }
+
+        let embed = if elem.embed != u32::MAX {
+            let value = strs.get(elem.embed as usize).ok_or(Error::ColumnMismatch)?;
+            if elem.len != 1 {
+                return Err(Error::DecodeError);
+            }
+
+            Some(Arc::new(
+                serde_json::from_str(value).map_err(|_| Error::DecodeError)?,
+            ))
+        } else {
+            None
+        };
+        let mut new_elem = Elem::new_with_embed(
+            decode_id(clients, elem.client, elem.counter)?.ok_or(Error::ColumnMismatch)?,
+            decode_id(clients, elem.left_client, elem.left_counter)?,
+            decode_id(clients, elem.right_client, elem.right_counter)?,
+            string,
+            embed,
+        );
+        str_index = end;
+        new_elem.status = Status {
+            future: elem.future,
+            deleted_times: elem.deleted_times,
+        };
+        for _ in 0..elem.anchor_len {
+            let anchor = anchor_iter.next().ok_or(Error::ColumnMismatch)?;
+            if !(1..=ann_len).contains(&anchor.ann) {
+                return Err(Error::ColumnMismatch);
+            }
+
+            new_elem.anchor_set.insert_ann(
+                anchor.ann,
+                anchor_type(anchor.is_before),
+                anchor.is_start,
+            );
+        }
+
+        text.content.push(new_elem);
+    }
+
+    text.cursor_map.flush();
+
+    if anchor_iter.next().is_some() {
+        return Err(Error::ColumnMismatch);
+    }
+
+    for span in encoded.removed.iter() {
+        text.removed.insert(
+            decode_id(clients, span.client, span.counter)?.ok_or(Error::ColumnMismatch)?,
+            RemovedSpan {
+                len: span.len as usize,
+                left: decode_id(clients, span.left_client, span.left_counter)?,
+                right: decode_id(clients, span.right_client, span.right_counter)?,
+            },
+        );
+    }
+
+    if encoded.vv_clients.len() != encoded.vv_counters.len() {
+        return Err(Error::ColumnMismatch);
+    }
+
+    let mut vv = VersionVector::default();
+    for (client, counter) in encoded.vv_clients.iter().zip(encoded.vv_counters.iter()) {
+        let client = clients.get(*client as usize).ok_or(Error::ColumnMismatch)?;
+        vv.vv.insert(*client, *counter);
+    }
+
+    text.store = OpStore::new_trimmed(client_id, vv, encoded.next_lamport);
+    Ok(text)
+}
@@ -1,4 +1,4 @@
-use crate::InternalString;
+use crate::{Behavior, Expand, InternalString};
 
 use super::*;
 
@@ -133,7 +133,7 @@ mod insert {
         assert_eq!(&text.to_string(), "你好");
 
         // annotate
-        text.annotate_utf16(0..1, bold());
+        text.annotate_utf16(0..1, bold()).unwrap();
         let spans = text.get_spans();
         assert_eq!(spans.len(), 2);
         assert_eq!(spans[0].insert, "你");
@@ -189,6 +189,15 @@ mod insert {
             assert_eq!(b.to_string(), text.to_string());
         }
     }
+
+    #[test]
+    fn convert_index_inside_element() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你好a");
+        assert_eq!(text.convert_index(1, IndexType::Utf16, IndexType::Utf8), 3);
+        assert_eq!(text.convert_index(2, IndexType::Utf16, IndexType::Utf8), 6);
+        assert_eq!(text.convert_index(3, IndexType::Utf8, IndexType::Utf16), 1);
+    }
 }
 
 mod apply {
@@ -234,8 +243,8 @@ mod apply {
         let mut b = RichText::new(2);
         a.insert(0, "aaa");
         b.insert(0, "bbb");
-        a.annotate(.., bold());
-        b.annotate(.., link());
+        a.annotate(.., bold()).unwrap();
+        b.annotate(.., link()).unwrap();
         a.merge(&b);
         b.merge(&a);
         assert_eq!(a.get_spans(), b.get_spans());
@@ -297,7 +306,7 @@ mod annotation {
     fn annotate_bold() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..=2, bold());
+        text.annotate(0..=2, bold()).unwrap();
         let ans = text.iter().collect::<Vec<_>>();
         assert_eq!(ans.len(), 2);
         assert_eq!(ans[0].len(), 3);
@@ -309,10 +318,10 @@ mod annotation {
     fn should_not_create_new_ann_from_thin_air() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(3..4, bold());
-        text.annotate(4..5, bold());
-        text.annotate(7..8, bold());
-        text.annotate(2..8, link());
+        text.annotate(3..4, bold()).unwrap();
+        text.annotate(4..5, bold()).unwrap();
+        text.annotate(7..8, bold()).unwrap();
+        text.annotate(2..8, link()).unwrap();
         text.delete(0..text.len());
         text.insert(0, "1");
         text.insert(1, "2");
@@ -327,7 +336,7 @@ mod annotation {
     fn annotate_link() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..3, link());
+        text.annotate(0..3, link()).unwrap();
         let ans = text.iter().collect::<Vec<_>>();
         assert_eq!(ans.len(), 2);
         assert_eq!(ans[0].len(), 3);
@@ -340,7 +349,7 @@ mod annotation {
     fn annotate_link_single_char() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(3..=3, link());
+        text.annotate(3..=3, link()).unwrap();
         let ans = text.iter().collect::<Vec<_>>();
         assert_eq!(ans.len(), 3);
         assert_eq!(ans[0].len(), 3);
@@ -354,7 +363,7 @@ mod annotation {
     fn annotate_whole_doc() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(.., expanding_style());
+        text.annotate(.., expanding_style()).unwrap();
         let ans = text.iter().collect::<Vec<_>>();
         assert_eq!(ans.len(), 1);
         assert_eq!(ans[0].len(), 9);
@@ -374,7 +383,7 @@ mod annotation {
     fn annotate_half_doc_start() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(..5, expanding_style());
+        text.annotate(..5, expanding_style()).unwrap();
         let ans = text.iter().collect::<Vec<_>>();
         assert_eq!(ans.len(), 2);
         assert_eq!(ans[0].len(), 5);
@@ -407,7 +416,7 @@ mod annotation {
     fn annotate_half_doc_end() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(5.., expanding_style());
+        text.annotate(5.., expanding_style()).unwrap();
         {
             let ans = text.iter().collect::<Vec<_>>();
             assert_eq!(ans.len(), 2);
@@ -440,12 +449,12 @@ mod annotation {
     fn test_simple_unbold() {
         let mut text = RichText::new(1);
         text.insert(0, "123");
-        text.annotate(0..1, bold());
+        text.annotate(0..1, bold()).unwrap();
         let ans = text.iter().collect::<Vec<_>>();
         assert_eq!(ans.len(), 2);
         assert_eq!(ans[0].attributes.len(), 1);
 
-        text.annotate(0..1, unbold());
+        text.annotate(0..1, unbold()).unwrap();
         let ans = text.iter().collect::<Vec<_>>();
         assert_eq!(ans.len(), 1);
         assert_eq!(ans[0].attributes.len(), 0);
@@ -456,8 +465,8 @@ mod annotation {
     fn test_unbold() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..5, bold());
-        text.annotate(3..5, unbold());
+        text.annotate(0..5, bold()).unwrap();
+        text.annotate(3..5, unbold()).unwrap();
         {
             let ans = text.iter().collect::<Vec<_>>();
             assert_eq!(ans.len(), 2);
@@ -481,8 +490,8 @@ mod annotation {
     fn test_unlink() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..5, link());
-        text.annotate(3..5, unlink());
+        text.annotate(0..5, link()).unwrap();
+        text.annotate(3..5, unlink()).unwrap();
         {
             let ans = text.iter().collect::<Vec<_>>();
             assert_eq!(ans.len(), 2);
@@ -506,8 +515,8 @@ mod annotation {
     fn expand() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..5, link());
-        text.annotate(0..5, bold());
+        text.annotate(0..5, link()).unwrap();
+        text.annotate(0..5, bold()).unwrap();
         {
             let ans = text.get_spans();
             assert_eq!(ans.len(), 2);
@@ -533,8 +542,8 @@ mod annotation {
     fn shrink() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..5, link());
-        text.annotate(0..5, bold());
+        text.annotate(0..5, link()).unwrap();
+        text.annotate(0..5, bold()).unwrap();
         text.delete(3..7);
         {
             let ans = text.get_spans();
@@ -551,7 +560,7 @@ mod annotation {
     fn insert_before_tombstone_bold() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..5, bold());
+        text.annotate(0..5, bold()).unwrap();
         text.delete(4..6);
         text.insert(4, "k");
         let spans = text.get_spans();
@@ -564,7 +573,7 @@ mod annotation {
     fn insert_after_tombstone_link() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..5, link());
+        text.annotate(0..5, link()).unwrap();
         text.delete(4..6);
         text.insert(4, "k");
         let spans = text.get_spans();
@@ -579,9 +588,9 @@ mod annotation {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
         // end anchor attached to `5`
-        text.annotate(0..5, link());
+        text.annotate(0..5, link()).unwrap();
         // end anchor attached to `6`
-        text.annotate(0..5, bold());
+        text.annotate(0..5, bold()).unwrap();
         // delete `5` and `6`
         text.delete(4..6);
         text.insert(4, "k");
@@ -602,7 +611,7 @@ mod annotation {
     fn apply_remote_annotation() {
         let mut text = RichText::new(1);
         text.insert(0, "123456789");
-        text.annotate(0..5, link());
+        text.annotate(0..5, link()).unwrap();
         let mut b = RichText::new(2);
         b.merge(&text);
         assert_eq!(b.get_spans(), text.get_spans());
@@ -615,7 +624,8 @@ mod annotation {
         text.annotate(
             0..5,
             Style::new_bold_like("test".into(), serde_json::Value::Number(18.into())),
-        );
+        )
+        .unwrap();
         {
             let spans = text.get_spans();
             let v = spans[0].attributes.get(&"test".into()).unwrap();
@@ -719,172 +729,3653 @@ mod get_line {
     fn get_line() {
         let mut text = RichText::new(1);
         text.insert(0, "Hello\nWorld\n");
-        assert_eq!(&text.get_line(0)[0].insert, "Hello\n");
-        assert_eq!(&text.get_line(1)[0].insert, "World\n");
-        assert_eq!(&text.get_line(2)[0].insert, "");
+        assert_eq!(&text.get_line(0).spans[0].insert, "Hello\n");
+        assert_eq!(&text.get_line(1).spans[0].insert, "World\n");
+        assert_eq!(&text.get_line(2).spans[0].insert, "");
         text.insert(0, "\n");
-        assert_eq!(&text.get_line(0)[0].insert, "\n");
-        assert_eq!(&text.get_line(1)[0].insert, "Hello\n");
-        assert_eq!(&text.get_line(2)[0].insert, "World\n");
-        assert_eq!(&text.get_line(3)[0].insert, "");
+        assert_eq!(&text.get_line(0).spans[0].insert, "\n");
+        assert_eq!(&text.get_line(1).spans[0].insert, "Hello\n");
+        assert_eq!(&text.get_line(2).spans[0].insert, "World\n");
+        assert_eq!(&text.get_line(3).spans[0].insert, "");
         text.insert(0, "xxx");
-        assert_eq!(&text.get_line(0)[0].insert, "xxx\n");
-        assert_eq!(&text.get_line(1)[0].insert, "Hello\n");
-        assert_eq!(&text.get_line(2)[0].insert, "World\n");
-        assert_eq!(&text.get_line(3)[0].insert, "");
+        assert_eq!(&text.get_line(0).spans[0].insert, "xxx\n");
+        assert_eq!(&text.get_line(1).spans[0].insert, "Hello\n");
+        assert_eq!(&text.get_line(2).spans[0].insert, "World\n");
+        assert_eq!(&text.get_line(3).spans[0].insert, "");
     }
 
     #[test]
     fn utf16() {
         let mut text = RichText::new(1);
         text.insert(0, "你好，\nWorld\n");
-        assert_eq!(&text.get_line(0)[0].insert, "你好，\n");
-        assert_eq!(&text.get_line(1)[0].insert, "World\n");
-        assert_eq!(&text.get_line(2)[0].insert, "");
+        assert_eq!(&text.get_line(0).spans[0].insert, "你好，\n");
+        assert_eq!(&text.get_line(1).spans[0].insert, "World\n");
+        assert_eq!(&text.get_line(2).spans[0].insert, "");
     }
 }
 
-mod delta {
-    use std::{
-        rc::Rc,
-        sync::atomic::{self, AtomicBool},
-    };
-
+mod iter_range {
     use fxhash::FxHashMap;
-    use serde_json::Value;
 
-    use crate::{
-        rich_text::{DeltaItem, IndexType},
-        RichText, Style,
-    };
+    use super::*;
+
+    fn char_boundaries(s: &str, index_type: IndexType) -> Vec<usize> {
+        let mut ans = vec![0];
+        let mut index = 0;
+        for c in s.chars() {
+            index += match index_type {
+                IndexType::Utf8 => c.len_utf8(),
+                IndexType::Utf16 => c.len_utf16(),
+                IndexType::Unicode => 1,
+            };
+            ans.push(index);
+        }
+        ans
+    }
+
+    /// expand spans into chars with their attributes, so that the result
+    /// doesn't depend on how the spans are merged
+    fn flatten(spans: &[Span]) -> Vec<(char, FxHashMap<InternalString, serde_json::Value>)> {
+        spans
+            .iter()
+            .flat_map(|span| span.insert.chars().map(|c| (c, span.attributes.clone())))
+            .collect()
+    }
 
     #[test]
-    fn append_newline_if_no_long_enough() {
+    fn iter_range() {
         let mut text = RichText::new(1);
-        text.insert(0, "测试123");
-        let mut attributes: FxHashMap<_, _> = Default::default();
-        attributes.insert("header".into(), Value::Bool(true));
-        text.apply_delta(
-            vec![
-                DeltaItem::retain(5),
-                DeltaItem::retain_with_attributes(1, attributes),
-            ]
-            .into_iter(),
-            crate::rich_text::IndexType::Utf16,
-        );
-        let spans = text.get_spans();
-        assert_eq!(spans[0].len(), 9);
-        assert_eq!(&spans[1].insert, "\n");
+        text.insert(0, "123456789");
+        text.annotate(2..5, bold()).unwrap();
+        let spans: Vec<_> = text.iter_range(1..4, IndexType::Utf8).collect();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].as_str(), "2");
+        assert!(spans[0].attributes.is_empty());
+        assert_eq!(spans[1].as_str(), "34");
+        assert!(spans[1].attributes.contains_key(&"bold".into()));
     }
 
     #[test]
-    fn apply_insert_should_remove_attributes_that_dont_exist() {
+    fn should_not_yield_empty_span_at_the_end() {
         let mut text = RichText::new(1);
-        text.insert(0, "测试123");
-        text.annotate_utf16(0..2, Style::new_bold_like("a".into(), Value::Bool(true)));
-        text.apply_delta(
-            vec![
-                DeltaItem::retain(1),
-                DeltaItem::insert("k".into(), IndexType::Utf16),
-            ]
-            .into_iter(),
-            IndexType::Utf16,
-        );
+        text.insert(0, "123456789");
+        text.annotate(2..5, bold()).unwrap();
+        let spans: Vec<_> = text.iter_range(0..2, IndexType::Utf8).collect();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].as_str(), "12");
+        assert_eq!(text.iter_range(3..3, IndexType::Utf8).count(), 0);
+    }
 
-        let spans = text.get_spans();
-        // &spans = [
-        //     Span {
-        //         insert: "测",
-        //         attributes: {
-        //             Atom('a' type=inline): Bool(true),
-        //         },
-        //     },
-        //     Span {
-        //         insert: "k",
-        //         attributes: {},
-        //     },
-        //     Span {
-        //         insert: "试",
-        //         attributes: {
-        //             Atom('a' type=inline): Bool(true),
-        //         },
-        //     },
-        //     Span {
-        //         insert: "123",
-        //         attributes: {},
-        //     },
-        // ]
-        assert_eq!(spans.len(), 4);
-        assert_eq!(spans[0].len(), 3);
-        assert!(!spans[0].attributes.is_empty());
-        assert_eq!(spans[1].len(), 1);
+    #[test]
+    fn should_include_styles_from_doc_start() {
+        let mut text = RichText::new(1);
+        text.insert(0, "123456789");
+        text.annotate(.., expanding_style()).unwrap();
+        let spans: Vec<_> = text.iter_range(3..5, IndexType::Utf8).collect();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].as_str(), "45");
+        assert!(spans[0].attributes.contains_key(&"expand".into()));
+
+        text.insert(0, "abc");
+        text.annotate(..4, link()).unwrap();
+        text.annotate(..2, unlink()).unwrap();
+        let spans: Vec<_> = text.iter_range(1..4, IndexType::Utf8).collect();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].as_str(), "b");
+        assert!(!spans[0].attributes.contains_key(&"link".into()));
+        assert_eq!(spans[1].as_str(), "c1");
+        assert!(spans[1].attributes.contains_key(&"link".into()));
+    }
+
+    #[test]
+    fn utf16() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你好😀world");
+        text.annotate_utf16(1..4, bold()).unwrap();
+        let spans: Vec<_> = text.iter_range(1..5, IndexType::Utf16).collect();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].as_str(), "好😀");
+        assert!(spans[0].attributes.contains_key(&"bold".into()));
+        assert_eq!(spans[1].as_str(), "w");
         assert!(spans[1].attributes.is_empty());
-        assert_eq!(spans[2].len(), 3);
-        assert!(!spans[2].attributes.is_empty());
-        assert_eq!(spans[3].len(), 3);
-        assert!(spans[3].attributes.is_empty());
     }
 
     #[test]
-    fn delta_event_insert_should_contain_all_attributes_simple() {
+    fn iter_range_matches_slice() {
+        let words = ["a", "bc", "你好", "😀", "\n", "xyz"];
+        let styles = [bold(), unbold(), link(), unlink(), expanding_style()];
+        arbtest::arbtest(|u| {
+            let mut text = RichText::new(1);
+            for _ in 0..u.int_in_range(1..=30)? {
+                let boundaries = char_boundaries(&text.to_string(), IndexType::Utf8);
+                let pos = *u.choose(&boundaries)?;
+                let end = *u.choose(&boundaries)?;
+                match u.int_in_range(0..=3)? {
+                    0 | 1 => text.insert(pos, u.choose(&words)?),
+                    2 => {
+                        if end > pos {
+                            text.delete(pos..end);
+                        }
+                    }
+                    _ => {
+                        if end > pos {
+                            text.annotate(pos..end, u.choose(&styles)?.clone()).unwrap();
+                        }
+                    }
+                }
+            }
+
+            let s = text.to_string();
+            let chars = flatten(&text.get_spans());
+            for index_type in [IndexType::Utf8, IndexType::Utf16, IndexType::Unicode] {
+                let boundaries = char_boundaries(&s, index_type);
+                let a = u.int_in_range(0..=boundaries.len() - 1)?;
+                let b = u.int_in_range(0..=boundaries.len() - 1)?;
+                let (a, b) = (a.min(b), a.max(b));
+                let range = boundaries[a]..boundaries[b];
+                let spans: Vec<Span> = text.iter_range(range.clone(), index_type).collect();
+                assert_eq!(
+                    spans.iter().map(|x| x.as_str()).collect::<String>(),
+                    text.slice_str(range, index_type)
+                );
+                assert_eq!(flatten(&spans), chars[a..b].to_vec());
+            }
+
+            Ok(())
+        });
+    }
+}
+
+mod sticky_index {
+    use super::*;
+
+    #[test]
+    fn should_follow_remote_edits() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let index = a.get_sticky_index(6, AnchorType::Before, IndexType::Utf8);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        b.insert(0, "abc");
+        a.merge(&b);
+        assert_eq!(a.to_string(), "abchello world");
+        assert_eq!(a.resolve_sticky_index(&index, IndexType::Utf8), Some(9));
+        assert_eq!(b.resolve_sticky_index(&index, IndexType::Utf8), Some(9));
+    }
+
+    #[test]
+    fn insert_at_the_position() {
         let mut text = RichText::new(1);
-        text.set_event_index_type(IndexType::Utf16);
-        text.insert(0, "1");
-        text.annotate(0..1, Style::new_bold_like("a".into(), Value::Bool(true)));
-        let invoked = Rc::new(AtomicBool::new(false));
-        let invoked_bk = Rc::clone(&invoked);
-        text.observe(Box::new(move |event| {
-            assert!(event.is_local);
-            assert_eq!(event.index_type, IndexType::Utf16);
-            assert_eq!(event.ops.len(), 2);
-            assert_eq!(
-                event.ops,
-                vec![
-                    DeltaItem::retain(1),
-                    DeltaItem::insert_with_attributes(
-                        "k".into(),
-                        IndexType::Utf16,
-                        vec![("a".into(), Value::Bool(true)),].into_iter().collect()
-                    ),
-                ]
-            );
-            invoked.store(true, atomic::Ordering::SeqCst);
-        }));
-        text.insert(1, "k");
-        let v = invoked_bk.load(atomic::Ordering::SeqCst);
-        assert!(v);
+        text.insert(0, "ab");
+        let before = text.get_sticky_index(1, AnchorType::Before, IndexType::Utf8);
+        let after = text.get_sticky_index(1, AnchorType::After, IndexType::Utf8);
+        text.insert(1, "xx");
+        assert_eq!(text.resolve_sticky_index(&before, IndexType::Utf8), Some(3));
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf8), Some(1));
     }
 
     #[test]
-    fn delta_event_insert_should_contain_all_attributes() {
+    fn deleted_char() {
         let mut text = RichText::new(1);
-        text.insert(0, "12345");
-        text.annotate(1..2, Style::new_bold_like("a".into(), Value::Bool(true)));
-        text.annotate(0..4, Style::new_bold_like("b".into(), Value::Bool(true)));
-        let invoked = Rc::new(AtomicBool::new(false));
-        let invoked_bk = Rc::clone(&invoked);
-        text.observe(Box::new(move |event| {
-            assert!(event.is_local);
-            assert_eq!(event.index_type, IndexType::Utf8);
-            assert_eq!(event.ops.len(), 2);
-            assert_eq!(
-                event.ops,
-                vec![
-                    DeltaItem::retain(2),
-                    DeltaItem::insert_with_attributes(
-                        "k".into(),
-                        IndexType::Utf8,
-                        vec![
-                            ("a".into(), Value::Bool(true)),
-                            ("b".into(), Value::Bool(true)),
-                        ]
-                        .into_iter()
-                        .collect()
-                    ),
-                ]
-            );
-            invoked.store(true, atomic::Ordering::SeqCst);
-        }));
+        text.insert(0, "hello world");
+        let before = text.get_sticky_index(6, AnchorType::Before, IndexType::Utf8);
+        let after = text.get_sticky_index(8, AnchorType::After, IndexType::Utf8);
+        text.delete(4..8);
+        assert_eq!(text.to_string(), "hellrld");
+        assert_eq!(text.resolve_sticky_index(&before, IndexType::Utf8), Some(4));
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf8), Some(4));
+        text.insert(0, "a");
+        assert_eq!(text.resolve_sticky_index(&before, IndexType::Utf8), Some(5));
+    }
+
+    #[test]
+    fn utf16() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你好world");
+        let index = text.get_sticky_index(2, AnchorType::Before, IndexType::Utf16);
+        let after = text.get_sticky_index(2, AnchorType::After, IndexType::Utf16);
+        assert_eq!(text.resolve_sticky_index(&index, IndexType::Utf8), Some(6));
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf8), Some(6));
+        text.insert_utf16(1, "呀");
+        assert_eq!(text.resolve_sticky_index(&index, IndexType::Utf16), Some(3));
+        assert_eq!(text.resolve_sticky_index(&index, IndexType::Utf8), Some(9));
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf16), Some(3));
+    }
+
+    #[test]
+    fn after_multibyte_char() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a你b");
+        let after = text.get_sticky_index(4, AnchorType::After, IndexType::Utf8);
+        text.insert(4, "xx");
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf8), Some(4));
+        text.insert(0, "你");
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf8), Some(7));
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf16), Some(3));
+    }
+
+    #[test]
+    fn after_surrogate_pair() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀b😀");
+        let after = text.get_sticky_index(3, AnchorType::After, IndexType::Utf16);
+        let end = text.get_sticky_index(6, AnchorType::After, IndexType::Utf16);
+        text.insert_utf16(3, "x");
+        text.insert_utf16(7, "c");
+        assert_eq!(text.to_string(), "a😀xb😀c");
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf16), Some(3));
+        assert_eq!(text.resolve_sticky_index(&end, IndexType::Utf16), Some(7));
+        assert_eq!(text.resolve_sticky_index(&end, IndexType::Utf8), Some(11));
+    }
+
+    #[test]
+    fn doc_boundaries() {
+        let mut text = RichText::new(1);
+        let end = text.get_sticky_index(0, AnchorType::Before, IndexType::Utf8);
+        let start = text.get_sticky_index(0, AnchorType::After, IndexType::Utf8);
+        text.insert(0, "abc");
+        assert_eq!(text.resolve_sticky_index(&end, IndexType::Utf8), Some(3));
+        assert_eq!(text.resolve_sticky_index(&start, IndexType::Utf8), Some(0));
+    }
+
+    #[test]
+    fn unknown_char() {
+        let mut a = RichText::new(1);
+        a.insert(0, "abc");
+        let index = a.get_sticky_index(1, AnchorType::Before, IndexType::Utf8);
+        let b = RichText::new(2);
+        assert_eq!(b.resolve_sticky_index(&index, IndexType::Utf8), None);
+    }
+
+    #[test]
+    fn serde() {
+        let mut text = RichText::new(1);
+        text.insert(0, "abc");
+        let index = text.get_sticky_index(1, AnchorType::After, IndexType::Utf8);
+        let json = serde_json::to_string(&index).unwrap();
+        let decoded: StickyIndex = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, index);
+        assert_eq!(
+            text.resolve_sticky_index(&decoded, IndexType::Utf8),
+            Some(1)
+        );
+    }
+}
+
+mod snapshot {
+    use super::*;
+
+    fn assert_same(a: &RichText, b: &RichText) {
+        assert_eq!(a.to_string(), b.to_string());
+        assert_eq!(a.get_spans(), b.get_spans());
+        assert_eq!(a.version().vv, b.version().vv);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(0..5, bold()).unwrap();
+        text.annotate(.., expanding_style()).unwrap();
+        text.delete(2..4);
+        text.insert(3, "你好\n");
+        text.annotate(6..9, link()).unwrap();
+        let other = RichText::from_snapshot(2, &text.export_snapshot()).unwrap();
+        other.check();
+        assert_same(&text, &other);
+        assert_eq!(text.len_utf16(), other.len_utf16());
+        assert_eq!(text.lines(), other.lines());
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let mut text = RichText::new(1);
+        for i in 0..200 {
+            text.insert(text.len(), &i.to_string());
+            text.insert(text.len(), " abc");
+        }
+        text.annotate(10..1000, bold()).unwrap();
+        text.delete(100..200);
+        let other = RichText::from_snapshot(2, &text.export_snapshot()).unwrap();
+        assert_same(&text, &other);
+    }
+
+    #[test]
+    fn edit_after_loading() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        a.annotate(0..5, bold()).unwrap();
+        let mut b = RichText::from_snapshot(2, &a.export_snapshot()).unwrap();
+        // bold expands after
+        b.insert(5, "!");
+        b.insert(0, "abc");
+        a.insert(11, "?");
+        a.merge(&b);
+        b.merge(&a);
+        assert_same(&a, &b);
+        assert_eq!(a.to_string(), "abchello! world?");
+        assert_eq!(a.get_spans()[0].insert, "abc");
+        assert_eq!(a.get_spans()[1].insert, "hello!");
+    }
+
+    #[test]
+    fn import_updates_after_snapshot() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let mut b = RichText::from_snapshot(2, &a.export_snapshot()).unwrap();
+        a.delete(0..6);
+        a.annotate(0..5, bold()).unwrap();
+        a.insert(5, "!");
+        b.import(&a.export(&b.version()).unwrap()).unwrap();
+        assert_same(&a, &b);
+        assert_eq!(b.to_string(), "world!");
+    }
+
+    #[test]
+    fn local_counter_continues() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let mut b = RichText::from_snapshot(1, &a.export_snapshot()).unwrap();
+        b.insert(5, " world");
+        assert_eq!(b.version().vv.get(&1), Some(&11));
+        a.merge(&b);
+        assert_same(&a, &b);
+        assert_eq!(a.to_string(), "hello world");
+    }
+
+    #[test]
+    fn snapshot_of_loaded_doc() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        a.annotate(1..3, bold()).unwrap();
+        let mut b = RichText::from_snapshot(2, &a.export_snapshot()).unwrap();
+        b.insert(0, "a");
+        let c = RichText::from_snapshot(3, &b.export_snapshot()).unwrap();
+        assert_same(&b, &c);
+    }
+
+    #[test]
+    fn malformed_snapshot() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello 你好");
+        text.annotate(1..3, bold()).unwrap();
+        text.delete(0..1);
+        let snapshot = text.export_snapshot();
+        for len in 0..snapshot.len() {
+            assert!(RichText::from_snapshot(2, &snapshot[..len]).is_err());
+        }
+
+        assert!(RichText::from_snapshot(2, &[0xff; 64]).is_err());
+    }
+
+    #[test]
+    fn corrupted_snapshot() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let mut snapshot = text.export_snapshot();
+        let last = snapshot.len() - 1;
+        snapshot[last] ^= 1;
+        assert!(matches!(
+            RichText::from_snapshot(2, &snapshot),
+            Err(Error::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn snapshot_incompatible_version() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let mut snapshot = text.export_snapshot();
+        assert_eq!(&snapshot[..4], b"PTSN");
+        // the snapshots without the header are format version 0
+        assert!(matches!(
+            RichText::from_snapshot(2, &snapshot[crate::rich_text::encoding::HEADER_LEN..]),
+            Err(Error::IncompatibleVersion(0))
+        ));
+        snapshot[4] += 1;
+        assert!(matches!(
+            RichText::from_snapshot(2, &snapshot),
+            Err(Error::IncompatibleVersion(2))
+        ));
+        assert!(matches!(
+            RichText::from_snapshot(2, &snapshot[..6]),
+            Err(Error::BadHeader)
+        ));
+    }
+}
+
+mod compact {
+    use super::*;
+
+    fn assert_same(a: &RichText, b: &RichText) {
+        assert_eq!(a.to_string(), b.to_string());
+        assert_eq!(a.get_spans(), b.get_spans());
+    }
+
+    fn tombstone_len(text: &RichText) -> usize {
+        text.content
+            .iter()
+            .filter(|x| x.is_dead())
+            .map(|x| x.atom_len())
+            .sum()
+    }
+
+    #[test]
+    fn remove_tombstones_and_ops() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.delete(0..6);
+        assert_eq!(tombstone_len(&text), 6);
+        text.compact(&text.version());
+        assert_eq!(tombstone_len(&text), 0);
+        assert_eq!(text.store.op_len(), 0);
+        assert_eq!(text.to_string(), "world");
+        assert_eq!(text.version().vv.get(&1), Some(&17));
+        text.insert(5, "!");
+        assert_eq!(text.to_string(), "world!");
+        text.check();
+    }
+
+    #[test]
+    fn keep_the_tombstones_after_the_version() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let vv = text.version();
+        text.delete(0..6);
+        text.compact(&vv);
+        assert_eq!(tombstone_len(&text), 6);
+        assert_eq!(text.store.op_len(), 1);
+    }
+
+    #[test]
+    fn merge_with_peer_that_has_not_compacted() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "hello world");
+        b.merge(&a);
+        b.delete(0..6);
+        b.delete(1..3);
+        a.merge(&b);
+        a.compact(&b.version());
+        assert_eq!(tombstone_len(&a), 0);
+        // insert around the removed tombstones
+        b.insert(0, "new ");
+        b.insert(5, "o");
+        b.insert(b.len(), "!");
+        a.insert(1, "1");
+        a.merge(&b);
+        b.merge(&a);
+        assert_same(&a, &b);
+        assert_eq!(a.to_string(), "new w1old!");
+    }
+
+    #[test]
+    fn concurrent_deletion_of_removed_chars() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "abc");
+        b.merge(&a);
+        a.delete(1..2);
+        b.delete(1..2);
+        b.merge(&a);
+        a.compact(&a.version());
+        assert_eq!(tombstone_len(&a), 0);
+        a.merge(&b);
+        b.merge(&a);
+        assert_same(&a, &b);
+        assert_eq!(a.to_string(), "ac");
+    }
+
+    #[test]
+    fn keep_anchored_tombstones() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "hello world");
+        a.annotate(0..5, bold()).unwrap();
+        // "h" carries the start anchor of bold
+        a.delete(0..1);
+        a.delete(5..6);
+        b.merge(&a);
+        a.compact(&a.version());
+        assert_eq!(tombstone_len(&a), 1);
+        b.insert(0, "H");
+        a.merge(&b);
+        assert_same(&a, &b);
+        assert_eq!(a.to_string(), "Hello orld");
+    }
+
+    #[test]
+    fn sticky_index_of_removed_char() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let before = text.get_sticky_index(2, AnchorType::Before, IndexType::Utf8);
+        let after = text.get_sticky_index(3, AnchorType::After, IndexType::Utf8);
+        text.delete(1..4);
+        text.compact(&text.version());
+        assert_eq!(tombstone_len(&text), 0);
+        assert_eq!(text.resolve_sticky_index(&before, IndexType::Utf8), Some(1));
+        assert_eq!(text.resolve_sticky_index(&after, IndexType::Utf8), Some(1));
+    }
+
+    #[test]
+    fn undo_after_compaction() {
+        let mut text = RichText::new(1);
+        text.enable_undo();
+        text.insert(0, "hello world");
+        text.undo_manager_mut().unwrap().clear();
+        text.delete(0..6);
+        text.compact(&text.version());
+        // the tombstones are needed to undo the deletion
+        assert_eq!(tombstone_len(&text), 6);
+        text.undo();
+        assert_eq!(text.to_string(), "hello world");
+    }
+
+    #[test]
+    fn snapshot_of_compacted_doc() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "hello world");
+        b.merge(&a);
+        b.delete(0..6);
+        a.merge(&b);
+        a.compact(&a.version());
+        let mut c = RichText::from_snapshot(3, &a.export_snapshot()).unwrap();
+        b.insert(0, "new ");
+        c.merge(&b);
+        a.merge(&b);
+        assert_same(&a, &c);
+        assert_eq!(c.to_string(), "new world");
+    }
+
+    #[test]
+    fn export_below_compacted_history() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let b = a.fork(2);
+        a.insert(5, " world");
+        a.compact(&a.version());
+        assert_eq!(a.trimmed_version().vv.get(&1), Some(&11));
+        assert!(matches!(
+            a.export(&b.version()),
+            Err(Error::HistoryTrimmed(id)) if id == OpID::new(1, 5)
+        ));
+        assert!(matches!(
+            a.export_stream(&Default::default(), &mut Vec::new()),
+            Err(Error::HistoryTrimmed(_))
+        ));
+        assert!(!a.export(&a.version()).unwrap().is_empty());
+    }
+}
+
+mod checkout {
+    use super::*;
+
+    #[test]
+    fn checkout_previous_version() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let v1 = text.version();
+        let spans_v1 = text.get_spans();
+        text.annotate(0..5, bold()).unwrap();
+        let v2 = text.version();
+        let spans_v2 = text.get_spans();
+        text.delete(0..6);
+        text.insert(0, "new ");
+        assert_eq!(text.checkout(&v1).get_spans(), spans_v1);
+        assert_eq!(text.checkout(&v2).get_spans(), spans_v2);
+        assert_eq!(text.checkout(&text.version()).get_spans(), text.get_spans());
+        assert!(text.checkout(&Default::default()).is_empty());
+    }
+
+    #[test]
+    fn checkout_version_of_peer() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "123");
+        b.merge(&a);
+        b.insert(3, "b");
+        a.insert(0, "a");
+        let b_version = b.version();
+        a.merge(&b);
+        assert_eq!(a.to_string(), "a123b");
+        assert_eq!(a.checkout(&b_version).to_string(), "123b");
+    }
+
+    #[test]
+    fn diff_between_versions() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let v1 = text.version();
+        text.delete(0..6);
+        text.insert(0, "new ");
+        text.insert(text.len(), "!");
+        let v2 = text.version();
+        let mut doc = text.checkout(&v1);
+        doc.apply_delta(text.diff(&v1, &v2).into_iter(), IndexType::Utf8)
+            .unwrap();
+        assert_eq!(doc.to_string(), "new world!");
+        assert!(text.diff(&v2, &v2).is_empty());
+    }
+
+    #[test]
+    fn diff_of_style() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let v1 = text.version();
+        text.annotate(
+            6..11,
+            Style {
+                expand: Expand::None,
+                behavior: Behavior::Merge,
+                type_: "link".into(),
+                value: Value::String("url".into()),
+            },
+        )
+        .unwrap();
+        let mut doc = text.checkout(&v1);
+        doc.apply_delta(text.diff(&v1, &text.version()).into_iter(), IndexType::Utf8)
+            .unwrap();
+        assert_eq!(doc.get_spans(), text.get_spans());
+        assert_eq!(doc.get_spans()[1].insert, "world");
+    }
+
+    #[test]
+    #[should_panic]
+    fn checkout_trimmed_history() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.compact(&text.version());
+        text.checkout(&Default::default());
+    }
+}
+
+mod import {
+    use super::*;
+    use crate::rich_text::{
+        encoding::{read_frame, OpContentType},
+        op::DeleteOp,
+    };
+
+    #[test]
+    fn import_truncated_data() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        a.annotate(0..5, bold()).unwrap();
+        let data = a.export(&Default::default()).unwrap();
+        let mut b = RichText::new(2);
+        b.insert(0, "b");
+        assert!(b.import(&data[..data.len() / 2]).is_err());
+        assert_eq!(b.to_string(), "b");
+        assert_eq!(b.version().vv.len(), 1);
+    }
+
+    #[test]
+    fn import_garbage() {
+        let mut b = RichText::new(2);
+        assert!(b.import(b"not an update").is_err());
+        assert!(b.import(&[]).is_err());
+        assert!(b.is_empty());
+    }
+
+    #[test]
+    fn import_dangling_id() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        a.delete(0..1);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        // (1, 3) is the id of the deletion, not a char
+        let mut updates: FxHashMap<ClientID, Vec<Op>> = Default::default();
+        updates.insert(
+            2,
+            vec![Op {
+                id: OpID::new(2, 0),
+                lamport: 10,
+                content: OpContent::Del(DeleteOp {
+                    start: OpID::new(1, 3),
+                    len: 1,
+                }),
+            }],
+        );
+        assert!(matches!(
+            b.import(&encode(updates)),
+            Err(Error::DanglingOpID(id)) if id == OpID::new(1, 3)
+        ));
+        assert_eq!(b.to_string(), "23");
+    }
+
+    #[test]
+    fn import_pending_op_with_dangling_id() {
+        let mut a = RichText::new(1);
+        a.insert(0, "ab");
+        // (1, 2) is the id of the annotation, not a char
+        a.annotate(0..1, bold()).unwrap();
+        let mut updates: FxHashMap<ClientID, Vec<Op>> = Default::default();
+        updates.insert(
+            2,
+            vec![Op {
+                id: OpID::new(2, 0),
+                lamport: 10,
+                content: OpContent::Del(DeleteOp {
+                    start: OpID::new(1, 2),
+                    len: 1,
+                }),
+            }],
+        );
+        let mut b = RichText::new(3);
+        // (1, 2) is unknown yet, so the deletion is pending
+        b.import(&encode(updates)).unwrap();
+        assert!(b.has_pending());
+        assert!(matches!(
+            b.import(&a.export(&Default::default()).unwrap()),
+            Err(Error::DanglingOpID(id)) if id == OpID::new(1, 2)
+        ));
+        assert_eq!(b.to_string(), "ab");
+        assert_eq!(b.get_spans(), a.get_spans());
+        assert!(!b.has_pending());
+    }
+
+    #[test]
+    fn import_corrupted_blob() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let mut data = a.export(&Default::default()).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let mut b = RichText::new(2);
+        assert!(matches!(b.import(&data), Err(Error::ChecksumMismatch)));
+        assert!(b.is_empty());
+    }
+
+    #[test]
+    fn import_incompatible_version() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let mut data = a.export(&Default::default()).unwrap();
+        assert_eq!(&data[..4], b"PTXT");
+        data[4] += 1;
+        let mut b = RichText::new(2);
+        assert!(matches!(
+            b.import(&data),
+            Err(Error::IncompatibleVersion(2))
+        ));
+    }
+
+    #[test]
+    fn import_compressed_blob() {
+        let mut a = RichText::new(1);
+        a.insert(0, &"hello world ".repeat(200));
+        a.annotate(0..5, bold()).unwrap();
+        let data = a.export(&Default::default()).unwrap();
+        // the body is gzipped
+        assert_eq!(data[5], 1);
+        let mut b = RichText::new(2);
+        b.import(&data).unwrap();
+        assert_eq!(b.get_spans(), a.get_spans());
+    }
+
+    #[test]
+    fn unknown_op_type() {
+        assert_eq!(OpContentType::try_from(1).unwrap(), OpContentType::Delete);
+        assert_eq!(OpContentType::try_from(4).unwrap(), OpContentType::Embed);
+        assert!(matches!(
+            OpContentType::try_from(5),
+            Err(Error::UnknownOpType(5))
+        ));
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let mut a = RichText::new(1);
+        a.insert(0, &"你好 world ".repeat(20_000));
+        a.annotate(0..6, bold()).unwrap();
+        a.delete(6..9);
+        let mut stream = Vec::new();
+        a.export_stream(&Default::default(), &mut stream).unwrap();
+        let mut reader = stream.as_slice();
+        let mut frames = 0;
+        while read_frame(&mut reader).unwrap().is_some() {
+            frames += 1;
+        }
+        assert_eq!(frames, 4);
+
+        let mut b = RichText::new(2);
+        b.import_stream(stream.as_slice()).unwrap();
+        assert_eq!(b.get_spans(), a.get_spans());
+        let version = b.version();
+        a.insert(0, "new");
+        let mut stream = Vec::new();
+        a.export_stream(&version, &mut stream).unwrap();
+        b.import_stream(stream.as_slice()).unwrap();
+        assert_eq!(b.to_string(), a.to_string());
+    }
+
+    #[test]
+    fn import_truncated_stream() {
+        let mut a = RichText::new(1);
+        a.insert(0, &"a".repeat(100_000));
+        let mut stream = Vec::new();
+        a.export_stream(&Default::default(), &mut stream).unwrap();
+        let mut b = RichText::new(2);
+        assert!(matches!(
+            b.import_stream(&stream[..stream.len() - 1]),
+            Err(Error::Io(_))
+        ));
+        // the first frame is imported
+        assert_eq!(b.len(), 64 * 1024);
+    }
+
+    #[test]
+    fn merge_keystroke_updates() {
+        let mut a = RichText::new(1);
+        let mut updates = Vec::new();
+        for (i, c) in "hello world".chars().enumerate() {
+            let version = a.version();
+            a.insert(i, &c.to_string());
+            updates.push(a.export(&version).unwrap());
+        }
+        let version = a.version();
+        a.annotate(0..5, bold()).unwrap();
+        a.delete(5..6);
+        updates.push(a.export(&version).unwrap());
+        // overlaps the updates of the keystrokes
+        updates.push(a.export(&updates_version(&a, 5)).unwrap());
+
+        let mut updates: Vec<&[u8]> = updates.iter().map(|x| x.as_slice()).collect();
+        let len: usize = updates.iter().map(|x| x.len()).sum();
+        updates.reverse();
+        let merged = merge_updates(&updates).unwrap();
+        assert!(merged.len() < len);
+        let mut b = RichText::new(2);
+        b.import(&merged).unwrap();
+        assert_eq!(b.get_spans(), a.get_spans());
+        assert_eq!(b.version().vv, a.version().vv);
+
+        let last = updates.len() - 1;
+        assert!(matches!(
+            merge_updates(&[updates[last], updates[last - 2]]),
+            Err(Error::UpdateGap(id)) if id == OpID::new(1, 1)
+        ));
+        assert!(merge_updates(&[b"not an update"]).is_err());
+    }
+
+    fn updates_version(text: &RichText, counter: Counter) -> VersionVector {
+        let mut vv = VersionVector::default();
+        vv.vv.insert(text.id(), counter);
+        vv
+    }
+
+    #[test]
+    fn decode_version_vector() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let vv = a.version();
+        assert_eq!(VersionVector::decode(&vv.encode()).unwrap().vv, vv.vv);
+        assert!(VersionVector::decode(b"not a version").is_err());
+    }
+}
+
+mod pending {
+    use std::{
+        mem::take,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    #[test]
+    fn out_of_order_updates() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let first = a.export(&Default::default()).unwrap();
+        let v1 = a.version();
+        a.insert(1, "abc");
+        a.annotate(0..3, bold()).unwrap();
+        let second = a.export(&v1).unwrap();
+
+        let mut b = RichText::new(2);
+        b.import(&second).unwrap();
+        assert!(b.is_empty());
+        assert!(b.has_pending());
+        assert_eq!(b.missing_deps().vv, v1.vv);
+        b.import(&first).unwrap();
+        assert!(!b.has_pending());
+        assert!(b.missing_deps().vv.is_empty());
+        assert_eq!(b.get_spans(), a.get_spans());
+    }
+
+    #[test]
+    fn wait_for_other_clients() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let mut c = RichText::new(3);
+        c.merge(&a);
+        c.insert(1, "abc");
+        c.delete(0..1);
+
+        let mut b = RichText::new(2);
+        // only the ops of c, which refer to the chars of a
+        b.import(&c.export(&a.version()).unwrap()).unwrap();
+        assert!(b.is_empty());
+        // the chars c refers to
+        let missing = b.missing_deps();
+        assert_eq!(missing.vv.len(), 1);
+        assert_eq!(missing.vv[&1], 2);
+        b.import(&a.export(&Default::default()).unwrap()).unwrap();
+        assert_eq!(b.to_string(), "abc23");
+        assert!(!b.has_pending());
+    }
+
+    #[test]
+    fn emit_released_ops() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let first = a.export(&Default::default()).unwrap();
+        let v1 = a.version();
+        a.insert(3, "456");
+        let second = a.export(&v1).unwrap();
+
+        let mut b = RichText::new(2);
+        let delta = Arc::new(Mutex::new(Vec::new()));
+        let delta_clone = delta.clone();
+        b.observe(Box::new(move |event| {
+            let mut delta = delta_clone.lock().unwrap();
+            *delta = compose(take(&mut *delta), event.ops.clone());
+        }))
+        .detach();
+        b.import(&second).unwrap();
+        b.import(&first).unwrap();
+        let mut c = RichText::new(3);
+        let delta = take(&mut *delta.lock().unwrap());
+        c.apply_delta(delta.into_iter(), IndexType::Utf8).unwrap();
+        assert_eq!(c.to_string(), "123456");
+    }
+
+    #[test]
+    fn pending_ops_survive_seen_updates() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let first = a.export(&Default::default()).unwrap();
+        let v1 = a.version();
+        a.insert(0, "0");
+        let second = a.export(&v1).unwrap();
+
+        let mut b = RichText::new(2);
+        b.import(&second).unwrap();
+        b.import(&second).unwrap();
+        assert_eq!(b.missing_deps().vv, v1.vv);
+        b.import(&first).unwrap();
+        b.import(&first).unwrap();
+        assert_eq!(b.to_string(), "0123");
+    }
+}
+
+mod transaction {
+    use std::{
+        mem::take,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn record_events(text: &mut RichText) -> Arc<Mutex<Vec<Event>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        text.observe(Box::new(move |event| {
+            events_clone.lock().unwrap().push(event.clone());
+        }))
+        .detach();
+        events
+    }
+
+    #[test]
+    fn emit_one_event() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let events = record_events(&mut text);
+        let mut other = RichText::new(2);
+        other.insert(0, "hello world");
+        text.transact(|txn| {
+            txn.annotate(0..5, bold()).unwrap();
+            txn.delete(6..11);
+            txn.insert(6, "peritext");
+        });
+        let events = take(&mut *events.lock().unwrap());
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_local);
+        assert_eq!(events[0].origin, None);
+        other
+            .apply_delta(events[0].ops.iter().cloned(), IndexType::Utf8)
+            .unwrap();
+        assert_eq!(other.get_spans(), text.get_spans());
+    }
+
+    #[test]
+    fn attach_origin() {
+        let mut text = RichText::new(1);
+        let events = record_events(&mut text);
+        text.transact(|txn| {
+            txn.set_origin("paste");
+            txn.insert(0, "hello");
+        });
+        text.insert(0, "1");
+        let events = take(&mut *events.lock().unwrap());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].origin, Some("paste".into()));
+        assert_eq!(events[1].origin, None);
+    }
+
+    #[test]
+    fn nested_transactions() {
+        let mut text = RichText::new(1);
+        let events = record_events(&mut text);
+        let len = text.transact(|txn| {
+            txn.insert(0, "hello");
+            txn.transact(|txn| {
+                assert!(txn.in_transaction());
+                txn.insert(5, " world");
+            });
+            txn.len()
+        });
+        assert_eq!(len, 11);
+        assert!(!text.in_transaction());
+        let events = take(&mut *events.lock().unwrap());
+        assert_eq!(events.len(), 1);
+        let mut other = RichText::new(2);
+        other
+            .apply_delta(events[0].ops.iter().cloned(), IndexType::Utf8)
+            .unwrap();
+        assert_eq!(other.to_string(), "hello world");
+    }
+
+    #[test]
+    fn panic_in_transaction() {
+        let mut text = RichText::new(1);
+        text.enable_undo();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::ZERO);
+        let events = record_events(&mut text);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            text.transact(|txn| {
+                txn.insert(0, "a");
+                panic!("abort the transaction");
+            })
+        }));
+        assert!(result.is_err());
+        assert!(!text.in_transaction());
+        assert!(events.lock().unwrap().is_empty());
+
+        // the undo group is closed, so the next edits are undone one by one
+        text.insert(1, "b");
+        text.insert(2, "c");
+        text.undo();
+        assert_eq!(text.to_string(), "ab");
+        assert_eq!(events.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn no_event_without_edits() {
+        let mut text = RichText::new(1);
+        let events = record_events(&mut text);
+        text.transact(|_| {});
+        assert!(events.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn undo_transaction() {
+        let mut text = RichText::new(1);
+        text.enable_undo();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::ZERO);
+        text.insert(0, "hello");
+        text.transact(|txn| {
+            txn.insert(5, " world");
+            txn.annotate(0..5, bold()).unwrap();
+            txn.delete(0..1);
+        });
+        assert_eq!(text.to_string(), "ello world");
+        assert!(text.undo().is_some());
+        assert_eq!(text.to_string(), "hello");
+        assert!(text.get_spans()[0].attributes.is_empty());
+        assert!(text.undo().is_some());
+        assert!(text.is_empty());
+    }
+}
+
+mod subscription {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    };
+
+    use super::*;
+
+    fn counter() -> (Arc<AtomicUsize>, Listener) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_clone = count.clone();
+        (
+            count,
+            Box::new(move |_| {
+                count_clone.fetch_add(1, SeqCst);
+            }),
+        )
+    }
+
+    #[test]
+    fn unsubscribe_on_drop() {
+        let mut text = RichText::new(1);
+        let (count, listener) = counter();
+        let subscription = text.observe(listener);
+        text.insert(0, "1");
+        assert_eq!(count.load(SeqCst), 1);
+        drop(subscription);
+        text.insert(0, "2");
+        assert_eq!(count.load(SeqCst), 1);
+        assert!(!text.has_listener());
+    }
+
+    #[test]
+    fn detach() {
+        let mut text = RichText::new(1);
+        let (count, listener) = counter();
+        text.observe(listener).detach();
+        text.insert(0, "1");
+        text.insert(0, "2");
+        assert_eq!(count.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn filter_remote_events() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        let (count, listener) = counter();
+        let _subscription = a.observe_filtered(EventFilter::remote(), listener);
+        a.insert(0, "1");
+        b.insert(0, "2");
+        a.merge(&b);
+        assert_eq!(count.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn filter_ann_type() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let (count, listener) = counter();
+        let filter = EventFilter::default().with_ann_type("bold");
+        let _subscription = text.observe_filtered(filter, listener);
+        text.annotate(0..5, link()).unwrap();
+        assert_eq!(count.load(SeqCst), 0);
+        text.annotate(0..5, bold()).unwrap();
+        assert_eq!(count.load(SeqCst), 1);
+        text.insert(1, "1");
+        assert_eq!(count.load(SeqCst), 2);
+        text.insert(9, "1");
+        assert_eq!(count.load(SeqCst), 2);
+        text.annotate(0..3, unbold()).unwrap();
+        assert_eq!(count.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn observe_channel() {
+        let mut text = RichText::new(1);
+        let receiver = text.observe_channel();
+        text.insert(0, "hello");
+        text.delete(0..1);
+        let handle = std::thread::spawn(move || receiver.try_iter().collect::<Vec<_>>());
+        let events = handle.join().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].ops, vec![DeltaItem::delete(1)]);
+        // the receiver is dropped
+        text.insert(0, "1");
+        assert!(!text.has_listener());
+    }
+}
+
+mod ann_query {
+    use std::ops::Range;
+
+    use super::*;
+
+    fn comment(value: &str) -> Style {
+        Style::new_comment_like("comment".into(), Value::String(value.into()))
+    }
+
+    #[test]
+    fn list_annotations() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(0..5, bold()).unwrap();
+        text.annotate(6..11, comment("a")).unwrap();
+        text.insert(0, "12");
+        let anns = text.annotations();
+        assert_eq!(anns.len(), 2);
+        assert_eq!(&*anns[0].1.type_, "bold");
+        assert_eq!(anns[0].2, 2..7);
+        assert_eq!(&*anns[1].1.type_, "comment");
+        assert_eq!(anns[1].1.value, Value::String("a".into()));
+        assert_eq!(anns[1].2, 8..13);
+        assert_eq!(anns[1].0, anns[1].1.id);
+        assert_eq!(text.annotation_range(anns[1].0), Some(8..13));
+    }
+
+    #[test]
+    fn range_follows_edits() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(6..11, comment("a")).unwrap();
+        let id = text.annotations()[0].0;
+        text.delete(7..9);
+        assert_eq!(text.annotation_range(id), Some(6..9));
+        // comments don't expand
+        text.insert(9, "!");
+        text.insert(6, "_");
+        assert_eq!(text.annotation_range(id), Some(7..10));
+        text.delete(5..);
+        assert_eq!(text.annotation_range(id), Some(5..5));
+    }
+
+    #[test]
+    fn range_at_doc_edges() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello");
+        text.annotate(.., expanding_style()).unwrap();
+        text.annotate(1..5, bold()).unwrap();
+        text.insert(5, " world");
+        text.insert(0, "0");
+        let ranges: Vec<_> = text.annotations().into_iter().map(|x| x.2).collect();
+        assert_eq!(ranges, vec![0..12, 2..12]);
+    }
+
+    #[test]
+    fn annotations_in_range() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你好 world");
+        text.annotate(7..12, comment("a")).unwrap();
+        text.annotate(0..3, bold()).unwrap();
+        let find = |range: Range<usize>, index_type| -> Vec<Range<usize>> {
+            text.annotations_in_range(range, index_type)
+                .into_iter()
+                .map(|x| x.2)
+                .collect()
+        };
+        assert_eq!(find(0..3, IndexType::Utf16), vec![0..1]);
+        assert_eq!(find(3..4, IndexType::Utf16), vec![3..8]);
+        assert_eq!(find(4..4, IndexType::Utf16), vec![3..8]);
+        assert_eq!(find(7..8, IndexType::Utf8), vec![7..12]);
+        assert_eq!(find(0..12, IndexType::Utf8), vec![7..12, 0..3]);
+    }
+
+    #[test]
+    fn remote_annotations() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        a.annotate(6..11, comment("a")).unwrap();
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        b.insert(0, "12");
+        a.merge(&b);
+        assert_eq!(a.annotations(), b.annotations());
+        assert_eq!(a.annotations()[0].2, 8..13);
+    }
+
+    #[test]
+    fn unknown_annotation() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello");
+        assert_eq!(text.annotation_range(OpID::new(2, 0)), None);
+        assert!(text.annotations().is_empty());
+    }
+}
+
+mod fork {
+    use super::*;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn rich_text_is_send() {
+        assert_send::<RichText>();
+    }
+
+    #[test]
+    fn fork_and_merge_back() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        a.annotate(0..2, bold()).unwrap();
+        let mut b = a.fork(2);
+        assert_eq!(b.id(), 2);
+        assert_eq!(b.get_spans(), a.get_spans());
+        b.insert(3, "456");
+        a.insert(0, "0");
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.to_string(), "0123456");
+        assert_eq!(a.get_spans(), b.get_spans());
+    }
+
+    #[test]
+    fn fork_exports_the_same() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        a.annotate(0..5, bold()).unwrap();
+        a.delete(5..6);
+        let b = a.fork(2);
+        assert_eq!(
+            b.export(&Default::default()).unwrap(),
+            a.export(&Default::default()).unwrap()
+        );
+        assert_eq!(b.get_spans(), a.get_spans());
+    }
+
+    #[test]
+    fn export_on_another_thread() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let fork = a.fork(2);
+        let data = std::thread::spawn(move || fork.export(&Default::default()).unwrap())
+            .join()
+            .unwrap();
+        a.insert(5, " world");
+        let mut b = RichText::new(3);
+        b.import(&data).unwrap();
+        assert_eq!(b.to_string(), "hello");
+    }
+}
+
+mod patch {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::rich_text::{delta::DeltaItem, error::Error};
+
+    fn comment(value: &str) -> Style {
+        Style::new_comment_like("comment".into(), Value::String(value.into()))
+    }
+
+    fn commented(client_id: u64) -> (RichText, OpID) {
+        let mut text = RichText::new(client_id);
+        text.insert(0, "hello world");
+        text.annotate(6..11, comment("a")).unwrap();
+        let id = text.annotations()[0].0;
+        (text, id)
+    }
+
+    fn enable_undo(text: &mut RichText) {
+        text.enable_undo();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::ZERO);
+    }
+
+    fn comment_of(text: &RichText, index: usize) -> Option<Value> {
+        let mut start = 0;
+        for span in text.get_spans() {
+            if start + span.len() > index {
+                return span.attributes.get("comment").cloned();
+            }
+            start += span.len();
+        }
+        None
+    }
+
+    #[test]
+    fn remove_comment() {
+        let (mut text, id) = commented(1);
+        text.annotate(0..5, comment("b")).unwrap();
+        text.remove_annotation(id).unwrap();
+        text.check();
+        assert_eq!(comment_of(&text, 7), None);
+        assert_eq!(comment_of(&text, 2), Some(Value::String("b".into())));
+        assert_eq!(text.annotation_range(id), None);
+        assert_eq!(text.annotations().len(), 1);
+        // the removed comment doesn't expand
+        text.insert(11, "!");
+        assert_eq!(comment_of(&text, 11), None);
+    }
+
+    #[test]
+    fn remove_annotation_at_doc_edges() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello");
+        text.annotate(.., expanding_style()).unwrap();
+        let id = text.annotations()[0].0;
+        text.remove_annotation(id).unwrap();
+        text.insert(5, " world");
+        text.insert(0, "0");
+        assert_eq!(text.get_spans().len(), 1);
+        assert!(text.get_spans()[0].attributes.is_empty());
+    }
+
+    #[test]
+    fn update_value() {
+        let (mut text, id) = commented(1);
+        text.update_annotation_value(id, Value::String("edited".into()))
+            .unwrap();
+        assert_eq!(comment_of(&text, 7), Some(Value::String("edited".into())));
+        assert_eq!(
+            text.annotations()[0].1.value,
+            Value::String("edited".into())
+        );
+    }
+
+    #[test]
+    fn unknown_annotation() {
+        let (mut text, id) = commented(1);
+        assert!(matches!(
+            text.remove_annotation(id.inc(1)),
+            Err(Error::UnknownAnnotation(x)) if x == id.inc(1)
+        ));
+        text.remove_annotation(id).unwrap();
+        assert!(matches!(
+            text.remove_annotation(id),
+            Err(Error::UnknownAnnotation(x)) if x == id
+        ));
+        assert!(matches!(
+            text.update_annotation_value(id, Value::Null),
+            Err(Error::UnknownAnnotation(x)) if x == id
+        ));
+    }
+
+    #[test]
+    fn concurrent_updates_converge() {
+        let (mut a, id) = commented(1);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.update_annotation_value(id, Value::String("from a".into()))
+            .unwrap();
+        b.update_annotation_value(id, Value::String("from b".into()))
+            .unwrap();
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.get_spans(), b.get_spans());
+        // the lamports are the same, the greater client id wins
+        assert_eq!(comment_of(&a, 7), Some(Value::String("from b".into())));
+    }
+
+    #[test]
+    fn removal_wins() {
+        let (mut a, id) = commented(1);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.remove_annotation(id).unwrap();
+        b.update_annotation_value(id, Value::String("edited".into()))
+            .unwrap();
+        b.update_annotation_value(id, Value::String("edited again".into()))
+            .unwrap();
+        a.merge(&b);
+        b.merge(&a);
+        a.check();
+        b.check();
+        assert_eq!(a.get_spans(), b.get_spans());
+        assert_eq!(comment_of(&a, 7), None);
+        assert_eq!(b.annotation_range(id), None);
+    }
+
+    #[test]
+    fn patch_before_annotation_is_pending() {
+        let (mut a, id) = commented(1);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        b.remove_annotation(id).unwrap();
+        let mut c = RichText::new(3);
+        c.import(&b.export(&a.version()).unwrap()).unwrap();
+        assert_eq!(c.to_string(), "");
+        c.import(&a.export(&Default::default()).unwrap()).unwrap();
+        assert_eq!(c.get_spans(), b.get_spans());
+        a.merge(&c);
+        assert_eq!(comment_of(&a, 7), None);
+    }
+
+    #[test]
+    fn snapshot_keeps_patches() {
+        let (mut text, id) = commented(1);
+        text.annotate(0..5, comment("b")).unwrap();
+        let b_id = text.annotations()[1].0;
+        let mut b = RichText::new(2);
+        b.merge(&text);
+        text.remove_annotation(id).unwrap();
+        text.update_annotation_value(b_id, Value::String("edited".into()))
+            .unwrap();
+        let mut other = RichText::from_snapshot(3, &text.export_snapshot()).unwrap();
+        other.check();
+        assert_eq!(other.get_spans(), text.get_spans());
+        assert_eq!(other.annotation_range(id), None);
+        // the concurrent value with a smaller lamport loses
+        b.update_annotation_value(b_id, Value::String("old".into()))
+            .unwrap();
+        other.merge(&b);
+        assert_eq!(other.get_spans(), text.get_spans());
+        assert!(other.remove_annotation(id).is_err());
+    }
+
+    #[test]
+    fn event_on_removal() {
+        let (mut text, id) = commented(1);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let _sub = text.observe(Box::new(move |event| {
+            events_clone.lock().unwrap().push(event.clone())
+        }));
+        text.remove_annotation(id).unwrap();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let mut attributes = FxHashMap::default();
+        attributes.insert("comment".to_string(), Value::Null);
+        assert_eq!(
+            events[0].ops,
+            vec![
+                DeltaItem::retain(6),
+                DeltaItem::retain_with_attributes(5, attributes)
+            ]
+        );
+    }
+
+    #[test]
+    fn move_comment() {
+        let (mut text, id) = commented(1);
+        text.move_annotation(id, 2..8).unwrap();
+        text.check();
+        assert_eq!(text.annotation_range(id), Some(2..8));
+        assert_eq!(comment_of(&text, 1), None);
+        assert_eq!(comment_of(&text, 2), Some(Value::String("a".into())));
+        assert_eq!(comment_of(&text, 9), None);
+        // the moved comment still doesn't expand
+        text.insert(8, "!");
+        assert_eq!(text.annotation_range(id), Some(2..8));
+        assert_eq!(text.annotations()[0].1.id, id);
+        // an empty range is ignored
+        text.move_annotation(id, 3..3).unwrap();
+        assert_eq!(text.annotation_range(id), Some(2..8));
+    }
+
+    #[test]
+    fn move_expanding_annotation_to_doc_edges() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(2..4, expanding_style()).unwrap();
+        let id = text.annotations()[0].0;
+        text.move_annotation(id, ..).unwrap();
+        text.insert(11, "!");
+        text.insert(0, "0");
+        assert_eq!(text.annotation_range(id), Some(0..13));
+        text.move_annotation_utf16(id, 1..3).unwrap();
+        assert_eq!(text.annotation_range(id), Some(1..3));
+        text.check();
+    }
+
+    #[test]
+    fn move_with_index_types() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你好😀world");
+        text.annotate(0..3, comment("a")).unwrap();
+        let id = text.annotations()[0].0;
+        text.move_annotation_unicode(id, 1..4).unwrap();
+        assert_eq!(text.annotation_range(id), Some(3..11));
+        assert_eq!(text.annotation_range_utf16(id), Some(1..5));
+        assert_eq!(text.annotation_range_unicode(id), Some(1..4));
+        text.move_annotation_utf16(id, 2..4).unwrap();
+        assert_eq!(text.annotation_range_unicode(id), Some(2..3));
+        text.check();
+    }
+
+    #[test]
+    fn concurrent_moves_converge() {
+        let (mut a, id) = commented(1);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.move_annotation(id, 0..5).unwrap();
+        b.move_annotation(id, 3..9).unwrap();
+        b.update_annotation_value(id, Value::String("b".into()))
+            .unwrap();
+        a.merge(&b);
+        b.merge(&a);
+        a.check();
+        b.check();
+        assert_eq!(a.get_spans(), b.get_spans());
+        // the lamports are the same, the greater client id wins
+        assert_eq!(a.annotation_range(id), Some(3..9));
+        assert_eq!(a.annotations()[0].1.value, Value::String("b".into()));
+    }
+
+    #[test]
+    fn removal_wins_over_move() {
+        let (mut a, id) = commented(1);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.move_annotation(id, 0..5).unwrap();
+        b.remove_annotation(id).unwrap();
+        a.merge(&b);
+        b.merge(&a);
+        a.check();
+        assert_eq!(a.get_spans(), b.get_spans());
+        assert_eq!(a.annotation_range(id), None);
+        assert!(a.get_spans().iter().all(|x| x.attributes.is_empty()));
+    }
+
+    #[test]
+    fn move_to_unknown_chars_is_pending() {
+        let (mut a, id) = commented(1);
+        let mut d = RichText::new(4);
+        d.merge(&a);
+        d.insert(11, "!!!");
+        let mut b = RichText::new(2);
+        b.merge(&d);
+        b.move_annotation(id, 9..14).unwrap();
+        let mut c = RichText::new(3);
+        c.merge(&a);
+        // the move arrives before the insertion it refers to
+        c.import(&b.export(&d.version()).unwrap()).unwrap();
+        assert_eq!(c.annotation_range(id), Some(6..11));
+        assert_eq!(c.missing_deps().vv.get(&4), Some(&3));
+        c.merge(&d);
+        assert_eq!(c.annotation_range(id), Some(9..14));
+        assert_eq!(c.get_spans(), b.get_spans());
+        a.merge(&b);
+        assert_eq!(a.get_spans(), b.get_spans());
+    }
+
+    #[test]
+    fn move_to_removed_chars() {
+        let (mut a, id) = commented(1);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.delete(6..9);
+        b.move_annotation(id, 6..9).unwrap();
+        b.merge(&a);
+        a.compact(&a.version());
+        a.merge(&b);
+        a.check();
+        assert_eq!(a.get_spans(), b.get_spans());
+        assert_eq!(a.annotation_range(id), Some(6..6));
+        assert_eq!(b.annotation_range(id), Some(6..6));
+    }
+
+    #[test]
+    fn snapshot_after_move() {
+        let (mut a, id) = commented(2);
+        let mut b = RichText::new(1);
+        b.merge(&a);
+        a.move_annotation(id, 0..3).unwrap();
+        let mut c = RichText::from_snapshot(3, &a.export_snapshot()).unwrap();
+        c.check();
+        assert_eq!(c.annotation_range(id), Some(0..3));
+        // the concurrent move has the same lamport and a smaller client id, so it loses
+        b.move_annotation(id, 0..5).unwrap();
+        c.merge(&b);
+        a.merge(&b);
+        assert_eq!(c.get_spans(), a.get_spans());
+        assert_eq!(c.annotation_range(id), Some(0..3));
+    }
+
+    #[test]
+    fn event_on_move() {
+        let (mut text, id) = commented(1);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let _sub = text.observe(Box::new(move |event| {
+            events_clone.lock().unwrap().push(event.clone())
+        }));
+        text.move_annotation(id, 2..8).unwrap();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let mut added = FxHashMap::default();
+        added.insert("comment".to_string(), Value::String("a".into()));
+        let mut removed = FxHashMap::default();
+        removed.insert("comment".to_string(), Value::Null);
+        assert_eq!(
+            events[0].ops,
+            vec![
+                DeltaItem::retain(2),
+                DeltaItem::retain_with_attributes(4, added),
+                DeltaItem::retain(2),
+                DeltaItem::retain_with_attributes(3, removed)
+            ]
+        );
+    }
+
+    #[test]
+    fn undo_move() {
+        let (mut text, id) = commented(1);
+        enable_undo(&mut text);
+        text.move_annotation(id, 2..8).unwrap();
+        text.delete(0..4);
+        text.undo();
+        text.undo();
+        assert_eq!(text.annotation_range(id), Some(6..11));
+        text.redo();
+        assert_eq!(text.annotation_range(id), Some(2..8));
+    }
+
+    #[test]
+    fn undo_allow_multiple_annotation() {
+        let mut text = RichText::new(1);
+        enable_undo(&mut text);
+        text.insert(0, "hello world");
+        text.annotate(6..11, comment("a")).unwrap();
+        assert_eq!(comment_of(&text, 7), Some(Value::String("a".into())));
+        text.undo();
+        assert_eq!(comment_of(&text, 7), None);
+        assert!(text.annotations().is_empty());
+        text.redo();
+        assert_eq!(comment_of(&text, 7), Some(Value::String("a".into())));
+        assert_eq!(text.annotations()[0].2, 6..11);
+    }
+
+    #[test]
+    fn undo_patches() {
+        let (mut text, id) = commented(1);
+        enable_undo(&mut text);
+        text.update_annotation_value(id, Value::String("b".into()))
+            .unwrap();
+        text.remove_annotation(id).unwrap();
+        assert_eq!(comment_of(&text, 7), None);
+        text.undo();
+        assert_eq!(comment_of(&text, 7), Some(Value::String("b".into())));
+        assert_eq!(text.annotations()[0].2, 6..11);
+        text.undo();
+        assert_eq!(comment_of(&text, 7), Some(Value::String("a".into())));
+        text.redo();
+        text.redo();
+        assert_eq!(comment_of(&text, 7), None);
+    }
+
+    #[test]
+    fn undo_removal_keeps_id() {
+        let (mut text, id) = commented(1);
+        enable_undo(&mut text);
+        let mut b = RichText::new(2);
+        b.merge(&text);
+        text.remove_annotation(id).unwrap();
+        b.merge(&text);
+        text.undo();
+        text.check();
+        assert_eq!(text.annotations()[0].0, id);
+        assert_eq!(text.annotation_range(id), Some(6..11));
+        b.merge(&text);
+        assert_eq!(b.annotation_range(id), Some(6..11));
+        assert_eq!(b.get_spans(), text.get_spans());
+        let other = RichText::from_snapshot(3, &text.export_snapshot()).unwrap();
+        assert_eq!(other.annotation_range(id), Some(6..11));
+        text.redo();
+        assert_eq!(text.annotation_range(id), None);
+        text.undo();
+        assert_eq!(text.annotation_range(id), Some(6..11));
+    }
+
+    #[test]
+    fn restore_wins_over_concurrent_move() {
+        let (mut a, id) = commented(1);
+        enable_undo(&mut a);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.remove_annotation(id).unwrap();
+        b.move_annotation(id, 0..5).unwrap();
+        let mut c = RichText::new(3);
+        c.merge(&b);
+        // the restoration is after the removal, so its lamport is greater than the move's
+        a.undo();
+        c.merge(&a);
+        b.merge(&a);
+        a.merge(&b);
+        for text in [&a, &b, &c] {
+            text.check();
+            assert_eq!(text.get_spans(), a.get_spans());
+            assert_eq!(text.annotation_range(id), Some(6..11));
+        }
+    }
+}
+
+mod schema {
+    use serde_json::json;
+
+    use super::*;
+    use crate::rich_text::{
+        error::Error, DeltaItem, StyleConfig, StyleKind, StyleSchema, ValueSchema,
+    };
+
+    fn app_schema() -> StyleSchema {
+        let mut schema = StyleSchema::default();
+        schema.register(
+            "residue-ref",
+            StyleConfig::new_comment_like().with_value(ValueSchema::String),
+        );
+        schema.register(
+            "citation",
+            StyleConfig::new_link_like().with_value(ValueSchema::Object),
+        );
+        schema.register(
+            "marker-link",
+            StyleConfig {
+                expand: Expand::Both,
+                ..StyleConfig::new_link_like().with_value(ValueSchema::Number)
+            },
+        );
+        schema
+    }
+
+    fn attributes(key: &str, value: Value) -> FxHashMap<String, Value> {
+        let mut attributes = FxHashMap::default();
+        attributes.insert(key.to_string(), value);
+        attributes
+    }
+
+    #[test]
+    fn default_schema() {
+        let schema = StyleSchema::default();
+        assert_eq!(schema.get("link").expand, Expand::None);
+        assert_eq!(schema.get("header").kind, StyleKind::Block);
+        assert!(!schema.is_registered("bold"));
+        assert_eq!(*schema.get("bold"), StyleConfig::new_bold_like());
+        assert_eq!(schema.erase_style("link").expand, Expand::Both);
+        assert_eq!(schema.get("comment").behavior, Behavior::AllowMultiple);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn infer_expand_from_default_schema() {
+        assert_eq!(Expand::infer_insert_expand("link"), Expand::None);
+        assert_eq!(Expand::infer_insert_expand("bold"), Expand::After);
+        assert_eq!(Expand::infer_delete_expand("comment"), Expand::Both);
+    }
+
+    #[test]
+    fn coerce_values() {
+        assert_eq!(ValueSchema::Bool.coerce(json!("true")), Some(json!(true)));
+        assert_eq!(ValueSchema::Number.coerce(json!("12")), Some(json!(12)));
+        assert_eq!(ValueSchema::Number.coerce(json!("1.5")), Some(json!(1.5)));
+        assert_eq!(ValueSchema::String.coerce(json!(12)), Some(json!("12")));
+        assert_eq!(ValueSchema::Object.coerce(json!("a")), None);
+        assert_eq!(ValueSchema::Bool.coerce(json!(1)), None);
+        assert_eq!(ValueSchema::Any.coerce(json!([1])), Some(json!([1])));
+    }
+
+    #[test]
+    fn apply_delta_with_domain_styles() {
+        let mut text = RichText::new(1);
+        text.set_style_schema(app_schema());
+        text.insert(0, "ATG GCA TTC");
+        for (range, value) in [(0..7, "r1"), (4..11, "r2")] {
+            text.apply_delta(
+                vec![
+                    DeltaItem::retain(range.start),
+                    DeltaItem::retain_with_attributes(
+                        range.len(),
+                        attributes("residue-ref", json!(value)),
+                    ),
+                ]
+                .into_iter(),
+                IndexType::Utf8,
+            )
+            .unwrap();
+        }
+        text.apply_delta(
+            vec![
+                DeltaItem::retain(8),
+                DeltaItem::retain_with_attributes(3, attributes("marker-link", json!("7"))),
+            ]
+            .into_iter(),
+            IndexType::Utf8,
+        )
+        .unwrap();
+
+        let anns = text.annotations();
+        assert_eq!(anns.len(), 3);
+        // residue refs are kept side by side
+        assert_eq!(anns[0].1.behavior, Behavior::AllowMultiple);
+        assert_eq!(anns[1].2, 4..11);
+        // the value is coerced to a number, and the marker link expands
+        assert_eq!(anns[2].1.value, json!(7));
+        text.insert(11, "A");
+        // the residue refs don't expand
+        assert_eq!(text.annotation_range(anns[1].0), Some(4..11));
+        text.insert(8, "A");
+        assert_eq!(text.annotation_range(anns[2].0), Some(8..13));
+    }
+
+    #[test]
+    fn reject_invalid_delta() {
+        let mut text = RichText::new(1);
+        text.set_style_schema(app_schema());
+        text.insert(0, "hello world");
+        let result = text.apply_delta(
+            vec![
+                DeltaItem::insert("new ".into(), IndexType::Utf8),
+                DeltaItem::retain_with_attributes(5, attributes("citation", json!("doi"))),
+            ]
+            .into_iter(),
+            IndexType::Utf8,
+        );
+        assert!(matches!(result, Err(Error::InvalidStyleValue(..))));
+        assert_eq!(text.to_string(), "hello world");
+        assert!(text.annotations().is_empty());
+
+        // removing a style doesn't need a valid value
+        text.annotate(
+            0..5,
+            text.style_schema()
+                .style("citation", json!({"doi": "1"}))
+                .unwrap(),
+        )
+        .unwrap();
+        text.apply_delta(
+            vec![DeltaItem::retain_with_attributes(
+                5,
+                attributes("citation", Value::Null),
+            )]
+            .into_iter(),
+            IndexType::Utf8,
+        )
+        .unwrap();
+        assert!(text.get_spans()[0].attributes.is_empty());
+    }
+
+    #[test]
+    fn annotate_with_invalid_value() {
+        let mut text = RichText::new(1);
+        text.style_schema_mut().register(
+            "level",
+            StyleConfig::new_bold_like().with_value(ValueSchema::Number),
+        );
+        text.insert(0, "hello");
+        let result = text.annotate(0..5, Style::new_bold_like("level".into(), json!("high")));
+        assert!(matches!(result, Err(Error::InvalidStyleValue(..))));
+        assert!(text.annotations().is_empty());
+    }
+
+    #[test]
+    fn annotate_against_registered_config() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello\n");
+        let result = text.annotate(0..5, Style::new_bold_like("comment".into(), json!("a")));
+        assert!(matches!(result, Err(Error::StyleMismatch(type_)) if &*type_ == "comment"));
+        let result = text.annotate(0..5, Style::new_erase_bold_like("link".into()));
+        assert!(matches!(result, Err(Error::StyleMismatch(_))));
+        let result = text.annotate(0..6, Style::new_link_like("header".into(), json!(1)));
+        assert!(matches!(result, Err(Error::NotInlineStyle(type_)) if &*type_ == "header"));
+        assert!(text.annotations().is_empty());
+
+        text.annotate(0..5, Style::new_erase_link_like("link".into()))
+            .unwrap();
+        // the unregistered types are not restricted
+        text.annotate(0..5, Style::new_link_like("bold".into(), json!(true)))
+            .unwrap();
+    }
+
+    #[test]
+    fn update_annotation_with_invalid_value() {
+        let mut text = RichText::new(1);
+        text.style_schema_mut().register(
+            "level",
+            StyleConfig::new_comment_like().with_value(ValueSchema::Number),
+        );
+        text.insert(0, "hello");
+        text.annotate(0..5, Style::new_comment_like("level".into(), json!(1)))
+            .unwrap();
+        let id = text.annotations()[0].0;
+        let result = text.update_annotation_value(id, json!("high"));
+        assert!(matches!(result, Err(Error::InvalidStyleValue(..))));
+        assert_eq!(text.annotations()[0].1.value, json!(1));
+        text.update_annotation_value(id, json!("2")).unwrap();
+        assert_eq!(text.annotations()[0].1.value, json!(2));
+    }
+}
+
+mod block {
+    use serde_json::json;
+
+    use super::*;
+    use crate::rich_text::{delta::DeltaItem, error::Error};
+
+    fn attributes(pairs: &[(&str, Value)]) -> FxHashMap<String, Value> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn line_attributes(pairs: &[(&str, Value)]) -> FxHashMap<InternalString, Value> {
+        pairs
+            .iter()
+            .map(|(key, value)| ((*key).into(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn format_line() {
+        let mut text = RichText::new(1);
+        text.insert(0, "Title\nbody");
+        text.format_line(0, "header", json!(1)).unwrap();
+        text.check();
+        assert_eq!(
+            text.line_attributes(0),
+            line_attributes(&[("header", json!(1))])
+        );
+        assert!(text.line_attributes(1).is_empty());
+        // only the line break has the attribute
+        assert!(text.slice(0..5, IndexType::Utf8)[0].attributes.is_empty());
+        // typing in the line doesn't spread the attribute
+        text.insert(5, "!");
+        assert!(text.slice(5..6, IndexType::Utf8)[0].attributes.is_empty());
+
+        // the last line gets a line break
+        text.format_line(1, "list", json!("bullet")).unwrap();
+        assert_eq!(text.to_string(), "Title!\nbody\n");
+        assert_eq!(
+            text.line_attributes(1),
+            line_attributes(&[("list", json!("bullet"))])
+        );
+        assert_eq!(text.lines(), 3);
+
+        text.format_line(0, "header", Value::Null).unwrap();
+        assert!(text.line_attributes(0).is_empty());
+    }
+
+    #[test]
+    fn get_line_attributes() {
+        let mut text = RichText::new(1);
+        text.insert(0, "Title\nbody\n");
+        text.annotate(0..5, Style::new_bold_like("bold".into(), json!(true)))
+            .unwrap();
+        text.format_line(0, "header", json!(2)).unwrap();
+        let line = text.get_line(0);
+        assert_eq!(line.spans.len(), 2);
+        assert_eq!(line.attributes, line_attributes(&[("header", json!(2))]));
+        assert!(text.get_line(1).attributes.is_empty());
+        assert_eq!(text.get_line(3), Line::default());
+    }
+
+    #[test]
+    fn format_line_with_inline_style() {
+        let mut text = RichText::new(1);
+        text.insert(0, "Title\n");
+        assert!(matches!(
+            text.format_line(0, "bold", json!(true)),
+            Err(Error::NotBlockStyle(type_)) if &*type_ == "bold"
+        ));
+        assert!(text.annotations().is_empty());
+    }
+
+    #[test]
+    fn format_line_out_of_range() {
+        let mut text = RichText::new(1);
+        text.insert(0, "Title\nbody");
+        assert!(matches!(
+            text.format_line(2, "header", json!(1)),
+            Err(Error::LineOutOfRange(2))
+        ));
+        assert_eq!(text.to_string(), "Title\nbody");
+        assert!(text.annotations().is_empty());
+    }
+
+    #[test]
+    fn concurrent_values_converge() {
+        let mut a = RichText::new(1);
+        a.insert(0, "Title\n");
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.format_line(0, "header", json!(1)).unwrap();
+        b.format_line(0, "header", json!(2)).unwrap();
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.line_attributes(0), b.line_attributes(0));
+        assert_eq!(a.line_attributes(0).len(), 1);
+    }
+
+    #[test]
+    fn concurrent_attributes_are_kept() {
+        let mut a = RichText::new(1);
+        a.insert(0, "Title\n");
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.format_line(0, "header", json!(1)).unwrap();
+        b.format_line(0, "align", json!("center")).unwrap();
+        a.merge(&b);
+        b.merge(&a);
+        let expected = line_attributes(&[("header", json!(1)), ("align", json!("center"))]);
+        assert_eq!(a.line_attributes(0), expected);
+        assert_eq!(b.line_attributes(0), expected);
+    }
+
+    #[test]
+    fn join_lines() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a\nb\n");
+        text.format_line(0, "header", json!(1)).unwrap();
+        text.format_line(1, "list", json!("bullet")).unwrap();
+        text.delete(1..2);
+        assert_eq!(text.lines(), 2);
+        assert_eq!(
+            text.line_attributes(0),
+            line_attributes(&[("list", json!("bullet"))])
+        );
+    }
+
+    #[test]
+    fn retain_block_attributes() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a\nb\nc");
+        text.apply_delta(
+            vec![DeltaItem::retain_with_attributes(
+                6,
+                attributes(&[("list", json!("ordered")), ("bold", json!(true))]),
+            )]
+            .into_iter(),
+            IndexType::Utf8,
+        )
+        .unwrap();
+        text.check();
+        // Quill formats its line break at the end of the doc as well
+        assert_eq!(text.to_string(), "a\nb\nc\n");
+        for line in 0..3 {
+            assert_eq!(
+                text.line_attributes(line),
+                line_attributes(&[("list", json!("ordered"))])
+            );
+            assert_eq!(
+                text.get_line(line).spans[0].attributes,
+                line_attributes(&[("bold", json!(true))])
+            );
+        }
+    }
+
+    #[test]
+    fn quill_delta_round_trip() {
+        let delta = vec![
+            DeltaItem::insert("Title".into(), IndexType::Utf16),
+            DeltaItem::insert_with_attributes(
+                "\n".into(),
+                IndexType::Utf16,
+                attributes(&[("header", json!(1))]),
+            ),
+            DeltaItem::insert("你好 ".into(), IndexType::Utf16),
+            DeltaItem::insert_with_attributes(
+                "world".into(),
+                IndexType::Utf16,
+                attributes(&[("bold", json!(true))]),
+            ),
+            DeltaItem::insert_with_attributes(
+                "\n".into(),
+                IndexType::Utf16,
+                attributes(&[("list", json!("bullet")), ("align", json!("right"))]),
+            ),
+            DeltaItem::insert("end\n".into(), IndexType::Utf16),
+        ];
+        let mut text = RichText::new(1);
+        text.apply_delta(delta.clone().into_iter(), IndexType::Utf16)
+            .unwrap();
+        text.check();
+        assert_eq!(text.to_delta(IndexType::Utf16), delta);
+        assert_eq!(
+            text.get_line(1).attributes,
+            line_attributes(&[("list", json!("bullet")), ("align", json!("right"))])
+        );
+    }
+
+    #[test]
+    fn invalid_block_value() {
+        let mut text = RichText::new(1);
+        text.style_schema_mut().register(
+            "header",
+            StyleConfig::new_block().with_value(ValueSchema::Number),
+        );
+        text.insert(0, "Title\n");
+        assert!(matches!(
+            text.format_line(0, "header", json!("big")),
+            Err(Error::InvalidStyleValue(..))
+        ));
+        assert!(text.line_attributes(0).is_empty());
+    }
+}
+
+mod embed {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::rich_text::delta::DeltaItem;
+
+    fn marker(id: u64) -> Value {
+        json!({ "marker": id })
+    }
+
+    fn embeds(text: &RichText) -> Vec<Value> {
+        text.get_spans()
+            .into_iter()
+            .filter_map(|x| x.embed)
+            .collect()
+    }
+
+    #[test]
+    fn insert_embed() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab");
+        text.insert_embed(1, marker(1));
+        text.insert_embed(2, marker(2));
+        text.check();
+        assert_eq!(text.len(), 4);
+        assert_eq!(text.len_utf16(), 4);
+        assert_eq!(text.to_string(), format!("a{EMBED_CHAR}{EMBED_CHAR}b"));
+        // the embeds are not merged
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 4);
+        assert_eq!(spans[1].embed, Some(marker(1)));
+        assert_eq!(spans[2].embed, Some(marker(2)));
+        assert_eq!(spans[3].embed, None);
+
+        text.delete(1..2);
+        assert_eq!(embeds(&text), vec![marker(2)]);
+    }
+
+    #[test]
+    fn embed_char_in_text_is_replaced() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a\u{1A}b");
+        assert_eq!(text.len(), 5);
+        text.insert_unicode(3, "\u{1A}");
+        text.insert_embed(0, marker(1));
+        text.check();
+        assert_eq!(text.to_string(), format!("{EMBED_CHAR}a\u{FFFD}b\u{FFFD}"));
+        assert_eq!(text.to_plain_text(), "a\u{FFFD}b\u{FFFD}");
+        assert_eq!(embeds(&text), vec![marker(1)]);
+
+        let mut text = RichText::new(1);
+        let bold: FxHashMap<String, Value> =
+            [("bold".to_string(), json!(true))].into_iter().collect();
+        let delta = vec![
+            DeltaItem::insert_with_attributes("x\u{1A}".into(), IndexType::Utf8, bold),
+            DeltaItem::insert("y".into(), IndexType::Utf8),
+        ];
+        text.apply_delta(delta.into_iter(), IndexType::Utf8)
+            .unwrap();
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].insert, "x\u{FFFD}");
+        assert_eq!(spans[0].attributes.get("bold"), Some(&json!(true)));
+        assert_eq!(spans[1].insert, "y");
+        assert!(spans.iter().all(|x| x.embed.is_none()));
+    }
+
+    #[test]
+    fn utf16_index() {
+        let mut text = RichText::new(1);
+        text.insert(0, "😀😀");
+        text.insert_embed_utf16(2, marker(1));
+        assert_eq!(text.len_utf16(), 5);
+        assert_eq!(text.len(), 9);
+        assert_eq!(text.convert_index(3, IndexType::Utf16, IndexType::Utf8), 5);
+        assert_eq!(text.slice(4..5, IndexType::Utf8)[0].embed, Some(marker(1)));
+    }
+
+    #[test]
+    fn style_embed() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab");
+        text.insert_embed(1, marker(1));
+        text.annotate(0..3, Style::new_bold_like("bold".into(), json!(true)))
+            .unwrap();
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[1].embed, Some(marker(1)));
+        assert_eq!(spans[1].attributes.get("bold"), Some(&json!(true)));
+    }
+
+    #[test]
+    fn merge_concurrent_embeds() {
+        let mut a = RichText::new(1);
+        a.insert(0, "ab");
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.insert_embed(1, marker(1));
+        b.insert_embed(1, marker(2));
+        b.insert(1, "x");
+        a.merge(&b);
+        b.merge(&a);
+        a.check();
+        assert_eq!(a.get_spans(), b.get_spans());
+        assert_eq!(embeds(&a).len(), 2);
+    }
+
+    #[test]
+    fn encode_embeds() {
+        let mut a = RichText::new(1);
+        a.insert(0, "ab");
+        a.insert_embed(1, marker(1));
+        a.insert_embed(0, json!({ "image": "a.png", "width": 100 }));
+        let mut b = RichText::new(2);
+        b.import(&a.export(&Default::default()).unwrap()).unwrap();
+        assert_eq!(a.get_spans(), b.get_spans());
+
+        let c = RichText::from_snapshot(3, &a.export_snapshot()).unwrap();
+        assert_eq!(a.get_spans(), c.get_spans());
+    }
+
+    #[test]
+    fn quill_delta() {
+        let embed = DeltaItem::embed(json!({ "image": "a.png" }));
+        assert_eq!(
+            serde_json::to_value(&embed).unwrap(),
+            json!({ "insert": { "image": "a.png" }, "attributes": null })
+        );
+        let item: DeltaItem =
+            serde_json::from_value(json!({ "insert": { "image": "a.png" } })).unwrap();
+        assert_eq!(item, embed);
+        let item: DeltaItem = serde_json::from_value(json!({ "insert": "a" })).unwrap();
+        assert!(matches!(item, DeltaItem::Insert { .. }));
+
+        let mut attributes = FxHashMap::default();
+        attributes.insert("link".to_string(), json!("https://example.com"));
+        let delta = vec![
+            DeltaItem::insert("see ".into(), IndexType::Utf16),
+            DeltaItem::embed_with_attributes(marker(1), attributes),
+            DeltaItem::insert("\n".into(), IndexType::Utf16),
+        ];
+        let mut text = RichText::new(1);
+        text.apply_delta(delta.clone().into_iter(), IndexType::Utf16)
+            .unwrap();
+        text.check();
+        assert_eq!(text.len_utf16(), 6);
+        assert_eq!(text.to_delta(IndexType::Utf16), delta);
+    }
+
+    #[test]
+    fn embed_events() {
+        let mut a = RichText::new(1);
+        let local = a.observe_channel();
+        let mut b = RichText::new(2);
+        let remote = b.observe_channel();
+        a.insert(0, "ab");
+        a.insert_embed(1, marker(1));
+        b.merge(&a);
+        let event = local.try_iter().last().unwrap();
+        assert_eq!(
+            event.ops,
+            vec![
+                DeltaItem::retain(1),
+                DeltaItem::embed_with_attributes(marker(1), Default::default())
+            ]
+        );
+        let embeds: Vec<_> = remote
+            .try_iter()
+            .flat_map(|x| x.ops)
+            .filter(|x| matches!(x, DeltaItem::Embed { .. }))
+            .collect();
+        assert_eq!(
+            embeds,
+            vec![DeltaItem::embed_with_attributes(
+                marker(1),
+                Default::default()
+            )]
+        );
+    }
+
+    #[test]
+    fn undo_deleted_embed() {
+        let mut text = RichText::new(1);
+        text.enable_undo();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::ZERO);
+        text.insert(0, "ab");
+        text.insert_embed(1, marker(1));
+        text.delete(0..3);
+        assert_eq!(text.len(), 0);
+        text.undo();
+        text.check();
+        assert_eq!(text.to_string(), format!("a{EMBED_CHAR}b"));
+        assert_eq!(embeds(&text), vec![marker(1)]);
+    }
+}
+
+mod unicode {
+    use super::*;
+    use crate::rich_text::delta::DeltaItem;
+
+    #[test]
+    fn len() {
+        let mut text = RichText::new(1);
+        text.insert(0, "😀a\ne\u{301}");
+        assert_eq!(text.len(), 9);
+        assert_eq!(text.len_utf16(), 6);
+        assert_eq!(text.len_unicode(), 5);
+        text.delete(0..4);
+        text.check();
+        assert_eq!(text.len_unicode(), 4);
+    }
+
+    #[test]
+    fn convert_index() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀b中c");
+        let (utf8, utf16, unicode) = (IndexType::Utf8, IndexType::Utf16, IndexType::Unicode);
+        assert_eq!(text.convert_index(2, unicode, utf8), 5);
+        assert_eq!(text.convert_index(2, unicode, utf16), 3);
+        assert_eq!(text.convert_index(4, unicode, utf8), 9);
+        assert_eq!(text.convert_index(9, utf8, unicode), 4);
+        assert_eq!(text.convert_index(3, utf16, unicode), 2);
+        assert_eq!(text.convert_index(5, unicode, utf8), 10);
+    }
+
+    #[test]
+    fn edit() {
+        let mut text = RichText::new(1);
+        text.insert_unicode(0, "😀😀");
+        text.insert_unicode(1, "中");
+        assert_eq!(text.to_string(), "😀中😀");
+        text.annotate_unicode(1..3, bold()).unwrap();
+        let spans = text.slice(1..2, IndexType::Unicode);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].insert, "中");
+        assert_eq!(text.slice_str(2..3, IndexType::Unicode), "😀");
+        assert_eq!(text.get_spans()[1].insert, "中😀");
+        text.delete_unicode(0..2);
+        text.check();
+        assert_eq!(text.to_string(), "😀");
+        assert_eq!(text.len_unicode(), 1);
+    }
+
+    #[test]
+    fn events() {
+        let mut text = RichText::new(1);
+        text.set_event_index_type(IndexType::Unicode);
+        let receiver = text.observe_channel();
+        text.insert(0, "😀a");
+        text.delete(4..5);
+        text.annotate(0..4, bold()).unwrap();
+        let events: Vec<_> = receiver.try_iter().map(|x| x.ops).collect();
+        assert_eq!(
+            events[0].last().unwrap(),
+            &DeltaItem::insert_with_attributes(
+                "😀a".into(),
+                IndexType::Unicode,
+                Default::default()
+            )
+        );
+        assert_eq!(events[0].last().unwrap().length(), 2);
+        assert_eq!(events[1], vec![DeltaItem::retain(1), DeltaItem::delete(1)]);
+        assert_eq!(events[2].last().unwrap().length(), 1);
+    }
+
+    #[test]
+    fn apply_delta() {
+        let mut text = RichText::new(1);
+        text.apply_delta(
+            vec![DeltaItem::insert("😀😀".into(), IndexType::Unicode)].into_iter(),
+            IndexType::Unicode,
+        )
+        .unwrap();
+        text.apply_delta(
+            vec![
+                DeltaItem::retain(1),
+                DeltaItem::insert("a".into(), IndexType::Unicode),
+            ]
+            .into_iter(),
+            IndexType::Unicode,
+        )
+        .unwrap();
+        assert_eq!(text.to_string(), "😀a😀");
+    }
+
+    #[test]
+    fn grapheme_boundaries() {
+        let mut text = RichText::new(1);
+        // e + combining acute accent, a family emoji with zero width joiners, crlf
+        text.insert(0, "e\u{301}👨\u{200D}👩\u{200D}👧x\r\ny");
+        assert_eq!(text.next_grapheme_boundary(0, IndexType::Unicode), 2);
+        assert_eq!(text.next_grapheme_boundary(2, IndexType::Unicode), 7);
+        assert_eq!(text.next_grapheme_boundary(7, IndexType::Unicode), 8);
+        assert_eq!(text.next_grapheme_boundary(8, IndexType::Unicode), 10);
+        assert_eq!(text.next_grapheme_boundary(10, IndexType::Unicode), 11);
+        assert_eq!(text.next_grapheme_boundary(11, IndexType::Unicode), 11);
+        assert_eq!(text.prev_grapheme_boundary(11, IndexType::Unicode), 10);
+        assert_eq!(text.prev_grapheme_boundary(10, IndexType::Unicode), 8);
+        assert_eq!(text.prev_grapheme_boundary(7, IndexType::Unicode), 2);
+        assert_eq!(text.prev_grapheme_boundary(1, IndexType::Unicode), 0);
+        assert!(!text.is_grapheme_boundary(1, IndexType::Unicode));
+        assert!(!text.is_grapheme_boundary(9, IndexType::Unicode));
+        assert!(text.is_grapheme_boundary(10, IndexType::Unicode));
+        assert!(text.is_grapheme_boundary(11, IndexType::Unicode));
+        // "e\u{301}" is 3 bytes and 2 utf16 units
+        assert_eq!(text.next_grapheme_boundary(0, IndexType::Utf8), 3);
+        assert_eq!(text.next_grapheme_boundary(0, IndexType::Utf16), 2);
+    }
+
+    #[test]
+    fn grapheme_boundaries_after_deletion() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab\u{301}");
+        text.delete(1..2);
+        assert_eq!(text.to_string(), "a\u{301}");
+        assert_eq!(text.next_grapheme_boundary(0, IndexType::Unicode), 2);
+    }
+}
+
+mod position {
+    use super::*;
+
+    #[test]
+    fn line_range() {
+        let mut text = RichText::new(1);
+        text.insert(0, "Hello\n你好\n");
+        assert_eq!(text.line_range(0, IndexType::Utf8), 0..6);
+        assert_eq!(text.line_range(1, IndexType::Utf8), 6..13);
+        assert_eq!(text.line_range(1, IndexType::Utf16), 6..9);
+        assert_eq!(text.line_range(2, IndexType::Utf8), 13..13);
+        text.delete(5..6);
+        assert_eq!(text.lines(), 2);
+        assert_eq!(text.line_range(0, IndexType::Unicode), 0..8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn line_range_out_of_bound() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a\nb");
+        text.line_range(2, IndexType::Utf8);
+    }
+
+    #[test]
+    fn index_to_line_col() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab\n\n你好c");
+        assert_eq!(text.index_to_line_col(0, IndexType::Utf8), (0, 0));
+        assert_eq!(text.index_to_line_col(2, IndexType::Utf8), (0, 2));
+        assert_eq!(text.index_to_line_col(3, IndexType::Utf8), (1, 0));
+        assert_eq!(text.index_to_line_col(4, IndexType::Utf8), (2, 0));
+        assert_eq!(text.index_to_line_col(10, IndexType::Utf8), (2, 6));
+        assert_eq!(text.index_to_line_col(6, IndexType::Utf16), (2, 2));
+        assert_eq!(text.index_to_line_col(7, IndexType::Utf16), (2, 3));
+    }
+
+    #[test]
+    fn line_col_to_index() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab\n\n你好c");
+        assert_eq!(text.line_col_to_index(0, 1, IndexType::Utf8), 1);
+        assert_eq!(text.line_col_to_index(1, 0, IndexType::Utf8), 3);
+        assert_eq!(text.line_col_to_index(2, 1, IndexType::Utf16), 5);
+        // clamped to the end of the line
+        assert_eq!(text.line_col_to_index(0, 10, IndexType::Utf8), 2);
+        assert_eq!(text.line_col_to_index(1, 10, IndexType::Utf8), 3);
+        assert_eq!(text.line_col_to_index(2, 10, IndexType::Utf8), 11);
+    }
+
+    #[test]
+    fn round_trip_with_tombstones() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "123\n456\n789");
+        b.merge(&a);
+        a.delete(2..6);
+        b.insert(5, "\nx");
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.to_string(), b.to_string());
+        for index in 0..=a.len() {
+            let (line, col) = a.index_to_line_col(index, IndexType::Utf8);
+            assert_eq!(a.line_col_to_index(line, col, IndexType::Utf8), index);
+            let range = a.line_range(line, IndexType::Utf8);
+            assert!(range.start <= index && index <= range.end);
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let text = RichText::new(1);
+        assert_eq!(text.index_to_line_col(0, IndexType::Utf8), (0, 0));
+        assert_eq!(text.line_col_to_index(0, 1, IndexType::Utf8), 0);
+        assert_eq!(text.line_range(0, IndexType::Utf8), 0..0);
+    }
+}
+
+mod search {
+    use std::time::Duration;
+
+    #[cfg(feature = "search-regex")]
+    use regex::Regex;
+
+    use super::*;
+
+    #[test]
+    fn find_literal() {
+        let mut text = RichText::new(1);
+        text.insert(0, "😀ab😀ab");
+        let find = |index_type| text.find("ab", index_type).collect::<Vec<_>>();
+        assert_eq!(find(IndexType::Utf8), vec![4..6, 10..12]);
+        assert_eq!(find(IndexType::Utf16), vec![2..4, 6..8]);
+        assert_eq!(find(IndexType::Unicode), vec![1..3, 4..6]);
+        assert_eq!(text.find("", IndexType::Utf8).count(), 0);
+        assert_eq!(text.find("abc", IndexType::Utf8).count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "search-regex")]
+    fn find_regex() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a12b345");
+        text.delete(0..1);
+        let regex = Regex::new(r"\d+").unwrap();
+        let ans: Vec<_> = text.find(&regex, IndexType::Utf8).collect();
+        assert_eq!(ans, vec![0..2, 3..6]);
+    }
+
+    #[test]
+    fn replace_range_keeps_styles() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(6..11, bold()).unwrap();
+        text.replace_range(6..11, "there", IndexType::Utf8);
+        text.check();
+        assert_eq!(text.to_string(), "hello there");
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[1].insert, "there");
+        assert!(spans[1].attributes.contains_key("bold"));
+    }
+
+    #[test]
+    fn replace_range_by_expand_rules() {
+        let mut text = RichText::new(1);
+        text.insert(0, "abc");
+        text.annotate(0..1, bold()).unwrap();
+        text.annotate(0..1, link()).unwrap();
+        text.replace_range(0..3, "x", IndexType::Utf8);
+        assert_eq!(text.to_string(), "x");
+        let spans = text.get_spans();
+        assert!(spans[0].attributes.contains_key("bold"));
+        assert!(!spans[0].attributes.contains_key("link"));
+
+        text.insert(1, "yz");
+        text.replace_range(1..3, "", IndexType::Utf8);
+        assert_eq!(text.to_string(), "x");
+        text.replace_range(1..1, "w", IndexType::Utf8);
+        assert_eq!(text.to_string(), "xw");
+    }
+
+    #[test]
+    fn replace_all_in_one_event() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a-b-😀-c");
+        let receiver = text.observe_channel();
+        assert_eq!(text.replace_all("-", "+"), 3);
+        assert_eq!(text.to_string(), "a+b+😀+c");
+        let events: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_local);
+        assert_eq!(text.replace_all("-", "+"), 0);
+        assert_eq!(receiver.try_iter().count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "search-regex")]
+    fn replace_all_regex() {
+        let mut text = RichText::new(1);
+        text.insert(0, "x@y and z@w");
+        let regex = Regex::new(r"(\w+)@(\w+)").unwrap();
+        assert_eq!(text.replace_all(&regex, "$2 at $1"), 2);
+        assert_eq!(text.to_string(), "y at x and w at z");
+    }
+
+    #[test]
+    fn undo_replace_all() {
+        let mut text = RichText::new(1);
+        text.enable_undo();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::ZERO);
+        text.insert(0, "aXbXc");
+        text.annotate(0..5, bold()).unwrap();
+        text.replace_all("X", "yy");
+        assert_eq!(text.to_string(), "ayybyyc");
+        assert_eq!(text.get_spans().len(), 1);
+        text.undo();
+        text.check();
+        assert_eq!(text.to_string(), "aXbXc");
+    }
+
+    #[test]
+    fn merge_concurrent_replace() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "one two");
+        b.merge(&a);
+        a.replace_all("two", "2");
+        b.insert(7, "!");
+        b.replace_range(0..3, "1", IndexType::Utf8);
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.to_string(), "1 2!");
+        assert_eq!(a.to_string(), b.to_string());
+    }
+}
+
+mod export {
+    use serde_json::json;
+
+    use super::*;
+    use crate::rich_text::{DefaultStyleSerializer, HtmlElement, HtmlStyle, StyleSerializer};
+
+    fn note() -> RichText {
+        let mut text = RichText::new(1);
+        text.insert(0, "Title\nHello bold and link\nitem1\nitem2\n");
+        text.format_line(0, "header", json!(1)).unwrap();
+        text.annotate(12..16, Style::new_bold_like("bold".into(), json!(true)))
+            .unwrap();
+        text.annotate(
+            21..25,
+            Style::new_link_like("link".into(), json!("https://x.com")),
+        )
+        .unwrap();
+        text.format_line(2, "list", json!("bullet")).unwrap();
+        text.format_line(3, "list", json!("bullet")).unwrap();
+        text
+    }
+
+    #[test]
+    fn markdown() {
+        let text = note();
+        assert_eq!(
+            text.to_markdown(&DefaultStyleSerializer::default()),
+            "# Title\n\nHello **bold** and [link](https://x.com)\n\n- item1\n- item2"
+        );
+    }
+
+    #[test]
+    fn html() {
+        let text = note();
+        assert_eq!(
+            text.to_html(&DefaultStyleSerializer::default()),
+            "<h1>Title</h1><p>Hello <strong>bold</strong> and <a href=\"https://x.com\">link</a></p>\
+             <ul><li>item1</li><li>item2</li></ul>"
+        );
+    }
+
+    #[test]
+    fn plain_text() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab");
+        text.insert_embed(1, json!({ "image": "https://x.com/a.png" }));
+        assert_eq!(text.to_plain_text(), "ab");
+        let serializer = DefaultStyleSerializer::default();
+        assert_eq!(text.to_markdown(&serializer), "a![](https://x.com/a.png)b");
+        assert_eq!(
+            text.to_html(&serializer),
+            "<p>a<img src=\"https://x.com/a.png\">b</p>"
+        );
+        text.insert_embed(0, json!({ "formula": "e=mc^2" }));
+        assert_eq!(text.to_markdown(&serializer), "a![](https://x.com/a.png)b");
+    }
+
+    #[test]
+    fn unknown_styles() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a <b> c");
+        text.annotate(0..1, Style::new_bold_like("color".into(), json!("red")))
+            .unwrap();
+        text.annotate(
+            2..5,
+            Style::new_comment_like("comment".into(), json!("note")),
+        )
+        .unwrap();
+        let mut serializer = DefaultStyleSerializer::default();
+        assert_eq!(
+            text.to_html(&serializer),
+            "<p><span data-color=\"red\">a</span> \
+             <span class=\"comment\" data-comment=\"note\">&lt;b&gt;</span> c</p>"
+        );
+        assert_eq!(text.to_markdown(&serializer), "a \\<b\\> c");
+        serializer.keep_unknown_styles = false;
+        assert_eq!(
+            text.to_html(&serializer),
+            "<p>a <span class=\"comment\" data-comment=\"note\">&lt;b&gt;</span> c</p>"
+        );
+    }
+
+    #[test]
+    fn sanitize() {
+        let mut text = RichText::new(1);
+        text.insert(0, "x\"y");
+        text.annotate(
+            0..3,
+            Style::new_link_like("link".into(), json!("javascript:alert(1)")),
+        )
+        .unwrap();
+        text.annotate(0..1, Style::new_bold_like("on click".into(), json!("\"")))
+            .unwrap();
+        let serializer = DefaultStyleSerializer::default();
+        assert_eq!(
+            text.to_html(&serializer),
+            "<p><a><span data-on-click=\"&quot;\">x</span>&quot;y</a></p>"
+        );
+        assert_eq!(text.to_markdown(&serializer), "x\"y");
+    }
+
+    #[test]
+    fn nested_styles() {
+        let mut text = RichText::new(1);
+        text.insert(0, "abc\n\nd");
+        text.annotate(0..2, Style::new_bold_like("bold".into(), json!(true)))
+            .unwrap();
+        text.annotate(1..3, Style::new_bold_like("italic".into(), json!(true)))
+            .unwrap();
+        assert_eq!(
+            text.to_html(&DefaultStyleSerializer::default()),
+            "<p><strong>a<em>b</em></strong><em>c</em></p><p><br></p><p>d</p>"
+        );
+    }
+
+    #[test]
+    fn custom_serializer() {
+        struct Highlight;
+
+        impl StyleSerializer for Highlight {
+            fn markdown_inline(&self, type_: &str, _: &Value) -> Option<(String, String)> {
+                (type_ == "highlight").then(|| ("==".into(), "==".into()))
+            }
+
+            fn markdown_block(&self, _: &str, _: &Value) -> Option<String> {
+                None
+            }
+
+            fn html_inline(&self, type_: &str, _: &Value) -> HtmlStyle {
+                match type_ {
+                    "highlight" => HtmlStyle::Element(HtmlElement::new("mark")),
+                    _ => HtmlStyle::Drop,
+                }
+            }
+
+            fn html_block(&self, _: &str, _: &Value) -> HtmlStyle {
+                HtmlStyle::Drop
+            }
+        }
+
+        let mut text = RichText::new(1);
+        text.insert(0, "abc\n");
+        text.annotate(1..2, Style::new_bold_like("highlight".into(), json!(true)))
+            .unwrap();
+        text.annotate(0..3, Style::new_bold_like("bold".into(), json!(true)))
+            .unwrap();
+        text.format_line(0, "header", json!(2)).unwrap();
+        assert_eq!(text.to_markdown(&Highlight), "a==b==c");
+        assert_eq!(text.to_html(&Highlight), "<p>a<mark>b</mark>c</p>");
+    }
+}
+
+mod markup_import {
+    use serde_json::json;
+
+    use super::*;
+    use crate::rich_text::{error::Error, DefaultStyleSerializer, StyleConfig, ValueSchema};
+
+    #[test]
+    #[cfg(feature = "markup-import")]
+    fn markdown() {
+        let markdown = "# Title\n\nHello **bold** and [link](https://x.com)\n\n- a\n- b";
+        let mut text = RichText::new(1);
+        text.insert_markdown(0, markdown).unwrap();
+        text.check();
+        assert_eq!(text.to_string(), "Title\nHello bold and link\na\nb\n");
+        assert_eq!(text.line_attributes(0)["header"], json!(1));
+        assert_eq!(text.line_attributes(3)["list"], json!("bullet"));
+        let spans = text.get_line(1).spans;
+        assert_eq!(spans[1].insert, "bold");
+        assert_eq!(spans[1].attributes["bold"], json!(true));
+        assert_eq!(spans[3].attributes["link"], json!("https://x.com"));
+        assert_eq!(
+            text.to_markdown(&DefaultStyleSerializer::default()),
+            markdown
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "markup-import")]
+    fn markdown_code_block() {
+        let mut text = RichText::new(1);
+        text.insert_markdown(0, "a `b`\n\n```\nfn a\n\nc\n```")
+            .unwrap();
+        assert_eq!(text.to_string(), "a b\nfn a\n\nc\n");
+        assert_eq!(text.get_line(0).spans[1].attributes["code"], json!(true));
+        assert!(text.line_attributes(0).is_empty());
+        for line in 1..4 {
+            assert_eq!(text.line_attributes(line)["code-block"], json!(true));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "markup-import")]
+    fn markdown_code_span_round_trip() {
+        let mut text = RichText::new(1);
+        text.insert(0, "run a_b* or `tick` and x");
+        let code = || Style::new_link_like("code".into(), json!(true));
+        text.annotate(4..8, code()).unwrap();
+        text.annotate(12..18, code()).unwrap();
+        let markdown = text.to_markdown(&DefaultStyleSerializer::default());
+        assert_eq!(markdown, "run ``a_b*`` or `` `tick` `` and x");
+        let mut other = RichText::new(2);
+        other.insert_markdown(0, &markdown).unwrap();
+        assert_eq!(other.to_string(), text.to_string());
+        assert_eq!(other.get_spans(), text.get_spans());
+    }
+
+    #[test]
+    #[cfg(feature = "markup-import")]
+    fn markdown_code_block_round_trip() {
+        let mut text = RichText::new(1);
+        text.insert_markdown(0, "```\nlet *a* = `b`;\n\\x\n```")
+            .unwrap();
+        let markdown = text.to_markdown(&DefaultStyleSerializer::default());
+        assert_eq!(markdown, "    let *a* = `b`;\n    \\x");
+        let mut other = RichText::new(2);
+        other.insert_markdown(0, &markdown).unwrap();
+        assert_eq!(other.to_string(), "let *a* = `b`;\n\\x\n");
+        assert_eq!(other.get_spans(), text.get_spans());
+    }
+
+    #[test]
+    #[cfg(feature = "markup-import")]
+    fn markdown_image() {
+        let mut text = RichText::new(1);
+        text.insert_markdown(0, "a![alt](https://x.com/a.png)b")
+            .unwrap();
+        assert_eq!(text.to_string(), format!("a{EMBED_CHAR}b"));
+        assert_eq!(
+            text.slice(1..2, IndexType::Utf8)[0].embed,
+            Some(json!({ "image": "https://x.com/a.png" }))
+        );
+    }
+
+    #[test]
+    fn html() {
+        let html = "<h2>T</h2><p>a <b>b</b> &amp; <a href=\"https://x.com\">c</a></p>\
+                    <ul><li>x</li></ul>";
+        let mut text = RichText::new(1);
+        text.insert_html(
+            0,
+            &format!("<html><head><title>t</title></head>{html}<script>bad()</script>"),
+        )
+        .unwrap();
+        text.check();
+        assert_eq!(text.to_string(), "T\na b & c\nx\n");
+        assert_eq!(text.to_html(&DefaultStyleSerializer::default()), html);
+    }
+
+    #[test]
+    fn html_text() {
+        let mut text = RichText::new(1);
+        text.insert_html(
+            0,
+            "<p>  a\n  b&lt;&#x41;&#66;&nbsp;</p><!-- x --><br><p>c < d</p>",
+        )
+        .unwrap();
+        assert_eq!(text.to_string(), "a b<AB\u{A0}\n\nc < d");
+        text.insert_html(0, "<pre>x\n  y</pre>").unwrap();
+        assert_eq!(text.line_attributes(1)["code-block"], json!(true));
+        assert_eq!(text.get_line(1).spans[0].insert, "  y");
+    }
+
+    #[test]
+    #[cfg(feature = "markup-import")]
+    fn insert_in_one_event() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab");
+        let receiver = text.observe_channel();
+        text.insert_markdown(1, "**x** *y*").unwrap();
+        assert_eq!(text.to_string(), "ax yb");
+        assert_eq!(receiver.try_iter().count(), 1);
+        let spans = text.get_spans();
+        assert_eq!(spans[1].insert, "x");
+        assert!(spans[1].attributes.contains_key("bold"));
+        assert_eq!(spans[3].insert, "y");
+        assert!(spans[3].attributes.contains_key("italic"));
+    }
+
+    #[test]
+    fn insert_at_utf16_and_unicode_index() {
+        let mut text = RichText::new(1);
+        text.insert(0, "😀好");
+        text.insert_html_utf16(2, "<b>a</b>").unwrap();
+        text.insert_html_unicode(3, "<i>b</i>").unwrap();
+        assert_eq!(text.to_string(), "😀a好b");
+        let spans = text.get_spans();
+        assert_eq!(spans[1].insert, "a");
+        assert!(spans[1].attributes.contains_key("bold"));
+        assert_eq!(spans[3].insert, "b");
+        assert!(spans[3].attributes.contains_key("italic"));
+    }
+
+    #[test]
+    #[cfg(feature = "markup-import")]
+    fn insert_markdown_at_unicode_index() {
+        let mut text = RichText::new(1);
+        text.insert(0, "😀好");
+        text.insert_markdown_unicode(1, "**a**").unwrap();
+        text.insert_markdown_utf16(0, "*b*").unwrap();
+        assert_eq!(text.to_string(), "b😀a好");
+        let spans = text.get_spans();
+        assert!(spans[0].attributes.contains_key("italic"));
+        assert_eq!(spans[2].insert, "a");
+        assert!(spans[2].attributes.contains_key("bold"));
+    }
+
+    #[test]
+    fn invalid_value() {
+        let mut text = RichText::new(1);
+        text.style_schema_mut().register(
+            "bold",
+            StyleConfig::new_bold_like().with_value(ValueSchema::Number),
+        );
+        let result = text.insert_html(0, "a <b>b</b>");
+        assert!(matches!(result, Err(Error::InvalidStyleValue(..))));
+        assert!(text.is_empty());
+    }
+}
+
+mod sim {
+    use rand::Rng;
+
+    use crate::sim::{NetworkConfig, Simulation};
+
+    fn random_run(seed: u64, config: NetworkConfig) -> Simulation {
+        let mut sim = Simulation::new(4, seed, config);
+        for i in 0..200 {
+            let peer = sim.rng().gen_range(0..4);
+            sim.random_edit(peer);
+            if sim.rng().gen_bool(0.3) {
+                sim.broadcast(peer);
+            }
+            match i {
+                50 => sim.partition(&[&[0, 1], &[2, 3]]),
+                120 => sim.heal(),
+                _ => {}
+            }
+            sim.tick();
+        }
+        sim
+    }
+
+    #[test]
+    fn reliable_network() {
+        let mut sim = Simulation::new(3, 0, NetworkConfig::reliable());
+        sim.peer_mut(0).insert(0, "abc");
+        sim.broadcast(0);
+        sim.tick();
+        sim.peer_mut(1).insert(3, "d");
+        sim.peer_mut(2).delete(0..1);
+        sim.broadcast_all();
+        assert_eq!(sim.in_flight(), 4);
+        sim.tick();
+        assert_eq!(sim.in_flight(), 0);
+        sim.assert_converged();
+        assert_eq!(sim.peer(0).to_string(), "bcd");
+    }
+
+    #[test]
+    fn converge_after_settle() {
+        for seed in 0..10 {
+            let mut sim = random_run(seed, NetworkConfig::unreliable());
+            sim.settle();
+            sim.assert_converged();
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let a = random_run(7, NetworkConfig::unreliable());
+        let b = random_run(7, NetworkConfig::unreliable());
+        assert_eq!(a.now(), b.now());
+        assert_eq!(a.in_flight(), b.in_flight());
+        for (a, b) in a.peers().iter().zip(b.peers()) {
+            assert_eq!(a.get_spans(), b.get_spans());
+        }
+    }
+
+    #[test]
+    fn drop_and_partition() {
+        let config = NetworkConfig {
+            drop_rate: 1.0,
+            ..NetworkConfig::reliable()
+        };
+        let mut sim = Simulation::new(2, 0, config);
+        sim.peer_mut(0).insert(0, "a");
+        sim.broadcast(0);
+        sim.run(10);
+        assert!(sim.peer(1).is_empty());
+
+        let mut sim = Simulation::new(3, 0, NetworkConfig::reliable());
+        sim.partition(&[&[0], &[1]]);
+        sim.peer_mut(0).insert(0, "a");
+        sim.broadcast(0);
+        sim.run(10);
+        assert!(sim.is_partitioned(0, 2));
+        assert!(sim.peer(1).is_empty());
+        assert!(sim.peer(2).is_empty());
+        sim.settle();
+        sim.assert_converged();
+        assert_eq!(sim.peer(2).to_string(), "a");
+    }
+
+    #[test]
+    fn compacted_and_uncompacted_peers() {
+        let mut sim = Simulation::new(3, 0, NetworkConfig::reliable());
+        sim.peer_mut(0).insert(0, "abcde");
+        sim.broadcast(0);
+        sim.tick();
+        // peer 1 inserts after "c" while peer 2 deletes it
+        sim.peer_mut(1).insert(3, "X");
+        sim.peer_mut(2).delete(2..3);
+        sim.broadcast(2);
+        sim.tick();
+        sim.sync(0, 1);
+        // every peer has the deletion, but peer 2 doesn't have "X" yet
+        let vv = sim.peer(2).version();
+        sim.peer_mut(0).compact(&vv);
+        // "c" is the left origin of "X", so its tombstone is kept
+        assert_eq!(
+            sim.peer(0).content.iter().filter(|x| x.is_dead()).count(),
+            1
+        );
+
+        // concurrent insertions next to the tombstone
+        sim.peer_mut(0).insert(2, "Z");
+        sim.peer_mut(1).insert(2, "W");
+        sim.peer_mut(2).insert(2, "Y");
+        sim.settle();
+        sim.assert_converged();
+        assert_eq!(sim.peer(2).len(), 8);
+    }
+
+    #[test]
+    fn sync_peer_behind_compaction() {
+        let mut sim = Simulation::new(2, 0, NetworkConfig::reliable());
+        sim.partition(&[&[0], &[1]]);
+        sim.peer_mut(0).insert(0, "hello");
+        sim.broadcast(0);
+        let vv = sim.peer(0).version();
+        sim.peer_mut(0).compact(&vv);
+        sim.peer_mut(1).insert(0, "world");
+        sim.broadcast(1);
+        sim.settle();
+        sim.assert_converged();
+        assert_eq!(sim.peer(1).len(), 10);
+        assert_eq!(sim.peer(1).id(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn diverged() {
+        let mut sim = Simulation::new(2, 0, NetworkConfig::reliable());
+        sim.peer_mut(0).insert(0, "a");
+        sim.assert_converged();
+    }
+}
+
+mod undo {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::rich_text::test_utils::{fuzzing_undo, fuzzing_undo_all};
+
+    fn undo_text(client_id: u64) -> RichText {
+        let mut text = RichText::new(client_id);
+        text.enable_undo();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::ZERO);
+        text
+    }
+
+    #[test]
+    fn undo_insert() {
+        let mut text = undo_text(1);
+        text.insert(0, "hello");
+        text.insert(5, " world");
+        assert!(text.undo().is_some());
+        assert_eq!(text.to_string(), "hello");
+        assert!(text.undo().is_some());
+        assert_eq!(text.to_string(), "");
+        assert!(text.undo().is_none());
+        assert!(text.redo().is_some());
+        assert!(text.redo().is_some());
+        assert_eq!(text.to_string(), "hello world");
+        assert!(text.redo().is_none());
+    }
+
+    #[test]
+    fn after_anchor_of_multibyte_chars() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a你b");
+        let anchor = text.index_to_anchor(4, AnchorType::After, IndexType::Utf8);
+        assert_eq!(anchor, Anchor::after(OpID::new(1, 3)));
+        assert_eq!(text.anchor_to_index(&anchor, IndexType::Utf8), 4);
+
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀");
+        let anchor = text.index_to_anchor(3, AnchorType::After, IndexType::Utf16);
+        assert_eq!(anchor, Anchor::after(OpID::new(1, 4)));
+        text.insert_utf16(3, "b");
+        assert_eq!(text.anchor_to_index(&anchor, IndexType::Utf16), 3);
+    }
+
+    #[test]
+    fn undo_annotation_of_emoji() {
+        let mut text = undo_text(1);
+        text.insert(0, "a😀");
+        text.annotate_utf16(1..3, bold()).unwrap();
+        text.undo();
+        assert!(text.get_spans().iter().all(|x| x.attributes.is_empty()));
+        text.redo();
+        assert_eq!(text.get_spans()[1].insert, "😀");
+        assert!(text.get_spans()[1].attributes.contains_key("bold"));
+    }
+
+    #[test]
+    fn undo_delete_should_restore_styles() {
+        let mut text = undo_text(1);
+        text.insert(0, "hello world");
+        text.annotate(0..5, bold()).unwrap();
+        text.delete(0..6);
+        assert_eq!(text.to_string(), "world");
+        text.undo();
+        assert_eq!(text.to_string(), "hello world");
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].insert, "hello");
+        assert!(spans[0].attributes.contains_key("bold"));
+        assert_eq!(spans[1].insert, " world");
+        assert!(spans[1].attributes.is_empty());
+    }
+
+    #[test]
+    fn undo_annotate() {
+        let mut text = undo_text(1);
+        text.insert(0, "hello");
+        text.annotate(0..5, bold()).unwrap();
+        text.undo();
+        assert_eq!(text.get_spans().len(), 1);
+        assert!(text.get_spans()[0].attributes.is_empty());
+        text.redo();
+        assert!(text.get_spans()[0].attributes.contains_key("bold"));
+    }
+
+    #[test]
+    fn undo_annotate_should_restore_prev_value() {
+        let mut text = undo_text(1);
+        text.insert(0, "hello world");
+        let mut style = link();
+        style.value = "a".into();
+        text.annotate(0..11, style.clone()).unwrap();
+        style.value = "b".into();
+        text.annotate(0..5, style).unwrap();
+        assert_eq!(text.get_spans().len(), 2);
+        text.undo();
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].attributes.get("link"), Some(&"a".into()));
+    }
+
+    #[test]
+    fn undo_unbold() {
+        let mut text = undo_text(1);
+        text.insert(0, "hello");
+        text.annotate(0..5, bold()).unwrap();
+        text.annotate(1..3, unbold()).unwrap();
+        assert_eq!(text.get_spans().len(), 3);
+        text.undo();
+        assert_eq!(text.get_spans().len(), 1);
+        assert!(text.get_spans()[0].attributes.contains_key("bold"));
+    }
+
+    #[test]
+    fn should_not_undo_remote_edits() {
+        let mut a = undo_text(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "hello");
+        b.merge(&a);
+        b.insert(5, " world");
+        a.merge(&b);
+        assert!(a.undo().is_some());
+        assert_eq!(a.to_string(), " world");
+        assert!(a.undo().is_none());
+        b.merge(&a);
+        assert_eq!(b.to_string(), " world");
+    }
+
+    #[test]
+    fn undo_insert_after_its_deletion_is_undone() {
+        let mut text = undo_text(1);
+        text.insert(0, "abc");
+        text.delete(1..2);
+        text.undo();
+        assert_eq!(text.to_string(), "abc");
+        text.undo();
+        assert_eq!(text.to_string(), "");
+        text.redo();
+        assert_eq!(text.to_string(), "abc");
+        text.redo();
+        assert_eq!(text.to_string(), "ac");
+        text.undo();
+        text.undo();
+        assert_eq!(text.to_string(), "");
+    }
+
+    #[test]
+    fn undo_utf16() {
+        let mut text = undo_text(1);
+        text.insert(0, "你好");
+        text.delete_utf16(0..1);
+        text.insert_utf16(1, "呀");
+        assert_eq!(text.to_string(), "好呀");
+        text.undo();
+        text.undo();
+        assert_eq!(text.to_string(), "你好");
+        text.undo();
+        assert_eq!(text.to_string(), "");
+    }
+
+    #[test]
+    fn new_edit_should_clear_redo() {
+        let mut text = undo_text(1);
+        text.insert(0, "a");
+        text.undo();
+        text.insert(0, "b");
+        assert!(text.redo().is_none());
+        assert_eq!(text.to_string(), "b");
+    }
+
+    /// Use a clock that only moves when the returned time is advanced
+    fn manual_clock(text: &mut RichText) -> Arc<Mutex<Instant>> {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let clock = now.clone();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_clock(move || *clock.lock().unwrap());
+        now
+    }
+
+    #[test]
+    fn merge_by_interval() {
+        let mut text = RichText::new(1);
+        text.enable_undo();
+        text.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::from_millis(500));
+        let now = manual_clock(&mut text);
+        text.insert(0, "a");
+        *now.lock().unwrap() += Duration::from_millis(300);
+        text.insert(1, "b");
+        *now.lock().unwrap() += Duration::from_millis(300);
+        text.delete(0..1);
+        assert_eq!(text.undo_manager().unwrap().undo_len(), 1);
+        *now.lock().unwrap() += Duration::from_millis(500);
+        text.insert(1, "c");
+        assert_eq!(text.undo_manager().unwrap().undo_len(), 2);
+        text.undo();
+        assert_eq!(text.to_string(), "b");
+        text.undo();
+        assert_eq!(text.to_string(), "");
+    }
+
+    #[test]
+    fn group() {
+        let mut text = undo_text(1);
+        text.undo_manager_mut().unwrap().start_group();
+        text.insert(0, "a");
+        text.insert(1, "b");
+        text.undo_manager_mut().unwrap().end_group();
+        text.insert(2, "c");
+        text.undo();
+        assert_eq!(text.to_string(), "ab");
+        text.undo();
+        assert_eq!(text.to_string(), "");
+    }
+
+    #[test]
+    fn restore_selection() {
+        let mut text = undo_text(1);
+        text.insert(0, "hello");
+        text.set_undo_selection(5..5, IndexType::Utf8);
+        text.insert(5, " world");
+        text.set_undo_selection(11..11, IndexType::Utf8);
+        let outcome = text.undo().unwrap();
+        assert_eq!(outcome.selection, Some(5..5));
+        let outcome = text.redo().unwrap();
+        assert_eq!(outcome.selection, Some(11..11));
+    }
+
+    #[test]
+    fn fuzz_undo_all() {
+        use crate::{rich_text::test_utils::Action::*, test_utils::AnnotationType::*};
+        fuzzing_undo_all(vec![
+            Insert {
+                actor: 0,
+                pos: 0,
+                content: 1234,
+            },
+            Annotate {
+                actor: 0,
+                pos: 1,
+                len: 2,
+                annotation: Bold,
+            },
+            Insert {
+                actor: 0,
+                pos: 2,
+                content: 56,
+            },
+            Delete {
+                actor: 0,
+                pos: 1,
+                len: 3,
+            },
+            Annotate {
+                actor: 0,
+                pos: 0,
+                len: 3,
+                annotation: Link,
+            },
+            Delete {
+                actor: 0,
+                pos: 0,
+                len: 2,
+            },
+        ])
+    }
+
+    #[test]
+    fn fuzz_undo_five_actors() {
+        use crate::{
+            rich_text::test_utils::{Action::*, UndoFuzzAction},
+            test_utils::AnnotationType::*,
+        };
+        fuzzing_undo(
+            5,
+            vec![
+                UndoFuzzAction::Edit(Insert {
+                    actor: 0,
+                    pos: 0,
+                    content: 1234,
+                }),
+                UndoFuzzAction::Edit(Sync(1, 0)),
+                UndoFuzzAction::Edit(Delete {
+                    actor: 1,
+                    pos: 1,
+                    len: 2,
+                }),
+                UndoFuzzAction::Edit(Annotate {
+                    actor: 0,
+                    pos: 0,
+                    len: 3,
+                    annotation: Bold,
+                }),
+                UndoFuzzAction::Undo(0),
+                UndoFuzzAction::Edit(Sync(0, 1)),
+                UndoFuzzAction::Undo(0),
+                UndoFuzzAction::Undo(1),
+                UndoFuzzAction::Redo(0),
+            ],
+        )
+    }
+}
+
+mod delta {
+    use std::sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    };
+
+    use fxhash::FxHashMap;
+    use serde_json::Value;
+
+    use crate::{
+        rich_text::{DeltaItem, IndexType},
+        RichText, Style,
+    };
+
+    #[test]
+    fn append_newline_if_no_long_enough() {
+        let mut text = RichText::new(1);
+        text.insert(0, "测试123");
+        let mut attributes: FxHashMap<_, _> = Default::default();
+        attributes.insert("header".into(), Value::Bool(true));
+        text.apply_delta(
+            vec![
+                DeltaItem::retain(5),
+                DeltaItem::retain_with_attributes(1, attributes),
+            ]
+            .into_iter(),
+            crate::rich_text::IndexType::Utf16,
+        )
+        .unwrap();
+        let spans = text.get_spans();
+        assert_eq!(spans[0].len(), 9);
+        assert_eq!(&spans[1].insert, "\n");
+    }
+
+    #[test]
+    fn apply_insert_should_remove_attributes_that_dont_exist() {
+        let mut text = RichText::new(1);
+        text.insert(0, "测试123");
+        text.annotate_utf16(0..2, Style::new_bold_like("a".into(), Value::Bool(true)))
+            .unwrap();
+        text.apply_delta(
+            vec![
+                DeltaItem::retain(1),
+                DeltaItem::insert("k".into(), IndexType::Utf16),
+            ]
+            .into_iter(),
+            IndexType::Utf16,
+        )
+        .unwrap();
+
+        let spans = text.get_spans();
+        // &spans = [
+        //     Span {
+        //         insert: "测",
+        //         attributes: {
+        //             Atom('a' type=inline): Bool(true),
+        //         },
+        //     },
+        //     Span {
+        //         insert: "k",
+        //         attributes: {},
+        //     },
+        //     Span {
+        //         insert: "试",
+        //         attributes: {
+        //             Atom('a' type=inline): Bool(true),
+        //         },
+        //     },
+        //     Span {
+        //         insert: "123",
+        //         attributes: {},
+        //     },
+        // ]
+        assert_eq!(spans.len(), 4);
+        assert_eq!(spans[0].len(), 3);
+        assert!(!spans[0].attributes.is_empty());
+        assert_eq!(spans[1].len(), 1);
+        assert!(spans[1].attributes.is_empty());
+        assert_eq!(spans[2].len(), 3);
+        assert!(!spans[2].attributes.is_empty());
+        assert_eq!(spans[3].len(), 3);
+        assert!(spans[3].attributes.is_empty());
+    }
+
+    #[test]
+    fn delta_event_insert_should_contain_all_attributes_simple() {
+        let mut text = RichText::new(1);
+        text.set_event_index_type(IndexType::Utf16);
+        text.insert(0, "1");
+        text.annotate(0..1, Style::new_bold_like("a".into(), Value::Bool(true)))
+            .unwrap();
+        let invoked = Arc::new(AtomicBool::new(false));
+        let invoked_bk = Arc::clone(&invoked);
+        text.observe(Box::new(move |event| {
+            assert!(event.is_local);
+            assert_eq!(event.index_type, IndexType::Utf16);
+            assert_eq!(event.ops.len(), 2);
+            assert_eq!(
+                event.ops,
+                vec![
+                    DeltaItem::retain(1),
+                    DeltaItem::insert_with_attributes(
+                        "k".into(),
+                        IndexType::Utf16,
+                        vec![("a".into(), Value::Bool(true)),].into_iter().collect()
+                    ),
+                ]
+            );
+            invoked.store(true, atomic::Ordering::SeqCst);
+        }))
+        .detach();
+        text.insert(1, "k");
+        let v = invoked_bk.load(atomic::Ordering::SeqCst);
+        assert!(v);
+    }
+
+    #[test]
+    fn delta_event_insert_should_contain_all_attributes() {
+        let mut text = RichText::new(1);
+        text.insert(0, "12345");
+        text.annotate(1..2, Style::new_bold_like("a".into(), Value::Bool(true)))
+            .unwrap();
+        text.annotate(0..4, Style::new_bold_like("b".into(), Value::Bool(true)))
+            .unwrap();
+        let invoked = Arc::new(AtomicBool::new(false));
+        let invoked_bk = Arc::clone(&invoked);
+        text.observe(Box::new(move |event| {
+            assert!(event.is_local);
+            assert_eq!(event.index_type, IndexType::Utf8);
+            assert_eq!(event.ops.len(), 2);
+            assert_eq!(
+                event.ops,
+                vec![
+                    DeltaItem::retain(2),
+                    DeltaItem::insert_with_attributes(
+                        "k".into(),
+                        IndexType::Utf8,
+                        vec![
+                            ("a".into(), Value::Bool(true)),
+                            ("b".into(), Value::Bool(true)),
+                        ]
+                        .into_iter()
+                        .collect()
+                    ),
+                ]
+            );
+            invoked.store(true, atomic::Ordering::SeqCst);
+        }))
+        .detach();
         text.insert(2, "k");
         let v = invoked_bk.load(atomic::Ordering::SeqCst);
         assert!(v);
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, rc::Rc};
+use std::sync::{Arc, Mutex};
 
 use crate::{test_utils::AnnotationType, InternalString};
 
@@ -7,6 +7,8 @@ use arbitrary::Arbitrary;
 
 mod fuzz_line_breaks;
 pub use fuzz_line_breaks::{fuzzing_line_break, Action as LineBreakFuzzAction};
+mod fuzz_undo;
+pub use fuzz_undo::{fuzzing_undo, fuzzing_undo_all, Action as UndoFuzzAction};
 
 pub struct Actor {
     pub text: RichText,
@@ -226,14 +228,14 @@ pub fn fuzzing(actor_num: usize, actions: Vec<Action>) {
 pub fn fuzzing_utf16(actor_num: usize, actions: Vec<Action>) {
     let mut actors = vec![];
     let followers = vec![
-        Rc::new(RefCell::new(String::new())),
-        Rc::new(RefCell::new(String::new())),
+        Arc::new(Mutex::new(String::new())),
+        Arc::new(Mutex::new(String::new())),
     ];
     for i in 0..actor_num {
         if i <= 1 {
             let mut actor = Actor::new(i);
             let f = followers[i].clone();
-            actor.text.observe(Box::new(move |event| {
+            let subscription = actor.text.observe(Box::new(move |event| {
                 let mut index = 0;
                 for op in event.ops.iter() {
                     match op {
@@ -241,15 +243,22 @@ pub fn fuzzing_utf16(actor_num: usize, actions: Vec<Action>) {
                             index += *retain;
                         }
                         crate::rich_text::delta::DeltaItem::Insert { insert, .. } => {
-                            f.borrow_mut().insert_str(index, insert);
+                            f.lock().unwrap().insert_str(index, insert);
                             index += insert.len();
                         }
+                        crate::rich_text::delta::DeltaItem::Embed { .. } => {
+                            f.lock()
+                                .unwrap()
+                                .insert(index, crate::rich_text::EMBED_CHAR);
+                            index += 1;
+                        }
                         crate::rich_text::delta::DeltaItem::Delete { delete } => {
-                            f.borrow_mut().drain(index..index + *delete);
+                            f.lock().unwrap().drain(index..index + *delete);
                         }
                     }
                 }
             }));
+            subscription.detach();
 
             actors.push(actor);
         } else {
@@ -276,7 +285,7 @@ pub fn fuzzing_utf16(actor_num: usize, actions: Vec<Action>) {
             debug_log::group_end!();
             assert_eq!(a.text.get_spans(), b.text.get_spans());
             if i <= 1 {
-                assert_eq!(a.text.to_string(), followers[i].borrow().to_string());
+                assert_eq!(a.text.to_string(), followers[i].lock().unwrap().to_string());
             }
         }
     }
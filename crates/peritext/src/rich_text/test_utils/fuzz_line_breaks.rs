@@ -1,4 +1,4 @@
-use std::{cell::RefCell, rc::Rc};
+use std::sync::{Arc, Mutex};
 
 use arbitrary::Arbitrary;
 
@@ -109,9 +109,9 @@ fn apply_to_str(actions: &[Action]) -> String {
 pub fn fuzzing_line_break(mut actions: Vec<Action>) {
     let mut rich_text = RichText::new(1);
     let follower = String::new();
-    let follower_ref = Rc::new(RefCell::new(follower));
-    let follower_bk = Rc::clone(&follower_ref);
-    rich_text.observe(Box::new(move |event| {
+    let follower_ref = Arc::new(Mutex::new(follower));
+    let follower_bk = Arc::clone(&follower_ref);
+    let subscription = rich_text.observe(Box::new(move |event| {
         let mut index = 0;
         for op in event.ops.iter() {
             match op {
@@ -119,22 +119,30 @@ pub fn fuzzing_line_break(mut actions: Vec<Action>) {
                     index += *retain;
                 }
                 crate::rich_text::delta::DeltaItem::Insert { insert, .. } => {
-                    follower_ref.borrow_mut().insert_str(index, insert);
+                    follower_ref.lock().unwrap().insert_str(index, insert);
                     index += insert.len();
                 }
+                crate::rich_text::delta::DeltaItem::Embed { .. } => {
+                    follower_ref
+                        .lock()
+                        .unwrap()
+                        .insert(index, crate::rich_text::EMBED_CHAR);
+                    index += 1;
+                }
                 crate::rich_text::delta::DeltaItem::Delete { delete } => {
-                    follower_ref.borrow_mut().drain(index..index + *delete);
+                    follower_ref.lock().unwrap().drain(index..index + *delete);
                 }
             }
         }
     }));
+    subscription.detach();
 
     preprocess(&mut actions);
     debug_log::debug_dbg!("actions: {:?}", &actions);
     apply(&mut rich_text, &actions);
     let s = apply_to_str(&actions);
     assert_eq!(rich_text.to_string(), s);
-    assert_eq!(&follower_bk.borrow().as_str(), &s);
+    assert_eq!(&follower_bk.lock().unwrap().as_str(), &s);
     if rich_text.is_empty() {
         assert!(s.is_empty());
         return;
@@ -142,7 +150,7 @@ pub fn fuzzing_line_break(mut actions: Vec<Action>) {
     debug_log::debug_dbg!("{:?}", &s);
     rich_text.debug_log(true);
     for (ln, str) in s.split('\n').enumerate() {
-        assert_eq!(&rich_text.get_line(ln)[0].insert.trim(), &str);
+        assert_eq!(&rich_text.get_line(ln).spans[0].insert.trim(), &str);
     }
 }
 
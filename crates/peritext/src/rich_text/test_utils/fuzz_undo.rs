@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+
+use super::{apply_action, preprocess_action, Action as EditAction, Actor};
+
+#[derive(Arbitrary, Clone, Debug, Copy)]
+pub enum Action {
+    Edit(EditAction),
+    Undo(u8),
+    Redo(u8),
+}
+
+fn new_actor(id: usize) -> Actor {
+    let mut actor = Actor::new(id);
+    actor.text.enable_undo();
+    let undo = actor.text.undo_manager_mut().unwrap();
+    undo.set_merge_interval(Duration::ZERO);
+    undo.set_max_stack_len(usize::MAX);
+    actor
+}
+
+/// Undo and redo never break the convergence
+pub fn fuzzing_undo(actor_num: usize, actions: Vec<Action>) {
+    let mut actors = vec![];
+    for i in 0..actor_num {
+        actors.push(new_actor(i));
+    }
+
+    for action in actions {
+        debug_log::group!("{:?},", &action);
+        match action {
+            Action::Edit(mut action) => {
+                preprocess_action(&actors, &mut action);
+                apply_action(&mut actors, action);
+            }
+            Action::Undo(actor) => {
+                let actor = actor as usize % actors.len();
+                actors[actor].text.undo();
+            }
+            Action::Redo(actor) => {
+                let actor = actor as usize % actors.len();
+                actors[actor].text.redo();
+            }
+        }
+        debug_log::group_end!();
+    }
+
+    for i in 0..actors.len() {
+        for j in (i + 1)..actors.len() {
+            let (a, b) = arref::array_mut_ref!(&mut actors, [i, j]);
+            debug_log::group!("merge {i}<-{j}");
+            a.merge(b);
+            debug_log::group_end!();
+            debug_log::group!("merge {i}->{j}");
+            b.merge(a);
+            assert_eq!(a.text.get_spans(), b.text.get_spans());
+            debug_log::group_end!();
+        }
+    }
+}
+
+/// Undo every step of a single actor then redo them, the text should go
+/// through the same states in reverse order
+pub fn fuzzing_undo_all(actions: Vec<EditAction>) {
+    let mut actors = vec![new_actor(1)];
+    let mut snapshots = vec![actors[0].text.to_string()];
+    for mut action in actions {
+        if matches!(action, EditAction::Sync(..)) {
+            continue;
+        }
+
+        preprocess_action(&actors, &mut action);
+        debug_log::group!("{:?},", &action);
+        apply_action(&mut actors, action);
+        debug_log::group_end!();
+        if actors[0].text.undo_manager().unwrap().undo_len() == snapshots.len() {
+            snapshots.push(actors[0].text.to_string());
+        }
+    }
+
+    let text = &mut actors[0].text;
+    for expected in snapshots.iter().rev().skip(1) {
+        assert!(text.undo().is_some());
+        assert_eq!(&text.to_string(), expected);
+    }
+    assert!(text.undo().is_none());
+
+    for expected in snapshots.iter().skip(1) {
+        assert!(text.redo().is_some());
+        assert_eq!(&text.to_string(), expected);
+    }
+    assert!(text.redo().is_none());
+}
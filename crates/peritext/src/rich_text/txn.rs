@@ -0,0 +1,118 @@
+//! Transactions batch several edits into one event and one undo step.
+
+use std::{
+    mem::take,
+    ops::{Deref, DerefMut},
+};
+
+use crate::InternalString;
+
+use super::{delta::compose, event::Event, DeltaItem, RichText};
+
+/// The events emitted inside the current transaction
+#[derive(Debug, Default)]
+pub(super) struct TxnState {
+    ops: Vec<DeltaItem>,
+    /// whether all the events are local, `None` if there is no event
+    is_local: Option<bool>,
+    origin: Option<InternalString>,
+}
+
+impl TxnState {
+    pub fn push(&mut self, event: Event) {
+        self.ops = compose(take(&mut self.ops), event.ops);
+        self.is_local = Some(self.is_local.unwrap_or(true) && event.is_local);
+    }
+}
+
+/// A handle to the document inside [RichText::transact].
+///
+/// It derefs to [RichText], so all the edit methods can be called on it.
+pub struct Transaction<'a> {
+    doc: &'a mut RichText,
+}
+
+impl Transaction<'_> {
+    /// Attach the origin of the edits to the event, e.g. a user id or "paste"
+    pub fn set_origin(&mut self, origin: impl Into<InternalString>) {
+        self.doc.txn.as_mut().unwrap().origin = Some(origin.into());
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = RichText;
+
+    fn deref(&self) -> &Self::Target {
+        self.doc
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.doc
+    }
+}
+
+/// Ends the transaction when it's dropped, so a panic in [RichText::transact] that
+/// is caught doesn't leave the transaction or the undo group open
+struct TxnGuard<'a> {
+    doc: &'a mut RichText,
+}
+
+impl TxnGuard<'_> {
+    fn finish(&mut self) -> Option<TxnState> {
+        let txn = self.doc.txn.take()?;
+        if let Some(undo) = &mut self.doc.undo {
+            undo.end_group();
+        }
+
+        Some(txn)
+    }
+}
+
+impl Drop for TxnGuard<'_> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl RichText {
+    /// Run the edits in `f` as a transaction.
+    ///
+    /// The listeners receive one event that composes all the changes made in `f`,
+    /// after `f` returns. If undo is enabled, the local edits in `f` are undone
+    /// together.
+    ///
+    /// A nested transaction is merged into the outer one. If `f` panics, the
+    /// transaction is ended without emitting the event.
+    pub fn transact<R>(&mut self, f: impl FnOnce(&mut Transaction) -> R) -> R {
+        if self.txn.is_some() {
+            return f(&mut Transaction { doc: self });
+        }
+
+        self.txn = Some(TxnState::default());
+        if let Some(undo) = &mut self.undo {
+            undo.start_group();
+        }
+
+        let mut guard = TxnGuard { doc: self };
+        let ans = f(&mut Transaction { doc: &mut *guard.doc });
+        let txn = guard.finish().unwrap();
+        drop(guard);
+        if let Some(is_local) = txn.is_local {
+            self.emit(Event {
+                ops: txn.ops,
+                is_local,
+                index_type: self.event_index_type,
+                origin: txn.origin,
+            });
+        }
+
+        ans
+    }
+
+    /// Whether it's inside [RichText::transact]
+    pub fn in_transaction(&self) -> bool {
+        self.txn.is_some()
+    }
+}
@@ -0,0 +1,711 @@
+//! Undo/redo of the local edits.
+//!
+//! Only the ops created by the local peer are recorded. Undoing them creates new ops
+//! (delete the inserted text, re-insert the deleted text, restore the styles), so the
+//! result can be merged with other peers like any other edit and the edits of the
+//! remote peers are never reverted.
+
+use std::{
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+use generic_btree::rle::HasLength;
+use serde_json::Value;
+
+use crate::{
+    Anchor, AnchorRange, AnchorType, Behavior, Counter, Expand, InternalString, OpID, PatchAction,
+    Style,
+};
+
+use super::{
+    ann::{AnnIdx, Span},
+    rich_tree::{
+        query::{IndexFinder, IndexType},
+        utf16::{get_unicode_len, get_utf16_len},
+    },
+    RichText,
+};
+
+const DEFAULT_MERGE_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_STACK_LEN: usize = 100;
+
+/// The record of a local op that has enough information to revert it
+#[derive(Debug, Clone)]
+pub(super) enum UndoOp {
+    Insert {
+        id: OpID,
+        len: usize,
+    },
+    Delete {
+        /// the deleted id spans in document order
+        pieces: Vec<(OpID, usize)>,
+        /// the deleted content with its styles
+        spans: Vec<Span>,
+    },
+    Annotate {
+        /// the id of the annotation
+        id: OpID,
+        type_: InternalString,
+        behavior: Behavior,
+        /// the expand type used when the style is added
+        expand: Expand,
+        /// the values of the style inside the range before the annotation
+        prev_values: Vec<(Anchor, Anchor, Option<Value>)>,
+    },
+    RemoveAnnotation {
+        id: OpID,
+        /// the range before the removal
+        range: AnchorRange,
+    },
+    RestoreAnnotation {
+        id: OpID,
+    },
+    SetAnnotationValue {
+        id: OpID,
+        /// the value before the change
+        value: Value,
+    },
+    MoveAnnotation {
+        id: OpID,
+        /// the range before the move
+        range: AnchorRange,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Selection {
+    start: Anchor,
+    end: Anchor,
+    index_type: IndexType,
+}
+
+#[derive(Debug)]
+struct StackItem {
+    ops: Vec<UndoOp>,
+    selection: Option<Selection>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    Undoing,
+    Redoing,
+}
+
+/// Deleted text is re-inserted with new ids when it's restored. The remap is
+/// used to find the new ids of the old ones.
+#[derive(Debug, Clone, Copy)]
+struct Remap {
+    old: OpID,
+    len: usize,
+    new: OpID,
+}
+
+/// The source of the current time, see [UndoManager::set_clock]
+struct Clock(Box<dyn Fn() -> Instant + Send>);
+
+impl std::fmt::Debug for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// The result of [RichText::undo] and [RichText::redo]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoOutcome {
+    /// The selection recorded by [RichText::set_undo_selection] before the reverted change.
+    /// It uses the index type given when it was recorded.
+    pub selection: Option<Range<usize>>,
+}
+
+#[derive(Debug)]
+pub struct UndoManager {
+    undo_stack: Vec<StackItem>,
+    redo_stack: Vec<StackItem>,
+    merge_interval: Duration,
+    max_stack_len: usize,
+    clock: Clock,
+    last_record: Option<Instant>,
+    group_depth: usize,
+    group_has_item: bool,
+    state: State,
+    selection: Option<Selection>,
+    remap: Vec<Remap>,
+}
+
+impl Default for UndoManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UndoManager {
+    pub fn new() -> Self {
+        Self {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            merge_interval: DEFAULT_MERGE_INTERVAL,
+            max_stack_len: DEFAULT_MAX_STACK_LEN,
+            clock: Clock(Box::new(Instant::now)),
+            last_record: None,
+            group_depth: 0,
+            group_has_item: false,
+            state: State::Normal,
+            selection: None,
+            remap: Vec::new(),
+        }
+    }
+
+    /// Local edits that happen within the interval are merged into one undo step.
+    ///
+    /// Use [Duration::ZERO] to disable the merging.
+    pub fn set_merge_interval(&mut self, interval: Duration) {
+        self.merge_interval = interval;
+    }
+
+    /// Replace the clock that decides whether the edits are within the merge interval.
+    /// It's [Instant::now] by default.
+    ///
+    /// A manual clock makes the merging deterministic, e.g. in the tests.
+    pub fn set_clock(&mut self, clock: impl Fn() -> Instant + Send + 'static) {
+        self.clock = Clock(Box::new(clock));
+    }
+
+    pub fn set_max_stack_len(&mut self, len: usize) {
+        self.max_stack_len = len.max(1);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    pub fn undo_len(&self) -> usize {
+        self.undo_stack.len()
+    }
+
+    pub fn redo_len(&self) -> usize {
+        self.redo_stack.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.last_record = None;
+    }
+
+    /// All the local edits until the matching [UndoManager::end_group] are merged
+    /// into one undo step, regardless of the merge interval.
+    ///
+    /// Groups can be nested, only the outermost one takes effect.
+    pub fn start_group(&mut self) {
+        if self.group_depth == 0 {
+            self.group_has_item = false;
+        }
+
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        self.group_depth = self.group_depth.saturating_sub(1);
+        if self.group_depth == 0 {
+            // the next edit should not be merged into the group
+            self.last_record = None;
+        }
+    }
+
+    fn record(&mut self, op: UndoOp) {
+        match self.state {
+            State::Undoing => {
+                self.redo_stack.last_mut().unwrap().ops.push(op);
+                return;
+            }
+            State::Redoing => {
+                self.undo_stack.last_mut().unwrap().ops.push(op);
+                return;
+            }
+            State::Normal => {}
+        }
+
+        self.redo_stack.clear();
+        let now = (self.clock.0)();
+        let new_item = self.undo_stack.is_empty()
+            || if self.group_depth > 0 {
+                !self.group_has_item
+            } else {
+                match self.last_record {
+                    Some(last) => now.duration_since(last) >= self.merge_interval,
+                    None => true,
+                }
+            };
+        if new_item {
+            self.undo_stack.push(StackItem {
+                ops: Vec::new(),
+                selection: self.selection,
+            });
+            if self.undo_stack.len() > self.max_stack_len {
+                self.undo_stack.remove(0);
+            }
+        }
+
+        self.undo_stack.last_mut().unwrap().ops.push(op);
+        self.last_record = Some(now);
+        if self.group_depth > 0 {
+            self.group_has_item = true;
+        }
+    }
+
+    /// Find the current ids of the given id span
+    fn resolve_ids(&self, id: OpID, len: usize, ans: &mut Vec<(OpID, usize)>) {
+        let end_counter = id.counter + len as Counter;
+        for remap in self.remap.iter() {
+            if remap.old.client != id.client {
+                continue;
+            }
+
+            let start = remap.old.counter.max(id.counter);
+            let end = (remap.old.counter + remap.len as Counter).min(end_counter);
+            if start >= end {
+                continue;
+            }
+
+            if id.counter < start {
+                self.resolve_ids(id, (start - id.counter) as usize, ans);
+            }
+            self.resolve_ids(
+                remap.new.inc(start - remap.old.counter),
+                (end - start) as usize,
+                ans,
+            );
+            if end < end_counter {
+                self.resolve_ids(OpID::new(id.client, end), (end_counter - end) as usize, ans);
+            }
+            return;
+        }
+
+        ans.push((id, len));
+    }
+
+    /// The id spans that the recorded steps rely on.
+    /// Their tombstones cannot be removed by compaction.
+    pub(super) fn referenced_ids(&self) -> Vec<(OpID, usize)> {
+        let mut ans = Vec::new();
+        let mut anchors = Vec::new();
+        let selections = self
+            .undo_stack
+            .iter()
+            .chain(self.redo_stack.iter())
+            .map(|item| &item.selection)
+            .chain(std::iter::once(&self.selection));
+        for selection in selections.flatten() {
+            anchors.push(selection.start);
+            anchors.push(selection.end);
+        }
+
+        for item in self.undo_stack.iter().chain(self.redo_stack.iter()) {
+            for op in item.ops.iter() {
+                match op {
+                    UndoOp::Insert { id, len } => ans.push((*id, *len)),
+                    UndoOp::Delete { pieces, .. } => ans.extend_from_slice(pieces),
+                    UndoOp::Annotate { prev_values, .. } => {
+                        for (start, end, _) in prev_values {
+                            anchors.push(*start);
+                            anchors.push(*end);
+                        }
+                    }
+                    UndoOp::RemoveAnnotation { range, .. }
+                    | UndoOp::MoveAnnotation { range, .. } => {
+                        anchors.push(range.start);
+                        anchors.push(range.end);
+                    }
+                    UndoOp::SetAnnotationValue { .. } | UndoOp::RestoreAnnotation { .. } => {}
+                }
+            }
+        }
+
+        ans.extend(self.remap.iter().map(|remap| (remap.new, remap.len)));
+        ans.extend(anchors.into_iter().filter_map(|x| Some((x.id?, 1))));
+        ans
+    }
+
+    fn resolve_anchor(&self, anchor: &Anchor) -> Anchor {
+        match anchor.id {
+            Some(id) => {
+                let mut ans = Vec::with_capacity(1);
+                self.resolve_ids(id, 1, &mut ans);
+                Anchor {
+                    id: Some(ans[0].0),
+                    type_: anchor.type_,
+                }
+            }
+            None => *anchor,
+        }
+    }
+}
+
+impl RichText {
+    /// Start recording the local edits, so that they can be reverted by [RichText::undo]
+    pub fn enable_undo(&mut self) {
+        if self.undo.is_none() {
+            self.undo = Some(UndoManager::new());
+        }
+    }
+
+    pub fn undo_manager(&self) -> Option<&UndoManager> {
+        self.undo.as_ref()
+    }
+
+    pub fn undo_manager_mut(&mut self) -> Option<&mut UndoManager> {
+        self.undo.as_mut()
+    }
+
+    /// Record the current selection of the user.
+    ///
+    /// It's stored with the next undo step, and it's returned when the step is undone.
+    pub fn set_undo_selection(&mut self, selection: Range<usize>, index_type: IndexType) {
+        if self.undo.is_none() {
+            return;
+        }
+
+        let start = self.index_to_anchor(selection.start, AnchorType::Before, index_type);
+        let end = self.index_to_anchor(selection.end, AnchorType::Before, index_type);
+        self.undo.as_mut().unwrap().selection = Some(Selection {
+            start,
+            end,
+            index_type,
+        });
+    }
+
+    /// Revert the last local undo step.
+    ///
+    /// Return `None` if there is nothing to undo
+    pub fn undo(&mut self) -> Option<UndoOutcome> {
+        self.undo_or_redo(true)
+    }
+
+    /// Revert the last undo.
+    ///
+    /// Return `None` if there is nothing to redo
+    pub fn redo(&mut self) -> Option<UndoOutcome> {
+        self.undo_or_redo(false)
+    }
+
+    fn undo_or_redo(&mut self, is_undo: bool) -> Option<UndoOutcome> {
+        let manager = self.undo.as_mut()?;
+        let item = if is_undo {
+            manager.undo_stack.pop()?
+        } else {
+            manager.redo_stack.pop()?
+        };
+
+        // the ops created here are recorded to the opposite stack
+        let new_item = StackItem {
+            ops: Vec::new(),
+            selection: manager.selection,
+        };
+        if is_undo {
+            manager.redo_stack.push(new_item);
+            manager.state = State::Undoing;
+        } else {
+            manager.undo_stack.push(new_item);
+            manager.state = State::Redoing;
+        }
+
+        for op in item.ops.iter().rev() {
+            self.apply_inverse(op);
+        }
+
+        let manager = self.undo.as_mut().unwrap();
+        manager.state = State::Normal;
+        manager.last_record = None;
+        manager.selection = item.selection;
+
+        let selection = item.selection.map(|selection| {
+            let manager = self.undo.as_ref().unwrap();
+            let start = manager.resolve_anchor(&selection.start);
+            let end = manager.resolve_anchor(&selection.end);
+            self.anchor_to_index(&start, selection.index_type)
+                ..self.anchor_to_index(&end, selection.index_type)
+        });
+        Some(UndoOutcome { selection })
+    }
+
+    fn apply_inverse(&mut self, op: &UndoOp) {
+        match op {
+            UndoOp::Insert { id, len } => {
+                let mut spans = Vec::new();
+                self.undo
+                    .as_ref()
+                    .unwrap()
+                    .resolve_ids(*id, *len, &mut spans);
+                for (id, len) in spans {
+                    self.delete_id_span(id, len);
+                }
+            }
+            UndoOp::Delete { pieces, spans } => self.restore_deleted(pieces, spans),
+            UndoOp::Annotate {
+                id,
+                behavior: Behavior::AllowMultiple,
+                ..
+            } => {
+                // It cannot be reverted by annotating the range again,
+                // because the ranges with AllowMultiple behavior never override each other.
+                // It may be removed by others already
+                let _ = self.remove_annotation(*id);
+            }
+            UndoOp::Annotate {
+                type_,
+                expand,
+                prev_values,
+                ..
+            } => {
+                for (start, end, value) in prev_values {
+                    let manager = self.undo.as_ref().unwrap();
+                    let start = manager.resolve_anchor(start);
+                    let end = manager.resolve_anchor(end);
+                    let start = self.anchor_to_index(&start, IndexType::Utf8);
+                    let end = self.anchor_to_index(&end, IndexType::Utf8);
+                    if start >= end {
+                        continue;
+                    }
+
+                    let style = match value {
+                        Some(value) => Style {
+                            expand: *expand,
+                            behavior: Behavior::Merge,
+                            type_: type_.clone(),
+                            value: value.clone(),
+                        },
+                        None => Style {
+                            expand: expand.toggle(),
+                            behavior: Behavior::Delete,
+                            type_: type_.clone(),
+                            value: Value::Null,
+                        },
+                    };
+                    self.annotate_inner(start..end, style, IndexType::Utf8);
+                }
+            }
+            UndoOp::RemoveAnnotation { id, range } => {
+                // it keeps its id, so the references to it (e.g. the threads of a comment) still work
+                let manager = self.undo.as_ref().unwrap();
+                let range = AnchorRange {
+                    start: manager.resolve_anchor(&range.start),
+                    end: manager.resolve_anchor(&range.end),
+                };
+                // it may be restored by others already
+                let _ = self.patch_annotation(*id, PatchAction::Restore(range));
+            }
+            UndoOp::RestoreAnnotation { id } => {
+                // it may be removed by others already
+                let _ = self.remove_annotation(*id);
+            }
+            UndoOp::SetAnnotationValue { id, value } => {
+                // the annotation may be removed already
+                let _ = self.update_annotation_value(*id, value.clone());
+            }
+            UndoOp::MoveAnnotation { id, range } => {
+                let manager = self.undo.as_ref().unwrap();
+                let range = AnchorRange {
+                    start: manager.resolve_anchor(&range.start),
+                    end: manager.resolve_anchor(&range.end),
+                };
+                // the annotation may be removed already
+                let _ = self.patch_annotation(*id, PatchAction::Move(range));
+            }
+        }
+    }
+
+    /// Delete the alive chars inside the id span
+    fn delete_id_span(&mut self, mut id: OpID, mut len: usize) {
+        while len > 0 {
+            let cursor = self.find_cursor(id);
+            let (is_dead, piece_len) = {
+                let node = self.content.get_node(cursor.leaf);
+                let elem = &node.elements()[cursor.elem_index];
+                (elem.is_dead(), len.min(elem.rle_len() - cursor.offset))
+            };
+            if !is_dead {
+                let index = self.get_index_from_path(cursor, IndexType::Utf8);
+                self.delete_inner(index..index + piece_len, IndexType::Utf8);
+            }
+
+            id = id.inc(piece_len as Counter);
+            len -= piece_len;
+        }
+    }
+
+    /// Insert the deleted content back to where it was, with the same styles
+    fn restore_deleted(&mut self, pieces: &[(OpID, usize)], spans: &[Span]) {
+        let Some(&(first, _)) = pieces.first() else {
+            return;
+        };
+
+        let index = self.anchor_to_index(&Anchor::before(first), IndexType::Utf8);
+        let mut new_id = self.next_id();
+        let mut start = index;
+        for span in spans {
+            // the new ids are continuous, as they are inserted one after another
+            let embed = span.embed.clone().map(Arc::new);
+            self.insert_with_embed(start, &span.insert, embed, IndexType::Utf8);
+            start += span.len();
+        }
+
+        let mut start = index;
+        for span in spans {
+            let end = start + span.len();
+            self.restore_styles(start..end, &span.attributes);
+            start = end;
+        }
+
+        let manager = self.undo.as_mut().unwrap();
+        for &(old, len) in pieces {
+            manager.remap.push(Remap {
+                old,
+                len,
+                new: new_id,
+            });
+            new_id = new_id.inc(len as Counter);
+        }
+    }
+
+    /// Make the styles of the re-inserted text match the styles it had before deletion
+    fn restore_styles(
+        &mut self,
+        range: Range<usize>,
+        attributes: &FxHashMap<InternalString, Value>,
+    ) {
+        let current = self
+            .iter_range(range.clone(), IndexType::Utf8)
+            .next()
+            .unwrap()
+            .attributes;
+        for key in current.keys() {
+            if !attributes.contains_key(key) {
+                let style = self.schema.erase_style(key);
+                self.annotate_inner(range.clone(), style, IndexType::Utf8);
+            }
+        }
+
+        for (key, value) in attributes {
+            if current.get(key) == Some(value) {
+                continue;
+            }
+
+            let style = Style {
+                expand: self.schema.get(key).expand,
+                behavior: Behavior::Merge,
+                type_: key.clone(),
+                value: value.clone(),
+            };
+            self.annotate_inner(range.clone(), style, IndexType::Utf8);
+        }
+    }
+
+    #[inline]
+    pub(super) fn record_undo(&mut self, op: UndoOp) {
+        if let Some(undo) = &mut self.undo {
+            undo.record(op);
+        }
+    }
+
+    /// Capture the content that is going to be deleted by a local deletion
+    pub(super) fn capture_deletion(
+        &self,
+        start: usize,
+        end: usize,
+        index_type: IndexType,
+    ) -> Option<UndoOp> {
+        let spans = self.slice(start..end, index_type);
+        let start = self.content.query::<IndexFinder>(&(start, index_type));
+        let end = self.content.query::<IndexFinder>(&(end, index_type));
+        let mut pieces = Vec::new();
+        for span in self.content.iter_range(start..end) {
+            if span.elem.is_dead() {
+                continue;
+            }
+
+            let start = span.start.unwrap_or(0);
+            let end = span.end.unwrap_or(span.elem.rle_len());
+            if end > start {
+                pieces.push((span.elem.id.inc(start as Counter), end - start));
+            }
+        }
+
+        if pieces.is_empty() {
+            None
+        } else {
+            Some(UndoOp::Delete { pieces, spans })
+        }
+    }
+
+    /// Capture the values of the style inside the range before it's annotated
+    pub(super) fn capture_annotation(
+        &self,
+        range: Range<usize>,
+        style: &Style,
+        index_type: IndexType,
+    ) -> UndoOp {
+        let mut prev_values: Vec<(Anchor, Anchor, Option<Value>)> = Vec::new();
+        if style.behavior != Behavior::AllowMultiple {
+            let mut index = range.start;
+            for span in self.iter_range(range, index_type) {
+                let len = match index_type {
+                    IndexType::Utf8 => span.len(),
+                    IndexType::Utf16 => get_utf16_len(&span.insert),
+                    IndexType::Unicode => get_unicode_len(&span.insert),
+                };
+                let value = span.attributes.get(&style.type_).cloned();
+                let end = self.index_to_anchor(index + len, AnchorType::After, index_type);
+                match prev_values.last_mut() {
+                    Some(last) if last.2 == value => last.1 = end,
+                    _ => prev_values.push((
+                        self.index_to_anchor(index, AnchorType::Before, index_type),
+                        end,
+                        value,
+                    )),
+                }
+                index += len;
+            }
+        }
+
+        UndoOp::Annotate {
+            // the annotation is the next local op
+            id: self.next_id(),
+            type_: style.type_.clone(),
+            behavior: style.behavior,
+            expand: if style.behavior == Behavior::Delete {
+                style.expand.toggle()
+            } else {
+                style.expand
+            },
+            prev_values,
+        }
+    }
+
+    /// Capture the state of the annotation before it's patched
+    pub(super) fn capture_patch(&self, idx: AnnIdx, action: &PatchAction) -> UndoOp {
+        let ann = self.ann.get_ann_by_idx(idx).unwrap();
+        match action {
+            PatchAction::Remove => UndoOp::RemoveAnnotation {
+                id: ann.id,
+                range: ann.range.clone(),
+            },
+            PatchAction::Restore(_) => UndoOp::RestoreAnnotation { id: ann.id },
+            PatchAction::SetValue(_) => UndoOp::SetAnnotationValue {
+                id: ann.id,
+                value: ann.value.clone(),
+            },
+            PatchAction::Move(_) => UndoOp::MoveAnnotation {
+                id: ann.id,
+                range: ann.range.clone(),
+            },
+        }
+    }
+}
@@ -4,6 +4,8 @@ use serde_columnar::to_vec;
 
 use crate::{ClientID, Counter};
 
+use super::Error;
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct VersionVector {
     pub vv: FxHashMap<ClientID, Counter>,
@@ -28,12 +30,12 @@ impl VersionVector {
         to_vec(&v).unwrap()
     }
 
-    pub fn decode(data: &[u8]) -> VersionVector {
-        let v: Vec<Item> = serde_columnar::from_bytes(data).unwrap();
+    pub fn decode(data: &[u8]) -> Result<VersionVector, Error> {
+        let v: Vec<Item> = serde_columnar::from_bytes(data).map_err(|_| Error::DecodeError)?;
         let mut vv = VersionVector::default();
         for item in v {
             vv.vv.insert(item.client, item.counter);
         }
-        vv
+        Ok(vv)
     }
 }
@@ -0,0 +1,345 @@
+//! Deterministic simulation of peers that sync over an unreliable network.
+//!
+//! Every peer is a [RichText]. The local updates of a peer are sent to the other
+//! peers by [Simulation::broadcast], and the network delivers them after a random
+//! latency, so they may arrive out of order. They can also be duplicated or
+//! dropped, and the messages between the groups of a partition are dropped. All
+//! the randomness comes from one seeded RNG, so a failed run can be replayed with
+//! its seed.
+//!
+//! The dropped messages are only recovered by [Simulation::sync] or
+//! [Simulation::settle], which play the role of the anti-entropy of a real sync
+//! layer. After settling, [Simulation::assert_converged] checks that all the peers
+//! have the same spans and the same version.
+//!
+//! ```ignore
+//! use peritext::sim::{NetworkConfig, Simulation};
+//! use rand::Rng;
+//!
+//! let mut sim = Simulation::new(3, 42, NetworkConfig::unreliable());
+//! for _ in 0..100 {
+//!     let peer = sim.rng().gen_range(0..3);
+//!     sim.random_edit(peer);
+//!     sim.broadcast(peer);
+//!     sim.tick();
+//! }
+//! sim.settle();
+//! sim.assert_converged();
+//! ```
+
+use std::{collections::BTreeMap, ops::Range};
+
+use fxhash::FxHashMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::json;
+
+use crate::{Error, RichText, Style, VersionVector};
+
+/// The behavior of the simulated network
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    /// The range of the latency of a message, in ticks
+    pub latency: Range<u64>,
+    /// Whether the messages between two peers can arrive in a different order
+    /// than they were sent
+    pub reorder: bool,
+    /// The probability that a message is delivered twice
+    pub duplicate_rate: f64,
+    /// The probability that a message is lost
+    pub drop_rate: f64,
+}
+
+impl NetworkConfig {
+    /// Deliver every message once, in order, in the next tick
+    pub fn reliable() -> Self {
+        Self {
+            latency: 1..2,
+            reorder: false,
+            duplicate_rate: 0.0,
+            drop_rate: 0.0,
+        }
+    }
+
+    /// Deliver the messages after 1 to 9 ticks in any order, and duplicate or drop
+    /// a tenth of them
+    pub fn unreliable() -> Self {
+        Self {
+            latency: 1..10,
+            reorder: true,
+            duplicate_rate: 0.1,
+            drop_rate: 0.1,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self::reliable()
+    }
+}
+
+#[derive(Debug)]
+struct Message {
+    from: usize,
+    to: usize,
+    data: Vec<u8>,
+}
+
+/// Peers that edit the same document and sync over a virtual network
+pub struct Simulation {
+    peers: Vec<RichText>,
+    /// The versions of the peers at their last broadcasts
+    sent: Vec<VersionVector>,
+    config: NetworkConfig,
+    rng: StdRng,
+    now: u64,
+    /// The messages in flight, by the delivery tick and the sending order
+    in_flight: BTreeMap<(u64, u64), Message>,
+    next_seq: u64,
+    /// The last delivery tick of each link, so the messages keep their order
+    /// when the network doesn't reorder
+    last_delivery: FxHashMap<(usize, usize), u64>,
+    /// The partition group of each peer
+    groups: Vec<usize>,
+}
+
+impl Simulation {
+    /// Create the peers with the client ids `0..peers`. The RNG is seeded with `seed`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the latency range is empty or a rate is not in `0.0..=1.0`.
+    pub fn new(peers: usize, seed: u64, config: NetworkConfig) -> Self {
+        assert!(!config.latency.is_empty());
+        assert!((0.0..=1.0).contains(&config.duplicate_rate));
+        assert!((0.0..=1.0).contains(&config.drop_rate));
+        Self {
+            peers: (0..peers).map(|i| RichText::new(i as u64)).collect(),
+            sent: vec![VersionVector::default(); peers],
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            now: 0,
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            last_delivery: FxHashMap::default(),
+            groups: vec![0; peers],
+        }
+    }
+
+    pub fn peer(&self, peer: usize) -> &RichText {
+        &self.peers[peer]
+    }
+
+    pub fn peer_mut(&mut self, peer: usize) -> &mut RichText {
+        &mut self.peers[peer]
+    }
+
+    pub fn peers(&self) -> &[RichText] {
+        &self.peers
+    }
+
+    /// The seeded RNG of the simulation. Use it for the app-level randomness too,
+    /// so the whole run is determined by the seed
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// The current tick
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// The number of the messages that are not delivered or dropped yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Send the updates of the peer since its last broadcast to all the other peers
+    pub fn broadcast(&mut self, from: usize) {
+        let version = self.peers[from].version();
+        if version.vv == self.sent[from].vv {
+            return;
+        }
+
+        // the compacted history can only reach the other peers by [Simulation::sync]
+        let mut since = self.sent[from].clone();
+        for (client, trimmed) in self.peers[from].trimmed_version().vv.iter() {
+            let counter = since.vv.entry(*client).or_insert(0);
+            *counter = (*counter).max(*trimmed);
+        }
+
+        let data = self.peers[from].export(&since).unwrap();
+        self.sent[from] = version;
+        for to in 0..self.peers.len() {
+            if to != from {
+                self.send(from, to, data.clone());
+            }
+        }
+    }
+
+    pub fn broadcast_all(&mut self) {
+        for from in 0..self.peers.len() {
+            self.broadcast(from);
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, data: Vec<u8>) {
+        if self.is_partitioned(from, to) || self.rng.gen_bool(self.config.drop_rate) {
+            return;
+        }
+
+        let copies = if self.rng.gen_bool(self.config.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut tick = self.now + self.rng.gen_range(self.config.latency.clone());
+            if !self.config.reorder {
+                let last = self.last_delivery.entry((from, to)).or_insert(0);
+                tick = tick.max(*last);
+                *last = tick;
+            }
+
+            self.in_flight.insert(
+                (tick, self.next_seq),
+                Message {
+                    from,
+                    to,
+                    data: data.clone(),
+                },
+            );
+            self.next_seq += 1;
+        }
+    }
+
+    /// Advance one tick and deliver the messages that arrive by then. The messages
+    /// across a partition are dropped.
+    pub fn tick(&mut self) {
+        self.now += 1;
+        let rest = self.in_flight.split_off(&(self.now + 1, 0));
+        let arrived = std::mem::replace(&mut self.in_flight, rest);
+        for message in arrived.into_values() {
+            if !self.is_partitioned(message.from, message.to) {
+                self.deliver(message.from, message.to, &message.data);
+            }
+        }
+    }
+
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    fn deliver(&mut self, from: usize, to: usize, data: &[u8]) {
+        if let Err(err) = self.peers[to].import(data) {
+            panic!("peer {to} failed to import the updates of peer {from}: {err}");
+        }
+    }
+
+    /// Split the peers into the groups, the messages between different groups are
+    /// dropped. The peers that are not listed form another group.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        self.groups.fill(0);
+        for (i, group) in groups.iter().enumerate() {
+            for &peer in group.iter() {
+                self.groups[peer] = i + 1;
+            }
+        }
+    }
+
+    /// Remove the partition. The messages dropped by it are not resent
+    pub fn heal(&mut self) {
+        self.groups.fill(0);
+    }
+
+    pub fn is_partitioned(&self, a: usize, b: usize) -> bool {
+        self.groups[a] != self.groups[b]
+    }
+
+    /// Send the updates that `to` is missing from `from` directly, without the network.
+    ///
+    /// If `from` compacted the history that `to` is missing, `to` is rebuilt from the
+    /// snapshot of `from` with its own updates imported on top.
+    pub fn sync(&mut self, to: usize, from: usize) {
+        match self.peers[from].export(&self.peers[to].version()) {
+            Ok(data) => self.deliver(from, to, &data),
+            Err(Error::HistoryTrimmed(_)) => {
+                let snapshot = self.peers[from].export_snapshot();
+                let mut peer = RichText::from_snapshot(self.peers[to].id(), &snapshot).unwrap();
+                let local = self.peers[to]
+                    .export(&peer.version())
+                    .expect("the peer compacted the history that the snapshot misses");
+                if let Err(err) = peer.import(&local) {
+                    panic!("peer {to} failed to import its updates on the snapshot of peer {from}: {err}");
+                }
+
+                self.peers[to] = peer;
+            }
+            Err(err) => panic!("peer {from} failed to export the updates for peer {to}: {err}"),
+        }
+    }
+
+    /// Heal the partition, deliver the messages in flight, and then sync all the
+    /// peers with each other, so every update reaches every peer
+    pub fn settle(&mut self) {
+        self.heal();
+        while !self.in_flight.is_empty() {
+            self.tick();
+        }
+
+        for peer in 1..self.peers.len() {
+            self.sync(0, peer);
+        }
+        for peer in 1..self.peers.len() {
+            self.sync(peer, 0);
+        }
+    }
+
+    /// Assert that all the peers have the same spans and the same version, and no
+    /// pending updates
+    pub fn assert_converged(&self) {
+        let first = &self.peers[0];
+        for (i, peer) in self.peers.iter().enumerate() {
+            assert!(!peer.has_pending(), "peer {i} has pending updates");
+            assert_eq!(
+                peer.version().vv,
+                first.version().vv,
+                "the versions of peer {i} and peer 0 differ"
+            );
+            assert_eq!(
+                peer.get_spans(),
+                first.get_spans(),
+                "the spans of peer {i} and peer 0 differ"
+            );
+        }
+    }
+
+    /// Make a random local edit on the peer: insert a short text, or delete or
+    /// annotate a range of at most 5 chars. The text can be multibyte and contain
+    /// line breaks.
+    pub fn random_edit(&mut self, peer: usize) {
+        const TEXTS: [&str; 6] = ["a", "bc", "def", "\n", "你好", "😀"];
+        let text = &mut self.peers[peer];
+        let len = text.len_unicode();
+        let index = self.rng.gen_range(0..=len);
+        let end = (index + self.rng.gen_range(1..=5)).min(len);
+        match self.rng.gen_range(0..4) {
+            _ if index == end => {
+                text.insert_unicode(index, TEXTS[self.rng.gen_range(0..TEXTS.len())]);
+            }
+            0 | 1 => text.insert_unicode(index, TEXTS[self.rng.gen_range(0..TEXTS.len())]),
+            2 => text.delete_unicode(index..end),
+            _ => {
+                let style = match self.rng.gen_range(0..4) {
+                    0 => Style::new_bold_like("bold".into(), json!(true)),
+                    1 => Style::new_erase_bold_like("bold".into()),
+                    2 => Style::new_link_like("link".into(), json!("https://x.com")),
+                    _ => Style::new_comment_like("comment".into(), json!(peer)),
+                };
+                text.annotate_unicode(index..end, style).unwrap();
+            }
+        }
+    }
+}
@@ -15,6 +15,8 @@ enum RichTextError {
         target_index: usize,
         highest_index: usize,
     },
+    #[error(transparent)]
+    Text(#[from] peritext::Error),
 }
 
 pub struct RichText {
@@ -58,8 +60,13 @@ impl RichText {
         self.inner.slice_str(range, IndexType::Utf16).into()
     }
 
-    pub fn annotate(&mut self, range: impl RangeBounds<usize>, formatting: Formatting) {
-        self.inner.annotate(range, formatting.into());
+    pub fn annotate(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        formatting: Formatting,
+    ) -> Result<(), RichTextError> {
+        self.inner.annotate(range, formatting.into())?;
+        Ok(())
     }
 
     pub fn get_spans(&self) -> Vec<Span> {